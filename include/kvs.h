@@ -0,0 +1,53 @@
+/* C API of the kvs key-value store, see src/ffi.rs. Build the shared library with
+ * `cargo rustc --release --lib --features ffi --crate-type cdylib`. */
+
+#ifndef KVS_H
+#define KVS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Returned by every function */
+typedef enum KvsStatus {
+  KVS_OK = 0,
+  KVS_NOT_FOUND = 1,
+  /* A null pointer, or a string that isn't UTF-8 or can't hold the value */
+  KVS_INVALID_ARGUMENT = 2,
+  KVS_IO_ERROR = 3,
+  KVS_SERIALIZATION_ERROR = 4,
+  KVS_ERROR = 5,
+} KvsStatus;
+
+/* Opaque handle on an open store or a connection to a server */
+typedef struct KvsHandle KvsHandle;
+
+/* Opens the store in the directory `path`, creating it if needed. Close it with kvs_close. */
+int kvs_open(const char *path, KvsHandle **out);
+
+/* Same as kvs_open, for a sled database in the directory `path` */
+int kvs_open_sled(const char *path, KvsHandle **out);
+
+/* Handle sending every call to the server at `addr`, given as <ip>:<port>. Connections are made
+ * per call, so this only fails on a malformed address. */
+int kvs_connect(const char *addr, KvsHandle **out);
+
+int kvs_set(const KvsHandle *handle, const char *key, const char *value);
+
+/* Stores the value of `key` in `value_out`, to be freed with kvs_free_string. Returns
+ * KVS_NOT_FOUND and leaves `value_out` null if the key has no value. */
+int kvs_get(const KvsHandle *handle, const char *key, char **value_out);
+
+/* Returns KVS_NOT_FOUND if `key` has no value */
+int kvs_remove(const KvsHandle *handle, const char *key);
+
+void kvs_free_string(char *value);
+
+/* Flushes and closes the store or connection */
+int kvs_close(KvsHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
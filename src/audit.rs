@@ -0,0 +1,165 @@
+//! Append-only log of the writes a server applied and who asked for them.
+//!
+//! The crate has no authentication yet, so writes are attributed to the address of the
+//! connection they came in on. Records are JSON lines spread over numbered files, the oldest of
+//! which are removed once there are more than the log keeps.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::KvRequest;
+use crate::Result;
+
+const AUDIT_PREFIX: &str = "audit-";
+const AUDIT_EXTENSION: &str = "log";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub at: SystemTime,
+    /// Who asked for the write, the address of the client while there is no authentication
+    pub principal: String,
+    /// Idempotency token of the write, if it carried one
+    pub request_id: Option<u64>,
+    /// Kind of write, like set or rm
+    pub op: String,
+    pub key: String,
+}
+
+impl AuditRecord {
+    /// Record of `request` by `principal`, if it is a write to be audited
+    pub fn of<V>(request: &KvRequest<String, V>, principal: String) -> Option<AuditRecord> {
+        let op = match request {
+            KvRequest::Set(_) => "set",
+            KvRequest::SetEx(_) => "setex",
+            KvRequest::SetIf(_) => "setif",
+            KvRequest::Rm(_) => "rm",
+            KvRequest::Merge(_) | KvRequest::FetchMerge(_) => "merge",
+            KvRequest::Lock(_) => "lock",
+            KvRequest::Unlock(_) => "unlock",
+            KvRequest::SetEphemeral(_) => "set_ephemeral",
+            KvRequest::IfVersion { request, .. } => return AuditRecord::of(request, principal),
+            KvRequest::Idempotent { token, request } => {
+                let record = AuditRecord::of(request, principal)?;
+                return Some(AuditRecord {
+                    request_id: Some(*token),
+                    ..record
+                });
+            }
+            _ => return None,
+        };
+        Some(AuditRecord {
+            at: SystemTime::now(),
+            principal,
+            request_id: None,
+            op: op.to_owned(),
+            key: request.key()?.clone(),
+        })
+    }
+}
+
+struct ActiveFile {
+    id: u64,
+    file: File,
+    len: u64,
+}
+
+pub struct AuditLog {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    active: Mutex<ActiveFile>,
+}
+
+fn file_name(id: u64) -> String {
+    format!("{}{:020}.{}", AUDIT_PREFIX, id, AUDIT_EXTENSION)
+}
+
+/// Ids of the audit files in `dir`, oldest first
+fn file_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| {
+            name.strip_prefix(AUDIT_PREFIX)?
+                .strip_suffix(&format!(".{}", AUDIT_EXTENSION))?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+impl AuditLog {
+    /// Appends to the log in `dir`, which is created if missing. A new file is started once the
+    /// current one reaches `max_file_bytes`, and only the latest `max_files` files are kept.
+    pub fn open(dir: &Path, max_file_bytes: u64, max_files: usize) -> Result<AuditLog> {
+        fs::create_dir_all(dir)?;
+        let id = file_ids(dir)?.last().copied().unwrap_or(0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(file_name(id)))?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            dir: dir.to_path_buf(),
+            max_file_bytes,
+            max_files: max_files.max(1),
+            active: Mutex::new(ActiveFile { id, file, len }),
+        })
+    }
+
+    /// Appends `record`, handing it to the OS before returning
+    pub fn append(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut active = self.active.lock()?;
+        if active.len > 0 && active.len + line.len() as u64 > self.max_file_bytes {
+            self.rotate(&mut active)?;
+        }
+        active.file.write_all(&line)?;
+        active.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, active: &mut ActiveFile) -> Result<()> {
+        let id = active.id + 1;
+        active.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(file_name(id)))?;
+        active.id = id;
+        active.len = 0;
+        let ids = file_ids(&self.dir)?;
+        let excess = ids.len().saturating_sub(self.max_files);
+        for old in &ids[..excess] {
+            fs::remove_file(self.dir.join(file_name(*old)))?;
+        }
+        Ok(())
+    }
+
+    /// Hands every record of the log in `dir` to `f`, oldest first. A record cut short by a
+    /// crash ends its file and is skipped.
+    pub fn read(dir: &Path, mut f: impl FnMut(AuditRecord)) -> Result<()> {
+        for id in file_ids(dir)? {
+            let file = match File::open(dir.join(file_name(id))) {
+                Ok(file) => file,
+                // Rotated away while reading
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(record) => f(record),
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+//! Workloads for measuring an engine or a remote server.
+//!
+//! A workload loads a set of keys and then runs a mix of random gets and sets against them
+//! from several pool jobs at once, timing every operation. `compare` runs the same workload
+//! against several engines, to see what an engine costs over another or over the network, it
+//! needs the `engine-kvs`, `engine-sled` and `client` features.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hasher};
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use std::net::SocketAddr;
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use serde::Serialize;
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use crate::client::KvsClient;
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use crate::engine::sled::SledKvsEngine;
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+use crate::engine::store::KvStore;
+use crate::engine::KvsEngine;
+use crate::metrics::{Histogram, Percentiles};
+use crate::thread_pool::ThreadPool;
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    /// Number of distinct keys, all of them are set before timing starts
+    pub keys: usize,
+    /// Length of every value in bytes
+    pub value_size: usize,
+    /// Number of timed operations, spread over the jobs
+    pub operations: usize,
+    /// Share of operations that are gets, the rest are sets
+    pub read_ratio: f64,
+    /// Number of jobs running operations at the same time
+    pub concurrency: u32,
+    /// Picks the values and operations, runs with the same seed and concurrency do the same
+    /// operations. Random if none.
+    pub seed: Option<u64>,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            keys: 10_000,
+            value_size: 100,
+            operations: 100_000,
+            read_ratio: 0.9,
+            concurrency: 8,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub reads: Percentiles,
+    pub writes: Percentiles,
+    /// Wall clock time of the timed operations, without loading the keys
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Operations per second over all jobs
+    pub fn throughput(&self) -> f64 {
+        (self.reads.count + self.writes.count) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} ops in {:?}, {:.0} ops/s",
+            self.reads.count + self.writes.count,
+            self.elapsed,
+            self.throughput()
+        )?;
+        writeln!(f, "reads:  {}", self.reads)?;
+        write!(f, "writes: {}", self.writes)
+    }
+}
+
+/// Small xorshift generator, good enough to pick keys and operations
+struct Rng(u64);
+
+impl Rng {
+    /// Generator `stream` of `seed`, so that every job of a run gets its own
+    fn new(seed: Option<u64>, stream: u64) -> Rng {
+        let state = match seed {
+            Some(seed) => crate::stable_hash(&[seed.to_le_bytes(), stream.to_le_bytes()].concat()),
+            None => RandomState::new().build_hasher().finish(),
+        };
+        // Zero would only ever produce zeros
+        Rng(state | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound.max(1) as u64) as usize
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+fn key(index: usize) -> String {
+    format!("key{:010}", index)
+}
+
+fn value(size: usize, rng: &mut Rng) -> String {
+    (0..size)
+        .map(|_| char::from(b'a' + rng.below(26) as u8))
+        .collect()
+}
+
+/// Loads the keys of `workload` into `engine`, then runs its operations on `pool`
+pub fn run<P, E>(pool: &P, engine: E, workload: &Workload) -> Result<Report>
+where
+    P: ThreadPool,
+    E: KvsEngine<String, String>,
+{
+    let mut rng = Rng::new(workload.seed, 0);
+    for index in 0..workload.keys {
+        engine.set(key(index), value(workload.value_size, &mut rng))?;
+    }
+
+    let concurrency = workload.concurrency.max(1) as usize;
+    let reads = Arc::new(Histogram::default());
+    let writes = Arc::new(Histogram::default());
+    let start = Instant::now();
+    let jobs: Vec<_> = (0..concurrency)
+        .map(|job| {
+            let engine = engine.clone();
+            let workload = workload.clone();
+            let reads = Arc::clone(&reads);
+            let writes = Arc::clone(&writes);
+            // Earlier jobs take the remainder
+            let operations = workload.operations / concurrency
+                + usize::from(job < workload.operations % concurrency);
+            pool.spawn_with_handle(move || -> Result<()> {
+                let mut rng = Rng::new(workload.seed, job as u64 + 1);
+                for _ in 0..operations {
+                    let key = key(rng.below(workload.keys));
+                    if rng.chance(workload.read_ratio) {
+                        let started = Instant::now();
+                        engine.get(key)?;
+                        reads.record(started.elapsed());
+                    } else {
+                        let value = value(workload.value_size, &mut rng);
+                        let started = Instant::now();
+                        engine.set(key, value)?;
+                        writes.record(started.elapsed());
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for job in jobs {
+        job.join()??;
+    }
+    Ok(Report {
+        elapsed: start.elapsed(),
+        reads: reads.percentiles(),
+        writes: writes.percentiles(),
+    })
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+/// Engine `compare` runs a workload against
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// A `KvStore` in the directory
+    Kvs(PathBuf),
+    /// A `SledKvsEngine` in the directory
+    Sled(PathBuf),
+    /// A `KvsClient` of the server at the address, like one on loopback
+    Remote(SocketAddr),
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Kvs(_) => write!(f, "kvs"),
+            Target::Sled(_) => write!(f, "sled"),
+            Target::Remote(addr) => write!(f, "remote {}", addr),
+        }
+    }
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+/// Reports of the same workload run against several targets
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub workload: Workload,
+    pub reports: Vec<(Target, Report)>,
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+/// A report flattened to numbers, latencies in microseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct ComparisonRow {
+    pub target: String,
+    pub operations: u64,
+    pub elapsed_ms: f64,
+    pub throughput: f64,
+    /// Throughput over the throughput of the first target
+    pub relative_throughput: f64,
+    pub read_p50_us: f64,
+    pub read_p99_us: f64,
+    pub read_max_us: f64,
+    pub write_p50_us: f64,
+    pub write_p99_us: f64,
+    pub write_max_us: f64,
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+impl Comparison {
+    pub fn rows(&self) -> Vec<ComparisonRow> {
+        let first = self
+            .reports
+            .first()
+            .map_or(f64::EPSILON, |(_, report)| report.throughput());
+        let micros = |latency: Duration| latency.as_secs_f64() * 1e6;
+        self.reports
+            .iter()
+            .map(|(target, report)| ComparisonRow {
+                target: target.to_string(),
+                operations: report.reads.count + report.writes.count,
+                elapsed_ms: report.elapsed.as_secs_f64() * 1e3,
+                throughput: report.throughput(),
+                relative_throughput: report.throughput() / first,
+                read_p50_us: micros(report.reads.p50),
+                read_p99_us: micros(report.reads.p99),
+                read_max_us: micros(report.reads.max),
+                write_p50_us: micros(report.writes.p50),
+                write_p99_us: micros(report.writes.p99),
+                write_max_us: micros(report.writes.max),
+            })
+            .collect()
+    }
+
+    /// One line per target after a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "target,operations,elapsed_ms,throughput,relative_throughput,read_p50_us,\
+             read_p99_us,read_max_us,write_p50_us,write_p99_us,write_max_us\n",
+        );
+        for row in self.rows() {
+            // Writing to a string can't fail
+            let _ = writeln!(
+                csv,
+                "{},{},{:.3},{:.1},{:.3},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1}",
+                row.target,
+                row.operations,
+                row.elapsed_ms,
+                row.throughput,
+                row.relative_throughput,
+                row.read_p50_us,
+                row.read_p99_us,
+                row.read_max_us,
+                row.write_p50_us,
+                row.write_p99_us,
+                row.write_max_us
+            );
+        }
+        csv
+    }
+
+    /// The rows as a JSON array
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.rows())?)
+    }
+}
+
+#[cfg(all(feature = "engine-kvs", feature = "engine-sled", feature = "client"))]
+/// Runs `workload` against every target in turn, with the same seed for all of them so that
+/// they do the same operations. Kvs and sled targets should start out empty.
+pub fn compare<P: ThreadPool>(
+    pool: &P,
+    targets: &[Target],
+    workload: &Workload,
+) -> Result<Comparison> {
+    let workload = Workload {
+        seed: Some(workload.seed.unwrap_or_else(|| Rng::new(None, 0).next())),
+        ..workload.clone()
+    };
+    let mut reports = Vec::new();
+    for target in targets {
+        let report = match target {
+            Target::Kvs(path) => run(pool, KvStore::open(path)?, &workload)?,
+            Target::Sled(path) => run(pool, SledKvsEngine::new(path)?, &workload)?,
+            Target::Remote(addr) => run(pool, KvsClient::new(*addr), &workload)?,
+        };
+        reports.push((target.clone(), report));
+    }
+    Ok(Comparison { workload, reports })
+}
@@ -0,0 +1,331 @@
+use clap::clap_derive::ArgEnum;
+use clap::{Args, Parser, Subcommand};
+use kvs::audit::AuditLog;
+use kvs::bench::{self, Report, Workload};
+use kvs::client::KvsClient;
+use kvs::config::{ClientConfig, Env};
+use kvs::engine::analyze::AnalyzeOptions;
+use kvs::engine::namespace::NamespaceQuota;
+#[cfg(feature = "engine-sled")]
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::net::parse_addr;
+use kvs::thread_pool::naive::NaiveThreadPool;
+use kvs::thread_pool::priority::PriorityThreadPool;
+use kvs::thread_pool::rayon::RayonThreadPool;
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::work_stealing::WorkStealingThreadPool;
+use kvs::thread_pool::{ThreadPool, ThreadPoolConfig};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Args)]
+struct CompactArgs {
+    /// directory of the store, which must not be in use by a server, the store directory of
+    /// KVS_DATA_DIR or ./db/store if not given
+    #[clap(long, value_parser)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct AnalyzeArgs {
+    /// directory of the store, which must not be in use by a server, the store directory of
+    /// KVS_DATA_DIR or ./db/store if not given
+    #[clap(long, value_parser)]
+    path: Option<PathBuf>,
+    /// number of largest values to list
+    #[clap(long, value_parser, default_value_t = 10)]
+    top: usize,
+    /// keys are grouped by the part before the first occurrence of this
+    #[clap(long, value_parser, default_value_t = ':')]
+    delimiter: char,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum BenchEngine {
+    Kvs,
+    #[cfg(feature = "engine-sled")]
+    Sled,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum BenchPool {
+    SharedQueue,
+    Priority,
+    WorkStealing,
+    Rayon,
+    Naive,
+}
+
+#[derive(Debug, Args)]
+struct BenchArgs {
+    /// directory to create the benchmarked store in
+    #[clap(long, value_parser, default_value = "./bench-db")]
+    path: PathBuf,
+
+    /// benchmark the server at this address instead of a local store
+    #[clap(long, value_parser = parse_addr, conflicts_with = "path")]
+    addr: Option<SocketAddr>,
+
+    /// engine of the local store
+    #[clap(long, value_enum, default_value = "kvs")]
+    engine: BenchEngine,
+
+    /// pool running the operations
+    #[clap(long, value_enum, default_value = "shared-queue")]
+    pool: BenchPool,
+
+    /// number of distinct keys
+    #[clap(long, default_value_t = Workload::default().keys)]
+    keys: usize,
+
+    /// length of every value in bytes
+    #[clap(long, default_value_t = Workload::default().value_size)]
+    value_size: usize,
+
+    /// number of timed operations
+    #[clap(long, default_value_t = Workload::default().operations)]
+    operations: usize,
+
+    /// share of operations that are gets, between 0 and 1
+    #[clap(long, default_value_t = Workload::default().read_ratio)]
+    read_ratio: f64,
+
+    /// operations running at the same time, also the size of the pool
+    #[clap(long, default_value_t = Workload::default().concurrency)]
+    concurrency: u32,
+}
+
+#[derive(Debug, Args)]
+struct StatsArgs {
+    /// address of the server, the first of KVS_ADDR or 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
+    /// also list this many of the keys requested the most of late, with about how many times
+    #[clap(long, default_value_t = 0)]
+    top_keys: u32,
+}
+
+#[derive(Debug, Args)]
+struct QuotaArgs {
+    /// address of the server, which must be started with --namespaces, the first of KVS_ADDR or
+    /// 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
+    /// namespace to limit, the part of keys before the first ':'
+    #[clap(value_parser)]
+    namespace: String,
+    /// most keys the namespace can hold, unlimited if not set
+    #[clap(long, value_parser)]
+    max_keys: Option<u64>,
+    /// most bytes of keys and values the namespace can hold, unlimited if not set
+    #[clap(long, value_parser)]
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+struct LogLevelArgs {
+    /// address of the server, the first of KVS_ADDR or 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
+    /// off, error, warn, info, debug or trace
+    #[clap(value_parser)]
+    level: String,
+}
+
+#[derive(Debug, Args)]
+struct ResumeArgs {
+    /// address of the server, the first of KVS_ADDR or 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+struct ScrubArgs {
+    /// address of the server, the first of KVS_ADDR or 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
+    /// rewrite the damaged keys with their value from another node of the cluster
+    #[clap(long)]
+    repair: bool,
+}
+
+#[derive(Debug, Args)]
+struct AuditArgs {
+    /// directory of the audit log, as given to kvs-server --audit-log
+    #[clap(long, value_parser)]
+    path: PathBuf,
+    /// only list writes to this key
+    #[clap(long)]
+    key: Option<String>,
+    /// only list writes by this principal
+    #[clap(long)]
+    principal: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// merge every segment of a closed store, dropping overwritten values and tombstones
+    Compact(CompactArgs),
+    /// report value sizes, the largest values and key counts per prefix of a closed store
+    Analyze(AnalyzeArgs),
+    /// time a workload of gets and sets against a store or a server
+    Bench(BenchArgs),
+    /// print request latency percentiles of a server
+    Stats(StatsArgs),
+    /// set the quota of a namespace of a server
+    Quota(QuotaArgs),
+    /// change the level of the messages a server logs, without restarting it
+    LogLevel(LogLevelArgs),
+    /// let a server take writes again after a full disk or failing writes stopped them
+    Resume(ResumeArgs),
+    /// read back everything the store of a server keeps on disk to find damaged records
+    Scrub(ScrubArgs),
+    /// list the writes recorded in an audit log, oldest first
+    Audit(AuditArgs),
+}
+
+#[derive(Debug, Parser)] // requires `derive` feature
+#[clap(author, version, about, long_about = None)]
+struct KvAdminArgs {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+fn bench_with_pool<P: ThreadPool>(args: &BenchArgs, workload: &Workload) -> kvs::Result<Report> {
+    let pool = P::with_config(ThreadPoolConfig::new(workload.concurrency).with_name("kvs-bench"))?;
+    match (args.addr, &args.engine) {
+        (Some(addr), _) => bench::run(&pool, KvsClient::new(addr), workload),
+        (None, BenchEngine::Kvs) => bench::run(&pool, KvStore::open(&args.path)?, workload),
+        #[cfg(feature = "engine-sled")]
+        (None, BenchEngine::Sled) => bench::run(&pool, SledKvsEngine::new(&args.path)?, workload),
+    }
+}
+
+fn main() -> kvs::Result<()> {
+    let args = KvAdminArgs::parse();
+    let config = ClientConfig::from_env(&Env::from_process())?;
+    let store_path = |path: Option<PathBuf>| path.unwrap_or_else(|| config.data_dir.join("store"));
+    match args.command {
+        Command::Compact(compact_args) => {
+            KvStore::<String, String>::compact_offline(&store_path(compact_args.path))
+        }
+        Command::Analyze(analyze_args) => {
+            let store = KvStore::<String, String>::open(&store_path(analyze_args.path))?;
+            let report = store.analyze(AnalyzeOptions {
+                prefix_delimiter: analyze_args.delimiter,
+                top: analyze_args.top,
+            })?;
+            println!("{}", report);
+            Ok(())
+        }
+        Command::Bench(bench_args) => {
+            let workload = Workload {
+                keys: bench_args.keys,
+                value_size: bench_args.value_size,
+                operations: bench_args.operations,
+                read_ratio: bench_args.read_ratio,
+                concurrency: bench_args.concurrency,
+                seed: None,
+            };
+            let report = match bench_args.pool {
+                BenchPool::SharedQueue => {
+                    bench_with_pool::<SharedQueueThreadPool>(&bench_args, &workload)
+                }
+                BenchPool::Priority => {
+                    bench_with_pool::<PriorityThreadPool>(&bench_args, &workload)
+                }
+                BenchPool::WorkStealing => {
+                    bench_with_pool::<WorkStealingThreadPool>(&bench_args, &workload)
+                }
+                BenchPool::Rayon => bench_with_pool::<RayonThreadPool>(&bench_args, &workload),
+                BenchPool::Naive => bench_with_pool::<NaiveThreadPool>(&bench_args, &workload),
+            }?;
+            println!("{}", report);
+            Ok(())
+        }
+        Command::Stats(stats_args) => {
+            let client = KvsClient::new(stats_args.addr.unwrap_or(config.addr));
+            let stats = client.stats()?;
+            println!("get:    {}", stats.get);
+            println!("set:    {}", stats.set);
+            println!("remove: {}", stats.remove);
+            println!("other:  {}", stats.other);
+            if let Some(disk_bytes) = stats.disk_bytes {
+                println!("disk:   {} bytes", disk_bytes);
+            }
+            for (namespace, usage) in &stats.namespaces {
+                print!(
+                    "namespace {:?}: {} keys, {} bytes",
+                    namespace, usage.keys, usage.bytes
+                );
+                if let Some(max_keys) = usage.quota.max_keys {
+                    print!(", max {} keys", max_keys);
+                }
+                if let Some(max_bytes) = usage.quota.max_bytes {
+                    print!(", max {} bytes", max_bytes);
+                }
+                println!();
+            }
+            if stats_args.top_keys > 0 {
+                for hot_key in client.top_keys(stats_args.top_keys)? {
+                    println!("hot key {:?}: ~{} requests", hot_key.key, hot_key.count);
+                }
+            }
+            Ok(())
+        }
+        Command::Audit(audit_args) => AuditLog::read(&audit_args.path, |record| {
+            let matches = |filter: &Option<String>, value: &str| {
+                filter.as_ref().is_none_or(|filter| filter == value)
+            };
+            if !matches(&audit_args.key, &record.key)
+                || !matches(&audit_args.principal, &record.principal)
+            {
+                return;
+            }
+            let at = record
+                .at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let request_id = record
+                .request_id
+                .map_or_else(|| "-".to_owned(), |id| id.to_string());
+            println!(
+                "{} {} {} {} {}",
+                at, record.principal, request_id, record.op, record.key
+            );
+        }),
+        Command::LogLevel(log_level_args) => {
+            let client = KvsClient::new(log_level_args.addr.unwrap_or(config.addr));
+            let previous = client.set_log_level(&log_level_args.level)?;
+            println!("{} -> {}", previous, log_level_args.level);
+            Ok(())
+        }
+        Command::Resume(resume_args) => {
+            let client = KvsClient::new(resume_args.addr.unwrap_or(config.addr));
+            match client.resume_writes()? {
+                Some(error) => println!("writes resumed, they were stopped after: {}", error),
+                None => println!("writes weren't stopped"),
+            }
+            Ok(())
+        }
+        Command::Scrub(scrub_args) => {
+            let client = KvsClient::new(scrub_args.addr.unwrap_or(config.addr));
+            match client.scrub(scrub_args.repair)? {
+                Some(report) => println!("{}", report),
+                None => println!("the engine of the server can't be scrubbed"),
+            }
+            Ok(())
+        }
+        Command::Quota(quota_args) => KvsClient::new(quota_args.addr.unwrap_or(config.addr))
+            .set_quota(
+                quota_args.namespace,
+                NamespaceQuota {
+                    max_keys: quota_args.max_keys,
+                    max_bytes: quota_args.max_bytes,
+                },
+            ),
+    }
+}
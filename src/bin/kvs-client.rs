@@ -1,10 +1,13 @@
 use clap::{Args, Parser, Subcommand};
-use kvs::protocol::{KvRequest, KvResponse};
+use kvs::client::KvsClient;
+use kvs::config::{ClientConfig, Env};
+use kvs::engine::SetCondition;
+use kvs::frame::Encoding;
+use kvs::net::parse_addr;
+use kvs::protocol::KvRequest;
+use kvs::watch::WatchEvent;
 use kvs::{KvsError, Result};
-use std::{
-    io::Write,
-    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream},
-};
+use std::{net::SocketAddr, time::Duration};
 
 #[derive(Debug, Args)]
 struct SetArgs {
@@ -13,12 +16,28 @@ struct SetArgs {
 
     /// value to set for the key
     value: String,
+
+    /// seconds after which the key is removed
+    #[clap(long)]
+    ttl: Option<u64>,
+
+    /// only set the key if it has no value
+    #[clap(long, conflicts_with_all = &["ttl", "xx"])]
+    nx: bool,
+
+    /// only set the key if it has a value
+    #[clap(long, conflicts_with = "ttl")]
+    xx: bool,
 }
 
 #[derive(Debug, Args)]
 struct GetArgs {
     /// key to get the value for
     key: String,
+
+    /// print the value along with the seq, time and size of its write, as JSON
+    #[clap(long)]
+    meta: bool,
 }
 
 #[derive(Debug, Args)]
@@ -27,19 +46,73 @@ struct RmArgs {
     key: String,
 }
 
+#[derive(Debug, Args)]
+struct LockArgs {
+    /// name of the lock
+    key: String,
+
+    /// seconds after which the lock is released if it wasn't unlocked
+    #[clap(long, default_value_t = 30)]
+    lease: u64,
+}
+
+#[derive(Debug, Args)]
+struct UnlockArgs {
+    /// name of the lock
+    key: String,
+
+    /// fencing token printed by lock
+    token: u64,
+}
+
+#[derive(Debug, Args)]
+struct WatchArgs {
+    /// key to watch, every key is watched if not given
+    key: Option<String>,
+}
+
 #[derive(Debug, Subcommand)]
 enum Method {
     Set(SetArgs),
     Get(GetArgs),
     Rm(RmArgs),
+    /// print changes to keys as they happen
+    Watch(WatchArgs),
+    /// print every key in ascending order
+    Keys,
+    /// take a lock and print its fencing token
+    Lock(LockArgs),
+    /// release a lock taken with a fencing token
+    Unlock(UnlockArgs),
 }
 
 impl From<Method> for KvRequest<String, String> {
     fn from(m: Method) -> Self {
         match m {
-            Method::Set(set_args) => KvRequest::Set((set_args.key, set_args.value)),
+            Method::Set(set_args) => match (set_args.ttl, set_args.nx, set_args.xx) {
+                (Some(ttl), _, _) => {
+                    KvRequest::SetEx((set_args.key, set_args.value, Duration::from_secs(ttl)))
+                }
+                (None, true, _) => {
+                    KvRequest::SetIf((set_args.key, set_args.value, SetCondition::Absent))
+                }
+                (None, _, true) => {
+                    KvRequest::SetIf((set_args.key, set_args.value, SetCondition::Present))
+                }
+                (None, false, false) => KvRequest::Set((set_args.key, set_args.value)),
+            },
+            Method::Get(get_args) if get_args.meta => KvRequest::GetWithMeta(get_args.key),
             Method::Get(set_args) => KvRequest::Get(set_args.key),
             Method::Rm(set_args) => KvRequest::Rm(set_args.key),
+            Method::Watch(watch_args) => KvRequest::Watch(watch_args.key),
+            Method::Keys => KvRequest::Keys {
+                cursor: None,
+                limit: u32::MAX,
+            },
+            Method::Lock(lock_args) => {
+                KvRequest::Lock((lock_args.key, Duration::from_secs(lock_args.lease)))
+            }
+            Method::Unlock(unlock_args) => KvRequest::Unlock((unlock_args.key, unlock_args.token)),
         }
     }
 }
@@ -51,42 +124,64 @@ struct KvClientArgs {
     #[clap(subcommand)]
     method: Method,
 
-    /// address to connect to the server
-    #[clap(short, long, value_parser, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
-}
+    /// address to connect to the server, the first of KVS_ADDR or 127.0.0.1:4000 if not given
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
 
-fn make_request(
-    command: &KvRequest<String, String>,
-    mut stream: TcpStream,
-) -> Result<KvResponse<String>> {
-    serde_json::to_writer(&mut stream, command)?;
-    stream.write(b"\n\n")?;
-    stream.shutdown(Shutdown::Write)?;
-    let response: KvResponse<String> = serde_json::from_reader(&stream)?;
-    Ok(response)
+    /// send JSON instead of msgpack, for debugging, also turned on by KVS_JSON=true
+    #[clap(long)]
+    json: bool,
 }
 
 fn main() -> Result<()> {
     let args = KvClientArgs::parse();
 
-    let stream = TcpStream::connect(args.addr)?;
+    let config = ClientConfig::from_env(&Env::from_process())?;
+    let encoding = match args.json || config.json {
+        true => Encoding::Json,
+        false => Encoding::Msgpack,
+    };
+    let mut builder = KvsClient::builder()
+        .addr(args.addr.unwrap_or(config.addr))
+        .encoding(encoding);
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    let client = builder.build()?;
+
+    if let Method::Keys = args.method {
+        for key in client.keys() {
+            println!("{}", key?);
+        }
+        return Ok(());
+    }
 
     let server_command: KvRequest<String, String> = args.method.into();
+    if let KvRequest::Watch(key) = server_command {
+        for event in client.watch(key)? {
+            match event? {
+                WatchEvent::Set((key, value)) => println!("set {} {}", key, value),
+                WatchEvent::Removed(key) => println!("rm {}", key),
+                WatchEvent::Expired(expired) => println!("expired {}", expired.key),
+            }
+        }
+        return Ok(());
+    }
 
-    match make_request(&server_command, stream)?.value {
+    let is_get = matches!(
+        server_command,
+        KvRequest::Get(_) | KvRequest::GetWithMeta(_)
+    );
+    match client.request(server_command) {
         Ok(optional_value) => match optional_value {
             Some(val) => {
                 println!("{}", val);
                 Ok(())
             }
             None => {
-                match server_command {
-                    KvRequest::Get(_k) => {
-                        println!("Key not found!");
-                    }
-                    _ => {}
-                };
+                if is_get {
+                    println!("Key not found!");
+                }
                 Ok(())
             }
         },
@@ -96,7 +191,7 @@ fn main() -> Result<()> {
                     eprintln!("Key not found!");
                 }
                 _ => {
-                    eprintln!("{:?}", e);
+                    eprintln!("{}", e);
                 }
             };
             Err(e)
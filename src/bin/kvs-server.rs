@@ -1,40 +1,228 @@
-use clap::clap_derive::ArgEnum;
 use clap::Parser;
 use kvs::{
-    engine::KvsEngine, thread_pool::shared_queue::SharedQueueThreadPool, thread_pool::ThreadPool,
+    audit::{AuditLog, AuditRecord},
+    cluster::{Cluster, ClusterConfig, FileTermStore, Role},
+    config::{
+        parse_key_hasher, parse_peer, parse_sync_policy, Env, KvsEngineType, Peer, ServerConfig,
+    },
+    engine::follower::KvFollower,
+    engine::hasher::KeyHasher,
+    engine::namespace::{NamespacedEngine, Namespaces},
+    engine::scrub::ScrubReport,
+    engine::store::{KvStore, KvStoreOptions, SyncPolicy},
+    engine::{KvsEngine, SetCondition},
+    frame::{self, Compression, Framing},
+    idempotency::IdempotencyCache,
+    merkle::MerkleTree,
+    metrics::{HotKeys, Latency},
+    net::{self, parse_addr, SocketOptions},
+    protocol::{
+        Feature, Handshake, KeysCursor, KeysPage, KvRequest, KvResponse, ServerStats,
+        SnapshotMessage, Topology,
+    },
+    session::Sessions,
+    thread_pool::priority::{Priority, PriorityThreadPool},
+    thread_pool::{ThreadPool, ThreadPoolConfig},
+    txn::{Transactions, PREPARED_PREFIX},
+    values::ValueMerge,
+    watch::{WatchEvent, Watcher},
     KvsError, Result,
 };
+#[cfg(feature = "http")]
+use kvs::{http::HttpGateway, thread_pool::shared_queue::SharedQueueThreadPool};
+#[cfg(feature = "otel")]
+use kvs::{
+    otel::{Metric, OtelConfig},
+    thread_pool::ThreadPoolMetrics,
+};
 use log::*;
-use serde::{Deserialize, Serialize};
 use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    fmt::Display,
     fs::{self, OpenOptions},
+    hash::{BuildHasher, Hasher},
     io::Write,
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
-    path::Path,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+const POOL_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+// Quotas of namespaces, in the data directory
+const QUOTAS_FILE: &str = "quotas.json";
+// Term and vote of this node in the cluster, in the data directory
+const CLUSTER_TERM_FILE: &str = "cluster_term.json";
+// Most keys answered per Keys request, whatever the client asks for
+const MAX_KEYS_PAGE: u32 = 10_000;
+// Pages end early once their keys add up to this many bytes
+const MAX_KEYS_PAGE_BYTES: usize = 1024 * 1024;
+// Entries sent per message of a snapshot
+const SNAPSHOT_BATCH: usize = 256;
+// How often a snapshot stream waiting for changes checks that the replica is still there
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Debug, Clone, ArgEnum, PartialEq, Serialize, Deserialize)]
-pub enum KvsEngineType {
-    Sled,
-    Kvs,
-}
+/// Requests read from a connection, along with the framing of their responses
+type Requests = (Vec<KvRequest<String, String>>, Option<Framing>);
 
+/// Flags override the `KVS_` environment variables, which override the settings of the config
+/// file, see `kvs::config`
 #[derive(Debug, Parser)] // requires `derive` feature
 #[clap(author, version, about, long_about = None)]
 struct KvServerArgs {
-    #[clap(short, long, value_parser, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
+    /// TOML or YAML file to read settings from, YAML for .yaml and .yml files, KVS_CONFIG if not
+    /// given
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    /// address to listen on, can be repeated to listen on several like 0.0.0.0:4000 and [::]:4000,
+    /// 127.0.0.1:4000 if not given here or in the config file
+    #[clap(short, long, value_parser = parse_addr)]
+    addr: Vec<SocketAddr>,
     #[clap(short, long, value_enum)]
     engine: Option<KvsEngineType>,
+    /// directory the store is kept in, ./db by default
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// id of this server within its cluster, required with --peer
+    #[clap(long)]
+    node_id: Option<u64>,
+    /// other member of the cluster as <id>=<addr>, can be repeated
+    #[clap(long, value_parser = parse_peer)]
+    peer: Vec<(u64, SocketAddr)>,
+    /// number of worker threads, 0 starts one per cpu
+    #[clap(long)]
+    threads: Option<u32>,
+    /// when the kvs engine pushes writes to disk: flush, always or buffered
+    #[clap(long, value_parser = parse_sync_policy)]
+    sync_policy: Option<SyncPolicy>,
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector to export spans and metrics to
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otel: Option<SocketAddr>,
+    /// bytes of records the kvs engine may hold before sets fail, 0 disables the limit
+    #[clap(long)]
+    max_disk_bytes: Option<u64>,
+    /// stop instead of repairing damage a crash left in the kvs store, like a torn last record
+    #[clap(long)]
+    no_repair: bool,
+    /// milliseconds a write may wait on compaction or an fsync before a stall warning is logged,
+    /// 0 disables it
+    #[clap(long)]
+    stall_threshold_ms: Option<u64>,
+    /// hash function of the key maps of the kvs engine: siphash, the default, which clients
+    /// can't make collide, ahash, or fx, the fastest, for trusted clients only
+    #[clap(long, value_parser = parse_key_hasher)]
+    key_hasher: Option<KeyHasher>,
+    /// seconds between scrubs reading back the whole store to catch damage and repair it from
+    /// the other nodes of the cluster, 0 disables them
+    #[clap(long)]
+    scrub_interval_secs: Option<u64>,
+    /// account keys and bytes per namespace, the part of keys before the first ':', and enforce
+    /// the quotas set with kvs-admin quota
+    #[clap(long)]
+    namespaces: bool,
+    /// serve reads from the kvs store another server in this directory writes to, lagging it by
+    /// up to this many milliseconds
+    #[clap(long)]
+    follow: Option<u64>,
+    /// directory to record every write in, along with the address of the client asking for it
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    /// size in bytes after which a new audit log file is started, 64MiB by default
+    #[clap(long)]
+    audit_log_file_bytes: Option<u64>,
+    /// number of audit log files kept, older ones are removed, 16 by default
+    #[clap(long)]
+    audit_log_files: Option<usize>,
+    /// disable Nagle's algorithm on client connections, sending answers right away
+    #[clap(long)]
+    nodelay: bool,
+    /// seconds a client connection stays idle before it is probed with TCP keepalives
+    #[clap(long)]
+    keepalive: Option<u64>,
+    /// size in bytes of the kernel receive buffer of client connections
+    #[clap(long)]
+    recv_buffer: Option<usize>,
+    /// size in bytes of the kernel send buffer of client connections
+    #[clap(long)]
+    send_buffer: Option<usize>,
+    /// address to serve the REST gateway on, GET/PUT/DELETE /keys/{key}
+    #[cfg(feature = "http")]
+    #[clap(long)]
+    http: Option<SocketAddr>,
+    /// fraction of requests from 0 to 1 logged at info level along with their key, the others
+    /// are logged at debug level with a hash of the key
+    #[clap(long)]
+    trace_sample_rate: Option<f64>,
     // #[clap(short = 'v', long, parse(from_occurrences))]
     // verbose: usize,
 }
 
+impl KvServerArgs {
+    /// Settings of the config file if given, with the variables of `env` in place of theirs
+    /// and the flags given here in place of those
+    fn into_config(self, env: &Env) -> Result<ServerConfig> {
+        let mut config = match self.config.or(env.get("KVS_CONFIG")?) {
+            Some(path) => ServerConfig::load(&path)?,
+            None => ServerConfig::default(),
+        };
+        config.apply_env(env)?;
+        if !self.addr.is_empty() {
+            config.addr = self.addr;
+        }
+        config.engine = self.engine.or(config.engine);
+        config.data_dir = self.data_dir.unwrap_or(config.data_dir);
+        config.node_id = self.node_id.or(config.node_id);
+        if !self.peer.is_empty() {
+            config.peers = self
+                .peer
+                .into_iter()
+                .map(|(id, addr)| Peer { id, addr })
+                .collect();
+        }
+        config.threads = self.threads.unwrap_or(config.threads);
+        config.sync_policy = self.sync_policy.unwrap_or(config.sync_policy);
+        #[cfg(feature = "otel")]
+        {
+            config.otel = self.otel.or(config.otel);
+        }
+        config.max_disk_bytes = self.max_disk_bytes.unwrap_or(config.max_disk_bytes);
+        config.repair &= !self.no_repair;
+        config.stall_threshold_ms = self.stall_threshold_ms.unwrap_or(config.stall_threshold_ms);
+        config.key_hasher = self.key_hasher.unwrap_or(config.key_hasher);
+        config.scrub_interval_secs = self
+            .scrub_interval_secs
+            .unwrap_or(config.scrub_interval_secs);
+        config.namespaces |= self.namespaces;
+        config.follow = self.follow.or(config.follow);
+        config.audit_log = self.audit_log.or(config.audit_log);
+        config.audit_log_file_bytes = self
+            .audit_log_file_bytes
+            .unwrap_or(config.audit_log_file_bytes);
+        config.audit_log_files = self.audit_log_files.unwrap_or(config.audit_log_files);
+        config.nodelay |= self.nodelay;
+        config.keepalive = self.keepalive.or(config.keepalive);
+        config.recv_buffer = self.recv_buffer.or(config.recv_buffer);
+        config.send_buffer = self.send_buffer.or(config.send_buffer);
+        #[cfg(feature = "http")]
+        {
+            config.http = self.http.or(config.http);
+        }
+        config.trace_sample_rate = self.trace_sample_rate.unwrap_or(config.trace_sample_rate);
+        Ok(config)
+    }
+}
+
 fn parse_kv_config(db_path: &Path, engine: Option<KvsEngineType>) -> Result<KvsEngineType> {
     if !db_path.exists() {
-        fs::create_dir_all(&db_path)?;
+        fs::create_dir_all(db_path)?;
     }
     let config_file_path = db_path.join("config.info");
     if config_file_path.exists() {
@@ -45,14 +233,11 @@ fn parse_kv_config(db_path: &Path, engine: Option<KvsEngineType>) -> Result<KvsE
                 .open(&config_file_path)
                 .unwrap(),
         )?;
-        match engine {
-            Some(e) => {
-                if previous_config != e {
-                    return Err(KvsError::WrongEngine);
-                }
+        if let Some(e) = engine {
+            if previous_config != e {
+                return Err(KvsError::WrongEngine);
             }
-            _ => {}
-        };
+        }
         Ok(previous_config)
     } else {
         let new_config_file = std::fs::File::create(&config_file_path)?;
@@ -62,31 +247,1229 @@ fn parse_kv_config(db_path: &Path, engine: Option<KvsEngineType>) -> Result<KvsE
     }
 }
 
-fn start_listening(addr: SocketAddr, store: impl KvsEngine<String, String>) -> kvs::Result<()> {
+/// Latencies of answered requests by kind, and the keys they were for the most
+#[derive(Debug, Default)]
+struct RequestMetrics {
+    get: Latency,
+    set: Latency,
+    remove: Latency,
+    other: Latency,
+    hot_keys: HotKeys,
+}
+
+impl RequestMetrics {
+    fn latency(&self, request: &KvRequest<String, String>) -> &Latency {
+        match request {
+            KvRequest::Get(_) | KvRequest::GetWithMeta(_) | KvRequest::ReplicaGet { .. } => {
+                &self.get
+            }
+            KvRequest::Set(_) | KvRequest::SetEx(_) | KvRequest::SetIf(_) => &self.set,
+            KvRequest::Rm(_) => &self.remove,
+            KvRequest::Idempotent { request, .. } | KvRequest::IfVersion { request, .. } => {
+                self.latency(request)
+            }
+            _ => &self.other,
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    fn otel_metrics(&self) -> Vec<Metric> {
+        [
+            ("get", &self.get),
+            ("set", &self.set),
+            ("remove", &self.remove),
+            ("other", &self.other),
+        ]
+        .into_iter()
+        .map(|(kind, latency)| Metric::Latency {
+            name: format!("kvs.server.{}_latency", kind),
+            percentiles: latency.percentiles(),
+        })
+        .collect()
+    }
+
+    fn stats(&self) -> ServerStats {
+        ServerStats {
+            get: self.get.percentiles(),
+            set: self.set.percentiles(),
+            remove: self.remove.percentiles(),
+            other: self.other.percentiles(),
+            disk_bytes: None,
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+/// Where to export telemetry to, along with the metrics of the engine
+#[cfg(feature = "otel")]
+struct Telemetry {
+    config: OtelConfig,
+    engine_metrics: Box<dyn Fn() -> Vec<Metric> + Send>,
+}
+
+#[cfg(feature = "otel")]
+fn pool_otel_metrics(metrics: &ThreadPoolMetrics) -> Vec<Metric> {
+    vec![
+        Metric::Gauge {
+            name: "kvs.pool.queued".to_owned(),
+            value: metrics.queued() as i64,
+        },
+        Metric::Gauge {
+            name: "kvs.pool.busy".to_owned(),
+            value: metrics.busy() as i64,
+        },
+        Metric::Counter {
+            name: "kvs.pool.completed".to_owned(),
+            value: metrics.completed(),
+        },
+        Metric::Counter {
+            name: "kvs.pool.panics".to_owned(),
+            value: metrics.panics(),
+        },
+    ]
+}
+
+/// State shared by the threads serving connections
+#[derive(Clone)]
+struct Server<E> {
+    store: E,
+    cluster: Option<Arc<Cluster>>,
+    watcher: Arc<Watcher<String, String>>,
+    idempotency: Arc<IdempotencyCache<String>>,
+    metrics: Arc<RequestMetrics>,
+    // Count of writes applied, for telling whether a key listing saw them all
+    writes: Arc<AtomicU64>,
+    // Last fencing token handed out, held while taking or releasing a lock so that a lock can't
+    // change hands in between
+    locks: Arc<Mutex<u64>>,
+    sessions: Arc<Sessions<String>>,
+    transactions: Arc<Transactions<String, String>>,
+    merkle: Arc<MerkleTree>,
+    namespaces: Option<Namespaces>,
+    audit: Option<Arc<AuditLog>>,
+    // Fraction of requests logged along with their key
+    trace_sample_rate: f64,
+}
+
+impl<E: KvsEngine<String, String>> Server<E> {
+    fn new(store: E, cluster: Option<Arc<Cluster>>, namespaces: Option<Namespaces>) -> Server<E> {
+        Server {
+            store,
+            cluster,
+            watcher: Arc::new(Watcher::default()),
+            idempotency: Arc::new(IdempotencyCache::default()),
+            metrics: Arc::new(RequestMetrics::default()),
+            writes: Arc::new(AtomicU64::new(0)),
+            locks: Arc::new(Mutex::new(0)),
+            sessions: Arc::new(Sessions::default()),
+            transactions: Arc::new(Transactions::default()),
+            merkle: Arc::new(MerkleTree::default()),
+            namespaces,
+            audit: None,
+            trace_sample_rate: 0.0,
+        }
+    }
+
+    /// Records the writes served from now on in `audit`
+    fn with_audit(self, audit: AuditLog) -> Server<E> {
+        Server {
+            audit: Some(Arc::new(audit)),
+            ..self
+        }
+    }
+
+    /// Logs `rate` of the requests served from now on along with their key, see `RequestTrace`
+    fn with_trace_sample_rate(self, rate: f64) -> Server<E> {
+        Server {
+            trace_sample_rate: rate,
+            ..self
+        }
+    }
+
+    fn handle_request(&self, request: KvRequest<String, String>) -> Result<Option<String>> {
+        if let Some(token) = request.idempotency_token() {
+            return self
+                .idempotency
+                .run(token, || self.handle_untracked_request(request));
+        }
+        self.handle_untracked_request(request)
+    }
+
+    fn handle_untracked_request(
+        &self,
+        request: KvRequest<String, String>,
+    ) -> Result<Option<String>> {
+        // Locks and ephemeral keys are made of the writes below, only those get replicated
+        let request = match request {
+            KvRequest::Idempotent { request, .. }
+                if matches!(
+                    *request,
+                    KvRequest::Lock(_) | KvRequest::Unlock(_) | KvRequest::SetEphemeral(_)
+                ) =>
+            {
+                *request
+            }
+            request => request,
+        };
+        match request {
+            KvRequest::Lock((key, lease)) => return self.lock(key, lease),
+            KvRequest::Unlock((key, token)) => return self.unlock(key, token),
+            KvRequest::OpenSession(timeout) => {
+                return Ok(Some(self.sessions.open(timeout).to_string()))
+            }
+            KvRequest::Heartbeat(session) => return self.sessions.heartbeat(session).map(|_| None),
+            // Expired keys can be read until the next expiry pass removes them
+            KvRequest::GetWithMeta(key) if self.watcher.is_expired(&key) => return Ok(None),
+            KvRequest::GetWithMeta(key) => {
+                return match self.store.get_with_meta(key)? {
+                    Some(found) => Ok(Some(serde_json::to_string(&found)?)),
+                    None => Ok(None),
+                }
+            }
+            KvRequest::SetQuota { namespace, quota } => {
+                let namespaces = self
+                    .namespaces
+                    .as_ref()
+                    .ok_or(KvsError::NamespacesDisabled)?;
+                return namespaces.set_quota(namespace, quota).map(|_| None);
+            }
+            KvRequest::CloseSession(session) => {
+                self.remove_ephemeral_keys(self.sessions.close(session)?);
+                return Ok(None);
+            }
+            KvRequest::SetEphemeral((key, value, session)) => {
+                return self.set_ephemeral(key, value, session)
+            }
+            KvRequest::Begin => return Ok(Some(self.transactions.begin().to_string())),
+            KvRequest::TxnGet((txn, key)) => {
+                return match self.transactions.read(txn, &key)? {
+                    Some(KvRequest::Set((_, value))) => Ok(Some(value)),
+                    Some(_) => Ok(None),
+                    None => self.handle_untracked_request(KvRequest::Get(key)),
+                }
+            }
+            KvRequest::TxnWrite { txn, request } => {
+                return self.transactions.write(txn, *request).map(|_| None)
+            }
+            KvRequest::MerkleHashes { level, nodes } => {
+                let hashes = self.merkle.hashes(&self.store, level, &nodes)?;
+                return Ok(Some(serde_json::to_string(&hashes)?));
+            }
+            KvRequest::MerkleLeaf(leaf) => {
+                let entries = self.merkle.entries(&self.store, leaf)?;
+                return Ok(Some(serde_json::to_string(&entries)?));
+            }
+            KvRequest::Prepare(txn) => return self.prepare(txn),
+            KvRequest::Commit(txn) => return self.commit(txn),
+            KvRequest::Abort(txn) => {
+                if self.transactions.abort(txn)? {
+                    self.handle_untracked_request(KvRequest::Rm(prepared_key(txn)))?;
+                }
+                return Ok(None);
+            }
+            _ => {}
+        }
+        match &request {
+            // Expired keys can be read until the next expiry pass removes them
+            KvRequest::ReplicaGet { max_lag, .. } => {
+                let lag = self.cluster.as_ref().map_or(0, |cluster| cluster.lag());
+                if lag > *max_lag {
+                    return Err(KvsError::ReplicaLagging(lag));
+                }
+                if request
+                    .key()
+                    .is_some_and(|key| self.watcher.is_expired(key))
+                {
+                    return Ok(None);
+                }
+            }
+            KvRequest::Get(key) if self.watcher.is_expired(key) => return Ok(None),
+            // Conditions are checked against the engine, so it can't still hold expired keys
+            KvRequest::SetIf((key, _, _)) if self.watcher.is_expired(key) => self.expire_keys(),
+            KvRequest::IfVersion { request, .. }
+                if request
+                    .key()
+                    .is_some_and(|key| self.watcher.is_expired(key)) =>
+            {
+                self.expire_keys()
+            }
+            _ => {}
+        }
+        let _written = request.is_write().then(|| self.transactions.write_guard());
+        self.apply(request)
+    }
+
+    /// Applies `request` to the store or the cluster and notes any write it makes. Writes must
+    /// hold the write guard of the transactions, which commits already do.
+    fn apply(&self, request: KvRequest<String, String>) -> Result<Option<String>> {
+        let applied = request.clone();
+        let result = match (request, self.cluster.as_deref()) {
+            (KvRequest::Cluster(message), Some(cluster)) => {
+                cluster.handle_message(message).map(|_| None)
+            }
+            (
+                KvRequest::Replicate {
+                    term,
+                    leader,
+                    seq,
+                    request,
+                },
+                Some(cluster),
+            ) => cluster.handle_replicate(&self.store, term, leader, seq, *request),
+            (request, Some(cluster)) if request.is_write() => {
+                cluster.handle_write(&self.store, request)
+            }
+            (KvRequest::Get(key), Some(cluster)) => self.get_repairing(cluster, key),
+            (request, _) => request.apply(&self.store),
+        }?;
+        if applied.is_write() || matches!(applied, KvRequest::Replicate { .. }) {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            for key in applied.touched_keys() {
+                self.merkle.written(key);
+            }
+        }
+        if applied.is_write() {
+            for key in applied.touched_keys() {
+                self.sessions.detach(key);
+                self.transactions.written(key);
+            }
+        }
+        self.watcher.applied(&applied);
+        Ok(result)
+    }
+
+    /// Applies the writes of transaction `txn` one after the other. Removing a key that is
+    /// already gone does nothing, so that only failures of the engine stop a commit halfway.
+    fn commit(&self, txn: u64) -> Result<Option<String>> {
+        let mut commit = self.transactions.commit(txn)?;
+        for write in std::mem::take(&mut commit.writes) {
+            match self.apply(write) {
+                Ok(_) | Err(KvsError::NonExistantKey) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if commit.prepared {
+            self.apply(KvRequest::Rm(prepared_key(txn)))?;
+        }
+        Ok(None)
+    }
+
+    /// Prepares transaction `txn`, persisting its writes in the store before answering
+    fn prepare(&self, txn: u64) -> Result<Option<String>> {
+        let writes = self.transactions.prepare(txn)?;
+        let record = serde_json::to_string(&writes)?;
+        if let Err(e) = self.handle_untracked_request(KvRequest::Set((prepared_key(txn), record))) {
+            self.transactions.abort(txn)?;
+            return Err(e);
+        }
+        Ok(None)
+    }
+
+    /// Picks up the transactions that were prepared but neither committed nor aborted before
+    /// the server went down, for their coordinator to finish them
+    fn restore_prepared(&self) -> Result<()> {
+        let mut after = PREPARED_PREFIX.to_owned();
+        loop {
+            let keys = self.store.scan_keys(Some(after.clone()), 100)?;
+            let done = keys.len() < 100;
+            for key in keys {
+                let txn = match key.strip_prefix(PREPARED_PREFIX) {
+                    Some(txn) => txn.parse().map_err(|_| KvsError::InvalidValue)?,
+                    None => return Ok(()),
+                };
+                if let Some(record) = self.store.get(key.clone())? {
+                    info!("Restoring prepared transaction {}", txn);
+                    self.transactions
+                        .restore(txn, serde_json::from_str(&record)?);
+                }
+                after = key;
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sets the key of the lock to a new fencing token unless it is held, and has it expire with
+    /// the lease. Tokens count up from the clock in microseconds, so they keep growing across
+    /// restarts and leader changes as long as clocks roughly agree.
+    fn lock(&self, key: String, lease: Duration) -> Result<Option<String>> {
+        let mut last_token = self.locks.lock()?;
+        let now = SystemTime::now();
+        let clock = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let token = clock.max(*last_token + 1);
+        *last_token = token;
+        let set = KvRequest::SetIf((key.clone(), token.to_string(), SetCondition::Absent));
+        self.handle_untracked_request(set)?;
+        // After the set, which clears any expiry of the key
+        self.watcher.expire_at(key, now + lease);
+        Ok(Some(token.to_string()))
+    }
+
+    /// Removes the key of the lock if it still holds `token`
+    fn unlock(&self, key: String, token: u64) -> Result<Option<String>> {
+        let _locks = self.locks.lock()?;
+        if self.watcher.is_expired(&key) {
+            return Err(KvsError::ConditionNotMet);
+        }
+        match self.store.get(key.clone())? {
+            Some(held) if held == token.to_string() => {
+                match self.handle_untracked_request(KvRequest::Rm(key)) {
+                    // Expired right after the check
+                    Err(KvsError::NonExistantKey) => Err(KvsError::ConditionNotMet),
+                    result => result,
+                }
+            }
+            _ => Err(KvsError::ConditionNotMet),
+        }
+    }
+
+    /// Sets `key` and ties it to `session`
+    fn set_ephemeral(&self, key: String, value: String, session: u64) -> Result<Option<String>> {
+        if !self.sessions.is_open(session) {
+            return Err(KvsError::UnknownSession);
+        }
+        self.handle_untracked_request(KvRequest::Set((key.clone(), value)))?;
+        if let Err(e) = self.sessions.attach(session, key.clone()) {
+            // The session ended while the key was being set
+            self.remove_ephemeral_keys(vec![key]);
+            return Err(e);
+        }
+        Ok(None)
+    }
+
+    fn remove_ephemeral_keys(&self, keys: Vec<String>) {
+        for key in keys {
+            match self.handle_untracked_request(KvRequest::Rm(key.clone())) {
+                Ok(_) | Err(KvsError::NonExistantKey) => debug!("Removed ephemeral key {}", key),
+                Err(e) => warn!("Could not remove ephemeral key {}: {:?}", key, e),
+            }
+        }
+    }
+
+    /// Removes keys whose ttl ran out and tells their subscribers, then the ephemeral keys of
+    /// sessions that went without heartbeats. In a cluster only the leader removes them,
+    /// followers get the removals through replication.
+    fn expire_keys(&self) {
+        let cluster = self.cluster.as_deref();
+        if cluster.is_some_and(|cluster| cluster.role() != Role::Leader) {
+            return;
+        }
+        self.remove_ephemeral_keys(self.sessions.take_expired(SystemTime::now()));
+        for key in self.watcher.expired(SystemTime::now()) {
+            // Writes hold the guard while they update expiries, so the key is only removed if no
+            // write replaced it since it was listed
+            let _written = self.transactions.write_guard();
+            let expired = match self.watcher.take_expired(&key, SystemTime::now()) {
+                Some(expired) => expired,
+                None => continue,
+            };
+            let request = KvRequest::Rm(key);
+            let result = match cluster {
+                Some(cluster) => cluster.handle_write(&self.store, request),
+                None => request.apply(&self.store),
+            };
+            match result {
+                Ok(_) | Err(KvsError::NonExistantKey) => {
+                    debug!("Expired key {}", expired.key);
+                    self.writes.fetch_add(1, Ordering::SeqCst);
+                    self.transactions.written(&expired.key);
+                    self.merkle.written(&expired.key);
+                    self.watcher.publish(&WatchEvent::Expired(expired));
+                }
+                Err(e) => warn!("Could not expire key {}: {:?}", expired.key, e),
+            }
+        }
+    }
+
+    /// Reads the request of a connection, negotiating the protocol first if the client starts
+    /// with a handshake
+    /// Reads the requests of a connection, several of them if the client pipelines them after
+    /// agreeing to `Feature::Batching` in the handshake
+    fn read_requests(&self, s: &TcpStream) -> Result<Requests> {
+        let next_request = |framing| -> Result<Option<KvRequest<String, String>>> {
+            frame::read_message(s, framing)
+        };
+        let mut request = next_request(None)?.ok_or(KvsError::Other)?;
+        let mut framing = None;
+        let mut batching = false;
+        if let KvRequest::Handshake(client) = request {
+            let negotiated = Handshake::new(vec![
+                Feature::Subscriptions,
+                Feature::Compression,
+                Feature::Json,
+                Feature::Batching,
+            ])
+            .with_compression(vec![Compression::Lz4, Compression::Zstd])
+            .negotiate(&client);
+            debug!("Negotiated {:?}", negotiated);
+            frame::write_message(s, &negotiated, None)?;
+            let negotiated = negotiated?;
+            framing = negotiated.framing();
+            batching = negotiated.supports(Feature::Batching);
+            request = next_request(framing)?.ok_or(KvsError::Other)?;
+        }
+        // The requests are the last thing the client sends, read up to the end so closing the
+        // connection doesn't reset it
+        let mut requests = vec![request];
+        while let Some(request) = next_request(framing)? {
+            if !batching {
+                return Err(KvsError::Other);
+            }
+            requests.push(request);
+        }
+        Ok((requests, framing))
+    }
+
+    /// Nodes of the cluster as JSON, `own` being the address the client reached this one at
+    fn topology(&self, own: SocketAddr) -> Result<Option<String>> {
+        let topology = match &self.cluster {
+            Some(cluster) => cluster.topology(own),
+            None => Topology {
+                primary: Some(own),
+                replicas: Vec::new(),
+            },
+        };
+        Ok(Some(serde_json::to_string(&topology)?))
+    }
+
+    /// Reads back the store to find damage done to it, rewriting the damaged keys with their
+    /// value from another node of the cluster if `repair` is set. Answered with the report as
+    /// JSON.
+    fn scrub(&self, repair: bool) -> Result<Option<String>> {
+        let mut report = match self.store.scrub()? {
+            Some(report) => report,
+            None => return Ok(None),
+        };
+        if repair {
+            self.repair_keys(&mut report);
+        }
+        info!(
+            "scrubbed {} files, {} bytes: {} damaged, {} bad keys, {} repaired",
+            report.files,
+            report.bytes,
+            report.damage.len(),
+            report.bad_keys.len(),
+            report.repaired.len()
+        );
+        Ok(Some(serde_json::to_string(&report)?))
+    }
+
+    /// Rewrites the bad keys of `report` with their value on another node of the cluster
+    fn repair_keys(&self, report: &mut ScrubReport<String>) {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => {
+                if !report.bad_keys.is_empty() {
+                    warn!(
+                        "No other node to repair {} keys from",
+                        report.bad_keys.len()
+                    );
+                }
+                return;
+            }
+        };
+        for key in &report.bad_keys {
+            if self
+                .repair_key(cluster, key, &"damage found by a scrub")
+                .is_some()
+            {
+                report.repaired.push(key.clone());
+            }
+        }
+    }
+
+    /// Value of `key`, fetched from another node of the cluster and written back if the record
+    /// of this node turns out to be corrupt
+    fn get_repairing(&self, cluster: &Cluster, key: String) -> Result<Option<String>> {
+        match self.store.get(key.clone()) {
+            Err(e) if matches!(e.root(), KvsError::CorruptRecord(_)) => {
+                self.repair_key(cluster, &key, &e).ok_or(e)
+            }
+            result => result,
+        }
+    }
+
+    /// Writes `key` again with its value on another node of the cluster, after `damage` was
+    /// found in its copy on this node, and returns the value it has now. Only this node is
+    /// written to, the others already have the value. None if it couldn't be repaired.
+    fn repair_key(
+        &self,
+        cluster: &Cluster,
+        key: &String,
+        damage: &dyn Display,
+    ) -> Option<Option<String>> {
+        let value = match cluster.read_from_peers::<String, String>(key) {
+            Some(Some(value)) => value,
+            Some(None) => {
+                warn!(
+                    "Could not repair key {}, the other nodes don't have it",
+                    key
+                );
+                return None;
+            }
+            None => {
+                warn!("Could not repair key {}, no other node answered", key);
+                return None;
+            }
+        };
+        // Atomic batches ask for repairs holding the write guard of the transactions, so only
+        // the cluster write lock is taken. It keeps replicated writes out until the damaged
+        // record is replaced, and those are the only other writes of a cluster node.
+        let repaired = cluster.write_locally(|| match self.store.get(key.clone()) {
+            Err(e) if matches!(e.root(), KvsError::CorruptRecord(_)) => {
+                self.store.set(key.clone(), value.clone()).map(|_| None)
+            }
+            // Written since the value was fetched, which replaced the damaged record
+            current => current.map(Some),
+        });
+        match repaired {
+            Ok(None) => {
+                warn!("Repaired key {} from another node after {}", key, damage);
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                self.merkle.written(key);
+                self.transactions.written(key);
+                self.watcher
+                    .applied(&KvRequest::Set((key.clone(), value.clone())));
+                Some(Some(value))
+            }
+            Ok(Some(current)) => Some(current),
+            Err(e) => {
+                warn!("Could not repair key {}: {:?}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Request latencies along with the disk usage of the engine, as JSON
+    fn stats(&self) -> Result<Option<String>> {
+        let stats = ServerStats {
+            disk_bytes: self.store.disk_usage()?,
+            namespaces: match &self.namespaces {
+                Some(namespaces) => namespaces.stats()?,
+                None => BTreeMap::new(),
+            },
+            ..self.metrics.stats()
+        };
+        Ok(Some(serde_json::to_string(&stats)?))
+    }
+
+    /// Responses to the requests of a `Batch`, as JSON
+    fn batch(
+        &self,
+        s: &TcpStream,
+        requests: Vec<KvRequest<String, String>>,
+        atomic: bool,
+    ) -> Result<Option<String>> {
+        let responses: Vec<KvResponse<String>> = if atomic {
+            self.atomic_batch(requests)?
+                .into_iter()
+                .map(|value| KvResponse { value: Ok(value) })
+                .collect()
+        } else {
+            requests
+                .into_iter()
+                .map(|request| KvResponse {
+                    value: self.answer(s, request),
+                })
+                .collect()
+        };
+        Ok(Some(serde_json::to_string(&responses)?))
+    }
+
+    /// Answers `requests` holding the write guard of the transactions, so that nothing else is
+    /// written meanwhile. Gets see the sets and removes before them, which are then applied
+    /// with a single `WriteBatch`. Removing a key that has no value does nothing.
+    fn atomic_batch(
+        &self,
+        requests: Vec<KvRequest<String, String>>,
+    ) -> Result<Vec<Option<String>>> {
+        let _written = self.transactions.write_guard();
+        let mut writes = Vec::new();
+        // Value of every key written so far, none once removed
+        let mut pending = HashMap::new();
+        let mut values = Vec::with_capacity(requests.len());
+        for request in requests {
+            let value = match request {
+                KvRequest::Get(key) => match pending.get(&key) {
+                    Some(value) => Option::clone(value),
+                    None => self.handle_untracked_request(KvRequest::Get(key))?,
+                },
+                KvRequest::Set((key, value)) => {
+                    pending.insert(key.clone(), Some(value.clone()));
+                    writes.push((key, Some(value)));
+                    None
+                }
+                KvRequest::Rm(key) => {
+                    pending.insert(key.clone(), None);
+                    writes.push((key, None));
+                    None
+                }
+                _ => return Err(KvsError::Other),
+            };
+            values.push(value);
+        }
+        if !writes.is_empty() {
+            self.apply(KvRequest::WriteBatch(writes))?;
+        }
+        Ok(values)
+    }
+
+    /// Keys requested the most of late, as JSON
+    fn top_keys(&self, limit: u32) -> Result<Option<String>> {
+        let top = self.metrics.hot_keys.top_keys(limit as usize);
+        Ok(Some(serde_json::to_string(&top)?))
+    }
+
+    /// Page of keys answering a `Keys` request as JSON, with pages capped at `MAX_KEYS_PAGE` keys
+    /// and `MAX_KEYS_PAGE_BYTES`. Everything needed for the next page goes into its cursor.
+    fn keys_page(&self, cursor: Option<String>, limit: u32) -> Result<Option<String>> {
+        let (after, seq) = match cursor {
+            Some(token) => {
+                let cursor = KeysCursor::<String>::decode(&token)?;
+                (Some(cursor.after), cursor.seq)
+            }
+            None => (None, self.writes.load(Ordering::SeqCst)),
+        };
+        let limit = limit.min(MAX_KEYS_PAGE) as usize;
+        let mut keys = self.store.scan_keys(after, limit)?;
+        let mut full = keys.len() == limit;
+        let mut bytes = 0;
+        if let Some(end) = keys.iter().position(|key| {
+            bytes += key.len();
+            bytes > MAX_KEYS_PAGE_BYTES
+        }) {
+            // Always list a key so that the listing moves on
+            keys.truncate(end.max(1));
+            full = true;
+        }
+        let next = match keys.last() {
+            Some(last) if full => Some(
+                KeysCursor {
+                    after: last.clone(),
+                    seq,
+                }
+                .encode()?,
+            ),
+            _ => None,
+        };
+        let stale = self.writes.load(Ordering::SeqCst) != seq;
+        Ok(Some(serde_json::to_string(&KeysPage {
+            keys,
+            next,
+            stale,
+        })?))
+    }
+
+    /// Answers a request read from `s` or subscribes it to changes
+    fn serve_request(
+        &self,
+        s: TcpStream,
+        request: KvRequest<String, String>,
+        framing: Option<Framing>,
+    ) -> Result<()> {
+        match request {
+            KvRequest::Watch(key) => {
+                debug!("Subscribing to {:?}", key);
+                self.watcher.subscribe(key, s, framing)?;
+            }
+            KvRequest::Snapshot => {
+                debug!("Streaming a snapshot");
+                let store = self.store.clone();
+                thread::spawn(move || {
+                    if let Err(e) = stream_snapshot(store, s, framing) {
+                        debug!("Snapshot stream ended: {:?}", e);
+                    }
+                });
+            }
+            request => self.serve_batch(&s, vec![request], framing)?,
+        }
+        Ok(())
+    }
+
+    /// Answers `requests` in order, writing every response to `s` at once
+    fn serve_batch(
+        &self,
+        s: &TcpStream,
+        requests: Vec<KvRequest<String, String>>,
+        framing: Option<Framing>,
+    ) -> Result<()> {
+        let mut responses = Vec::new();
+        for request in requests {
+            let result = self.answer(s, request);
+            frame::write_message(&mut responses, &KvResponse { value: result }, framing)?;
+        }
+        let mut stream = s;
+        stream.write_all(&responses)?;
+        Ok(())
+    }
+
+    /// Result of a request that is answered with a single response
+    fn answer(&self, s: &TcpStream, request: KvRequest<String, String>) -> Result<Option<String>> {
+        match request {
+            // They take over the connection, which a batch can't give them
+            KvRequest::Watch(_) | KvRequest::Snapshot => Err(KvsError::Other),
+            KvRequest::Topology => self.topology(s.local_addr()?),
+            KvRequest::Stats => self.stats(),
+            KvRequest::SetLogLevel(level) => set_log_level(&level),
+            KvRequest::ResumeWrites => self.store.resume_writes(),
+            KvRequest::Scrub { repair } => self.scrub(repair),
+            KvRequest::Keys { cursor, limit } => self.keys_page(cursor, limit),
+            KvRequest::TopKeys { limit } => self.top_keys(limit),
+            KvRequest::Batch { requests, atomic } => self.batch(s, requests, atomic),
+            // Atomic batches carry the token of their writes, the others one in each write
+            KvRequest::Idempotent { token, request }
+                if matches!(*request, KvRequest::Batch { atomic: true, .. }) =>
+            {
+                self.idempotency.run(token, || self.answer(s, *request))
+            }
+            request => {
+                let trace = RequestTrace::start(&request, self.trace_sample_rate);
+                let latency = self.metrics.latency(&request);
+                if let Some(key) = request.key() {
+                    self.metrics.hot_keys.record(key);
+                }
+                let audit = self.audit.as_ref().and_then(|audit| {
+                    let principal = s.peer_addr().ok()?.to_string();
+                    Some((audit, AuditRecord::of(&request, principal)?))
+                });
+                let result = self.handle_request(request);
+                latency.record(trace.start.elapsed());
+                if let (Some((audit, record)), Ok(_)) = (audit, &result) {
+                    if let Err(e) = audit.append(&record) {
+                        warn!("Could not record {:?} in the audit log: {:?}", record, e);
+                    }
+                }
+                trace.finish(&result);
+                result
+            }
+        }
+    }
+}
+
+/// Span around a request, whose fields are logged once it is answered and exported to
+/// OpenTelemetry when enabled. Keys are only given as a hash, except in the sampled requests, so
+/// that logs can be kept at debug level without filling up with keys. Without a `tracing`
+/// subscriber the events go to the `log` logger, so that `kvs-admin log-level` filters them.
+struct RequestTrace {
+    span: tracing::span::EnteredSpan,
+    op: &'static str,
+    // In hex, or `-` for requests without a key
+    key_hash: String,
+    // Only for sampled requests
+    key: Option<String>,
+    // Of the keys and values sent
+    bytes: usize,
+    start: Instant,
+    #[cfg(feature = "otel")]
+    otel: kvs::otel::Span,
+}
+
+impl RequestTrace {
+    /// Enters the span of `request` until the trace is finished
+    fn start(request: &KvRequest<String, String>, sample_rate: f64) -> RequestTrace {
+        let key = request.key();
+        let sampled = sample_rate > 0.0 && random_fraction() < sample_rate;
+        let op = request_kind(request);
+        let key_hash = key.map_or_else(
+            || "-".to_owned(),
+            |key| format!("{:016x}", kvs::stable_hash(key.as_bytes())),
+        );
+        let span = tracing::debug_span!(
+            "request",
+            op,
+            key_hash = %key_hash,
+            bytes = tracing::field::Empty,
+            latency = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        );
+        RequestTrace {
+            span: span.entered(),
+            op,
+            key_hash,
+            key: key.filter(|_| sampled).cloned(),
+            bytes: key.map_or(0, String::len) + request_value(request).map_or(0, String::len),
+            start: Instant::now(),
+            #[cfg(feature = "otel")]
+            otel: kvs::otel::span("kvs.request").with_attribute("request", op),
+        }
+    }
+
+    fn finish(self, result: &Result<Option<String>>) {
+        let latency = self.start.elapsed();
+        let bytes = self.bytes
+            + result
+                .as_ref()
+                .map_or(0, |value| value.as_ref().map_or(0, String::len));
+        let outcome = match result {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => e.to_string(),
+        };
+        self.span.record("bytes", bytes);
+        self.span.record("latency", tracing::field::debug(latency));
+        self.span.record("outcome", outcome.as_str());
+        match &self.key {
+            Some(key) => tracing::info!(
+                op = %self.op,
+                key = ?key,
+                key_hash = %self.key_hash,
+                bytes,
+                latency = ?latency,
+                outcome = %outcome,
+                "request"
+            ),
+            None => tracing::debug!(
+                op = %self.op,
+                key_hash = %self.key_hash,
+                bytes,
+                latency = ?latency,
+                outcome = %outcome,
+                "request"
+            ),
+        }
+        #[cfg(feature = "otel")]
+        {
+            let mut span = self
+                .otel
+                .with_attribute("key_hash", &self.key_hash)
+                .with_attribute("bytes", bytes);
+            if let Some(key) = &self.key {
+                span = span.with_attribute("key", key);
+            }
+            span.record_result(result);
+        }
+    }
+}
+
+/// Value a request carries, if any
+fn request_value(request: &KvRequest<String, String>) -> Option<&String> {
+    match request {
+        KvRequest::Set((_, value))
+        | KvRequest::SetEx((_, value, _))
+        | KvRequest::SetIf((_, value, _))
+        | KvRequest::Merge((_, value))
+        | KvRequest::FetchMerge((_, value))
+        | KvRequest::SetEphemeral((_, value, _)) => Some(value),
+        KvRequest::Idempotent { request, .. }
+        | KvRequest::IfVersion { request, .. }
+        | KvRequest::Replicate { request, .. }
+        | KvRequest::TxnWrite { request, .. } => request_value(request),
+        _ => None,
+    }
+}
+
+/// Uniformly picked from [0, 1)
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Name of the kind of request, for spans
+fn request_kind(request: &KvRequest<String, String>) -> &'static str {
+    match request {
+        KvRequest::Handshake(_) => "handshake",
+        KvRequest::Set(_) => "set",
+        KvRequest::Rm(_) => "rm",
+        KvRequest::Get(_) => "get",
+        KvRequest::GetWithMeta(_) => "get_with_meta",
+        KvRequest::ReplicaGet { .. } => "replica_get",
+        KvRequest::SetEx(_) => "setex",
+        KvRequest::SetIf(_) => "setif",
+        KvRequest::Merge(_) => "merge",
+        KvRequest::FetchMerge(_) => "fetch_merge",
+        KvRequest::Lock(_) => "lock",
+        KvRequest::Unlock(_) => "unlock",
+        KvRequest::OpenSession(_) => "open_session",
+        KvRequest::Heartbeat(_) => "heartbeat",
+        KvRequest::CloseSession(_) => "close_session",
+        KvRequest::SetEphemeral(_) => "set_ephemeral",
+        KvRequest::SetQuota { .. } => "set_quota",
+        KvRequest::Begin => "begin",
+        KvRequest::TxnGet(_) => "txn_get",
+        KvRequest::TxnWrite { .. } => "txn_write",
+        KvRequest::Prepare(_) => "prepare",
+        KvRequest::Commit(_) => "commit",
+        KvRequest::Abort(_) => "abort",
+        KvRequest::Watch(_) => "watch",
+        KvRequest::Cluster(_) => "cluster",
+        KvRequest::Replicate { .. } => "replicate",
+        KvRequest::Idempotent { request, .. } => request_kind(request),
+        KvRequest::IfVersion { .. } => "if_version",
+        KvRequest::Stats => "stats",
+        KvRequest::Keys { .. } => "keys",
+        KvRequest::TopKeys { .. } => "top_keys",
+        KvRequest::MerkleHashes { .. } => "merkle_hashes",
+        KvRequest::MerkleLeaf(_) => "merkle_leaf",
+        KvRequest::Snapshot => "snapshot",
+        KvRequest::Topology => "topology",
+        KvRequest::SetLogLevel(_) => "set_log_level",
+        KvRequest::ResumeWrites => "resume_writes",
+        KvRequest::Scrub { .. } => "scrub",
+        KvRequest::Batch { .. } => "batch",
+        KvRequest::WriteBatch(_) => "write_batch",
+    }
+}
+
+/// Sends every entry of `store` over `s` followed by every change made after them, until the
+/// other side hangs up or falls too far behind
+fn stream_snapshot(
+    store: impl KvsEngine<String, String>,
+    s: TcpStream,
+    framing: Option<Framing>,
+) -> Result<()> {
+    let (seq, entries) = match store.snapshot() {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return frame::write_message(&s, &KvResponse::<String> { value: Err(e) }, framing)
+        }
+    };
+    frame::write_message(&s, &KvResponse::<String> { value: Ok(None) }, framing)?;
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        let batch: Vec<_> = entries.by_ref().take(SNAPSHOT_BATCH).collect();
+        frame::write_message(&s, &SnapshotMessage::Entries(batch), framing)?;
+    }
+    frame::write_message(
+        &s,
+        &SnapshotMessage::<String, String>::Complete { seq },
+        framing,
+    )?;
+    let mut changes = store.changes(seq)?;
+    loop {
+        let message = match changes.next_timeout(SNAPSHOT_POLL_INTERVAL) {
+            Ok(Some(change)) => SnapshotMessage::Change(change),
+            Ok(None) => {
+                // Nothing is read from the replica, an end of stream means it hung up
+                s.set_nonblocking(true)?;
+                let hung_up = matches!(s.peek(&mut [0]), Ok(0));
+                s.set_nonblocking(false)?;
+                if hung_up {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => {
+                frame::write_message(&s, &SnapshotMessage::<String, String>::Failed(e), framing)?;
+                return Ok(());
+            }
+        };
+        frame::write_message(&s, &message, framing)?;
+    }
+}
+
+/// Key the writes of prepared transaction `txn` are persisted under
+fn prepared_key(txn: u64) -> String {
+    format!("{}{}", PREPARED_PREFIX, txn)
+}
+
+/// Gets, topology requests and cluster messages are answered before anything else waiting,
+/// subscriptions, stats and key listings last
+/// Logs messages at `level` and above from now on, returning the level logged at before
+fn set_log_level(level: &str) -> Result<Option<String>> {
+    let level = LevelFilter::from_str(level).map_err(|_| {
+        KvsError::InvalidConfig(format!(
+            "unknown log level {}, expected off, error, warn, info, debug or trace",
+            level
+        ))
+    })?;
+    let previous = log::max_level();
+    log::set_max_level(level);
+    warn!("log level changed from {} to {}", previous, level);
+    Ok(Some(previous.to_string().to_lowercase()))
+}
+
+fn request_priority(request: &KvRequest<String, String>) -> Priority {
+    match request {
+        KvRequest::Get(_)
+        | KvRequest::GetWithMeta(_)
+        | KvRequest::ReplicaGet { .. }
+        | KvRequest::Cluster(_)
+        | KvRequest::Topology
+        | KvRequest::SetLogLevel(_)
+        | KvRequest::ResumeWrites => Priority::High,
+        KvRequest::Watch(_)
+        | KvRequest::Stats
+        | KvRequest::Keys { .. }
+        | KvRequest::TopKeys { .. }
+        | KvRequest::MerkleHashes { .. }
+        | KvRequest::MerkleLeaf(_)
+        | KvRequest::Snapshot
+        | KvRequest::Scrub { .. } => Priority::Low,
+        _ => Priority::Normal,
+    }
+}
+
+/// Serves the REST gateway on `addr` from a thread of its own
+#[cfg(feature = "http")]
+fn start_http_gateway(addr: SocketAddr, engine: impl KvsEngine<String, String>) -> kvs::Result<()> {
     let listener = TcpListener::bind(addr)?;
-    let thread_pool = SharedQueueThreadPool::new(10)?;
+    let pool = SharedQueueThreadPool::with_config(ThreadPoolConfig::new(0).with_name("kvs-http"))?;
+    info!("serving HTTP on {}", addr);
+    thread::spawn(move || HttpGateway::new(engine).serve(listener, &pool));
+    Ok(())
+}
+
+/// Serves `store`, wrapped to account for namespaces when enabled
+fn serve(
+    config: &ServerConfig,
+    path: &Path,
+    store: impl KvsEngine<String, String>,
+    cluster: Option<Arc<Cluster>>,
+    #[cfg(feature = "otel")] telemetry: Option<Telemetry>,
+) -> kvs::Result<()> {
+    if !config.namespaces {
+        return start_listening(
+            config,
+            store,
+            cluster,
+            None,
+            #[cfg(feature = "otel")]
+            telemetry,
+        );
+    }
+    let store = NamespacedEngine::new(store, Some(&path.join(QUOTAS_FILE)))?;
+    let namespaces = store.namespaces();
+    start_listening(
+        config,
+        store,
+        cluster,
+        Some(namespaces),
+        #[cfg(feature = "otel")]
+        telemetry,
+    )
+}
+
+fn start_listening(
+    config: &ServerConfig,
+    store: impl KvsEngine<String, String>,
+    cluster: Option<Arc<Cluster>>,
+    namespaces: Option<Namespaces>,
+    #[cfg(feature = "otel")] telemetry: Option<Telemetry>,
+) -> kvs::Result<()> {
+    #[cfg(feature = "http")]
+    if let Some(http) = config.http {
+        start_http_gateway(http, store.clone())?;
+    }
+    let mut listeners = config
+        .addr
+        .iter()
+        .map(|addr| net::bind(*addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let thread_pool = Arc::new(PriorityThreadPool::with_config(
+        ThreadPoolConfig::new(config.threads).with_name("kvs-worker"),
+    )?);
+    let mut server = Server::new(store, cluster, namespaces);
+    server.restore_prepared()?;
+    if let Some(dir) = &config.audit_log {
+        let audit = AuditLog::open(dir, config.audit_log_file_bytes, config.audit_log_files)?;
+        server = server.with_audit(audit);
+    }
+    server = server.with_trace_sample_rate(config.trace_sample_rate);
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = telemetry {
+        let request_metrics = Arc::clone(&server.metrics);
+        let pool_metrics = thread_pool.metrics();
+        kvs::otel::start(telemetry.config, move || {
+            let mut metrics = (telemetry.engine_metrics)();
+            metrics.extend(request_metrics.otel_metrics());
+            metrics.extend(pool_otel_metrics(&pool_metrics));
+            metrics
+        });
+    }
+    let metrics = thread_pool.metrics();
+    thread::spawn(move || loop {
+        thread::sleep(POOL_METRICS_INTERVAL);
+        info!(
+            "thread pool: {} queued, {} busy, {} completed, {} panicked",
+            metrics.queued(),
+            metrics.busy(),
+            metrics.completed(),
+            metrics.panics()
+        );
+    });
+    {
+        let server = server.clone();
+        thread::spawn(move || loop {
+            thread::sleep(EXPIRY_INTERVAL);
+            server.expire_keys();
+        });
+    }
+    if config.scrub_interval_secs > 0 {
+        let server = server.clone();
+        let interval = Duration::from_secs(config.scrub_interval_secs);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = server.scrub(true) {
+                warn!("Could not scrub the store: {:?}", e);
+            }
+        });
+    }
+    let options = SocketOptions {
+        nodelay: config.nodelay,
+        keepalive: config.keepalive.map(Duration::from_secs),
+        recv_buffer: config.recv_buffer,
+        send_buffer: config.send_buffer,
+    };
+    // Every address but the last is served from a thread of its own
+    let last = listeners.pop().expect("the config has an address");
+    for listener in listeners {
+        let server = server.clone();
+        let thread_pool = Arc::clone(&thread_pool);
+        let options = options.clone();
+        thread::spawn(move || accept_connections(listener, server, thread_pool, options));
+    }
+    accept_connections(last, server, thread_pool, options);
+    Ok(())
+}
+
+/// Hands the connections accepted on `listener` to the thread pool, with `options` set
+fn accept_connections<E: KvsEngine<String, String>>(
+    listener: TcpListener,
+    server: Server<E>,
+    thread_pool: Arc<PriorityThreadPool>,
+    options: SocketOptions,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("listening on {}", addr);
+    }
     for stream in listener.incoming() {
         match stream {
-            Ok(mut s) => {
-                let store = store.clone();
-                thread_pool.spawn(move || match serde_json::from_reader(&s) {
-                    Ok(deserialized) => {
-                        debug!("Got from stream: {:?}", deserialized);
-                        let result = match deserialized {
-                            kvs::protocol::KvRequest::Set(kv) => {
-                                store.set(kv.0, kv.1).map(|_| None)
-                            }
-                            kvs::protocol::KvRequest::Get(k) => store.get(k),
-                            kvs::protocol::KvRequest::Rm(k) => store.remove(k).map(|_| None),
+            Ok(s) => {
+                if let Err(e) = options.apply(&s) {
+                    warn!("Could not set socket options: {}", e);
+                }
+                let server = server.clone();
+                let pool = Arc::downgrade(&thread_pool);
+                // Requests are read right away, then wait their turn to be answered
+                thread_pool.spawn_with_priority(Priority::High, move || {
+                    let (mut requests, framing) = match server.read_requests(&s) {
+                        Ok(read) => read,
+                        Err(e) => return info!("Could not read request: {:?}", e),
+                    };
+                    // Batches wait for their least urgent request
+                    let priority = requests
+                        .iter()
+                        .map(request_priority)
+                        .max()
+                        .unwrap_or(Priority::Normal);
+                    let serve = move || {
+                        let served = if requests.len() == 1 {
+                            server.serve_request(s, requests.remove(0), framing)
+                        } else {
+                            server.serve_batch(&s, requests, framing)
                         };
-                        debug!("Response from store: {:?}", result);
-                        serde_json::to_writer(&s, &kvs::protocol::KvResponse { value: result })
-                            .unwrap();
-                        s.write(b"\n\n").unwrap();
-                        drop(s);
-                    }
-                    Err(err) => {
-                        info!("Could not parse message: {}", err.to_string());
+                        if let Err(e) = served {
+                            info!("Could not serve connection: {:?}", e);
+                        }
+                    };
+                    match (priority, pool.upgrade()) {
+                        (priority, Some(pool)) if priority != Priority::High => {
+                            pool.spawn_with_priority(priority, serve)
+                        }
+                        _ => serve(),
                     }
                 });
             }
@@ -95,32 +1478,129 @@ fn start_listening(addr: SocketAddr, store: impl KvsEngine<String, String>) -> k
             }
         }
     }
-    Ok(())
 }
 
 fn main() -> kvs::Result<()> {
     stderrlog::new()
         .module(module_path!())
-        .verbosity(2)
+        .module("kvs")
+        // Messages are filtered by the level of the log facade instead, which kvs-admin
+        // log-level changes while the server runs
+        .verbosity(4)
         .init()
         .unwrap();
+    log::set_max_level(LevelFilter::Info);
     warn!("version: {}", VERSION);
 
-    let args = KvServerArgs::parse();
+    let config = KvServerArgs::parse()
+        .into_config(&Env::from_process())
+        .and_then(|config| config.validate().map(|_| config));
+    if let Err(e) = &config {
+        error!("{}", e);
+    }
+    let config = config?;
 
-    info!("configuration: {:?}", args);
+    info!("configuration: {:?}", config);
 
-    let path = Path::new("./db");
+    let path = config.data_dir.as_path();
 
-    let engine = parse_kv_config(path, args.engine)?;
+    let engine = parse_kv_config(path, config.engine)?;
 
     info!("final engine: {:?}", engine);
 
+    let cluster = config
+        .node_id
+        .map(|id| {
+            let peers: HashMap<u64, SocketAddr> = config
+                .peers
+                .iter()
+                .map(|peer| (peer.id, peer.addr))
+                .collect();
+            info!("joining cluster as node {} with peers {:?}", id, peers);
+            let terms = FileTermStore::new(path.join(CLUSTER_TERM_FILE));
+            Cluster::start(ClusterConfig::new(id, peers), Arc::new(terms))
+        })
+        .transpose()?;
+
     match engine {
-        KvsEngineType::Kvs => start_listening(args.addr, kvs::engine::store::KvStore::open(&path.join("store"))?),
-        KvsEngineType::Sled => start_listening(
-            args.addr,
-            kvs::engine::sled::SledKvsEngine::new(&path.join("sled"))?,
-        ), // Need to implement Sled Engine
+        KvsEngineType::Kvs => {
+            let options = KvStoreOptions {
+                sync_policy: config.sync_policy,
+                max_disk_bytes: config.max_disk_bytes,
+                repair: config.repair,
+                stall_threshold: Duration::from_millis(config.stall_threshold_ms),
+                key_hasher: config.key_hasher,
+                ..KvStoreOptions::default()
+            };
+            if let Some(staleness) = config.follow {
+                let follower =
+                    KvFollower::open(&path.join("store"), Duration::from_millis(staleness))?;
+                return serve(
+                    &config,
+                    path,
+                    follower,
+                    None,
+                    #[cfg(feature = "otel")]
+                    config.otel.map(|endpoint| Telemetry {
+                        config: OtelConfig::new(endpoint),
+                        engine_metrics: Box::new(Vec::new),
+                    }),
+                );
+            }
+            let store = match KvStore::open_with_options(&path.join("store"), options) {
+                Ok(store) => store.with_merge_operator(ValueMerge),
+                Err(e) if e.is_recoverable() => {
+                    error!("The store needs repair, start with repair on to fix it");
+                    return Err(e);
+                }
+                Err(e) => {
+                    error!("Could not open the store: {}", e);
+                    return Err(e);
+                }
+            };
+            #[cfg(feature = "otel")]
+            let telemetry = config.otel.map(|endpoint| {
+                let store = store.clone();
+                Telemetry {
+                    config: OtelConfig::new(endpoint),
+                    engine_metrics: Box::new(move || store.stats().otel_metrics()),
+                }
+            });
+            serve(
+                &config,
+                path,
+                store,
+                cluster,
+                #[cfg(feature = "otel")]
+                telemetry,
+            )
+        }
+        #[cfg(not(feature = "engine-sled"))]
+        KvsEngineType::Sled => {
+            error!("This build has no sled engine, it needs the engine-sled feature");
+            Err(KvsError::WrongEngine)
+        }
+        #[cfg(feature = "engine-sled")]
+        KvsEngineType::Sled => {
+            if config.max_disk_bytes > 0 {
+                warn!("max_disk_bytes only applies to the kvs engine");
+            }
+            if config.follow.is_some() {
+                error!("Only the kvs engine can be followed, this directory has a sled engine");
+                return Err(KvsError::WrongEngine);
+            }
+            let sled = kvs::engine::sled::SledKvsEngine::new(&path.join("sled"))?;
+            serve(
+                &config,
+                path,
+                sled,
+                cluster,
+                #[cfg(feature = "otel")]
+                config.otel.map(|endpoint| Telemetry {
+                    config: OtelConfig::new(endpoint),
+                    engine_metrics: Box::new(Vec::new),
+                }),
+            )
+        }
     }
 }
@@ -0,0 +1,1435 @@
+//! Client for a kvs server.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde::de::DeserializeOwned;
+
+use crate::discovery::Discovery;
+use crate::engine::namespace::NamespaceQuota;
+use crate::engine::scrub::ScrubReport;
+use crate::engine::tail::Change;
+use crate::engine::{KvsEngine, SetCondition, ValueMeta};
+use crate::frame::{self, Compression, Encoding};
+use crate::net::SocketOptions;
+use crate::protocol::{
+    Feature, Handshake, HotKey, KeysCursor, KeysPage, KvRequest, KvResponse, ServerStats,
+    SnapshotMessage, Topology,
+};
+use crate::values::{Bitmap, HyperLogLog, List, Map, Set, ValueOp};
+use crate::watch::WatchEvent;
+use crate::{KvsError, Result};
+
+/// Redirects to a cluster leader followed per attempt, so nodes that disagree on the leader
+/// can't bounce a request around forever
+const MAX_REDIRECTS: u32 = 3;
+
+/// How often and how patiently failed requests are retried. Requests are retried when the
+/// connection fails or the cluster is between leaders.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts made after the first one before giving up
+    pub max_retries: u32,
+    /// Longest wait before the first retry, doubled for each retry after it
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gives up on the first failure
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Time to wait before retry number `retry`, counting from 1. It is picked at random up to
+    /// the exponential backoff so that clients failing together don't retry together.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << (retry - 1).min(31))
+            .min(self.max_backoff);
+        Duration::from_nanos(random_u64() % (backoff.as_nanos() as u64).max(1))
+    }
+}
+
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// `request` tagged with a new idempotency token if it is a write without one, so that it is
+/// only applied once however many times it is sent
+fn with_idempotency_token(request: KvRequest<String, String>) -> KvRequest<String, String> {
+    if request.is_write() && request.idempotency_token().is_none() {
+        KvRequest::Idempotent {
+            token: random_u64(),
+            request: Box::new(request),
+        }
+    } else {
+        request
+    }
+}
+
+/// Whether a request that failed with `e` may succeed if sent again
+fn is_retryable(e: &KvsError) -> bool {
+    matches!(
+        e.root(),
+        KvsError::IOError(_)
+            | KvsError::SerializationError(_)
+            | KvsError::NotLeader(None)
+            | KvsError::NoQuorum
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct KvsClient {
+    addr: SocketAddr,
+    json: bool,
+    retry_policy: RetryPolicy,
+    cache: Option<Arc<ReadCache>>,
+    replicas: Vec<SocketAddr>,
+    // Nodes learned from the cluster, shared with clones, overriding the ones given
+    topology: Arc<RwLock<Option<Topology>>>,
+    timeout: Option<Duration>,
+    discovery: Option<Arc<Discovery>>,
+    socket_options: SocketOptions,
+    // Codecs offered in the handshake, in order of preference
+    compression: Vec<Compression>,
+}
+
+impl KvsClient {
+    pub fn new(addr: SocketAddr) -> KvsClient {
+        KvsClient {
+            addr,
+            json: false,
+            retry_policy: RetryPolicy::default(),
+            cache: None,
+            replicas: Vec::new(),
+            topology: Arc::new(RwLock::new(None)),
+            timeout: None,
+            discovery: None,
+            socket_options: SocketOptions::default(),
+            compression: vec![Compression::Lz4, Compression::Zstd],
+        }
+    }
+
+    /// Builder for clients configured beyond the address, which checks the settings together
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            addr: None,
+            discovery: None,
+            replicas: Vec::new(),
+            timeout: None,
+            retry_policy: RetryPolicy::default(),
+            socket_options: SocketOptions::default(),
+            encoding: Encoding::Msgpack,
+            compression: vec![Compression::Lz4, Compression::Zstd],
+            cache: None,
+        }
+    }
+
+    /// Client for the servers `name` resolves to, see `Discovery`. The topology is refreshed
+    /// from them, resolved again at most every `refresh_interval`, whenever the primary fails.
+    pub fn discover(name: &str, refresh_interval: Duration) -> Result<KvsClient> {
+        let discovery = Discovery::new(name, refresh_interval)?;
+        let addrs = discovery.addrs();
+        Ok(KvsClient {
+            discovery: Some(Arc::new(discovery)),
+            ..KvsClient::new(addrs[0]).with_replicas(addrs[1..].to_vec())
+        })
+    }
+
+    /// Address of the server this client sends requests to first, the primary of the cluster
+    /// as last learned or the address the client was made with
+    pub fn addr(&self) -> SocketAddr {
+        match &*self.topology.read().unwrap() {
+            Some(Topology {
+                primary: Some(primary),
+                ..
+            }) => *primary,
+            _ => self.addr,
+        }
+    }
+
+    /// Address the client was made with, whatever primary it learned of since
+    pub fn given_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Replicas reads accepting staleness go to, as last learned or as given
+    pub fn replicas(&self) -> Vec<SocketAddr> {
+        match &*self.topology.read().unwrap() {
+            Some(topology) => topology.replicas.clone(),
+            None => self.replicas.clone(),
+        }
+    }
+
+    /// Asks the known nodes in turn for the topology of the cluster until one answers, and
+    /// sends requests to the primary it names from then on. Failing requests do this on their
+    /// own before they are retried.
+    pub fn refresh_topology(&self) -> Result<Topology> {
+        let mut nodes = vec![self.addr()];
+        nodes.extend(self.replicas());
+        nodes.push(self.addr);
+        nodes.extend(&self.replicas);
+        if let Some(discovery) = &self.discovery {
+            nodes.extend(discovery.addrs());
+        }
+        let mut seen = HashSet::new();
+        nodes.retain(|node| seen.insert(*node));
+        let mut error = KvsError::Other;
+        for node in nodes {
+            let answer = self
+                .connect(node, self.timeout)
+                .and_then(|connection| connection.request(&KvRequest::Topology))
+                .and_then(|response| response.value?.ok_or(KvsError::Other));
+            match answer.and_then(|topology| Ok(serde_json::from_str::<Topology>(&topology)?)) {
+                Ok(topology) => {
+                    debug!("learned topology {:?} from {}", topology, node);
+                    *self.topology.write().unwrap() = Some(topology.clone());
+                    return Ok(topology);
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Caches the results of up to `capacity` gets, shared with clones of this client. The
+    /// cache watches every key on the server to drop entries that change, and is bypassed
+    /// while it isn't watching.
+    pub fn with_cache(mut self, capacity: usize) -> KvsClient {
+        let cache = Arc::new(ReadCache::new(capacity));
+        let watching = KvsClient {
+            cache: None,
+            ..self.clone()
+        };
+        let weak = Arc::downgrade(&cache);
+        thread::spawn(move || watching.keep_cache(weak));
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Followers of the cluster led by the server this client is for, which reads that accept
+    /// some staleness are spread over
+    pub fn with_replicas(mut self, replicas: Vec<SocketAddr>) -> KvsClient {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Gives up on connecting, sending or receiving after `timeout`, counting the server as
+    /// failed. Subscriptions and snapshots wait on as long as it takes.
+    pub fn with_timeout(mut self, timeout: Duration) -> KvsClient {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `options` on every connection to the servers, like `TCP_NODELAY` for small requests
+    pub fn with_socket_options(mut self, options: SocketOptions) -> KvsClient {
+        self.socket_options = options;
+        self
+    }
+
+    /// Sends JSON instead of msgpack, for debugging
+    pub fn with_json(mut self, json: bool) -> KvsClient {
+        self.json = json;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> KvsClient {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn connect(&self, addr: SocketAddr, timeout: Option<Duration>) -> Result<Connection> {
+        Connection::open(
+            addr,
+            self.json,
+            timeout,
+            &self.socket_options,
+            &self.compression,
+        )
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.request(KvRequest::Get(key)),
+        };
+        let generation = match cache.lookup(&key) {
+            Lookup::Hit(value) => return Ok(value),
+            Lookup::Miss(generation) => generation,
+            Lookup::Unavailable => return self.request(KvRequest::Get(key)),
+        };
+        let value = self.request(KvRequest::Get(key.clone()))?;
+        cache.insert(key, value.clone(), generation);
+        Ok(value)
+    }
+
+    /// Gets `key` along with the seq, time and size of the write of its value, bypassing the
+    /// cache. The meta is none if the engine of the server doesn't keep track of it.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<(String, Option<ValueMeta>)>> {
+        match self.request(KvRequest::GetWithMeta(key))? {
+            Some(found) => Ok(Some(serde_json::from_str(&found)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets `key` from a replica picked at random, as long as it is at most `max_lag` writes
+    /// behind the leader. Falls back to `get` when the replica lags further or can't be reached.
+    pub fn get_with_max_lag(&self, key: String, max_lag: u64) -> Result<Option<String>> {
+        let replicas = self.replicas();
+        if replicas.is_empty() {
+            return self.get(key);
+        }
+        let replica = KvsClient {
+            addr: replicas[(random_u64() % replicas.len() as u64) as usize],
+            retry_policy: RetryPolicy::none(),
+            cache: None,
+            replicas: Vec::new(),
+            topology: Arc::new(RwLock::new(None)),
+            ..self.clone()
+        };
+        let request = KvRequest::ReplicaGet {
+            key: key.clone(),
+            max_lag,
+        };
+        match replica.send(request) {
+            Err(KvsError::ReplicaLagging(lag)) => {
+                debug!("replica {} is {} writes behind", replica.addr, lag);
+                self.get(key)
+            }
+            Err(KvsError::IOError(e)) => {
+                debug!("replica {} unreachable: {}", replica.addr, e);
+                self.get(key)
+            }
+            result => result,
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.request(KvRequest::Set((key, value))).map(|_| ())
+    }
+
+    /// Sets `key` and has the server remove it once `ttl` runs out
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.request(KvRequest::SetEx((key, value, ttl)))
+            .map(|_| ())
+    }
+
+    /// Sets `key` if `condition` holds on the server, returning whether it was set
+    pub fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        match self.request(KvRequest::SetIf((key, value, condition))) {
+            Ok(_) => Ok(true),
+            Err(KvsError::ConditionNotMet) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets `key` if it is at `expected_version` on the server, or has no value when none is
+    /// expected, returning whether it was set. Versions come from `get_with_meta`.
+    pub fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: Option<u64>,
+    ) -> Result<bool> {
+        self.if_version(KvRequest::Set((key, value)), expected_version)
+    }
+
+    /// Removes `key` if it is at `expected_version` on the server, see `set_if_version`
+    pub fn remove_if_version(&self, key: String, expected_version: Option<u64>) -> Result<bool> {
+        self.if_version(KvRequest::Rm(key), expected_version)
+    }
+
+    fn if_version(
+        &self,
+        request: KvRequest<String, String>,
+        expected_version: Option<u64>,
+    ) -> Result<bool> {
+        let request = KvRequest::IfVersion {
+            expected_version,
+            request: Box::new(request),
+        };
+        match self.request(request) {
+            Ok(_) => Ok(true),
+            Err(KvsError::ConditionNotMet) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Begins an optimistic transaction, whose writes are held back by the server until it
+    /// commits
+    pub fn begin(&self) -> Result<Transaction> {
+        let id = self.request(KvRequest::Begin)?.ok_or(KvsError::Other)?;
+        let id = id
+            .parse()
+            .map_err(|_| KvsError::SerializationError(format!("bad transaction id {}", id)))?;
+        Ok(Transaction {
+            client: self.clone(),
+            id,
+            written: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Has the server merge `operand` into the value of `key`, see `values::ValueOp`
+    pub fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.request(KvRequest::Merge((key, operand))).map(|_| ())
+    }
+
+    /// Same as `merge`, returning the value `key` had right before
+    pub fn fetch_merge(&self, key: String, operand: String) -> Result<Option<String>> {
+        self.request(KvRequest::FetchMerge((key, operand)))
+    }
+
+    /// Adds `by` to the counter of `key`
+    pub fn incr(&self, key: String, by: i64) -> Result<()> {
+        self.merge(key, ValueOp::Incr(by).encode()?)
+    }
+
+    /// Adds `items` to the HyperLogLog sketch of `key`
+    pub fn pfadd(&self, key: String, items: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::PfAdd(items).encode()?)
+    }
+
+    /// Estimate of the distinct items added to the sketch of `key`
+    pub fn pfcount(&self, key: String) -> Result<u64> {
+        match self.get(key)? {
+            Some(hll) => Ok(HyperLogLog::decode(&hll)?.count()),
+            None => Ok(0),
+        }
+    }
+
+    pub fn setbit(&self, key: String, bit: u64, value: bool) -> Result<()> {
+        self.merge(key, ValueOp::SetBit((bit, value)).encode()?)
+    }
+
+    pub fn getbit(&self, key: String, bit: u64) -> Result<bool> {
+        match self.get(key)? {
+            Some(bitmap) => Ok(Bitmap::decode(&bitmap)?.get(bit)),
+            None => Ok(false),
+        }
+    }
+
+    /// Pushes `items` to the front of the list of `key`, each in turn
+    pub fn lpush(&self, key: String, items: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::LPush(items).encode()?)
+    }
+
+    /// Pushes `items` to the back of the list of `key`
+    pub fn rpush(&self, key: String, items: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::RPush(items).encode()?)
+    }
+
+    /// Takes the item at the front of the list of `key` off, none if it is empty
+    pub fn lpop(&self, key: String) -> Result<Option<String>> {
+        let list = self.fetch_merge(key, ValueOp::LPop.encode()?)?;
+        Ok(list
+            .map(|list| List::decode(&list))
+            .transpose()?
+            .and_then(|mut list| list.items.pop_front()))
+    }
+
+    /// Takes the item at the back of the list of `key` off, none if it is empty
+    pub fn rpop(&self, key: String) -> Result<Option<String>> {
+        let list = self.fetch_merge(key, ValueOp::RPop.encode()?)?;
+        Ok(list
+            .map(|list| List::decode(&list))
+            .transpose()?
+            .and_then(|mut list| list.items.pop_back()))
+    }
+
+    pub fn sadd(&self, key: String, members: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::SAdd(members).encode()?)
+    }
+
+    pub fn srem(&self, key: String, members: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::SRem(members).encode()?)
+    }
+
+    /// Members of the set of `key` in ascending order
+    pub fn smembers(&self, key: String) -> Result<Vec<String>> {
+        match self.get(key)? {
+            Some(set) => Ok(Set::decode(&set)?.members.into_iter().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Sets `field` of the map of `key`, sending only the field and not the whole map
+    pub fn hset(&self, key: String, field: String, value: String) -> Result<()> {
+        self.merge(key, ValueOp::HSet((field, value)).encode()?)
+    }
+
+    pub fn hget(&self, key: String, field: String) -> Result<Option<String>> {
+        Ok(self.hgetall(key)?.remove(&field))
+    }
+
+    pub fn hdel(&self, key: String, fields: Vec<String>) -> Result<()> {
+        self.merge(key, ValueOp::HDel(fields).encode()?)
+    }
+
+    /// Every field of the map of `key`
+    pub fn hgetall(&self, key: String) -> Result<BTreeMap<String, String>> {
+        match self.get(key)? {
+            Some(map) => Ok(Map::decode(&map)?.fields),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Sets `key` unless it has a value on the server
+    pub fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, SetCondition::Absent)
+    }
+
+    /// Sets `key` only if it has a value on the server
+    pub fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, SetCondition::Present)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.request(KvRequest::Rm(key)).map(|_| ())
+    }
+
+    /// Opens a session on the server ending once it goes without a heartbeat for `timeout`,
+    /// returning its id
+    pub fn open_session(&self, timeout: Duration) -> Result<u64> {
+        let id = self
+            .request(KvRequest::OpenSession(timeout))?
+            .ok_or(KvsError::Other)?;
+        id.parse()
+            .map_err(|_| KvsError::SerializationError(format!("bad session id {}", id)))
+    }
+
+    /// Keeps session `session` alive for another timeout
+    pub fn heartbeat(&self, session: u64) -> Result<()> {
+        self.request(KvRequest::Heartbeat(session)).map(|_| ())
+    }
+
+    /// Ends session `session`, removing its ephemeral keys
+    pub fn close_session(&self, session: u64) -> Result<()> {
+        self.request(KvRequest::CloseSession(session)).map(|_| ())
+    }
+
+    /// Sets `key` until session `session` ends, unless it is set or removed before
+    pub fn set_ephemeral(&self, key: String, value: String, session: u64) -> Result<()> {
+        self.request(KvRequest::SetEphemeral((key, value, session)))
+            .map(|_| ())
+    }
+
+    /// Opens a session that a thread keeps alive by sending heartbeats three times per `timeout`
+    /// until it is closed or dropped
+    pub fn session(&self, timeout: Duration) -> Result<Session> {
+        let id = self.open_session(timeout)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let client = self.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout / 3) {
+                match client.heartbeat(id) {
+                    Ok(()) => {}
+                    Err(KvsError::UnknownSession) => break,
+                    Err(e) => debug!("Heartbeat of session {} failed: {:?}", id, e),
+                }
+            }
+        });
+        Ok(Session {
+            client: self.clone(),
+            id,
+            stop: Some(stop),
+        })
+    }
+
+    /// Takes the lock named `key` until `lease` runs out, returning its fencing token, or none
+    /// while someone else holds it. Tokens of later holders of a lock are always greater, so
+    /// whatever the lock guards can turn away writes made with an older token.
+    pub fn lock(&self, key: String, lease: Duration) -> Result<Option<u64>> {
+        match self.request(KvRequest::Lock((key, lease))) {
+            Ok(token) => {
+                let token = token.ok_or(KvsError::Other)?;
+                let token = token
+                    .parse()
+                    .map_err(|_| KvsError::SerializationError(format!("bad token {}", token)))?;
+                Ok(Some(token))
+            }
+            Err(KvsError::ConditionNotMet) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Releases the lock named `key` taken with `token`, returning false if the lease ran out
+    /// first
+    pub fn unlock(&self, key: String, token: u64) -> Result<bool> {
+        match self.request(KvRequest::Unlock((key, token))) {
+            Ok(_) => Ok(true),
+            Err(KvsError::ConditionNotMet) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Request latency percentiles of the server
+    pub fn stats(&self) -> Result<ServerStats> {
+        let stats = self.request(KvRequest::Stats)?.ok_or(KvsError::Other)?;
+        Ok(serde_json::from_str(&stats)?)
+    }
+
+    /// Up to `n` of the keys the server was asked for most often of late, most requested first
+    pub fn top_keys(&self, n: u32) -> Result<Vec<HotKey>> {
+        let top = self
+            .request(KvRequest::TopKeys { limit: n })?
+            .ok_or(KvsError::Other)?;
+        Ok(serde_json::from_str(&top)?)
+    }
+
+    /// Has the server log messages at `level` and above, like debug while looking into an
+    /// incident, returning the level it logged at before
+    pub fn set_log_level(&self, level: &str) -> Result<String> {
+        self.request(KvRequest::SetLogLevel(level.to_owned()))?
+            .ok_or(KvsError::Other)
+    }
+
+    /// Lets the server take writes again after its store stopped them, like once space was
+    /// freed on a full disk. Returns the error that stopped them, none if they weren't.
+    pub fn resume_writes(&self) -> Result<Option<String>> {
+        self.request(KvRequest::ResumeWrites)
+    }
+
+    /// Has the server read back everything its store keeps on disk, and rewrite the damaged keys
+    /// with their value from another node of the cluster if `repair` is set. None if its engine
+    /// can't tell.
+    pub fn scrub(&self, repair: bool) -> Result<Option<ScrubReport<String>>> {
+        match self.request(KvRequest::Scrub { repair })? {
+            Some(report) => Ok(Some(serde_json::from_str(&report)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the quota of `namespace`, on servers started with namespaces accounted for
+    pub fn set_quota(&self, namespace: String, quota: NamespaceQuota) -> Result<()> {
+        self.request(KvRequest::SetQuota { namespace, quota })
+            .map(|_| ())
+    }
+
+    /// Up to `limit` keys in ascending order from `cursor`, the `next` cursor of the previous
+    /// page, or from the first key. The server may return fewer keys than asked for.
+    pub fn keys_page(&self, cursor: Option<String>, limit: u32) -> Result<KeysPage<String>> {
+        let page = self
+            .request(KvRequest::Keys { cursor, limit })?
+            .ok_or(KvsError::Other)?;
+        Ok(serde_json::from_str(&page)?)
+    }
+
+    /// Hashes of `nodes` at `level` of the server's Merkle tree, see `merkle::MerkleTree`
+    pub fn merkle_hashes(&self, level: u32, nodes: Vec<u64>) -> Result<Vec<u64>> {
+        let hashes = self
+            .request(KvRequest::MerkleHashes { level, nodes })?
+            .ok_or(KvsError::Other)?;
+        Ok(serde_json::from_str(&hashes)?)
+    }
+
+    /// Keys and values under `leaf` of the server's Merkle tree
+    pub fn merkle_leaf(&self, leaf: u64) -> Result<Vec<(String, String)>> {
+        let entries = self
+            .request(KvRequest::MerkleLeaf(leaf))?
+            .ok_or(KvsError::Other)?;
+        Ok(serde_json::from_str(&entries)?)
+    }
+
+    /// Every key of the server in ascending order, fetched a page at a time as the iterator
+    /// is consumed
+    pub fn keys(&self) -> Keys {
+        Keys {
+            client: self.clone(),
+            page: Vec::new().into_iter(),
+            next: None,
+            done: false,
+        }
+    }
+
+    /// Sends `request`, following redirects to the cluster leader and retrying failures as the
+    /// retry policy allows. Writes get an idempotency token first, so that a retry of a write
+    /// that reached the server before the failure isn't applied twice.
+    pub fn request(&self, request: KvRequest<String, String>) -> Result<Option<String>> {
+        let written = match (&self.cache, request.is_write()) {
+            (Some(_), true) => request.key().cloned(),
+            _ => None,
+        };
+        let result = self.send(request);
+        // Don't wait for the watch event to stop serving the old value to this client
+        if let (Some(cache), Some(key)) = (&self.cache, written) {
+            cache.invalidate(&key);
+        }
+        result
+    }
+
+    /// Sends `requests` over a single connection and returns their results in order, the server
+    /// answering them all in one write. Requests aren't retried nor redirected to the leader, and
+    /// subscriptions and snapshots fail as they need a connection of their own.
+    pub fn pipeline(
+        &self,
+        requests: Vec<KvRequest<String, String>>,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let connection = self.connect(self.addr(), self.timeout)?;
+        if !connection.negotiated.supports(Feature::Batching) {
+            return Err(KvsError::UnsupportedFeature(Feature::Batching));
+        }
+        connection.send_all(&requests)?;
+        let mut results = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let response: KvResponse<String> = connection.receive()?.ok_or(KvsError::Other)?;
+            if let (Some(cache), true) = (&self.cache, request.is_write()) {
+                if let Some(key) = request.key() {
+                    cache.invalidate(key);
+                }
+            }
+            results.push(response.value);
+        }
+        Ok(results)
+    }
+
+    /// Sends `requests` as one `KvRequest::Batch`, answered in a single round trip with their
+    /// results in order. With `atomic`, only gets, sets and removes can be batched and the
+    /// writes are applied all together or not at all, the batch failing as a whole if any
+    /// request fails. Removing a key that has no value then does nothing. Batches are retried,
+    /// and their writes only applied once, but only atomic ones are redirected to the leader of
+    /// a cluster.
+    pub fn batch(
+        &self,
+        requests: Vec<KvRequest<String, String>>,
+        atomic: bool,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        let written: Vec<String> = requests
+            .iter()
+            .filter(|request| request.is_write())
+            .filter_map(|request| request.key().cloned())
+            .collect();
+        // Atomic batches apply their writes together, so they carry a single token for them
+        let request = if !atomic {
+            let requests = requests.into_iter().map(with_idempotency_token).collect();
+            KvRequest::Batch { requests, atomic }
+        } else if requests.iter().any(KvRequest::is_write) {
+            KvRequest::Idempotent {
+                token: random_u64(),
+                request: Box::new(KvRequest::Batch { requests, atomic }),
+            }
+        } else {
+            KvRequest::Batch { requests, atomic }
+        };
+        let result = self.send(request);
+        if let Some(cache) = &self.cache {
+            for key in &written {
+                cache.invalidate(key);
+            }
+        }
+        let responses: Vec<KvResponse<String>> =
+            serde_json::from_str(&result?.ok_or(KvsError::Other)?)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| response.value)
+            .collect())
+    }
+
+    fn send(&self, request: KvRequest<String, String>) -> Result<Option<String>> {
+        let request = with_idempotency_token(request);
+        let mut addr = self.addr();
+        let mut retries = 0;
+        let mut redirects = 0;
+        loop {
+            let response = self
+                .connect(addr, self.timeout)
+                .and_then(|connection| connection.request(&request));
+            let error = match response {
+                Ok(KvResponse {
+                    value: Err(KvsError::NotLeader(Some(leader))),
+                }) if redirects < MAX_REDIRECTS => {
+                    // Writes to a cluster have to go through its leader
+                    addr = leader;
+                    redirects += 1;
+                    continue;
+                }
+                Ok(KvResponse { value: Err(e) }) if is_retryable(&e) => e,
+                Ok(response) => return response.value,
+                Err(e) if is_retryable(&e) => e,
+                Err(e) => return Err(e),
+            };
+            if retries >= self.retry_policy.max_retries {
+                return Err(error);
+            }
+            retries += 1;
+            redirects = 0;
+            thread::sleep(self.retry_policy.backoff(retries));
+            // The primary may have failed over, retries go to the one the cluster knows of.
+            // Writes carry their idempotency token, so a write the failed primary applied
+            // isn't applied again.
+            if let KvsError::IOError(_) | KvsError::NotLeader(None) = error {
+                if let Err(e) = self.refresh_topology() {
+                    debug!("could not refresh topology: {:?}", e);
+                }
+            }
+            addr = self.addr();
+        }
+    }
+
+    /// Subscribes to changes to `key`, or to every key if not given
+    pub fn watch(&self, key: Option<String>) -> Result<Subscription> {
+        let connection = self.connect(self.addr(), None)?;
+        if !connection.negotiated.supports(Feature::Subscriptions) {
+            return Err(KvsError::UnsupportedFeature(Feature::Subscriptions));
+        }
+        connection.send(&KvRequest::Watch(key))?;
+        connection
+            .receive::<KvResponse<String>>()?
+            .ok_or(KvsError::Other)?
+            .value?;
+        Ok(Subscription { connection })
+    }
+
+    /// Keeps `cache` in sync with the server until it is dropped, resubscribing whenever the
+    /// subscription is lost
+    /// Streams every key and value of the server followed by the changes made after them, see
+    /// `SnapshotStream`
+    pub fn snapshot(&self) -> Result<SnapshotStream> {
+        let connection = self.connect(self.addr(), None)?;
+        if !connection.negotiated.supports(Feature::Subscriptions) {
+            return Err(KvsError::UnsupportedFeature(Feature::Subscriptions));
+        }
+        connection.send(&KvRequest::Snapshot)?;
+        connection
+            .receive::<KvResponse<String>>()?
+            .ok_or(KvsError::Other)?
+            .value?;
+        Ok(SnapshotStream { connection })
+    }
+
+    fn keep_cache(&self, cache: Weak<ReadCache>) {
+        let mut failures = 0;
+        loop {
+            match self.watch(None) {
+                Ok(subscription) => {
+                    failures = 0;
+                    match cache.upgrade() {
+                        Some(cache) => cache.start(&subscription.connection.stream),
+                        None => return,
+                    }
+                    for event in subscription {
+                        match (event, cache.upgrade()) {
+                            (Ok(event), Some(cache)) => cache.invalidate(event.key()),
+                            _ => break,
+                        }
+                    }
+                    match cache.upgrade() {
+                        Some(cache) => cache.stop(),
+                        None => return,
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not watch for cache invalidations: {:?}", e);
+                    if cache.strong_count() == 0 {
+                        return;
+                    }
+                    failures += 1;
+                }
+            }
+            thread::sleep(self.retry_policy.backoff(failures.max(1)));
+        }
+    }
+}
+
+/// Settings of a `KvsClient`, see `KvsClient::builder`. Nothing is checked until `build`.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    addr: Option<SocketAddr>,
+    discovery: Option<(String, Duration)>,
+    replicas: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    socket_options: SocketOptions,
+    encoding: Encoding,
+    compression: Vec<Compression>,
+    cache: Option<usize>,
+}
+
+impl ClientBuilder {
+    /// Server requests go to first
+    pub fn addr(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Finds the servers through `name` instead of an address, see `KvsClient::discover`
+    pub fn discover(mut self, name: &str, refresh_interval: Duration) -> ClientBuilder {
+        self.discovery = Some((name.to_owned(), refresh_interval));
+        self
+    }
+
+    /// See `KvsClient::with_replicas`
+    pub fn replicas(mut self, replicas: Vec<SocketAddr>) -> ClientBuilder {
+        self.replicas = replicas;
+        self
+    }
+
+    /// See `KvsClient::with_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> ClientBuilder {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn socket_options(mut self, options: SocketOptions) -> ClientBuilder {
+        self.socket_options = options;
+        self
+    }
+
+    /// Encoding of frame bodies, msgpack unless set
+    pub fn encoding(mut self, encoding: Encoding) -> ClientBuilder {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Codecs offered to servers in order of preference, none to never compress. Both lz4 and
+    /// zstd are offered unless set.
+    pub fn compression(mut self, compression: Vec<Compression>) -> ClientBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// See `KvsClient::with_cache`
+    pub fn cache(mut self, capacity: usize) -> ClientBuilder {
+        self.cache = Some(capacity);
+        self
+    }
+
+    /// Checks the settings, failing with `KvsError::InvalidConfig` on the first that can't be
+    /// used, and makes the client
+    pub fn build(self) -> Result<KvsClient> {
+        self.validate()?;
+        let client = match (self.addr, &self.discovery) {
+            (Some(addr), None) => KvsClient::new(addr).with_replicas(self.replicas),
+            (None, Some((name, refresh_interval))) => KvsClient::discover(name, *refresh_interval)?,
+            _ => unreachable!("validated"),
+        };
+        let client = KvsClient {
+            json: self.encoding == Encoding::Json,
+            retry_policy: self.retry_policy,
+            timeout: self.timeout,
+            socket_options: self.socket_options,
+            compression: self.compression,
+            ..client
+        };
+        Ok(match self.cache {
+            Some(capacity) => client.with_cache(capacity),
+            None => client,
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |problem: &str| Err(KvsError::InvalidConfig(problem.to_owned()));
+        match (self.addr, &self.discovery) {
+            (None, None) => return invalid("either an address or a name to discover is needed"),
+            (Some(_), Some(_)) => return invalid("an address and a name to discover are given"),
+            (None, Some(_)) if !self.replicas.is_empty() => {
+                return invalid("replicas are given along with a name to discover them by")
+            }
+            _ => {}
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            return invalid("the timeout is zero");
+        }
+        if self.retry_policy.initial_backoff > self.retry_policy.max_backoff {
+            return invalid("the initial backoff is longer than the max backoff");
+        }
+        let compression = &self.compression;
+        if (1..compression.len()).any(|i| compression[..i].contains(&compression[i])) {
+            return invalid("a compression codec is given twice");
+        }
+        if self.cache == Some(0) {
+            return invalid("the cache has no room");
+        }
+        Ok(())
+    }
+}
+
+enum Lookup {
+    Hit(Option<String>),
+    /// Not cached, the generation has to be passed back when inserting the value
+    Miss(u64),
+    /// The cache isn't watching the server, so it can't be trusted
+    Unavailable,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    /// Results of gets, including keys that didn't exist
+    entries: HashMap<String, Option<String>>,
+    // Keys from oldest to newest
+    order: VecDeque<String>,
+    /// Bumped on every invalidation, so that a get that raced with one doesn't cache what it read
+    generation: u64,
+    watching: bool,
+}
+
+#[derive(Debug)]
+struct ReadCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    // Connection of the subscription keeping the cache in sync, shut down when the cache is
+    // dropped so that the thread reading it stops
+    subscription: Mutex<Option<TcpStream>>,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                generation: 0,
+                watching: false,
+            }),
+            subscription: Mutex::new(None),
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Lookup {
+        let state = self.state.lock().unwrap();
+        if !state.watching {
+            return Lookup::Unavailable;
+        }
+        match state.entries.get(key) {
+            Some(value) => Lookup::Hit(value.clone()),
+            None => Lookup::Miss(state.generation),
+        }
+    }
+
+    /// Caches the value read for `key` unless something was invalidated since `generation`
+    fn insert(&self, key: String, value: Option<String>, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation || state.entries.contains_key(&key) {
+            return;
+        }
+        if state.order.len() >= self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.entries.insert(key.clone(), value);
+        state.order.push_back(key);
+    }
+
+    fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        if state.entries.remove(key).is_some() {
+            state.order.retain(|k| k != key);
+        }
+    }
+
+    fn start(&self, stream: &TcpStream) {
+        *self.subscription.lock().unwrap() = stream.try_clone().ok();
+        self.reset(true);
+    }
+
+    fn stop(&self) {
+        self.reset(false);
+    }
+
+    fn reset(&self, watching: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.order.clear();
+        state.generation += 1;
+        state.watching = watching;
+    }
+}
+
+impl Drop for ReadCache {
+    fn drop(&mut self) {
+        if let Some(stream) = self.subscription.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Session opened by `KvsClient::session`, closed when dropped
+pub struct Session {
+    client: KvsClient,
+    id: u64,
+    // Dropped to stop the heartbeat thread
+    stop: Option<mpsc::Sender<()>>,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Sets `key` until the session ends, unless it is set or removed before
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.client.set_ephemeral(key, value, self.id)
+    }
+
+    /// Ends the session, removing its ephemeral keys
+    pub fn close(mut self) -> Result<()> {
+        self.stop.take();
+        self.client.close_session(self.id)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.stop.take().is_some() {
+            if let Err(e) = self.client.close_session(self.id) {
+                debug!("Could not close session {}: {:?}", self.id, e);
+            }
+        }
+    }
+}
+
+/// Transaction begun by `KvsClient::begin`, aborted when dropped unless committed
+pub struct Transaction {
+    client: KvsClient,
+    id: u64,
+    written: Vec<String>,
+    done: bool,
+}
+
+impl Transaction {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Value of `key` as this transaction sees it, with its own writes applied
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.client.request(KvRequest::TxnGet((self.id, key)))
+    }
+
+    /// Sets `key` once the transaction commits
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(KvRequest::Set((key, value)))
+    }
+
+    /// Removes `key` once the transaction commits
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.write(KvRequest::Rm(key))
+    }
+
+    fn write(&mut self, request: KvRequest<String, String>) -> Result<()> {
+        if let Some(key) = request.key() {
+            self.written.push(key.clone());
+        }
+        self.client
+            .request(KvRequest::TxnWrite {
+                txn: self.id,
+                request: Box::new(request),
+            })
+            .map(|_| ())
+    }
+
+    /// Checks the transaction and has the server persist its writes, after which committing it
+    /// can't conflict. Used to commit transactions spanning servers in two phases.
+    pub fn prepare(&mut self) -> Result<()> {
+        let result = self.client.request(KvRequest::Prepare(self.id)).map(|_| ());
+        // A transaction failing its checks is gone from the server
+        self.done = matches!(
+            result,
+            Err(KvsError::Conflict) | Err(KvsError::UnknownTransaction)
+        );
+        result
+    }
+
+    /// Applies the writes of the transaction. Fails with `KvsError::Conflict` if a key it read
+    /// was written since, in which case the whole transaction can be run again.
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        let result = self.client.request(KvRequest::Commit(self.id)).map(|_| ());
+        if let Some(cache) = &self.client.cache {
+            for key in &self.written {
+                cache.invalidate(key);
+            }
+        }
+        result
+    }
+
+    pub fn abort(mut self) -> Result<()> {
+        self.done = true;
+        self.client.request(KvRequest::Abort(self.id)).map(|_| ())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Err(e) = self.client.request(KvRequest::Abort(self.id)) {
+                debug!("Could not abort transaction {}: {:?}", self.id, e);
+            }
+        }
+    }
+}
+
+/// Changes to watched keys, ends when the server closes the connection
+pub struct Subscription {
+    connection: Connection,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<WatchEvent<String, String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.connection.receive().transpose()
+    }
+}
+
+/// Snapshot of a server streamed over a connection, the entries first and then the changes made
+/// after them for as long as the stream is kept
+pub struct SnapshotStream {
+    connection: Connection,
+}
+
+impl SnapshotStream {
+    /// Writes every entry of the snapshot to `engine`, returning the seq changes continue from
+    pub fn restore(&mut self, engine: &impl KvsEngine<String, String>) -> Result<u64> {
+        loop {
+            match self.next().ok_or(KvsError::Other)?? {
+                SnapshotMessage::Entries(entries) => {
+                    for (key, value) in entries {
+                        engine.set(key, value)?;
+                    }
+                }
+                SnapshotMessage::Complete { seq } => return Ok(seq),
+                SnapshotMessage::Failed(e) => return Err(e),
+                SnapshotMessage::Change(_) => return Err(KvsError::Other),
+            }
+        }
+    }
+
+    /// Applies the changes following the snapshot to `engine` as they come, returning once the
+    /// stream ends
+    pub fn follow(&mut self, engine: &impl KvsEngine<String, String>) -> Result<()> {
+        for message in self {
+            let event = match message? {
+                SnapshotMessage::Change(event) => event,
+                SnapshotMessage::Failed(e) => return Err(e),
+                _ => return Err(KvsError::Other),
+            };
+            match event.change {
+                Change::Set((key, value)) => engine.set(key, value)?,
+                Change::Removed(key) => match engine.remove(key) {
+                    Ok(()) | Err(KvsError::NonExistantKey) => {}
+                    Err(e) => return Err(e),
+                },
+                Change::Merged((key, operand)) => engine.merge(key, operand)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SnapshotStream {
+    type Item = Result<SnapshotMessage<String, String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.connection.receive().transpose()
+    }
+}
+
+/// Connection to the server along with what was agreed on in the handshake
+struct Connection {
+    stream: TcpStream,
+    negotiated: Handshake,
+}
+
+impl Connection {
+    /// Connects to `addr`, giving up on any step that takes longer than `timeout` if given
+    fn open(
+        addr: SocketAddr,
+        json: bool,
+        timeout: Option<Duration>,
+        options: &SocketOptions,
+        compression: &[Compression],
+    ) -> Result<Connection> {
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        options.apply(&stream)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let mut features = vec![Feature::Subscriptions, Feature::Batching];
+        if !compression.is_empty() {
+            features.push(Feature::Compression);
+        }
+        if json {
+            features.push(Feature::Json);
+        }
+        let handshake = Handshake::new(features).with_compression(compression.to_vec());
+        frame::write_message(
+            &stream,
+            &KvRequest::<String, String>::Handshake(handshake),
+            None,
+        )?;
+        let negotiated =
+            frame::read_message::<Result<Handshake>>(&stream, None)?.ok_or(KvsError::Other)??;
+        Ok(Connection { stream, negotiated })
+    }
+
+    fn send(&self, request: &KvRequest<String, String>) -> Result<()> {
+        self.send_all(std::slice::from_ref(request))
+    }
+
+    /// Sends `requests` in a single write, more than one needs `Feature::Batching`
+    fn send_all(&self, requests: &[KvRequest<String, String>]) -> Result<()> {
+        let mut buffer = Vec::new();
+        for request in requests {
+            frame::write_message(&mut buffer, request, self.negotiated.framing())?;
+        }
+        (&self.stream).write_all(&buffer)?;
+        self.stream.shutdown(Shutdown::Write)?;
+        Ok(())
+    }
+
+    fn receive<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        frame::read_message(&self.stream, self.negotiated.framing())
+    }
+
+    fn request(self, request: &KvRequest<String, String>) -> Result<KvResponse<String>> {
+        self.send(request)?;
+        self.receive()?.ok_or(KvsError::Other)
+    }
+}
+
+// Keys asked for per request by `Keys`
+const KEYS_PAGE_SIZE: u32 = 1000;
+
+/// Iterator over the keys of a server returned by `KvsClient::keys`
+pub struct Keys {
+    client: KvsClient,
+    page: std::vec::IntoIter<String>,
+    next: Option<String>,
+    done: bool,
+}
+
+impl Iterator for Keys {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        loop {
+            if let Some(key) = self.page.next() {
+                return Some(Ok(key));
+            }
+            if self.done {
+                return None;
+            }
+            match self.client.keys_page(self.next.take(), KEYS_PAGE_SIZE) {
+                Ok(page) => {
+                    self.done = page.next.is_none();
+                    self.next = page.next;
+                    self.page = page.keys.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Lets anything written against an engine run against a server instead
+impl KvsEngine<String, String> for KvsClient {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        KvsClient::set_if(self, key, value, condition)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, Option<ValueMeta>)>> {
+        KvsClient::get_with_meta(self, key)
+    }
+
+    fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: Option<u64>,
+    ) -> Result<bool> {
+        KvsClient::set_if_version(self, key, value, expected_version)
+    }
+
+    fn remove_if_version(&self, key: String, expected_version: Option<u64>) -> Result<bool> {
+        KvsClient::remove_if_version(self, key, expected_version)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        KvsClient::merge(self, key, operand)
+    }
+
+    fn fetch_merge(&self, key: String, operand: String) -> Result<Option<String>> {
+        KvsClient::fetch_merge(self, key, operand)
+    }
+
+    /// Sent as an atomic batch of sets and removes
+    fn write_batch(&self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let requests = writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => KvRequest::Set((key, value)),
+                None => KvRequest::Rm(key),
+            })
+            .collect();
+        self.batch(requests, true).map(|_| ())
+    }
+
+    /// Scans past `after` start from a cursor made up on the spot rather than one from a server
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let limit = limit.min(u32::MAX as usize) as u32;
+        let cursor = match after {
+            Some(after) => Some(KeysCursor { after, seq: 0 }.encode()?),
+            None => None,
+        };
+        Ok(self.keys_page(cursor, limit)?.keys)
+    }
+}
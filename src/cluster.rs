@@ -0,0 +1,635 @@
+//! A simplified Raft for running several kvs servers as one cluster.
+//!
+//! Nodes elect a leader with randomized election timeouts and term based voting, and the leader
+//! keeps its followers from starting elections with heartbeats. Writes are only accepted by the
+//! leader, which forwards them to its followers with the regular request protocol and only
+//! applies them itself once a majority of the cluster has them. Unlike full Raft there is no log
+//! matching, so a node that missed writes while it was down doesn't catch up on them.
+//!
+//! Nodes only vote for candidates that applied writes at least as far as they did, so that a
+//! node that missed writes a majority had can't be elected and lose them. The term and vote of a
+//! node are saved in a `TermStore` before it answers, so that it can't vote twice in a term
+//! across a restart. How far a node applied writes is only kept in memory though, a restarted
+//! node votes like one that applied none until it gets a write from the leader.
+//!
+//! Writes are numbered by the leader, and heartbeats carry the number of the latest one, so that
+//! followers can tell how many writes they are behind and refuse reads past a staleness bound.
+//! Only writes missed within a term are noticed, a new leader's first write is taken as the new
+//! starting point.
+//!
+//! Time and messages go through a `Clock` and a `Transport`, real ones unless the cluster is
+//! run by the `sim` module.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::engine::KvsEngine;
+use crate::protocol::{ClusterMessage, KvRequest, KvResponse, Topology};
+use crate::{KvsError, Result};
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Id of this node, unique within the cluster
+    pub id: u64,
+    /// Ids and addresses of the other nodes
+    pub peers: HashMap<u64, SocketAddr>,
+    /// Minimum time without hearing from a leader before starting an election, the actual
+    /// timeout is randomized between one and two times this
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+}
+
+impl ClusterConfig {
+    pub fn new(id: u64, peers: HashMap<u64, SocketAddr>) -> ClusterConfig {
+        ClusterConfig {
+            id,
+            peers,
+            election_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Source of time for the election and heartbeat timers
+pub trait Clock: Send + Sync {
+    /// Time since a fixed start
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clock of the machine
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Carries messages between nodes
+pub trait Transport: Send + Sync {
+    /// Sends a JSON encoded request to the node at `addr` and returns its JSON encoded response,
+    /// failing with an IO error if the node can't be reached within `timeout`
+    fn call(&self, addr: SocketAddr, request: &[u8], timeout: Duration) -> Result<Vec<u8>>;
+}
+
+/// A connection per message, speaking the plain JSON protocol
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn call(&self, addr: SocketAddr, request: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout * 5))?;
+        stream.write_all(request)?;
+        stream.write_all(b"\n\n")?;
+        stream.shutdown(Shutdown::Write)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+}
+
+/// Term of a node and the candidate it voted for in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TermVote {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+}
+
+/// Keeps the term and vote of a node across restarts
+pub trait TermStore: Send + Sync {
+    /// Term and vote last saved, the first term without a vote if none was
+    fn load(&self) -> Result<TermVote>;
+    /// Returns once `term_vote` would survive a crash
+    fn save(&self, term_vote: TermVote) -> Result<()>;
+}
+
+/// Term and vote kept as JSON in a file, replaced whole on every save
+pub struct FileTermStore {
+    path: PathBuf,
+}
+
+impl FileTermStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileTermStore {
+        FileTermStore { path: path.into() }
+    }
+}
+
+impl TermStore for FileTermStore {
+    fn load(&self) -> Result<TermVote> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TermVote::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, term_vote: TermVote) -> Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&serde_json::to_vec(&term_vote)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Term and vote kept in memory, surviving restarts of the cluster it is given to again
+#[derive(Debug, Default)]
+pub struct MemoryTermStore {
+    term_vote: Mutex<TermVote>,
+}
+
+impl TermStore for MemoryTermStore {
+    fn load(&self) -> Result<TermVote> {
+        Ok(*self.term_vote.lock().unwrap())
+    }
+
+    fn save(&self, term_vote: TermVote) -> Result<()> {
+        *self.term_vote.lock().unwrap() = term_vote;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State {
+    term: u64,
+    voted_for: Option<u64>,
+    role: Role,
+    leader: Option<u64>,
+    // Last time a leader or candidate of the current term was heard from
+    last_contact: Duration,
+    // Time without contact after which this node starts an election, picked again after each
+    election_timeout: Duration,
+    // Seq of the last write applied without missing any before it
+    applied_seq: u64,
+    // Term of the last write applied
+    applied_term: u64,
+    // Latest seq the leader is known to have written
+    leader_seq: u64,
+}
+
+pub struct Cluster {
+    config: ClusterConfig,
+    clock: Arc<dyn Clock>,
+    transport: Arc<dyn Transport>,
+    terms: Arc<dyn TermStore>,
+    state: Mutex<State>,
+    // Keeps writes in the same order on the leader and its followers, and repairs of this node
+    // from landing in between
+    write_lock: Mutex<()>,
+}
+
+impl Cluster {
+    /// Joins the cluster as a follower in the term saved in `terms` and starts the election and
+    /// heartbeat timer
+    pub fn start(config: ClusterConfig, terms: Arc<dyn TermStore>) -> Result<Arc<Cluster>> {
+        let cluster = Cluster::new(
+            config,
+            Arc::new(SystemClock::new()),
+            Arc::new(TcpTransport),
+            terms,
+        )?;
+        let timer = cluster.clone();
+        thread::spawn(move || timer.run());
+        Ok(cluster)
+    }
+
+    /// Joins the cluster as a follower without starting the timer, `tick` has to be called
+    /// every heartbeat interval instead
+    pub fn new(
+        config: ClusterConfig,
+        clock: Arc<dyn Clock>,
+        transport: Arc<dyn Transport>,
+        terms: Arc<dyn TermStore>,
+    ) -> Result<Arc<Cluster>> {
+        let election_timeout = election_timeout(&config, clock.now());
+        let TermVote { term, voted_for } = terms.load()?;
+        Ok(Arc::new(Cluster {
+            state: Mutex::new(State {
+                term,
+                voted_for,
+                role: Role::Follower,
+                leader: None,
+                last_contact: clock.now(),
+                election_timeout,
+                applied_seq: 0,
+                applied_term: 0,
+                leader_seq: 0,
+            }),
+            config,
+            clock,
+            transport,
+            terms,
+            write_lock: Mutex::new(()),
+        }))
+    }
+
+    pub fn role(&self) -> Role {
+        self.state.lock().unwrap().role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.lock().unwrap().term
+    }
+
+    /// Leader and other nodes as seen by this node, which is reached at `own`
+    pub fn topology(&self, own: SocketAddr) -> Topology {
+        let state = self.state.lock().unwrap();
+        let primary = match state.role {
+            Role::Leader => Some(own),
+            _ => state
+                .leader
+                .and_then(|id| self.config.peers.get(&id).copied()),
+        };
+        let mut replicas: Vec<SocketAddr> = self
+            .config
+            .peers
+            .values()
+            .copied()
+            .chain(Some(own))
+            .filter(|addr| Some(*addr) != primary)
+            .collect();
+        replicas.sort();
+        Topology { primary, replicas }
+    }
+
+    /// Writes of the leader this node hasn't applied. Followers that haven't heard from a
+    /// leader for a while can't tell and count as infinitely behind.
+    pub fn lag(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        if state.role == Role::Leader {
+            return 0;
+        }
+        if state.leader.is_none()
+            || self.clock.now() - state.last_contact > self.config.election_timeout * 2
+        {
+            return u64::MAX;
+        }
+        state.leader_seq.saturating_sub(state.applied_seq)
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.config.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    // Other nodes in the order of their ids, so that a simulation sends in the same order
+    fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self.config.peers.iter().collect();
+        peers.sort();
+        peers.into_iter().map(|(_, addr)| *addr).collect()
+    }
+
+    fn run(&self) {
+        loop {
+            self.clock.sleep(self.config.heartbeat_interval);
+            self.tick();
+        }
+    }
+
+    /// Sends heartbeats if this node leads, or starts an election if it hasn't heard from a
+    /// leader for its election timeout
+    pub fn tick(&self) {
+        let (role, since_contact, timeout) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.role,
+                self.clock.now() - state.last_contact,
+                state.election_timeout,
+            )
+        };
+        if role == Role::Leader {
+            self.send_heartbeats();
+        } else if since_contact >= timeout {
+            self.run_election();
+            self.state.lock().unwrap().election_timeout =
+                election_timeout(&self.config, self.clock.now());
+        }
+    }
+
+    /// Saves the term and vote of `state`
+    fn save_term(&self, state: &State) -> Result<()> {
+        self.terms.save(TermVote {
+            term: state.term,
+            voted_for: state.voted_for,
+        })
+    }
+
+    /// Moves to a newer term seen in a message or response, stepping down if needed
+    fn observe_term(&self, state: &mut State, term: u64) {
+        if term > state.term {
+            if state.role == Role::Leader {
+                info!(
+                    "Node {} stepping down, saw term {} while leading term {}",
+                    self.config.id, term, state.term
+                );
+            }
+            state.term = term;
+            state.voted_for = None;
+            state.role = Role::Follower;
+            state.leader = None;
+            state.leader_seq = 0;
+            // Having no vote in the term is safe to lose, a vote saves the term along with it
+            if let Err(e) = self.save_term(state) {
+                warn!(
+                    "Node {} could not save term {}: {:?}",
+                    self.config.id, term, e
+                );
+            }
+        }
+    }
+
+    fn run_election(&self) {
+        let (term, applied_term, applied_seq) = {
+            let mut state = self.state.lock().unwrap();
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.config.id);
+            state.leader = None;
+            state.last_contact = self.clock.now();
+            if let Err(e) = self.save_term(&state) {
+                warn!(
+                    "Node {} could not save its vote for term {}: {:?}",
+                    self.config.id, state.term, e
+                );
+                state.role = Role::Follower;
+                return;
+            }
+            (state.term, state.applied_term, state.applied_seq)
+        };
+        info!(
+            "Node {} starting election for term {}",
+            self.config.id, term
+        );
+        let request = KvRequest::<(), ()>::Cluster(ClusterMessage::RequestVote {
+            term,
+            candidate: self.config.id,
+            applied_term,
+            applied_seq,
+        });
+        let mut votes = 1;
+        for addr in self.peers() {
+            match self.send::<(), ()>(addr, &request) {
+                Ok(KvResponse { value: Ok(_) }) => votes += 1,
+                Ok(KvResponse {
+                    value: Err(KvsError::StaleTerm(newer)),
+                }) => self.observe_term(&mut self.state.lock().unwrap(), newer),
+                Ok(_) => {}
+                Err(e) => debug!("Vote request to {} failed: {:?}", addr, e),
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.term == term && state.role == Role::Candidate && votes >= self.majority() {
+            info!(
+                "Node {} became leader for term {} with {} votes",
+                self.config.id, term, votes
+            );
+            state.role = Role::Leader;
+            state.leader = Some(self.config.id);
+            drop(state);
+            self.send_heartbeats();
+        }
+    }
+
+    fn send_heartbeats(&self) {
+        let (term, seq) = {
+            let state = self.state.lock().unwrap();
+            (state.term, state.applied_seq)
+        };
+        let request = KvRequest::<(), ()>::Cluster(ClusterMessage::Heartbeat {
+            term,
+            leader: self.config.id,
+            seq,
+        });
+        for addr in self.peers() {
+            match self.send::<(), ()>(addr, &request) {
+                Ok(KvResponse {
+                    value: Err(KvsError::StaleTerm(newer)),
+                }) => self.observe_term(&mut self.state.lock().unwrap(), newer),
+                Ok(_) => {}
+                Err(e) => debug!("Heartbeat to {} failed: {:?}", addr, e),
+            }
+        }
+    }
+
+    // Accepts a message from the leader of `term`, failing if that term is over
+    fn accept_leader(&self, state: &mut State, term: u64, leader: u64) -> Result<()> {
+        if term < state.term {
+            return Err(KvsError::StaleTerm(state.term));
+        }
+        self.observe_term(state, term);
+        state.role = Role::Follower;
+        state.leader = Some(leader);
+        state.last_contact = self.clock.now();
+        Ok(())
+    }
+
+    /// Answers a vote request or heartbeat from another node
+    pub fn handle_message(&self, message: ClusterMessage) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match message {
+            ClusterMessage::RequestVote {
+                term,
+                candidate,
+                applied_term,
+                applied_seq,
+            } => {
+                if term < state.term {
+                    return Err(KvsError::StaleTerm(state.term));
+                }
+                self.observe_term(&mut state, term);
+                // A candidate missing writes this node has could lose them once elected
+                if (applied_term, applied_seq) < (state.applied_term, state.applied_seq) {
+                    return Err(KvsError::VoteDenied);
+                }
+                match state.voted_for {
+                    Some(voted_for) if voted_for != candidate => Err(KvsError::VoteDenied),
+                    _ => {
+                        state.voted_for = Some(candidate);
+                        if let Err(e) = self.save_term(&state) {
+                            state.voted_for = None;
+                            return Err(e);
+                        }
+                        state.last_contact = self.clock.now();
+                        Ok(())
+                    }
+                }
+            }
+            ClusterMessage::Heartbeat { term, leader, seq } => {
+                self.accept_leader(&mut state, term, leader)?;
+                state.leader_seq = state.leader_seq.max(seq);
+                Ok(())
+            }
+        }
+    }
+
+    /// Applies a write forwarded by the leader of `term`
+    pub fn handle_replicate<K, V, E>(
+        &self,
+        engine: &E,
+        term: u64,
+        leader: u64,
+        seq: u64,
+        request: KvRequest<K, V>,
+    ) -> Result<Option<V>>
+    where
+        K: Clone,
+        E: KvsEngine<K, V>,
+    {
+        let _write_guard = self.write_lock.lock().unwrap();
+        self.accept_leader(&mut self.state.lock().unwrap(), term, leader)?;
+        let result = match request.apply(engine) {
+            // The leader already checked the key exists
+            Err(KvsError::NonExistantKey) => Ok(None),
+            result => result,
+        }?;
+        let mut state = self.state.lock().unwrap();
+        if seq == state.applied_seq + 1 || term > state.applied_term {
+            state.applied_seq = seq;
+            state.applied_term = term;
+        }
+        state.leader_seq = state.leader_seq.max(seq);
+        Ok(result)
+    }
+
+    /// Replicates a set or remove to the followers if this node is the leader, and applies it
+    /// once a majority of the cluster has it. Fails without applying it otherwise, though the
+    /// followers that got it keep it.
+    pub fn handle_write<K, V, E>(&self, engine: &E, request: KvRequest<K, V>) -> Result<Option<V>>
+    where
+        K: Serialize + Clone,
+        V: Serialize + DeserializeOwned + Clone,
+        E: KvsEngine<K, V>,
+    {
+        let _write_guard = self.write_lock.lock().unwrap();
+        let (role, term, leader, seq) = {
+            let state = self.state.lock().unwrap();
+            (state.role, state.term, state.leader, state.applied_seq + 1)
+        };
+        if role != Role::Leader {
+            let leader_addr = leader.and_then(|id| self.config.peers.get(&id).copied());
+            return Err(KvsError::NotLeader(leader_addr));
+        }
+        // Versions are seqs of this node, replicas apply whatever passed the check here. Writes
+        // are serialized by the write lock, so the check still holds when the write is applied.
+        request.check(engine)?;
+        let replicated = KvRequest::Replicate {
+            term,
+            leader: self.config.id,
+            seq,
+            request: Box::new(request.clone().without_version_check()),
+        };
+        let mut acks = 1;
+        for addr in self.peers() {
+            match self.send::<K, V>(addr, &replicated) {
+                Ok(KvResponse { value: Ok(_) }) => acks += 1,
+                Ok(KvResponse {
+                    value: Err(KvsError::StaleTerm(newer)),
+                }) => self.observe_term(&mut self.state.lock().unwrap(), newer),
+                Ok(KvResponse { value: Err(e) }) => {
+                    debug!("Replicating to {} failed: {:?}", addr, e)
+                }
+                Err(e) => debug!("Replicating to {} failed: {:?}", addr, e),
+            }
+        }
+        if acks < self.majority() {
+            return Err(KvsError::NoQuorum);
+        }
+        let result = request.apply(engine)?;
+        let mut state = self.state.lock().unwrap();
+        state.applied_seq = seq;
+        state.applied_term = term;
+        Ok(result)
+    }
+
+    /// Runs `write` holding the write lock, for writes made to this node alone that no
+    /// replicated write may land in the middle of
+    pub fn write_locally<T>(&self, write: impl FnOnce() -> T) -> T {
+        let _write_guard = self.write_lock.lock().unwrap();
+        write()
+    }
+
+    /// Value of `key` on the first other node that is caught up with the leader, for restoring
+    /// a key whose copy on this node was damaged. None if no node could answer.
+    pub fn read_from_peers<K, V>(&self, key: &K) -> Option<Option<V>>
+    where
+        K: Serialize + Clone,
+        V: Serialize + DeserializeOwned,
+    {
+        let request = KvRequest::ReplicaGet {
+            key: key.clone(),
+            max_lag: 0,
+        };
+        for addr in self.peers() {
+            match self.send::<K, V>(addr, &request) {
+                Ok(KvResponse { value: Ok(value) }) => return Some(value),
+                Ok(KvResponse { value: Err(e) }) | Err(e) => {
+                    debug!("Reading from {} failed: {:?}", addr, e)
+                }
+            }
+        }
+        None
+    }
+
+    fn send<K, V>(&self, addr: SocketAddr, request: &KvRequest<K, V>) -> Result<KvResponse<V>>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+    {
+        let response = self.transport.call(
+            addr,
+            &serde_json::to_vec(request)?,
+            self.config.heartbeat_interval,
+        )?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+}
+
+// Randomized between one and two times the configured timeout, from the time and the node id
+// so that nodes started together still time out apart
+fn election_timeout(config: &ClusterConfig, now: Duration) -> Duration {
+    let base = config.election_timeout;
+    let seed = now.subsec_nanos() as u64 ^ config.id.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    base + Duration::from_nanos(
+        crate::stable_hash(&seed.to_le_bytes()) % base.as_nanos().max(1) as u64,
+    )
+}
@@ -0,0 +1,147 @@
+//! Settings shared by `kvs-server`, `kvs-client` and `kvs-admin`, layered so that flags win over
+//! environment variables, which win over the config file of the server, which wins over the
+//! defaults. The variables are:
+//!
+//! | Variable | Setting |
+//! | --- | --- |
+//! | `KVS_CONFIG` | config file of the server, see `ServerConfig::load` |
+//! | `KVS_ADDR` | addresses the server listens on, separated by commas. Clients connect to the first. |
+//! | `KVS_DATA_DIR` | directory of the store, for the server and the offline admin commands |
+//! | `KVS_ENGINE` | `kvs` or `sled` |
+//! | `KVS_THREADS` | worker threads of the server |
+//! | `KVS_SYNC_POLICY` | `flush`, `always` or `buffered` |
+//! | `KVS_MAX_DISK_BYTES` | bytes of records the kvs engine may hold |
+//! | `KVS_STALL_THRESHOLD_MS` | milliseconds a write may wait before it is logged as a stall |
+//! | `KVS_KEY_HASHER` | `siphash`, `ahash` or `fx` |
+//! | `KVS_SCRUB_INTERVAL_SECS` | seconds between scrubs of the store, 0 for none |
+//! | `KVS_NODE_ID` | id of the server within its cluster |
+//! | `KVS_PEERS` | other members of the cluster as `<id>=<addr>`, separated by commas |
+//! | `KVS_NAMESPACES` | `true` to account for namespaces |
+//! | `KVS_AUDIT_LOG` | directory to record writes in |
+//! | `KVS_HTTP` | address of the REST gateway |
+//! | `KVS_OTEL` | OpenTelemetry collector to export to |
+//! | `KVS_TRACE_SAMPLE_RATE` | fraction of requests the server logs with their key |
+//! | `KVS_TIMEOUT_MS` | milliseconds clients wait on a server |
+//! | `KVS_JSON` | `true` for clients to send JSON instead of msgpack |
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::net::{default_addr, parse_addr};
+use crate::{KvsError, Result};
+
+#[cfg(feature = "server")]
+mod server;
+
+#[cfg(feature = "server")]
+pub use self::server::{
+    parse_key_hasher, parse_peer, parse_sync_policy, KvsEngineType, Peer, ServerConfig,
+};
+
+/// Directory of the store when neither a flag nor a variable gives one
+pub const DEFAULT_DATA_DIR: &str = "./db";
+
+/// The `KVS_` variables of an environment
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    vars: HashMap<String, String>,
+}
+
+impl Env {
+    /// Variables of this process
+    pub fn from_process() -> Env {
+        Env::from_vars(env::vars())
+    }
+
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Env {
+        Env {
+            vars: vars
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("KVS_"))
+                .collect(),
+        }
+    }
+
+    /// Value of `name` parsed, none if it isn't set. Empty values count as not set.
+    pub fn get<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse(name, |value| value.parse())
+    }
+
+    /// Value of `name` split on commas with every part parsed by `parse`
+    pub fn get_list<T, E: Display>(
+        &self,
+        name: &str,
+        parse: impl Fn(&str) -> std::result::Result<T, E>,
+    ) -> Result<Option<Vec<T>>> {
+        self.parse(name, |value| {
+            value.split(',').map(|part| parse(part.trim())).collect()
+        })
+    }
+
+    /// `KVS_ADDR`
+    pub fn addrs(&self) -> Result<Option<Vec<SocketAddr>>> {
+        self.get_list("KVS_ADDR", parse_addr)
+    }
+
+    /// `KVS_DATA_DIR`
+    pub fn data_dir(&self) -> Result<Option<PathBuf>> {
+        self.get("KVS_DATA_DIR")
+    }
+
+    fn parse<T, E: Display>(
+        &self,
+        name: &str,
+        parse: impl FnOnce(&str) -> std::result::Result<T, E>,
+    ) -> Result<Option<T>> {
+        match self.vars.get(name).map(|value| value.trim()) {
+            None | Some("") => Ok(None),
+            Some(value) => parse(value)
+                .map(Some)
+                .map_err(|e| KvsError::InvalidConfig(format!("{}={}: {}", name, value, e))),
+        }
+    }
+}
+
+/// Settings of `kvs-client` and of the `kvs-admin` commands that talk to a server or open a
+/// store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub addr: SocketAddr,
+    pub data_dir: PathBuf,
+    pub timeout: Option<Duration>,
+    pub json: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            addr: default_addr(),
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            timeout: None,
+            json: false,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// The defaults with the variables of `env` in place of theirs
+    pub fn from_env(env: &Env) -> Result<ClientConfig> {
+        let mut config = ClientConfig::default();
+        if let Some(addrs) = env.addrs()? {
+            config.addr = addrs[0];
+        }
+        config.data_dir = env.data_dir()?.unwrap_or(config.data_dir);
+        config.timeout = env.get("KVS_TIMEOUT_MS")?.map(Duration::from_millis);
+        config.json = env.get("KVS_JSON")?.unwrap_or(config.json);
+        Ok(config)
+    }
+}
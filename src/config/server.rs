@@ -0,0 +1,284 @@
+//! Settings of the server, read from a TOML or YAML file and overridden by environment
+//! variables and the flags of `kvs-server`. Every setting has a default, so a file only needs
+//! the ones it changes:
+//!
+//! ```toml
+//! addr = ["0.0.0.0:4000", "[::]:4000"]
+//! engine = "kvs"
+//! data_dir = "/var/lib/kvs"
+//! threads = 8
+//! sync_policy = "always"
+//! max_disk_bytes = 10737418240
+//! ```
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::clap_derive::ArgEnum;
+use clap::ArgEnum as _;
+use serde::{Deserialize, Serialize};
+
+use super::{Env, DEFAULT_DATA_DIR};
+use crate::engine::hasher::KeyHasher;
+use crate::engine::store::SyncPolicy;
+use crate::net::default_addr;
+use crate::{KvsError, Result};
+
+#[derive(Debug, Clone, Copy, ArgEnum, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvsEngineType {
+    #[serde(alias = "sled")]
+    Sled,
+    #[serde(alias = "kvs")]
+    Kvs,
+}
+
+/// Other member of the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub id: u64,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on
+    pub addr: Vec<SocketAddr>,
+    /// Engine of a new data directory, existing ones keep theirs
+    pub engine: Option<KvsEngineType>,
+    pub data_dir: PathBuf,
+    /// Number of worker threads, 0 starts one per cpu
+    pub threads: u32,
+    /// Id of this server within its cluster, needs `peers`
+    pub node_id: Option<u64>,
+    pub peers: Vec<Peer>,
+    /// When the kvs engine pushes writes to disk
+    pub sync_policy: SyncPolicy,
+    /// Bytes of records the kvs engine may hold before sets fail, 0 disables the limit
+    pub max_disk_bytes: u64,
+    /// Repair damage a crash left in the kvs store when opening it
+    pub repair: bool,
+    /// Milliseconds a write of the kvs engine may wait on compaction or an fsync before it is
+    /// logged as a stall, 0 disables it
+    pub stall_threshold_ms: u64,
+    /// Hash function of the key maps of the kvs engine. Clients pick the keys, so the default
+    /// is SipHash, which they can't make collide.
+    pub key_hasher: KeyHasher,
+    /// Seconds between scrubs of the store, which repair the damage they find from the other
+    /// nodes of the cluster, 0 disables them
+    pub scrub_interval_secs: u64,
+    /// Account keys and bytes per namespace and enforce their quotas
+    pub namespaces: bool,
+    /// Serve reads from the kvs store another server in the data directory writes to, lagging
+    /// it by up to this many milliseconds
+    pub follow: Option<u64>,
+    /// Directory to record every write in
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_file_bytes: u64,
+    pub audit_log_files: usize,
+    pub nodelay: bool,
+    /// Seconds a client connection stays idle before it is probed with TCP keepalives
+    pub keepalive: Option<u64>,
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+    /// Address to serve the REST gateway on, needs the `http` feature
+    pub http: Option<SocketAddr>,
+    /// OTLP/HTTP endpoint to export spans and metrics to, needs the `otel` feature
+    pub otel: Option<SocketAddr>,
+    /// Fraction of requests, from 0 to 1, logged at info level with their key. The others
+    /// are logged at debug level with a hash of their key only.
+    pub trace_sample_rate: f64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: vec![default_addr()],
+            engine: None,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            threads: 0,
+            node_id: None,
+            peers: Vec::new(),
+            sync_policy: SyncPolicy::Flush,
+            max_disk_bytes: 0,
+            repair: true,
+            stall_threshold_ms: 1000,
+            key_hasher: KeyHasher::SipHash,
+            scrub_interval_secs: 0,
+            namespaces: false,
+            follow: None,
+            audit_log: None,
+            audit_log_file_bytes: 64 * 1024 * 1024,
+            audit_log_files: 16,
+            nodelay: false,
+            keepalive: None,
+            recv_buffer: None,
+            send_buffer: None,
+            http: None,
+            otel: None,
+            trace_sample_rate: 0.0,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the settings in `path`, as YAML for `.yaml` and `.yml` files and TOML otherwise
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            KvsError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+        })?;
+        let yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml" | "yml")
+        );
+        let parsed = match yaml {
+            true => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+            false => toml::from_str(&text).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| KvsError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Puts the variables set in `env` in place of these settings, see the `config` module for
+    /// their names
+    pub fn apply_env(&mut self, env: &Env) -> Result<()> {
+        if let Some(addr) = env.addrs()? {
+            self.addr = addr;
+        }
+        if let Some(data_dir) = env.data_dir()? {
+            self.data_dir = data_dir;
+        }
+        self.engine = env
+            .parse("KVS_ENGINE", |engine| KvsEngineType::from_str(engine, true))?
+            .or(self.engine);
+        self.threads = env.get("KVS_THREADS")?.unwrap_or(self.threads);
+        self.sync_policy = env
+            .parse("KVS_SYNC_POLICY", parse_sync_policy)?
+            .unwrap_or(self.sync_policy);
+        self.max_disk_bytes = env
+            .get("KVS_MAX_DISK_BYTES")?
+            .unwrap_or(self.max_disk_bytes);
+        self.stall_threshold_ms = env
+            .get("KVS_STALL_THRESHOLD_MS")?
+            .unwrap_or(self.stall_threshold_ms);
+        self.key_hasher = env
+            .parse("KVS_KEY_HASHER", parse_key_hasher)?
+            .unwrap_or(self.key_hasher);
+        self.scrub_interval_secs = env
+            .get("KVS_SCRUB_INTERVAL_SECS")?
+            .unwrap_or(self.scrub_interval_secs);
+        self.node_id = env.get("KVS_NODE_ID")?.or(self.node_id);
+        if let Some(peers) = env.get_list("KVS_PEERS", parse_peer)? {
+            self.peers = peers
+                .into_iter()
+                .map(|(id, addr)| Peer { id, addr })
+                .collect();
+        }
+        self.namespaces = env.get("KVS_NAMESPACES")?.unwrap_or(self.namespaces);
+        self.audit_log = env.get("KVS_AUDIT_LOG")?.or(self.audit_log.take());
+        self.http = env.get("KVS_HTTP")?.or(self.http);
+        self.otel = env.get("KVS_OTEL")?.or(self.otel);
+        self.trace_sample_rate = env
+            .get("KVS_TRACE_SAMPLE_RATE")?
+            .unwrap_or(self.trace_sample_rate);
+        Ok(())
+    }
+
+    /// Checks the settings before anything is opened or bound, failing with
+    /// `KvsError::InvalidConfig` naming the first that can't be used
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |problem: String| Err(KvsError::InvalidConfig(problem));
+        if self.addr.is_empty() {
+            return invalid("addr: no address to listen on".to_owned());
+        }
+        let mut addrs = HashSet::new();
+        if let Some(addr) = self.addr.iter().find(|addr| !addrs.insert(**addr)) {
+            return invalid(format!("addr: {} is given twice", addr));
+        }
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            return invalid(format!(
+                "data_dir: {} is not a directory",
+                self.data_dir.display()
+            ));
+        }
+        match (self.node_id, self.peers.is_empty()) {
+            (Some(_), true) => return invalid("node_id: a cluster needs peers".to_owned()),
+            (None, false) => return invalid("peers: a cluster needs a node_id".to_owned()),
+            _ => {}
+        }
+        let mut ids = HashSet::new();
+        for peer in &self.peers {
+            if Some(peer.id) == self.node_id {
+                return invalid(format!("peers: {} is the id of this server", peer.id));
+            }
+            if !ids.insert(peer.id) {
+                return invalid(format!("peers: {} is given twice", peer.id));
+            }
+        }
+        if self.follow.is_some() && self.node_id.is_some() {
+            return invalid("follow: a follower can't be a member of a cluster".to_owned());
+        }
+        if self.follow.is_some() && self.engine == Some(KvsEngineType::Sled) {
+            return invalid("follow: only the kvs engine can be followed".to_owned());
+        }
+        if self.audit_log.is_some() && (self.audit_log_file_bytes == 0 || self.audit_log_files == 0)
+        {
+            return invalid("audit_log: files need room for at least one record".to_owned());
+        }
+        if self.keepalive == Some(0) {
+            return invalid("keepalive: connections have to be idle for at least 1s".to_owned());
+        }
+        if !(0.0..=1.0).contains(&self.trace_sample_rate) {
+            return invalid(format!(
+                "trace_sample_rate: {} is not between 0 and 1",
+                self.trace_sample_rate
+            ));
+        }
+        if self.http.is_some() && !cfg!(feature = "http") {
+            return invalid(
+                "http: this build has no gateway, it needs the http feature".to_owned(),
+            );
+        }
+        if self.otel.is_some() && !cfg!(feature = "otel") {
+            return invalid(
+                "otel: this build has no exporter, it needs the otel feature".to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parses a cluster member given as `<id>=<addr>`
+pub fn parse_peer(peer: &str) -> std::result::Result<(u64, SocketAddr), String> {
+    let (id, addr) = peer
+        .split_once('=')
+        .ok_or_else(|| format!("expected <id>=<addr>, got {}", peer))?;
+    Ok((
+        id.parse()
+            .map_err(|e| format!("invalid peer id {}: {}", id, e))?,
+        addr.parse()
+            .map_err(|e| format!("invalid peer address {}: {}", addr, e))?,
+    ))
+}
+
+pub fn parse_sync_policy(policy: &str) -> std::result::Result<SyncPolicy, String> {
+    match policy {
+        "flush" => Ok(SyncPolicy::Flush),
+        "always" => Ok(SyncPolicy::Always),
+        "buffered" => Ok(SyncPolicy::Buffered),
+        policy => Err(format!(
+            "expected flush, always or buffered, got {}",
+            policy
+        )),
+    }
+}
+
+pub fn parse_key_hasher(hasher: &str) -> std::result::Result<KeyHasher, String> {
+    match hasher {
+        "siphash" => Ok(KeyHasher::SipHash),
+        "ahash" => Ok(KeyHasher::AHash),
+        "fx" => Ok(KeyHasher::Fx),
+        hasher => Err(format!("expected siphash, ahash or fx, got {}", hasher)),
+    }
+}
@@ -0,0 +1,72 @@
+//! Finding servers from a DNS name resolving to several addresses, like a headless Kubernetes
+//! service, instead of a list of addresses.
+//!
+//! Names are resolved through the system resolver, so only the address records of the name are
+//! used and the port comes with the name. They are resolved again once the addresses are older
+//! than the refresh interval, keeping the last addresses while the name doesn't resolve.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::{KvsError, Result};
+
+#[derive(Debug)]
+pub struct Discovery {
+    /// Name and port, like `kvs.default.svc.cluster.local:4000`
+    name: String,
+    refresh_interval: Duration,
+    resolved: Mutex<(Instant, Vec<SocketAddr>)>,
+}
+
+impl Discovery {
+    /// Resolves `name` right away, failing if it has no address
+    pub fn new(name: impl Into<String>, refresh_interval: Duration) -> Result<Discovery> {
+        let name = name.into();
+        let addrs = resolve(&name)?;
+        Ok(Discovery {
+            name,
+            refresh_interval,
+            resolved: Mutex::new((Instant::now(), addrs)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    /// Addresses of the name in ascending order, resolved again if they are older than the
+    /// refresh interval
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut resolved = self.resolved.lock().unwrap();
+        if resolved.0.elapsed() >= self.refresh_interval {
+            match resolve(&self.name) {
+                Ok(addrs) => {
+                    if addrs != resolved.1 {
+                        debug!("{} now resolves to {:?}", self.name, addrs);
+                    }
+                    resolved.1 = addrs;
+                }
+                Err(e) => warn!("Could not resolve {}: {:?}", self.name, e),
+            }
+            resolved.0 = Instant::now();
+        }
+        resolved.1.clone()
+    }
+}
+
+fn resolve(name: &str) -> Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = name.to_socket_addrs()?.collect();
+    addrs.sort();
+    addrs.dedup();
+    if addrs.is_empty() {
+        return Err(KvsError::IOError(format!("{} has no address", name)));
+    }
+    Ok(addrs)
+}
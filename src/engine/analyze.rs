@@ -0,0 +1,128 @@
+//! Reports on what a store holds, built one record at a time.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct AnalyzeOptions {
+    /// Keys are grouped by the part before the first occurrence of this, keys without it are
+    /// grouped under the empty prefix
+    pub prefix_delimiter: char,
+    /// Number of largest values to report
+    pub top: usize,
+}
+
+impl Default for AnalyzeOptions {
+    fn default() -> Self {
+        AnalyzeOptions {
+            prefix_delimiter: ':',
+            top: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    pub keys: u64,
+    pub value_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyspaceReport {
+    pub keys: u64,
+    pub value_bytes: u64,
+    /// Number of values by serialized size. Entry 0 counts values of at most 1 byte, entry `i`
+    /// those of more than `2^(i-1)` and at most `2^i` bytes.
+    pub value_sizes: Vec<u64>,
+    /// Keys with the largest values along with their sizes, largest first
+    pub largest: Vec<(String, u64)>,
+    pub prefixes: BTreeMap<String, PrefixStats>,
+    /// Removed keys whose tombstones are still kept
+    pub tombstones: u64,
+}
+
+impl fmt::Display for KeyspaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} keys, {} bytes of values, {} tombstones",
+            self.keys, self.value_bytes, self.tombstones
+        )?;
+        writeln!(f, "value sizes:")?;
+        for (bucket, count) in self.value_sizes.iter().enumerate() {
+            if *count > 0 {
+                writeln!(f, "  <= {} bytes: {}", 1u64 << bucket, count)?;
+            }
+        }
+        writeln!(f, "largest values:")?;
+        for (key, size) in &self.largest {
+            writeln!(f, "  {}: {} bytes", key, size)?;
+        }
+        write!(f, "prefixes:")?;
+        for (prefix, stats) in &self.prefixes {
+            write!(
+                f,
+                "\n  {:?}: {} keys, {} bytes",
+                prefix, stats.keys, stats.value_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a report from records fed to it, only holding on to the largest values seen so far
+pub(crate) struct KeyspaceAnalyzer {
+    options: AnalyzeOptions,
+    report: KeyspaceReport,
+    largest: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl KeyspaceAnalyzer {
+    pub(crate) fn new(options: AnalyzeOptions) -> KeyspaceAnalyzer {
+        KeyspaceAnalyzer {
+            options,
+            report: KeyspaceReport::default(),
+            largest: BinaryHeap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: String, value_size: u64) {
+        self.report.keys += 1;
+        self.report.value_bytes += value_size;
+        let bucket = value_size.max(1).next_power_of_two().trailing_zeros() as usize;
+        if self.report.value_sizes.len() <= bucket {
+            self.report.value_sizes.resize(bucket + 1, 0);
+        }
+        self.report.value_sizes[bucket] += 1;
+
+        let prefix = key
+            .split_once(self.options.prefix_delimiter)
+            .map(|(prefix, _)| prefix)
+            .unwrap_or("");
+        let stats = match self.report.prefixes.get_mut(prefix) {
+            Some(stats) => stats,
+            None => self.report.prefixes.entry(prefix.to_owned()).or_default(),
+        };
+        stats.keys += 1;
+        stats.value_bytes += value_size;
+
+        if self.options.top > 0 {
+            self.largest.push(Reverse((value_size, key)));
+            if self.largest.len() > self.options.top {
+                self.largest.pop();
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self, tombstones: u64) -> KeyspaceReport {
+        self.report.tombstones = tombstones;
+        self.report.largest = self
+            .largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, key))| (key, size))
+            .collect();
+        self.report
+    }
+}
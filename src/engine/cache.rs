@@ -0,0 +1,381 @@
+//! Engine keeping a bounded number of bytes in memory, evicting entries to make room for new
+//! ones, so the crate can be used as an embedded cache.
+//!
+//! Which entry goes first is up to an `EvictionPolicy`. `Lru`, `Lfu` and `TtlFirst` are provided,
+//! other policies can be plugged in by implementing the trait.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::super::KvsError;
+use super::{KvsEngine, Result, SetCondition, SmallestKeys};
+
+/// Decides which entry of a cache to evict. The cache tells it about every entry it holds, and
+/// asks it for a victim until the entry being set fits.
+pub trait EvictionPolicy<K>: Send + 'static {
+    /// `key` was set, expiring at `deadline` if it has one
+    fn inserted(&mut self, key: &K, deadline: Option<Instant>);
+    /// `key` was read
+    fn accessed(&mut self, key: &K);
+    /// `key` left the cache, whether removed, expired or evicted
+    fn removed(&mut self, key: &K);
+    /// Entry to evict next, if any
+    fn victim(&mut self) -> Option<K>;
+}
+
+/// Evicts the least recently set or read entry
+pub struct Lru<K> {
+    tick: u64,
+    ticks: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K> Default for Lru<K> {
+    fn default() -> Self {
+        Lru {
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + 'static> EvictionPolicy<K> for Lru<K> {
+    fn inserted(&mut self, key: &K, _deadline: Option<Instant>) {
+        self.accessed(key);
+    }
+    fn accessed(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some(previous) = self.ticks.insert(key.clone(), self.tick) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(self.tick, key.clone());
+    }
+    fn removed(&mut self, key: &K) {
+        if let Some(previous) = self.ticks.remove(key) {
+            self.order.remove(&previous);
+        }
+    }
+    fn victim(&mut self) -> Option<K> {
+        self.order.values().next().cloned()
+    }
+}
+
+/// Evicts the entry read the fewest times since it was set, the least recently used first
+/// among equals
+pub struct Lfu<K> {
+    tick: u64,
+    uses: HashMap<K, (u64, u64)>,
+    order: BTreeMap<(u64, u64), K>,
+}
+
+impl<K> Default for Lfu<K> {
+    fn default() -> Self {
+        Lfu {
+            tick: 0,
+            uses: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Lfu<K> {
+    fn touch(&mut self, key: &K, count: u64) {
+        self.tick += 1;
+        if let Some(previous) = self.uses.insert(key.clone(), (count, self.tick)) {
+            self.order.remove(&previous);
+        }
+        self.order.insert((count, self.tick), key.clone());
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + 'static> EvictionPolicy<K> for Lfu<K> {
+    fn inserted(&mut self, key: &K, _deadline: Option<Instant>) {
+        self.touch(key, 0);
+    }
+    fn accessed(&mut self, key: &K) {
+        let count = self.uses.get(key).map_or(0, |(count, _)| *count);
+        self.touch(key, count + 1);
+    }
+    fn removed(&mut self, key: &K) {
+        if let Some(previous) = self.uses.remove(key) {
+            self.order.remove(&previous);
+        }
+    }
+    fn victim(&mut self) -> Option<K> {
+        self.order.values().next().cloned()
+    }
+}
+
+/// Evicts the entry closest to expiring, and the least recently used one once no entry has a
+/// ttl
+pub struct TtlFirst<K> {
+    tick: u64,
+    deadlines: HashMap<K, (Instant, u64)>,
+    by_deadline: BTreeMap<(Instant, u64), K>,
+    lru: Lru<K>,
+}
+
+impl<K> Default for TtlFirst<K> {
+    fn default() -> Self {
+        TtlFirst {
+            tick: 0,
+            deadlines: HashMap::new(),
+            by_deadline: BTreeMap::new(),
+            lru: Lru::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + 'static> EvictionPolicy<K> for TtlFirst<K> {
+    fn inserted(&mut self, key: &K, deadline: Option<Instant>) {
+        if let Some(previous) = self.deadlines.remove(key) {
+            self.by_deadline.remove(&previous);
+        }
+        if let Some(deadline) = deadline {
+            self.tick += 1;
+            self.deadlines.insert(key.clone(), (deadline, self.tick));
+            self.by_deadline.insert((deadline, self.tick), key.clone());
+        }
+        self.lru.inserted(key, deadline);
+    }
+    fn accessed(&mut self, key: &K) {
+        self.lru.accessed(key);
+    }
+    fn removed(&mut self, key: &K) {
+        if let Some(previous) = self.deadlines.remove(key) {
+            self.by_deadline.remove(&previous);
+        }
+        self.lru.removed(key);
+    }
+    fn victim(&mut self) -> Option<K> {
+        match self.by_deadline.values().next() {
+            Some(key) => Some(key.clone()),
+            None => self.lru.victim(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Bytes of the keys and values held, as weighed by the cache
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    deadline: Option<Instant>,
+}
+
+struct CacheState<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    policy: Box<dyn EvictionPolicy<K>>,
+    stats: CacheStats,
+}
+
+impl<K: Eq + Hash + Clone + 'static, V> CacheState<K, V> {
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.policy.removed(key);
+        self.stats.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Takes `key` out if its ttl ran out
+    fn expire(&mut self, key: &K) {
+        let expired = self
+            .entries
+            .get(key)
+            .and_then(|entry| entry.deadline)
+            .is_some_and(|deadline| deadline <= Instant::now());
+        if expired {
+            self.remove(key);
+        }
+    }
+}
+
+/// Engine holding up to `max_bytes` of keys and values in memory. Sets evict entries picked by
+/// the eviction policy until the new entry fits, and fail with `KvsError::OutOfSpace` for
+/// entries larger than the whole cache. Entries set with a ttl are dropped once it runs out.
+pub struct CacheEngine<K, V> {
+    max_bytes: u64,
+    weigher: fn(&K, &V) -> u64,
+    state: Arc<Mutex<CacheState<K, V>>>,
+}
+
+// Clones share the entries, so they can't require K and V to be Clone like a derive would
+impl<K, V> Clone for CacheEngine<K, V> {
+    fn clone(&self) -> Self {
+        CacheEngine {
+            max_bytes: self.max_bytes,
+            weigher: self.weigher,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K: AsRef<[u8]>, V: AsRef<[u8]>> CacheEngine<K, V> {
+    /// Cache of up to `max_bytes` of keys and values, counting their lengths in bytes
+    pub fn new(max_bytes: u64, policy: impl EvictionPolicy<K>) -> CacheEngine<K, V> {
+        CacheEngine::with_weigher(max_bytes, policy, |key, value| {
+            (key.as_ref().len() + value.as_ref().len()) as u64
+        })
+    }
+}
+
+impl<K, V> CacheEngine<K, V> {
+    /// Cache of up to `max_bytes` as counted by `weigher`, for keys and values that aren't bytes
+    pub fn with_weigher(
+        max_bytes: u64,
+        policy: impl EvictionPolicy<K>,
+        weigher: fn(&K, &V) -> u64,
+    ) -> CacheEngine<K, V> {
+        CacheEngine {
+            max_bytes,
+            weigher,
+            state: Arc::new(Mutex::new(CacheState {
+                entries: HashMap::new(),
+                policy: Box::new(policy),
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let state = self.state.lock()?;
+        Ok(CacheStats {
+            entries: state.entries.len(),
+            ..state.stats
+        })
+    }
+}
+
+impl<K: Eq + Hash + Clone + 'static, V> CacheEngine<K, V> {
+    /// Sets `key` until `ttl` runs out
+    pub fn set_with_ttl(&self, key: K, value: V, ttl: Duration) -> Result<()> {
+        let mut state = self.state.lock()?;
+        self.insert(&mut state, key, value, Some(Instant::now() + ttl))
+    }
+
+    fn insert(
+        &self,
+        state: &mut CacheState<K, V>,
+        key: K,
+        value: V,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let size = (self.weigher)(&key, &value);
+        if size > self.max_bytes {
+            return Err(KvsError::OutOfSpace);
+        }
+        state.remove(&key);
+        while state.stats.bytes + size > self.max_bytes {
+            let victim = match state.policy.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+            state.remove(&victim);
+            state.stats.evictions += 1;
+        }
+        state.policy.inserted(&key, deadline);
+        state.stats.bytes += size;
+        state.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                deadline,
+            },
+        );
+        Ok(())
+    }
+}
+
+impl<K, V> KvsEngine<K, V> for CacheEngine<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn set(&self, key: K, value: V) -> Result<()> {
+        let mut state = self.state.lock()?;
+        self.insert(&mut state, key, value, None)
+    }
+    fn set_if(&self, key: K, value: V, condition: SetCondition) -> Result<bool> {
+        let mut state = self.state.lock()?;
+        state.expire(&key);
+        if state.entries.contains_key(&key) != (condition == SetCondition::Present) {
+            return Ok(false);
+        }
+        self.insert(&mut state, key, value, None)?;
+        Ok(true)
+    }
+    fn get(&self, key: K) -> Result<Option<V>> {
+        let mut state = self.state.lock()?;
+        state.expire(&key);
+        let value = state.entries.get(&key).map(|entry| entry.value.clone());
+        match value {
+            Some(_) => {
+                state.policy.accessed(&key);
+                state.stats.hits += 1;
+            }
+            None => state.stats.misses += 1,
+        }
+        Ok(value)
+    }
+    fn remove(&self, key: K) -> Result<()> {
+        let mut state = self.state.lock()?;
+        state.expire(&key);
+        match state.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NonExistantKey),
+        }
+    }
+    fn contains_key(&self, key: K) -> Result<bool> {
+        let mut state = self.state.lock()?;
+        state.expire(&key);
+        Ok(state.entries.contains_key(&key))
+    }
+    /// Entries too big for the cache fail the batch before anything is written. Nothing is kept
+    /// across a crash, so the lock held over the writes is enough.
+    fn write_batch(&self, writes: Vec<(K, Option<V>)>) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let too_big = |(key, value): &(K, Option<V>)| {
+            value
+                .as_ref()
+                .is_some_and(|value| (self.weigher)(key, value) > self.max_bytes)
+        };
+        if writes.iter().any(too_big) {
+            return Err(KvsError::OutOfSpace);
+        }
+        for (key, value) in writes {
+            match value {
+                Some(value) => self.insert(&mut state, key, value, None)?,
+                None => {
+                    state.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn scan_keys(&self, after: Option<K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        let now = Instant::now();
+        let state = self.state.lock()?;
+        let mut smallest = SmallestKeys::new(after.as_ref(), limit);
+        for (key, entry) in &state.entries {
+            if entry.deadline.is_none_or(|deadline| deadline > now) {
+                smallest.offer(key);
+            }
+        }
+        Ok(smallest.finish())
+    }
+}
@@ -0,0 +1,102 @@
+//! Serialization of the records, hints and other files making up a store.
+
+use std::io::Cursor;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::Result;
+use crate::KvsError;
+
+/// Turns records into bytes and back. Encoded records are written back to back, so a codec also
+/// has to tell where a record ends.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+
+    /// Decodes the record at the start of `bytes`, returning it along with the number of bytes
+    /// it took up
+    fn decode_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<(T, usize)>;
+}
+
+/// Compact msgpack, the fastest and smallest
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Msgpack;
+
+impl Codec for Msgpack {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    fn decode_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<(T, usize)> {
+        let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(bytes));
+        let value = T::deserialize(&mut deserializer)?;
+        Ok((value, deserializer.position() as usize))
+    }
+}
+
+/// One JSON document per line, for reading logs by eye
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut bytes = serde_json::to_vec(value)?;
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn decode_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<(T, usize)> {
+        let mut stream = serde_json::Deserializer::from_slice(bytes).into_iter::<T>();
+        let value = match stream.next() {
+            Some(value) => value?,
+            None => return Err(KvsError::SerializationError("no record left".to_owned())),
+        };
+        let mut end = stream.byte_offset();
+        if bytes.get(end) == Some(&b'\n') {
+            end += 1;
+        }
+        Ok((value, end))
+    }
+}
+
+/// Codec picked in `KvStoreOptions` for new stores. Existing stores keep the one recorded in
+/// their manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordCodec {
+    #[default]
+    Msgpack,
+    Json,
+}
+
+impl Codec for RecordCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            RecordCodec::Msgpack => Msgpack.encode(value),
+            RecordCodec::Json => Json.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            RecordCodec::Msgpack => Msgpack.decode(bytes),
+            RecordCodec::Json => Json.decode(bytes),
+        }
+    }
+
+    fn decode_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<(T, usize)> {
+        match self {
+            RecordCodec::Msgpack => Msgpack.decode_prefix(bytes),
+            RecordCodec::Json => Json.decode_prefix(bytes),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::prelude::FileExt;
+
+// Block size that offsets, lengths and buffer addresses of O_DIRECT writes are aligned to
+const ALIGNMENT: usize = 4096;
+
+/// Appends to a file opened with O_DIRECT.
+///
+/// Every write rewrites the block holding the end of the log and pads the last block with
+/// zeros, which replay already treats as the end of a log file.
+pub(crate) struct DirectFile {
+    file: File,
+    position: u64,
+    // Bytes of the partially filled block at the end of the log
+    tail: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl DirectFile {
+    /// Wraps a file opened with O_DIRECT whose log ends at `position`. `reader` must be a
+    /// plain handle on the same file, used to load the partially filled tail block.
+    pub(crate) fn new(file: File, reader: &File, position: u64) -> io::Result<DirectFile> {
+        let tail_len = (position % ALIGNMENT as u64) as usize;
+        let mut tail = vec![0u8; tail_len];
+        reader.read_exact_at(&mut tail, position - tail_len as u64)?;
+        Ok(DirectFile {
+            file,
+            position,
+            tail,
+            buf: Vec::new(),
+        })
+    }
+
+    pub(crate) fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    // Returns an ALIGNMENT aligned, zeroed window of `len` bytes inside `buf`
+    fn aligned(buf: &mut Vec<u8>, len: usize) -> &mut [u8] {
+        buf.clear();
+        buf.resize(len + ALIGNMENT, 0);
+        let start = buf.as_ptr().align_offset(ALIGNMENT);
+        &mut buf[start..start + len]
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let block_start = self.position - self.tail.len() as u64;
+        let len = self.tail.len() + data.len();
+        let padded_len = len.div_ceil(ALIGNMENT) * ALIGNMENT;
+        let block = DirectFile::aligned(&mut self.buf, padded_len);
+        block[..self.tail.len()].copy_from_slice(&self.tail);
+        block[self.tail.len()..len].copy_from_slice(data);
+        self.file.write_all_at(block, block_start)?;
+
+        let new_tail_start = len - len % ALIGNMENT;
+        self.tail = block[new_tail_start..len].to_vec();
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Exports of every live pair of a `KvStore`, read from the segments one record at a time while
+//! writes and compaction go on. Files compaction retires are left in place until the last export
+//! reading them is dropped, as they can otherwise be removed or reused for new segments from
+//! under it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::vec;
+
+use log::warn;
+
+use super::storage::SegmentReader;
+use super::store::{Key, KvStore, Value, ValueData};
+use super::Result;
+
+/// Files open exports read, by name
+pub(super) struct FilePins {
+    inner: Mutex<PinnedFiles>,
+}
+
+#[derive(Default)]
+struct PinnedFiles {
+    /// Open exports reading each file
+    readers: HashMap<String, usize>,
+    /// Files compaction retired while they were pinned, to remove once they no longer are
+    retired: HashSet<String>,
+}
+
+impl FilePins {
+    pub(super) fn new() -> FilePins {
+        FilePins {
+            inner: Mutex::new(PinnedFiles::default()),
+        }
+    }
+
+    pub(super) fn pin(&self, files: &[String]) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        for file in files {
+            *inner.readers.entry(file.clone()).or_default() += 1;
+        }
+    }
+
+    /// Unpins `files` for an export that ended, returning those that were retired meanwhile and
+    /// no other export reads anymore
+    pub(super) fn unpin(&self, files: &[String]) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut unpinned = Vec::new();
+        for file in files {
+            if let Some(count) = inner.readers.get_mut(file) {
+                *count -= 1;
+                if *count == 0 {
+                    inner.readers.remove(file);
+                    if inner.retired.remove(file) {
+                        unpinned.push(file.clone());
+                    }
+                }
+            }
+        }
+        unpinned
+    }
+
+    /// Whether `file` is pinned, in which case it is left to the last export reading it to
+    /// remove
+    pub(super) fn retire(&self, file: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.readers.contains_key(file) {
+            inner.retired.insert(file.to_owned());
+            return true;
+        }
+        false
+    }
+}
+
+/// Every live pair of a `KvStore` as it was when `KvStore::export` was called. Values with
+/// merge operands come first, folded when the export began, then the others in the order their
+/// records are laid out on disk.
+pub struct Export<K: Key, V: Value> {
+    store: KvStore<K, V>,
+    readers: BTreeMap<u64, Box<dyn SegmentReader>>,
+    /// Names of the files `readers` read, pinned until the export is dropped
+    files: Vec<String>,
+    folded: vec::IntoIter<(K, V)>,
+    records: vec::IntoIter<(K, ValueData)>,
+    buf: Vec<u8>,
+}
+
+impl<K: Key, V: Value> Export<K, V> {
+    pub(super) fn new(
+        store: KvStore<K, V>,
+        readers: BTreeMap<u64, Box<dyn SegmentReader>>,
+        files: Vec<String>,
+        folded: Vec<(K, V)>,
+        records: Vec<(K, ValueData)>,
+    ) -> Export<K, V> {
+        Export {
+            store,
+            readers,
+            files,
+            folded: folded.into_iter(),
+            records: records.into_iter(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<K: Key, V: Value> Iterator for Export<K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pair) = self.folded.next() {
+            return Some(Ok(pair));
+        }
+        for (key, record) in self.records.by_ref() {
+            match self
+                .store
+                .exported_value(&self.readers, &key, &record, &mut self.buf)
+            {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+impl<K: Key, V: Value> Drop for Export<K, V> {
+    fn drop(&mut self) {
+        // Files open on some platforms can't be removed, so the readers go first
+        self.readers.clear();
+        for file in self.store.unpin_files(&self.files) {
+            if let Err(e) = self.store.remove_file(&file) {
+                warn!("could not remove {} after export: {}", file, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Read-only opening of a store that another process on the same host writes to.
+//!
+//! A follower tails the segments listed in the manifest of the store, picking up the records
+//! appended since its last look from the lengths of the files. It only sees what the writer has
+//! flushed to the OS. Compaction replacing the segments makes the follower replay the store
+//! from the start. Stores reusing retired segments (`KvStoreOptions::reuse_files`) can't be
+//! followed, as a reused file changes under the readers the follower holds.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use super::codec::{Codec, RecordCodec};
+use super::manifest::{segment_name, value_log_name, Manifest};
+use super::storage::{LocalStorage, SegmentReader, SegmentStorage};
+use super::store::{decode_hints, hint_name, Key, KvRecord, KvStoreOptions, Value, ValueData};
+use super::{KvsEngine, SetCondition, SmallestKeys};
+use crate::{KvsError, Result};
+
+struct FollowerState<K> {
+    codec: RecordCodec,
+    // Segments replayed so far, in ascending order
+    segments: Vec<u64>,
+    readers: BTreeMap<u64, Box<dyn SegmentReader>>,
+    index: HashMap<K, ValueData>,
+    // Bytes of the last replayed segment taken in so far
+    position: u64,
+    refreshed_at: Instant,
+}
+
+impl<K> FollowerState<K> {
+    fn new(codec: RecordCodec) -> FollowerState<K> {
+        FollowerState {
+            codec,
+            segments: Vec::new(),
+            readers: BTreeMap::new(),
+            index: HashMap::new(),
+            position: 0,
+            refreshed_at: Instant::now(),
+        }
+    }
+}
+
+impl<K: Key> FollowerState<K> {
+    /// Points the index at a record read from the segment at `value_data`
+    fn replay<V>(&mut self, record: KvRecord<K, V>, value_data: ValueData) {
+        match record {
+            KvRecord::Set((key, _))
+            | KvRecord::TimedSet((key, _, _))
+            | KvRecord::Pointer((key, _, _)) => {
+                self.index.insert(key, value_data);
+            }
+            KvRecord::Rm(key) | KvRecord::Tombstone((key, _)) => {
+                self.index.remove(&key);
+            }
+            // Folding operands takes the merge operator, so they show up once compacted
+            KvRecord::Merge(_) => {}
+            // Never written, stamps aren't nested
+            KvRecord::Stamped(_) => {}
+            // Never handed over, `tail` holds on to the batch instead
+            KvRecord::Batch(_) => {}
+        }
+    }
+}
+
+/// Read-only engine serving a store written by another process, lagging it by up to
+/// `max_staleness`. Reads catch up with the writer once the last catch-up is older than that.
+/// Writes fail with `KvsError::ReadOnly`.
+pub struct KvFollower<K, V> {
+    storage: Arc<dyn SegmentStorage>,
+    state: Arc<RwLock<FollowerState<K>>>,
+    max_staleness: Duration,
+    phantom: PhantomData<V>,
+}
+
+impl<K, V> Clone for KvFollower<K, V> {
+    fn clone(&self) -> Self {
+        KvFollower {
+            storage: self.storage.clone(),
+            state: self.state.clone(),
+            max_staleness: self.max_staleness,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<K: Key, V: Value> KvFollower<K, V> {
+    /// Follows the store in `db_path`, which must have been created already
+    pub fn open(db_path: &Path, max_staleness: Duration) -> Result<KvFollower<K, V>> {
+        let storage = LocalStorage::new(db_path, KvStoreOptions::default())?;
+        KvFollower::open_with_storage(Arc::new(storage), max_staleness)
+    }
+
+    /// Follows the store kept in `storage`
+    pub fn open_with_storage(
+        storage: Arc<dyn SegmentStorage>,
+        max_staleness: Duration,
+    ) -> Result<KvFollower<K, V>> {
+        let follower = KvFollower {
+            storage,
+            state: Arc::new(RwLock::new(FollowerState::new(RecordCodec::default()))),
+            max_staleness,
+            phantom: PhantomData,
+        };
+        follower.refresh()?;
+        info!("Following {} keys", follower.state.read()?.index.len());
+        Ok(follower)
+    }
+
+    /// Catches up with the records the writer appended since the last refresh
+    pub fn refresh(&self) -> Result<()> {
+        let mut state = self.state.write()?;
+        self.catch_up(&mut state)
+    }
+
+    fn catch_up(&self, state: &mut FollowerState<K>) -> Result<()> {
+        let manifest = Manifest::load(self.storage.as_ref())?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        if state
+            .segments
+            .iter()
+            .any(|segment| !manifest.segments.contains(segment))
+        {
+            debug!("Segments were compacted, replaying the store");
+            *state = FollowerState::new(manifest.codec);
+        }
+        state.codec = manifest.codec;
+        for &segment in &manifest.segments {
+            let from = match state.segments.last() {
+                Some(&last) if last == segment => state.position,
+                _ if state.segments.contains(&segment) => continue,
+                _ => {
+                    let reader = self.storage.open(&segment_name(segment))?;
+                    state.readers.insert(segment, reader);
+                    state.segments.push(segment);
+                    let hints = match self.storage.read(&hint_name(segment))? {
+                        Some(hints) => decode_hints::<K>(state.codec, &hints)?,
+                        None => None,
+                    };
+                    if let Some(hints) = hints {
+                        // Segments with hints are sealed and only hold what the hints point at
+                        state.position = 0;
+                        for (key, offset, size, pointer) in hints {
+                            state.position = state.position.max(offset + size as u64);
+                            let value_data = ValueData {
+                                segment,
+                                offset,
+                                size,
+                                inline: None,
+                                pointer,
+                            };
+                            state.index.insert(key, value_data);
+                        }
+                        continue;
+                    }
+                    0
+                }
+            };
+            state.position = self.tail(state, segment, from)?;
+        }
+        state.refreshed_at = Instant::now();
+        Ok(())
+    }
+
+    /// Replays the records of `segment` from `from` on, returning the end of the last complete
+    /// record. Batches are only replayed once all of their records were written.
+    fn tail(&self, state: &mut FollowerState<K>, segment: u64, from: u64) -> Result<u64> {
+        let len = self.storage.len(&segment_name(segment))?.unwrap_or(0);
+        if len <= from {
+            return Ok(from);
+        }
+        let mut buf = vec![0u8; (len - from) as usize];
+        state.readers[&segment].read_exact_at(&mut buf, from)?;
+        let mut position = 0;
+        let mut replayed = 0;
+        // Records of the batch being read along with how many are left
+        let mut batch = Vec::new();
+        let mut left = 0;
+        // A record never starts with a zero byte, so one marks the preallocated tail
+        while position < buf.len() && buf[position] != 0 {
+            // The writer may be halfway through the last record, it is picked up next time
+            let (record, size) = match state
+                .codec
+                .decode_prefix::<KvRecord<K, V>>(&buf[position..])
+            {
+                Ok(decoded) => decoded,
+                Err(_) => break,
+            };
+            let value_data = ValueData {
+                segment,
+                offset: from + position as u64,
+                size,
+                inline: None,
+                pointer: None,
+            };
+            position += size;
+            match record.unstamp().0 {
+                KvRecord::Batch(len) => left = len,
+                record if left > 0 => {
+                    batch.push((record, value_data));
+                    left -= 1;
+                    if left == 0 {
+                        for (record, value_data) in batch.drain(..) {
+                            state.replay(record, value_data);
+                        }
+                    }
+                }
+                record => state.replay(record, value_data),
+            }
+            if left == 0 {
+                replayed = position;
+            }
+        }
+        Ok(from + replayed as u64)
+    }
+
+    /// Catches up with the writer if the last catch-up is older than `max_staleness`
+    fn refresh_if_stale(&self) -> Result<()> {
+        if self.state.read()?.refreshed_at.elapsed() < self.max_staleness {
+            return Ok(());
+        }
+        let mut state = self.state.write()?;
+        // Another reader may have caught up while this one waited for the lock
+        if state.refreshed_at.elapsed() < self.max_staleness {
+            return Ok(());
+        }
+        self.catch_up(&mut state)
+    }
+}
+
+impl<K, V> KvsEngine<K, V> for KvFollower<K, V>
+where
+    K: Key + Sync,
+    V: Value,
+{
+    fn set(&self, _key: K, _value: V) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+    fn get(&self, key: K) -> Result<Option<V>> {
+        self.refresh_if_stale()?;
+        let state = self.state.read()?;
+        let value_data = match state.index.get(&key) {
+            Some(value_data) => value_data,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; value_data.size];
+        state.readers[&value_data.segment].read_exact_at(&mut buf, value_data.offset)?;
+        match state.codec.decode::<KvRecord<K, V>>(&buf)?.unstamp().0 {
+            KvRecord::Set((_, value)) | KvRecord::TimedSet((_, value, _)) => Ok(Some(value)),
+            // Value logs aren't tailed, they are only read from when a record points at them
+            KvRecord::Pointer((_, pointer, _)) => {
+                let mut buf = vec![0u8; pointer.size];
+                self.storage
+                    .open(&value_log_name(pointer.log))?
+                    .read_exact_at(&mut buf, pointer.offset)?;
+                Ok(Some(state.codec.decode(&buf)?))
+            }
+            _ => Ok(None),
+        }
+    }
+    fn remove(&self, _key: K) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+    fn set_if(&self, _key: K, _value: V, _condition: SetCondition) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+    fn contains_key(&self, key: K) -> Result<bool> {
+        self.refresh_if_stale()?;
+        Ok(self.state.read()?.index.contains_key(&key))
+    }
+    fn scan_keys(&self, after: Option<K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        self.refresh_if_stale()?;
+        let state = self.state.read()?;
+        let mut smallest = SmallestKeys::new(after.as_ref(), limit);
+        for key in state.index.keys() {
+            smallest.offer(key);
+        }
+        Ok(smallest.finish())
+    }
+}
@@ -0,0 +1,98 @@
+//! Hash functions the in-memory key maps of a `KvStore` can use, see `KvStoreOptions::key_hasher`
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+use fxhash::FxHasher;
+use serde::{Deserialize, Serialize};
+
+/// Hash function of the key maps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyHasher {
+    /// SipHash with keys picked at random for every store, the hasher of the standard library.
+    /// Keys that collide can't be found without knowing them, so clients can't flood a map with
+    /// them to slow the server down.
+    #[default]
+    SipHash,
+    /// aHash with keys picked at random for every store, faster than SipHash and meant to
+    /// resist flooding as well, though it makes weaker promises
+    AHash,
+    /// The hash of rustc, the fastest on small keys. It isn't keyed, so only use it when the
+    /// keys can be trusted.
+    Fx,
+}
+
+/// Builds the hashers of `KeyHasher`
+#[derive(Clone)]
+pub(crate) enum KeyHashBuilder {
+    SipHash(RandomState),
+    AHash(ahash::RandomState),
+    Fx,
+}
+
+impl KeyHashBuilder {
+    pub(crate) fn new(hasher: KeyHasher) -> KeyHashBuilder {
+        match hasher {
+            KeyHasher::SipHash => KeyHashBuilder::SipHash(RandomState::new()),
+            KeyHasher::AHash => KeyHashBuilder::AHash(ahash::RandomState::new()),
+            KeyHasher::Fx => KeyHashBuilder::Fx,
+        }
+    }
+}
+
+impl BuildHasher for KeyHashBuilder {
+    type Hasher = KeyHashState;
+
+    fn build_hasher(&self) -> KeyHashState {
+        match self {
+            KeyHashBuilder::SipHash(state) => KeyHashState::SipHash(state.build_hasher()),
+            KeyHashBuilder::AHash(state) => KeyHashState::AHash(state.build_hasher()),
+            KeyHashBuilder::Fx => KeyHashState::Fx(FxHasher::default()),
+        }
+    }
+}
+
+/// Hasher of a `KeyHashBuilder`. Integers are handed on as they are, which Fx and aHash hash
+/// faster than their bytes.
+pub(crate) enum KeyHashState {
+    SipHash(DefaultHasher),
+    AHash(ahash::AHasher),
+    Fx(FxHasher),
+}
+
+macro_rules! forward {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self {
+            KeyHashState::SipHash(hasher) => hasher.$method($($arg),*),
+            KeyHashState::AHash(hasher) => hasher.$method($($arg),*),
+            KeyHashState::Fx(hasher) => hasher.$method($($arg),*),
+        }
+    };
+}
+
+impl Hasher for KeyHashState {
+    fn finish(&self) -> u64 {
+        forward!(self.finish())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        forward!(self.write(bytes))
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        forward!(self.write_u8(i))
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        forward!(self.write_u32(i))
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        forward!(self.write_u64(i))
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        forward!(self.write_usize(i))
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::codec::RecordCodec;
+use super::storage::SegmentStorage;
+use super::Result;
+use crate::{stable_hash, ErrorContext, KvsError, ResultExt};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+// The generation before the current one, kept until the next one is durable
+const PREVIOUS_MANIFEST_FILE: &str = "MANIFEST.prev";
+// Format of the files of a store, bumped when older builds can't read them anymore
+const FORMAT: u32 = 2;
+pub(crate) const LOG_EXTENSION: &str = "kvs";
+pub(crate) const VALUE_LOG_EXTENSION: &str = "vlog";
+
+/// Durable list of the segments making up a store
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Format the store was written in, manifests from before it was recorded are 0
+    #[serde(default)]
+    pub(crate) format: u32,
+    /// Counts up with every save, manifests from before it was recorded are 0
+    #[serde(default)]
+    pub(crate) generation: u64,
+    /// Id handed to the next segment that gets created, ids are never reused
+    pub(crate) next_segment_id: u64,
+    /// Live segments in replay order, which is always ascending id order
+    pub(crate) segments: Vec<u64>,
+    /// Serialization of the records, stores from before the choice of codec are all msgpack
+    #[serde(default)]
+    pub(crate) codec: RecordCodec,
+    /// Value logs holding the values records point at, in ascending id order. Values are
+    /// appended to the last one.
+    #[serde(default)]
+    pub(crate) value_logs: Vec<u64>,
+    /// Checksums of the segments and value logs that are no longer written to, by id. Active
+    /// files and those sealed before checksums were recorded have none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) checksums: BTreeMap<u64, FileChecksum>,
+    /// Seq past that of every write so far, stores from before writes were stamped have none.
+    /// Records of later writes may have higher ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) next_seq: Option<u64>,
+    /// Hash of the manifest serialized without it, older manifests have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<u64>,
+}
+
+/// `stable_hash` of the first `len` bytes of a sealed file, the rest being a preallocated tail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileChecksum {
+    pub(crate) len: u64,
+    pub(crate) hash: u64,
+}
+
+/// Name of the segment file with the given id. Ids are zero padded so that the file names sort
+/// the same way the ids do.
+pub(crate) fn segment_name(id: u64) -> String {
+    format!("{:020}.{}", id, LOG_EXTENSION)
+}
+
+/// Name of the value log with the given id, ids are shared with the segments
+pub(crate) fn value_log_name(id: u64) -> String {
+    format!("{:020}.{}", id, VALUE_LOG_EXTENSION)
+}
+
+impl Manifest {
+    /// Reads the manifest of the store in `storage`, if it has one. A crash while saving can
+    /// leave only the previous generation behind, which is used then.
+    pub(crate) fn load(storage: &dyn SegmentStorage) -> Result<Option<Manifest>> {
+        let previous = Manifest::read(storage, PREVIOUS_MANIFEST_FILE);
+        let manifest = match Manifest::read(storage, MANIFEST_FILE)? {
+            Some(manifest) => manifest,
+            None => {
+                let previous = previous?;
+                if let Some(previous) = &previous {
+                    info!(
+                        "No manifest, recovering from the previous generation {}",
+                        previous.generation
+                    );
+                }
+                return Ok(previous);
+            }
+        };
+        // Only an unreadable previous generation is fine, the current one is what counts
+        if let Ok(Some(previous)) = previous {
+            if previous.generation >= manifest.generation {
+                return Err(KvsError::CorruptStore(format!(
+                    "manifest generation {} isn't newer than the previous one, {}",
+                    manifest.generation, previous.generation
+                )));
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    fn read(storage: &dyn SegmentStorage, name: &str) -> Result<Option<Manifest>> {
+        let contents = match storage
+            .read(name)
+            .context(|| ErrorContext::new("read manifest").with_file(name))?
+        {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+        let mut manifest: Manifest = serde_json::from_slice(&contents)
+            .map_err(|e| KvsError::CorruptStore(format!("unreadable manifest: {}", e)))?;
+        if manifest.format > FORMAT {
+            return Err(KvsError::UnsupportedFormat(manifest.format));
+        }
+        if let Some(checksum) = manifest.checksum.take() {
+            if stable_hash(&serde_json::to_vec(&manifest)?) != checksum {
+                return Err(KvsError::CorruptStore(
+                    "manifest checksum mismatch".to_owned(),
+                ));
+            }
+        }
+        manifest.segments.sort_unstable();
+        manifest.value_logs.sort_unstable();
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replaces the manifest of the store in `storage` with this one, as its next
+    /// generation. The current generation is moved aside first and kept until the next save,
+    /// so that there is a whole manifest to load whenever a crash happens.
+    pub(crate) fn save(&mut self, storage: &dyn SegmentStorage) -> Result<()> {
+        self.generation += 1;
+        let (segments, value_logs) = (&self.segments, &self.value_logs);
+        self.checksums
+            .retain(|id, _| segments.contains(id) || value_logs.contains(id));
+        let mut sealed = Manifest {
+            format: FORMAT,
+            checksum: None,
+            ..self.clone()
+        };
+        sealed.checksum = Some(stable_hash(&serde_json::to_vec(&sealed)?));
+        // Missing after a crash right after moving it aside
+        if storage.len(MANIFEST_FILE)?.is_some() {
+            storage
+                .rename(MANIFEST_FILE, PREVIOUS_MANIFEST_FILE)
+                .context(|| ErrorContext::new("keep manifest").with_file(MANIFEST_FILE))?;
+        }
+        storage
+            .write(MANIFEST_FILE, &serde_json::to_vec(&sealed)?)
+            .context(|| ErrorContext::new("write manifest").with_file(MANIFEST_FILE))
+    }
+
+    pub(crate) fn allocate_segment_id(&mut self) -> u64 {
+        let id = self.next_segment_id;
+        self.next_segment_id += 1;
+        id
+    }
+
+    /// Segment and value log files in `storage` that aren't part of the store, left behind by a
+    /// crash between creating or retiring one and saving the manifest
+    pub(crate) fn orphaned_segments(&self, storage: &dyn SegmentStorage) -> Result<Vec<String>> {
+        let live: Vec<String> = self
+            .segments
+            .iter()
+            .map(|id| segment_name(*id))
+            .chain(self.value_logs.iter().map(|id| value_log_name(*id)))
+            .collect();
+        Ok(storage
+            .list()?
+            .into_iter()
+            .filter(|name| {
+                name.ends_with(&format!(".{}", LOG_EXTENSION))
+                    || name.ends_with(&format!(".{}", VALUE_LOG_EXTENSION))
+            })
+            .filter(|name| !live.contains(name))
+            .collect())
+    }
+}
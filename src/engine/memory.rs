@@ -0,0 +1,91 @@
+//! Engine keeping everything in memory, enabled by the `wasm` feature.
+//!
+//! It doesn't touch the file system or spawn threads, so apps compiled to WebAssembly can use
+//! the same `KvsEngine` abstraction as the rest of the crate. Data lives as long as the engine
+//! and its clones do.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, RwLock};
+
+use super::super::KvsError;
+use super::{KvsEngine, Result, SetCondition, SmallestKeys};
+
+#[derive(Debug)]
+pub struct MemoryEngine<K, V> {
+    map: Arc<RwLock<HashMap<K, V>>>,
+}
+
+impl<K, V> MemoryEngine<K, V> {
+    pub fn new() -> MemoryEngine<K, V> {
+        MemoryEngine {
+            map: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K, V> Default for MemoryEngine<K, V> {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
+
+// Clones share the map, so they can't require K and V to be Clone like a derive would
+impl<K, V> Clone for MemoryEngine<K, V> {
+    fn clone(&self) -> Self {
+        MemoryEngine {
+            map: self.map.clone(),
+        }
+    }
+}
+
+impl<K, V> KvsEngine<K, V> for MemoryEngine<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn set(&self, key: K, value: V) -> Result<()> {
+        self.map.write()?.insert(key, value);
+        Ok(())
+    }
+    fn set_if(&self, key: K, value: V, condition: SetCondition) -> Result<bool> {
+        let mut map = self.map.write()?;
+        if map.contains_key(&key) != (condition == SetCondition::Present) {
+            return Ok(false);
+        }
+        map.insert(key, value);
+        Ok(true)
+    }
+    fn get(&self, key: K) -> Result<Option<V>> {
+        Ok(self.map.read()?.get(&key).cloned())
+    }
+    fn remove(&self, key: K) -> Result<()> {
+        match self.map.write()?.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NonExistantKey),
+        }
+    }
+    fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.map.read()?.contains_key(&key))
+    }
+    fn write_batch(&self, writes: Vec<(K, Option<V>)>) -> Result<()> {
+        let mut map = self.map.write()?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+    fn scan_keys(&self, after: Option<K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        let mut smallest = SmallestKeys::new(after.as_ref(), limit);
+        for key in self.map.read()?.keys() {
+            smallest.offer(key);
+        }
+        Ok(smallest.finish())
+    }
+}
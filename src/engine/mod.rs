@@ -1,10 +1,303 @@
-use crate::Result;
+use std::collections::BinaryHeap;
+
+use self::scrub::ScrubReport;
+use self::tail::Tail;
+use crate::protocol::KvRequest;
+pub use crate::protocol::{SetCondition, ValueMeta};
+use crate::{KvsError, Result};
+
+/// Folds merge operands into the value of a key, so that writers can change a value without
+/// reading it first. Merges must be deterministic, replicas and replays fold the same operands.
+pub trait MergeOperator<K, V>: Send + Sync + 'static {
+    /// Value of `key` after applying `operand` to `existing`, none if the key has no value
+    fn merge(&self, key: &K, existing: Option<V>, operand: V) -> Option<V>;
+}
 
 pub trait KvsEngine<K, V>: Clone + Send + 'static {
     fn set(&self, key: K, value: V) -> Result<()>;
     fn get(&self, key: K) -> Result<Option<V>>;
+    /// Same as `get`, along with what the engine knows of the write of the value. Engines that
+    /// don't keep track of it answer without.
+    fn get_with_meta(&self, key: K) -> Result<Option<(V, Option<ValueMeta>)>> {
+        Ok(self.get(key)?.map(|value| (value, None)))
+    }
     fn remove(&self, key: K) -> Result<()>;
+    /// Sets `key` if `condition` holds, returns whether the value was set. Engines that can
+    /// override it to check atomically with the write, by default it checks with `contains_key`
+    /// before the `set`, missing a write landing in between.
+    fn set_if(&self, key: K, value: V, condition: SetCondition) -> Result<bool>
+    where
+        K: Clone,
+    {
+        match (condition, self.contains_key(key.clone())?) {
+            (SetCondition::Absent, false) | (SetCondition::Present, true) => {
+                self.set(key, value)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Sets `key` unless it has a value already
+    fn set_nx(&self, key: K, value: V) -> Result<bool>
+    where
+        K: Clone,
+    {
+        self.set_if(key, value, SetCondition::Absent)
+    }
+    /// Sets `key` only if it has a value already
+    fn set_xx(&self, key: K, value: V) -> Result<bool>
+    where
+        K: Clone,
+    {
+        self.set_if(key, value, SetCondition::Present)
+    }
+    /// Sets `key` only if its value was written by the write numbered `expected_version`, see
+    /// `ValueMeta::seq`, or only if it has no value when none is expected, like an HTTP
+    /// `If-Match` or `If-None-Match`. Checked atomically with the write, returns whether the
+    /// value was set. Values of engines that don't keep track of their writes have no version,
+    /// expecting one never matches.
+    fn set_if_version(&self, key: K, value: V, expected_version: Option<u64>) -> Result<bool>
+    where
+        K: Clone,
+    {
+        match expected_version {
+            Some(_) => Ok(false),
+            None => self.set_if(key, value, SetCondition::Absent),
+        }
+    }
+    /// Same as `set_if_version` for a removal, keys without a value fail with
+    /// `KvsError::NonExistantKey` when none is expected
+    fn remove_if_version(&self, key: K, expected_version: Option<u64>) -> Result<bool> {
+        match expected_version {
+            Some(_) => Ok(false),
+            None if self.contains_key(key)? => Ok(false),
+            None => Err(KvsError::NonExistantKey),
+        }
+    }
+    /// Checks whether `key` has a value, engines override it to avoid reading the value
+    fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+    /// Sets every key of `writes` with a value and removes every key without one, as one write:
+    /// a crash or error partway through leaves none of them. Removing a key that has no value
+    /// does nothing. Engines that can't fail with `KvsError::BatchUnsupported`.
+    fn write_batch(&self, _writes: Vec<(K, Option<V>)>) -> Result<()> {
+        Err(KvsError::BatchUnsupported)
+    }
+    /// Applies `operand` to the value of `key` with the merge operator of the engine, engines
+    /// without one fail with `KvsError::MergeUnsupported`
+    fn merge(&self, _key: K, _operand: V) -> Result<()> {
+        Err(KvsError::MergeUnsupported)
+    }
+    /// Same as `merge`, returning the value `key` had right before, like popping from a list
+    fn fetch_merge(&self, _key: K, _operand: V) -> Result<Option<V>> {
+        Err(KvsError::MergeUnsupported)
+    }
+    /// Bytes the engine takes up on disk, none for engines that don't keep anything there
+    fn disk_usage(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Lets writes through again after the engine stopped taking them, like once space was
+    /// freed on a full disk. Returns the error that stopped them, none if they weren't.
+    fn resume_writes(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Reads back everything the engine keeps on disk to find damage done to it since it was
+    /// written, see `scrub::ScrubReport`. None for engines that can't tell.
+    fn scrub(&self) -> Result<Option<ScrubReport<K>>> {
+        Ok(None)
+    }
+    /// Up to `limit` keys in ascending order, starting after `after` or at the first key.
+    /// Engines that can't list their keys fail with `KvsError::ScanUnsupported`.
+    fn scan_keys(&self, _after: Option<K>, _limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        Err(KvsError::ScanUnsupported)
+    }
+    /// Every key and value as of a single write, along with the seq of the first change made
+    /// after it for `changes` to continue from. Engines without a change feed fail with
+    /// `KvsError::ChangesUnsupported`.
+    fn snapshot(&self) -> Result<(u64, Vec<(K, V)>)> {
+        Err(KvsError::ChangesUnsupported)
+    }
+    /// Changes made from `from_seq` on, see the `tail` module
+    fn changes(&self, _from_seq: u64) -> Result<Tail<K, V>> {
+        Err(KvsError::ChangesUnsupported)
+    }
+}
+
+/// Whether a key is at `expected_version`, given the version of its value if it has one, see
+/// `KvsEngine::set_if_version`
+pub(crate) fn version_matches(version: Option<Option<u64>>, expected_version: Option<u64>) -> bool {
+    match (version, expected_version) {
+        (None, None) => true,
+        (Some(version), Some(expected)) => version == Some(expected),
+        _ => false,
+    }
+}
+
+/// Picks the `limit` smallest keys after `after` out of keys offered in any order, only holding
+/// on to `limit` of them at a time
+pub(crate) struct SmallestKeys<'a, K> {
+    after: Option<&'a K>,
+    limit: usize,
+    keys: BinaryHeap<K>,
 }
 
+impl<'a, K: Ord + Clone> SmallestKeys<'a, K> {
+    pub(crate) fn new(after: Option<&'a K>, limit: usize) -> SmallestKeys<'a, K> {
+        SmallestKeys {
+            after,
+            limit,
+            keys: BinaryHeap::new(),
+        }
+    }
+
+    pub(crate) fn offer(&mut self, key: &K) {
+        if self.limit == 0 || self.after.is_some_and(|after| key <= after) {
+            return;
+        }
+        if self.keys.len() == self.limit {
+            match self.keys.peek() {
+                Some(largest) if key < largest => {
+                    self.keys.pop();
+                }
+                _ => return,
+            }
+        }
+        self.keys.push(key.clone());
+    }
+
+    pub(crate) fn finish(self) -> Vec<K> {
+        self.keys.into_sorted_vec()
+    }
+}
+
+#[cfg(feature = "engine-kvs")]
+pub mod analyze;
+pub mod cache;
+#[cfg(feature = "engine-kvs")]
+pub mod codec;
+#[cfg(all(feature = "engine-kvs", target_os = "linux"))]
+mod direct;
+#[cfg(feature = "engine-kvs")]
+pub mod export;
+#[cfg(feature = "engine-kvs")]
+pub mod follower;
+#[cfg(feature = "engine-kvs")]
+pub mod hasher;
+#[cfg(feature = "engine-kvs")]
+mod manifest;
+#[cfg(feature = "wasm")]
+pub mod memory;
+pub mod namespace;
+mod platform;
+#[cfg(feature = "engine-kvs")]
+pub mod read_txn;
+#[cfg(feature = "s3")]
+mod s3;
+pub mod scrub;
+#[cfg(feature = "engine-sled")]
 pub mod sled;
+#[cfg(feature = "engine-kvs")]
+pub mod storage;
+#[cfg(feature = "engine-kvs")]
 pub mod store;
+pub mod tail;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
+impl<K: Clone, V> KvRequest<K, V> {
+    /// Fails like `apply` would when the condition of a write doesn't hold against `engine`,
+    /// without applying it
+    pub fn check<E: KvsEngine<K, V>>(&self, engine: &E) -> Result<()> {
+        match self {
+            KvRequest::SetIf((key, _, condition)) => {
+                match (condition, engine.contains_key(key.clone())?) {
+                    (SetCondition::Absent, false) | (SetCondition::Present, true) => Ok(()),
+                    _ => Err(KvsError::ConditionNotMet),
+                }
+            }
+            KvRequest::Rm(key) if !engine.contains_key(key.clone())? => {
+                Err(KvsError::NonExistantKey)
+            }
+            KvRequest::IfVersion {
+                expected_version,
+                request,
+            } => {
+                let key = request.key().ok_or(KvsError::Other)?;
+                let version = engine
+                    .get_with_meta(key.clone())?
+                    .map(|(_, meta)| meta.map(|meta| meta.seq));
+                if !version_matches(version, *expected_version) {
+                    return Err(KvsError::ConditionNotMet);
+                }
+                request.check(engine)
+            }
+            KvRequest::Idempotent { request, .. } => request.check(engine),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs a get, set or remove against `engine`
+    pub fn apply<E: KvsEngine<K, V>>(self, engine: &E) -> Result<Option<V>> {
+        match self {
+            KvRequest::Set(kv) => engine.set(kv.0, kv.1).map(|_| None),
+            KvRequest::Get(k) | KvRequest::ReplicaGet { key: k, .. } => engine.get(k),
+            KvRequest::SetEx((k, v, _)) => engine.set(k, v).map(|_| None),
+            KvRequest::SetIf((k, v, condition)) => match engine.set_if(k, v, condition)? {
+                true => Ok(None),
+                false => Err(KvsError::ConditionNotMet),
+            },
+            KvRequest::Rm(k) => engine.remove(k).map(|_| None),
+            KvRequest::Merge((k, operand)) => engine.merge(k, operand).map(|_| None),
+            KvRequest::FetchMerge((k, operand)) => engine.fetch_merge(k, operand),
+            KvRequest::WriteBatch(writes) => engine.write_batch(writes).map(|_| None),
+            KvRequest::Idempotent { request, .. } => request.apply(engine),
+            KvRequest::IfVersion {
+                expected_version,
+                request,
+            } => {
+                let applied = match *request {
+                    KvRequest::Set((k, v)) => engine.set_if_version(k, v, expected_version)?,
+                    KvRequest::Rm(k) => engine.remove_if_version(k, expected_version)?,
+                    _ => return Err(KvsError::Other),
+                };
+                match applied {
+                    true => Ok(None),
+                    false => Err(KvsError::ConditionNotMet),
+                }
+            }
+            KvRequest::Handshake(_)
+            | KvRequest::Watch(_)
+            | KvRequest::Cluster(_)
+            | KvRequest::Replicate { .. }
+            | KvRequest::Stats
+            | KvRequest::Keys { .. }
+            | KvRequest::TopKeys { .. }
+            | KvRequest::MerkleHashes { .. }
+            | KvRequest::MerkleLeaf(_)
+            | KvRequest::Snapshot
+            | KvRequest::Topology
+            | KvRequest::SetLogLevel(_)
+            | KvRequest::ResumeWrites
+            | KvRequest::Scrub { .. }
+            | KvRequest::GetWithMeta(_)
+            | KvRequest::Lock(_)
+            | KvRequest::Unlock(_)
+            | KvRequest::OpenSession(_)
+            | KvRequest::Heartbeat(_)
+            | KvRequest::CloseSession(_)
+            | KvRequest::SetEphemeral(_)
+            | KvRequest::Begin
+            | KvRequest::TxnGet(_)
+            | KvRequest::TxnWrite { .. }
+            | KvRequest::Prepare(_)
+            | KvRequest::Commit(_)
+            | KvRequest::Abort(_)
+            | KvRequest::SetQuota { .. }
+            | KvRequest::Batch { .. } => Err(KvsError::Other),
+        }
+    }
+}
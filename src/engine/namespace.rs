@@ -0,0 +1,320 @@
+//! Accounting and quotas for the namespaces keys are grouped in, for stores shared by tenants.
+//!
+//! The namespace of a key is the part before its first `:`, the same grouping `kvs-admin
+//! analyze` reports on. Keys without one are in the empty namespace.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use super::platform;
+use super::scrub::ScrubReport;
+use super::tail::Tail;
+use super::{version_matches, KvsEngine, SetCondition, ValueMeta};
+pub use crate::protocol::{NamespaceQuota, NamespaceStats};
+use crate::{KvsError, Result};
+
+pub const NAMESPACE_DELIMITER: char = ':';
+
+// Keys read per page when counting what a store already holds
+const SCAN_PAGE_SIZE: usize = 1000;
+
+pub fn namespace_of(key: &str) -> &str {
+    key.split_once(NAMESPACE_DELIMITER)
+        .map_or("", |(namespace, _)| namespace)
+}
+
+#[derive(Debug, Default)]
+struct Accounts {
+    usage: HashMap<String, NamespaceStats>,
+}
+
+impl Accounts {
+    /// Changes the usage of the namespace of `key` from holding `old` bytes for it to holding
+    /// `new`, failing if that breaks the quota of the namespace. Shrinking is always allowed.
+    fn update(&mut self, key: &str, old: Option<u64>, new: Option<u64>, check: bool) -> Result<()> {
+        let namespace = namespace_of(key);
+        let stats = self.usage.entry(namespace.to_owned()).or_default();
+        let keys = stats.keys + new.is_some() as u64 - old.is_some() as u64;
+        let bytes = stats.bytes + new.unwrap_or(0) - old.unwrap_or(0);
+        if check {
+            let over_keys = stats.quota.max_keys.is_some_and(|max| keys > max);
+            let over_bytes = stats.quota.max_bytes.is_some_and(|max| bytes > max);
+            if (over_keys && keys > stats.keys) || (over_bytes && bytes > stats.bytes) {
+                return Err(KvsError::QuotaExceeded(namespace.to_owned()));
+            }
+        }
+        stats.keys = keys;
+        stats.bytes = bytes;
+        Ok(())
+    }
+}
+
+/// Engine keeping count of the keys and bytes of every namespace of the engine it wraps, and
+/// failing writes that would take a namespace over its quota with `KvsError::QuotaExceeded`.
+/// Writes are run one at a time so that the accounts can't drift, and read the value they
+/// replace first to know its size.
+pub struct NamespacedEngine<E> {
+    engine: E,
+    namespaces: Namespaces,
+}
+
+impl<E: Clone> Clone for NamespacedEngine<E> {
+    fn clone(&self) -> Self {
+        NamespacedEngine {
+            engine: self.engine.clone(),
+            namespaces: self.namespaces.clone(),
+        }
+    }
+}
+
+/// Accounts of a `NamespacedEngine`, shared with it
+#[derive(Clone)]
+pub struct Namespaces {
+    accounts: Arc<Mutex<Accounts>>,
+    // File the quotas are kept in, if any
+    quotas_path: Option<Arc<PathBuf>>,
+}
+
+impl Namespaces {
+    /// Usage and quota of every namespace that has keys or a quota
+    pub fn stats(&self) -> Result<BTreeMap<String, NamespaceStats>> {
+        Ok(self
+            .accounts
+            .lock()?
+            .usage
+            .iter()
+            .filter(|(_, stats)| stats.keys > 0 || stats.quota != NamespaceQuota::default())
+            .map(|(namespace, stats)| (namespace.clone(), stats.clone()))
+            .collect())
+    }
+
+    /// Sets the quota of `namespace`. Namespaces already over it keep their keys, but can only
+    /// shrink until they are back under it.
+    pub fn set_quota(&self, namespace: String, quota: NamespaceQuota) -> Result<()> {
+        let mut accounts = self.accounts.lock()?;
+        accounts.usage.entry(namespace).or_default().quota = quota;
+        if let Some(path) = &self.quotas_path {
+            let quotas: BTreeMap<&String, NamespaceQuota> = accounts
+                .usage
+                .iter()
+                .filter(|(_, stats)| stats.quota != NamespaceQuota::default())
+                .map(|(namespace, stats)| (namespace, stats.quota))
+                .collect();
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, serde_json::to_vec(&quotas)?)?;
+            platform::durable_rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+fn entry_bytes(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+impl<E: KvsEngine<String, String>> NamespacedEngine<E> {
+    /// Wraps `engine`, reading every key and value it holds to start the accounts from. Quotas
+    /// are loaded from and saved to the JSON file `quotas_path` when given.
+    pub fn new(engine: E, quotas_path: Option<&Path>) -> Result<NamespacedEngine<E>> {
+        let mut accounts = Accounts::default();
+        let mut after = None;
+        loop {
+            let keys = engine.scan_keys(after.take(), SCAN_PAGE_SIZE)?;
+            for key in &keys {
+                if let Some(value) = engine.get(key.clone())? {
+                    accounts.update(key, None, Some(entry_bytes(key, &value)), false)?;
+                }
+            }
+            match keys.last() {
+                Some(last) if keys.len() == SCAN_PAGE_SIZE => after = Some(last.clone()),
+                _ => break,
+            }
+        }
+        if let Some(contents) = quotas_path.filter(|path| path.exists()).map(fs::read) {
+            let quotas: BTreeMap<String, NamespaceQuota> = serde_json::from_slice(&contents?)?;
+            for (namespace, quota) in quotas {
+                accounts.usage.entry(namespace).or_default().quota = quota;
+            }
+        }
+        info!("Accounted for {} namespaces", accounts.usage.len());
+        Ok(NamespacedEngine {
+            engine,
+            namespaces: Namespaces {
+                accounts: Arc::new(Mutex::new(accounts)),
+                quotas_path: quotas_path.map(|path| Arc::new(path.to_path_buf())),
+            },
+        })
+    }
+
+    fn set_accounted(&self, accounts: &mut Accounts, key: String, value: String) -> Result<()> {
+        let old = self.engine.get(key.clone())?;
+        let old = old.map(|old| entry_bytes(&key, &old));
+        let new = Some(entry_bytes(&key, &value));
+        accounts.update(&key, old, new, true)?;
+        if let Err(e) = self.engine.set(key.clone(), value) {
+            accounts.update(&key, new, old, false)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Accounts for `writes` one after the other, noting the key, old size and new size of
+    /// each write accounted for in `updates` for them to be undone
+    fn account_batch(
+        &self,
+        accounts: &mut Accounts,
+        writes: &[(String, Option<String>)],
+        updates: &mut Vec<(String, Option<u64>, Option<u64>)>,
+    ) -> Result<()> {
+        // Size of each key as of the writes accounted for so far
+        let mut sizes = HashMap::new();
+        for (key, value) in writes {
+            let old = match sizes.get(key) {
+                Some(&old) => old,
+                None => self
+                    .engine
+                    .get(key.clone())?
+                    .map(|old| entry_bytes(key, &old)),
+            };
+            let new = value.as_ref().map(|value| entry_bytes(key, value));
+            accounts.update(key, old, new, new.is_some())?;
+            sizes.insert(key.clone(), new);
+            updates.push((key.clone(), old, new));
+        }
+        Ok(())
+    }
+
+    pub fn namespaces(&self) -> Namespaces {
+        self.namespaces.clone()
+    }
+}
+
+impl<E: KvsEngine<String, String>> KvsEngine<String, String> for NamespacedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        self.set_accounted(&mut accounts, key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, Option<ValueMeta>)>> {
+        self.engine.get_with_meta(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let old = match self.engine.get(key.clone())? {
+            Some(old) => entry_bytes(&key, &old),
+            None => return Err(KvsError::NonExistantKey),
+        };
+        self.engine.remove(key.clone())?;
+        accounts.update(&key, Some(old), None, false)
+    }
+    /// Writes being run one at a time, the condition can be checked before the set
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        if self.engine.contains_key(key.clone())? != (condition == SetCondition::Present) {
+            return Ok(false);
+        }
+        self.set_accounted(&mut accounts, key, value)?;
+        Ok(true)
+    }
+    fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: Option<u64>,
+    ) -> Result<bool> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let version = self
+            .engine
+            .get_with_meta(key.clone())?
+            .map(|(_, meta)| meta.map(|meta| meta.seq));
+        if !version_matches(version, expected_version) {
+            return Ok(false);
+        }
+        self.set_accounted(&mut accounts, key, value)?;
+        Ok(true)
+    }
+    fn remove_if_version(&self, key: String, expected_version: Option<u64>) -> Result<bool> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let found = self.engine.get_with_meta(key.clone())?;
+        let version = found.as_ref().map(|(_, meta)| meta.map(|meta| meta.seq));
+        if !version_matches(version, expected_version) {
+            return Ok(false);
+        }
+        let old = match found {
+            Some((old, _)) => entry_bytes(&key, &old),
+            None => return Err(KvsError::NonExistantKey),
+        };
+        self.engine.remove(key.clone())?;
+        accounts.update(&key, Some(old), None, false)?;
+        Ok(true)
+    }
+    /// The merged value can't be known beforehand, so merges are accounted for once applied
+    /// and may take a namespace over its quota
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let old = self.engine.get(key.clone())?;
+        self.engine.merge(key.clone(), operand)?;
+        let new = self.engine.get(key.clone())?;
+        accounts.update(
+            &key,
+            old.map(|old| entry_bytes(&key, &old)),
+            new.map(|new| entry_bytes(&key, &new)),
+            false,
+        )
+    }
+    fn fetch_merge(&self, key: String, operand: String) -> Result<Option<String>> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let old = self.engine.fetch_merge(key.clone(), operand)?;
+        let new = self.engine.get(key.clone())?;
+        accounts.update(
+            &key,
+            old.as_ref().map(|old| entry_bytes(&key, old)),
+            new.map(|new| entry_bytes(&key, &new)),
+            false,
+        )?;
+        Ok(old)
+    }
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.engine.contains_key(key)
+    }
+    /// Every write is accounted for before the batch goes to the engine, and undone if one of
+    /// them breaks a quota or the engine fails the batch
+    fn write_batch(&self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let mut accounts = self.namespaces.accounts.lock()?;
+        let mut updates = Vec::with_capacity(writes.len());
+        let result = self
+            .account_batch(&mut accounts, &writes, &mut updates)
+            .and_then(|_| self.engine.write_batch(writes));
+        if let Err(e) = result {
+            for (key, old, new) in updates.into_iter().rev() {
+                accounts.update(&key, new, old, false)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+    fn disk_usage(&self) -> Result<Option<u64>> {
+        self.engine.disk_usage()
+    }
+    fn resume_writes(&self) -> Result<Option<String>> {
+        self.engine.resume_writes()
+    }
+    fn scrub(&self) -> Result<Option<ScrubReport<String>>> {
+        self.engine.scrub()
+    }
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.engine.scan_keys(after, limit)
+    }
+    fn snapshot(&self) -> Result<(u64, Vec<(String, String)>)> {
+        self.engine.snapshot()
+    }
+    fn changes(&self, from_seq: u64) -> Result<Tail<String, String>> {
+        self.engine.changes(from_seq)
+    }
+}
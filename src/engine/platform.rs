@@ -0,0 +1,113 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+/// Fills `buf` with the bytes of `file` starting at `offset` without moving a shared cursor
+#[cfg(unix)]
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+/// Fills `buf` with the bytes of `file` starting at `offset` without moving a shared cursor
+#[cfg(windows)]
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Returns options for opening a log file for writing. `dsync` only completes writes once their
+/// data is on disk, `direct` bypasses the page cache and is only supported on Linux.
+#[cfg(unix)]
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+pub(crate) fn log_open_options(dsync: bool, direct: bool) -> io::Result<OpenOptions> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut flags = 0;
+    if dsync {
+        flags |= libc::O_DSYNC;
+    }
+    if direct {
+        #[cfg(target_os = "linux")]
+        {
+            flags |= libc::O_DIRECT;
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(direct_unsupported());
+    }
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).custom_flags(flags);
+    Ok(open_options)
+}
+
+/// Returns options for opening a log file for writing. `dsync` only completes writes once their
+/// data is on disk, `direct` bypasses the page cache and is only supported on Linux.
+#[cfg(windows)]
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+pub(crate) fn log_open_options(dsync: bool, direct: bool) -> io::Result<OpenOptions> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_WRITE_THROUGH: u32 = 0x8000_0000;
+    if direct {
+        return Err(direct_unsupported());
+    }
+    let mut open_options = OpenOptions::new();
+    open_options.write(true);
+    if dsync {
+        open_options.custom_flags(FILE_FLAG_WRITE_THROUGH);
+    }
+    Ok(open_options)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn direct_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct IO is only supported on Linux",
+    )
+}
+
+/// Makes the creation, removal or renaming of entries in `dir_path` durable
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir_path: &Path) -> io::Result<()> {
+    File::open(dir_path)?.sync_all()
+}
+
+/// Makes the creation, removal or renaming of entries in `dir_path` durable.
+///
+/// Directories can't be flushed on Windows, NTFS journals the metadata of flushed files instead.
+#[cfg(windows)]
+pub(crate) fn sync_dir(_dir_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Renames `from` to `to`, replacing `to`, and only returns once the rename survives a crash
+pub(crate) fn durable_rename(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)?;
+    if cfg!(windows) {
+        // FlushFileBuffers on the renamed file also commits its directory entry
+        OpenOptions::new().write(true).open(to)?.sync_all()?;
+    }
+    match to.parent() {
+        Some(dir_path) if dir_path != Path::new("") => sync_dir(dir_path),
+        _ => sync_dir(Path::new(".")),
+    }
+}
@@ -0,0 +1,183 @@
+//! Read-only transactions seeing a `KvStore` as it was when they were opened, while writes go on.
+//! Writes made while one is open keep the value they replace in memory, until every transaction
+//! that may read it is dropped, and compaction carries the records of those values over to the
+//! new segment.
+
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use dashmap::DashMap;
+
+use super::store::{Key, KvStore, Value, ValueData};
+use super::Result;
+
+/// Value a key had right before a write, kept for the transactions opened before it
+#[derive(Debug, Clone)]
+pub(super) struct Prior {
+    /// Epoch the write moved the store to, transactions pinning an earlier one read this value
+    pub(super) until: u64,
+    /// Record of the value, none if the key had none
+    pub(super) record: Option<ValueData>,
+    /// Merge operands still to be folded into the value, oldest first
+    pub(super) operands: Vec<ValueData>,
+}
+
+/// Epochs pinned by the open transactions of a store, along with the values they may still read
+pub(super) struct ReadPins<K: Key> {
+    /// Writes made to the store, counting up under the writer lock
+    epoch: AtomicU64,
+    /// Open transactions by the epoch they pinned
+    open: Mutex<BTreeMap<u64, usize>>,
+    /// Count of open transactions, for writes to check without taking `open`
+    open_count: AtomicUsize,
+    /// Values replaced since the oldest open transaction, oldest first
+    priors: DashMap<K, Vec<Prior>>,
+}
+
+impl<K: Key> ReadPins<K> {
+    pub(super) fn new() -> ReadPins<K> {
+        ReadPins {
+            epoch: AtomicU64::new(0),
+            open: Mutex::new(BTreeMap::new()),
+            open_count: AtomicUsize::new(0),
+            priors: DashMap::new(),
+        }
+    }
+
+    /// Pins the current epoch for a new transaction. The caller holds the writer lock, so that
+    /// no write is halfway done.
+    pub(super) fn pin(&self) -> u64 {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        *open.entry(epoch).or_default() += 1;
+        self.open_count.fetch_add(1, Ordering::SeqCst);
+        epoch
+    }
+
+    /// Unpins `epoch` for a transaction that ended, dropping the values no open transaction can
+    /// read anymore
+    pub(super) fn unpin(&self, epoch: u64) {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = open.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&epoch);
+            }
+            self.open_count.fetch_sub(1, Ordering::SeqCst);
+        }
+        match open.keys().next() {
+            Some(&oldest) => self.priors.retain(|_, priors| {
+                priors.retain(|prior| prior.until > oldest);
+                !priors.is_empty()
+            }),
+            None => self.priors.clear(),
+        }
+    }
+
+    /// Moves on to the next epoch for a write of `key`, keeping the value `prior` gives if a
+    /// transaction is open. The caller holds the writer lock and hasn't changed the key yet.
+    pub(super) fn written(
+        &self,
+        key: &K,
+        prior: impl FnOnce() -> (Option<ValueData>, Vec<ValueData>),
+    ) {
+        let until = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.open_count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let (record, operands) = prior();
+        self.priors.entry(key.clone()).or_default().push(Prior {
+            until,
+            record,
+            operands,
+        });
+    }
+
+    /// Value `key` had at `epoch`, none if it wasn't written since
+    pub(super) fn prior(&self, key: &K, epoch: u64) -> Option<Prior> {
+        self.priors
+            .get(key)?
+            .iter()
+            .find(|prior| prior.until > epoch)
+            .cloned()
+    }
+
+    /// Keys with values kept
+    pub(super) fn keys(&self) -> Vec<K> {
+        self.priors
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Every record of the values kept, for compaction to carry over
+    pub(super) fn records(&self) -> Vec<(K, ValueData)> {
+        let mut records = Vec::new();
+        for entry in self.priors.iter() {
+            for prior in entry.value() {
+                let key = entry.key();
+                records.extend(
+                    prior
+                        .record
+                        .iter()
+                        .map(|record| (key.clone(), record.clone())),
+                );
+                records.extend(
+                    prior
+                        .operands
+                        .iter()
+                        .map(|record| (key.clone(), record.clone())),
+                );
+            }
+        }
+        records
+    }
+
+    /// Points the values kept at where compaction moved their records
+    pub(super) fn relocate(&self, relocate: impl Fn(&mut ValueData)) {
+        for mut entry in self.priors.iter_mut() {
+            for prior in entry.value_mut() {
+                prior.record.iter_mut().for_each(&relocate);
+                prior.operands.iter_mut().for_each(&relocate);
+            }
+        }
+    }
+}
+
+/// Read-only view of a `KvStore` as it was when `KvStore::read_txn` opened it, unpinned when
+/// dropped. Keep them short, every value written over in the meantime is held on to.
+pub struct ReadTxn<K: Key, V: Value> {
+    store: KvStore<K, V>,
+    epoch: u64,
+}
+
+impl<K: Key, V: Value> ReadTxn<K, V> {
+    pub(super) fn new(store: KvStore<K, V>, epoch: u64) -> ReadTxn<K, V> {
+        ReadTxn { store, epoch }
+    }
+
+    /// Epoch of the store the transaction reads, the count of writes made before it was opened
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        self.store.value_at(&key, self.epoch)
+    }
+
+    /// Pairs within `range`, in ascending key order
+    pub fn scan(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, V)>>
+    where
+        K: Ord,
+    {
+        self.store.scan_at(range, self.epoch)
+    }
+}
+
+impl<K: Key, V: Value> Drop for ReadTxn<K, V> {
+    fn drop(&mut self) {
+        self.store.unpin(self.epoch);
+    }
+}
@@ -0,0 +1,345 @@
+//! Requests to an S3 bucket for `S3Storage`, signed with AWS Signature Version 4.
+//!
+//! Every request opens its own HTTP/1.1 connection to the endpoint and addresses objects with
+//! path-style URLs, `/{bucket}/{prefix}{name}`. Connections are plain TCP, which suits MinIO and
+//! other S3 compatible servers on a private network. Reaching AWS over TLS takes a proxy.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{KvsError, Result};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE: &str = "s3";
+
+/// Where the files of an `S3Storage` are kept and the credentials to reach them
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Host and port requests are sent to, like `127.0.0.1:9000`, also sent as the Host header
+    pub endpoint: String,
+    pub bucket: String,
+    /// Put in front of the name of every file, so that several stores can share a bucket
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Sent along with temporary credentials
+    pub session_token: Option<String>,
+}
+
+impl S3Config {
+    pub fn new(
+        endpoint: impl Into<String>,
+        bucket: impl Into<String>,
+        access_key: impl Into<String>,
+        secret_key: impl Into<String>,
+    ) -> S3Config {
+        S3Config {
+            endpoint: endpoint.into(),
+            bucket: bucket.into(),
+            prefix: String::new(),
+            region: "us-east-1".to_owned(),
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            session_token: None,
+        }
+    }
+}
+
+pub(super) struct Response {
+    pub(super) status: u16,
+    pub(super) headers: Vec<(String, String)>,
+    pub(super) body: Vec<u8>,
+}
+
+impl Response {
+    pub(super) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub(super) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Error for a request that got an answer it didn't expect
+pub(super) fn unexpected(method: &str, name: &str, response: &Response) -> KvsError {
+    KvsError::IOError(format!(
+        "{} {} answered {}: {}",
+        method,
+        name,
+        response.status,
+        String::from_utf8_lossy(&response.body).trim()
+    ))
+}
+
+/// Path of the object `name`, or of the bucket itself if `name` is None, as sent in requests
+pub(super) fn object_path(config: &S3Config, name: Option<&str>) -> String {
+    let path = match name {
+        Some(name) => format!("/{}/{}{}", config.bucket, config.prefix, name),
+        None => format!("/{}", config.bucket),
+    };
+    uri_encode(&path, false)
+}
+
+/// Sends a signed request for the object `name`, or for the bucket itself if `name` is None.
+/// Header names in `headers` are lowercase, those starting with `x-amz-` get signed as well.
+pub(super) fn request(
+    config: &S3Config,
+    method: &str,
+    name: Option<&str>,
+    query: &[(&str, &str)],
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response> {
+    let path = object_path(config, name);
+    let mut query: Vec<(String, String)> = query
+        .iter()
+        .map(|(key, value)| (uri_encode(key, true), uri_encode(value, true)))
+        .collect();
+    query.sort();
+    let query = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let (amz, unsigned): (Vec<_>, Vec<_>) = headers
+        .iter()
+        .partition(|(name, _)| name.starts_with("x-amz-"));
+    let signed = sign(config, method, &path, &query, &amz, body, SystemTime::now());
+    let addr = config
+        .endpoint
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "endpoint has no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut head = format!(
+        "{} {}{}{} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        if query.is_empty() { "" } else { "?" },
+        query,
+        body.len()
+    );
+    for (name, value) in signed
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(unsigned.into_iter().copied())
+    {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    read_response(BufReader::new(stream), method == "HEAD")
+}
+
+/// Headers that sign a request with `body` and the `amz` headers to the canonical `path` and
+/// `query`, all of them along with the authorization
+fn sign<'a>(
+    config: &S3Config,
+    method: &str,
+    path: &str,
+    query: &str,
+    amz: &[&(&'a str, &str)],
+    body: &[u8],
+    now: SystemTime,
+) -> Vec<(&'a str, String)> {
+    let amz_date = amz_date(now);
+    let payload_hash = hex(&Sha256::digest(body));
+    let mut signed = vec![
+        ("host", config.endpoint.clone()),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &config.session_token {
+        signed.push(("x-amz-security-token", token.clone()));
+    }
+    signed.extend(amz.iter().map(|(name, value)| (*name, value.to_string())));
+    // Signing wants them sorted by name
+    signed.sort();
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>(),
+        signed_headers,
+        payload_hash
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, config.region, SERVICE);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = format!("AWS4{}", config.secret_key).into_bytes();
+    for part in [date, &config.region, SERVICE, "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key,
+        scope,
+        signed_headers,
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    );
+    signed.push(("authorization", authorization));
+    signed
+}
+
+fn read_response(mut reader: impl BufRead, head_only: bool) -> Result<Response> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| KvsError::IOError(format!("bad status line {:?}", line.trim_end())))?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    if head_only {
+        return Ok(response);
+    }
+    if response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| KvsError::IOError(format!("bad chunk size {:?}", size)))?;
+            if size == 0 {
+                break;
+            }
+            let start = response.body.len();
+            response.body.resize(start + size, 0);
+            reader.read_exact(&mut response.body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(len) = response.header("Content-Length") {
+        let len = len
+            .parse()
+            .map_err(|_| KvsError::IOError(format!("bad content length {:?}", len)))?;
+        response.body.resize(len, 0);
+        reader.read_exact(&mut response.body)?;
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+    Ok(response)
+}
+
+/// Text between every `<tag>` and `</tag>` of `xml`, unescaped
+pub(super) fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+// Percent-encodes everything but unreserved characters, and slashes unless `slash` is set
+fn uri_encode(s: &str, slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `time` as signing wants it, like `20130524T000000Z`
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,68 @@
+//! Reports of reading a store back to catch damage done to its files after they were written,
+//! like bit rot, before a read or a restart runs into it.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubReport<K> {
+    /// Segments and value logs read
+    pub files: u64,
+    pub bytes: u64,
+    /// Records decoded
+    pub records: u64,
+    /// Files that didn't match their checksum and records that didn't decode, a line each
+    pub damage: Vec<String>,
+    /// Keys whose current value is in a damaged part of a file, reads of them may fail or
+    /// return something else than what was written
+    pub bad_keys: Vec<K>,
+    /// Bad keys written again with their value from elsewhere, like another node of the cluster
+    #[serde(default)]
+    pub repaired: Vec<K>,
+}
+
+impl<K> Default for ScrubReport<K> {
+    fn default() -> Self {
+        ScrubReport {
+            files: 0,
+            bytes: 0,
+            records: 0,
+            damage: Vec::new(),
+            bad_keys: Vec::new(),
+            repaired: Vec::new(),
+        }
+    }
+}
+
+impl<K> ScrubReport<K> {
+    /// Whether nothing was found wrong
+    pub fn is_clean(&self) -> bool {
+        self.damage.is_empty()
+    }
+}
+
+impl<K: fmt::Display + PartialEq> fmt::Display for ScrubReport<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} bytes, {} records read, {} damaged, {} bad keys, {} repaired",
+            self.files,
+            self.bytes,
+            self.records,
+            self.damage.len(),
+            self.bad_keys.len(),
+            self.repaired.len()
+        )?;
+        for damage in &self.damage {
+            write!(f, "\n  {}", damage)?;
+        }
+        for key in &self.bad_keys {
+            match self.repaired.contains(key) {
+                true => write!(f, "\n  bad key {}: repaired", key)?,
+                false => write!(f, "\n  bad key {}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
 
-use sled::Db;
+use sled::{Db, IVec};
 
 use super::super::KvsError;
-use super::{KvsEngine, Result};
+use super::{KvsEngine, Result, SetCondition};
+use crate::keys::KeyCodec;
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
@@ -16,6 +18,64 @@ impl SledKvsEngine {
             db: sled::open(db_dir)?,
         })
     }
+
+    /// Sets a typed key, stored under its `KeyCodec` encoding so that `scan_typed` finds it in
+    /// key order. Typed keys share the tree with the string keys of `KvsEngine`, which are
+    /// stored as their plain bytes.
+    pub fn set_typed<K: KeyCodec>(&self, key: &K, value: String) -> Result<()> {
+        self.db.insert(key.to_key_bytes(), value.as_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get_typed<K: KeyCodec>(&self, key: &K) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .get(key.to_key_bytes())?
+            .map(|v| String::from_utf8(v.to_vec()).unwrap()))
+    }
+
+    pub fn remove_typed<K: KeyCodec>(&self, key: &K) -> Result<()> {
+        match self.db.remove(key.to_key_bytes())? {
+            Some(_v) => {
+                self.db.flush()?;
+                Ok(())
+            }
+            None => Err(KvsError::NonExistantKey),
+        }
+    }
+
+    /// Typed keys beginning with the fields of `prefix` along with their values, in key order,
+    /// see `KeyCodec::has_prefix`
+    pub fn scan_prefix_typed<K: KeyCodec, P: KeyCodec>(
+        &self,
+        prefix: &P,
+    ) -> Result<Vec<(K, String)>> {
+        self.db
+            .scan_prefix(prefix.to_key_bytes())
+            .map(typed_pair)
+            .collect()
+    }
+
+    /// Typed keys within `range` along with their values, in key order. String keys written
+    /// through `KvsEngine` that fall within the range fail to decode.
+    pub fn scan_typed<K: KeyCodec>(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, String)>> {
+        let bound = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.to_key_bytes()),
+            Bound::Excluded(key) => Bound::Excluded(key.to_key_bytes()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let range = (bound(range.start_bound()), bound(range.end_bound()));
+        self.db.range::<Vec<u8>, _>(range).map(typed_pair).collect()
+    }
+}
+
+fn typed_pair<K: KeyCodec>(pair: sled::Result<(IVec, IVec)>) -> Result<(K, String)> {
+    let (key, value) = pair?;
+    Ok((
+        K::from_key_bytes(&key)?,
+        String::from_utf8(value.to_vec()).unwrap(),
+    ))
 }
 
 impl From<sled::Error> for KvsError {
@@ -30,6 +90,28 @@ impl KvsEngine<String, String> for SledKvsEngine {
         self.db.flush()?;
         Ok(())
     }
+    /// Done with compare and swap, retried when another write got in between
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        loop {
+            let current = match condition {
+                SetCondition::Absent => None,
+                SetCondition::Present => match self.db.get(key.as_bytes())? {
+                    Some(current) => Some(current),
+                    None => return Ok(false),
+                },
+            };
+            match self
+                .db
+                .compare_and_swap(key.as_bytes(), current, Some(value.as_bytes()))?
+            {
+                Ok(()) => break,
+                Err(_) if condition == SetCondition::Present => continue,
+                Err(_) => return Ok(false),
+            }
+        }
+        self.db.flush()?;
+        Ok(true)
+    }
     fn get(&self, key: String) -> Result<Option<String>> {
         Ok(self
             .db
@@ -45,6 +127,37 @@ impl KvsEngine<String, String> for SledKvsEngine {
             None => Err(KvsError::NonExistantKey),
         }
     }
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key.as_bytes())?)
+    }
+    fn write_batch(&self, writes: Vec<(String, Option<String>)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.insert(key.as_bytes(), value.as_bytes()),
+                None => batch.remove(key.as_bytes()),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+    fn disk_usage(&self) -> Result<Option<u64>> {
+        Ok(Some(self.db.size_on_disk()?))
+    }
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let range = match &after {
+            Some(after) => self
+                .db
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+            None => self.db.iter(),
+        };
+        range
+            .keys()
+            .take(limit)
+            .map(|key| Ok(String::from_utf8(key?.to_vec()).unwrap()))
+            .collect()
+    }
 }
 impl Drop for SledKvsEngine {
     fn drop(&mut self) {
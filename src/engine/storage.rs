@@ -0,0 +1,689 @@
+//! Media the files of a store are kept on.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "s3")]
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "s3")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+
+use fs2::FileExt as _;
+#[cfg(feature = "s3")]
+use log::warn;
+
+#[cfg(target_os = "linux")]
+use super::direct::DirectFile;
+use super::manifest::LOG_EXTENSION;
+use super::platform;
+#[cfg(feature = "s3")]
+use super::s3;
+#[cfg(feature = "s3")]
+pub use super::s3::S3Config;
+use super::store::KvStoreOptions;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring::{Ring, UringAppender, UringReader};
+use super::Result;
+#[cfg(feature = "s3")]
+use crate::KvsError;
+
+/// Holds the segments, hint files and manifest of a store. Files are named by the store and all
+/// live side by side.
+pub trait SegmentStorage: Send + Sync + 'static {
+    /// Names of every file
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Contents of the file `name`, if there is one
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Atomically replaces the file `name` with `contents`, which survives a crash once this
+    /// returns
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()>;
+
+    /// Creates the empty segment `name`. Returns false without touching it if there is one
+    /// already.
+    fn create_segment(&self, name: &str) -> Result<bool>;
+
+    /// Length of the file `name`, if there is one
+    fn len(&self, name: &str) -> Result<Option<u64>> {
+        Ok(self.read(name)?.map(|contents| contents.len() as u64))
+    }
+
+    /// Handle for reading the file `name` at any offset
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>>;
+
+    /// Handle for appending to the file `name` after its first `position` bytes
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>>;
+
+    /// Renames `from` to `to`, replacing `to`, and only returns once the rename survives a crash
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Removes a file that is no longer needed, missing files are ignored
+    fn remove(&self, name: &str) -> Result<()>;
+}
+
+pub trait SegmentReader: Send + Sync {
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Fills every buffer of `reads` with the bytes starting at its offset, readers that can
+    /// batch reads override it
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        for (offset, buf) in reads {
+            self.read_exact_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait SegmentAppender: Write + Send {
+    /// Makes everything written so far survive a crash
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Writes `data` and makes it survive a crash along with everything written before,
+    /// appenders that can do both in one go override it
+    fn write_synced(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)?;
+        self.flush()?;
+        self.sync_data()
+    }
+}
+
+// Retired log files waiting to be reused
+const FREE_EXTENSION: &str = "free";
+
+/// Files in a directory of the local file system, the default storage. Preallocation, reuse of
+/// retired segments, O_DSYNC, O_DIRECT and io_uring are taken from the options of the store.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    dir_path: PathBuf,
+    options: KvStoreOptions,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    ring: Option<Arc<Ring>>,
+}
+
+impl LocalStorage {
+    /// Storage in `dir_path`, which is created if missing
+    pub fn new(dir_path: &Path, options: KvStoreOptions) -> Result<LocalStorage> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let ring = match options.io_uring {
+            true => Some(Arc::new(Ring::new()?)),
+            false => None,
+        };
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        if options.io_uring {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring needs Linux and the io-uring feature",
+            )
+            .into());
+        }
+        if !dir_path.exists() {
+            fs::create_dir_all(dir_path)?;
+        }
+        Ok(LocalStorage {
+            dir_path: dir_path.to_path_buf(),
+            options,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            ring,
+        })
+    }
+
+    fn file_path(&self, name: &str) -> PathBuf {
+        self.dir_path.join(name)
+    }
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|osstr| osstr.to_str())
+        .map(|str| str == extension)
+        .unwrap_or(false)
+}
+
+fn zero_fill(file: &mut File) -> Result<()> {
+    let zeros = vec![0u8; 64 * 1024];
+    let mut remaining = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let len = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+impl SegmentStorage for LocalStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(fs::read_dir(&self.dir_path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.file_path(name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn len(&self, name: &str) -> Result<Option<u64>> {
+        match fs::metadata(self.file_path(name)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let path = self.file_path(name);
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+        platform::durable_rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// A retired log file is renamed into place when reuse is enabled, and the file is
+    /// preallocated when configured
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        let path = self.file_path(name);
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut free_files = fs::read_dir(&self.dir_path)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| has_extension(p, FREE_EXTENSION));
+        let file = match free_files.next().filter(|_| self.options.reuse_files) {
+            Some(free_path) => {
+                platform::durable_rename(&free_path, &path)?;
+                let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+                // Stale records past the end of the new log would be replayed otherwise
+                zero_fill(&mut file)?;
+                file
+            }
+            None => file,
+        };
+        if self.options.preallocate_bytes > file.metadata()?.len() {
+            file.allocate(self.options.preallocate_bytes)?;
+        }
+        Ok(true)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        let file = File::open(self.file_path(name))?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &self.ring {
+            return Ok(Box::new(UringReader::new(file, ring.clone())));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        let path = self.file_path(name);
+        let open_options = platform::log_open_options(self.options.dsync, self.options.direct_io)?;
+        #[cfg(target_os = "linux")]
+        if self.options.direct_io {
+            let reader = File::open(&path)?;
+            return Ok(Box::new(DirectFile::new(
+                open_options.open(&path)?,
+                &reader,
+                position,
+            )?));
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &self.ring {
+            let file = open_options.open(&path)?;
+            return Ok(Box::new(UringAppender::new(file, ring.clone(), position)));
+        }
+        let mut file = open_options.open(&path)?;
+        file.seek(SeekFrom::Start(position))?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        platform::durable_rename(&self.file_path(from), &self.file_path(to))?;
+        Ok(())
+    }
+
+    /// Log files are kept around for reuse when enabled
+    fn remove(&self, name: &str) -> Result<()> {
+        let path = self.file_path(name);
+        if !path.exists() {
+            return Ok(());
+        }
+        if self.options.reuse_files && has_extension(&path, LOG_EXTENSION) {
+            platform::durable_rename(&path, &path.with_extension(FREE_EXTENSION))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl SegmentReader for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        platform::read_exact_at(self, buf, offset)
+    }
+}
+
+impl SegmentAppender for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SegmentAppender for DirectFile {
+    fn sync_data(&self) -> io::Result<()> {
+        DirectFile::sync_data(self)
+    }
+}
+
+type MemoryFile = Arc<RwLock<Vec<u8>>>;
+
+/// Files held in memory, for tests. Clones share their files, so a store can be reopened on a
+/// clone of the storage it was written to.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<BTreeMap<String, MemoryFile>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn file(&self, name: &str) -> Result<MemoryFile> {
+        match self.files.lock()?.get(name) {
+            Some(file) => Ok(file.clone()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+}
+
+impl SegmentStorage for MemoryStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.files.lock()?.keys().cloned().collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.files.lock()?.get(name) {
+            Some(file) => Ok(Some(file.read()?.clone())),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.files
+            .lock()?
+            .insert(name.to_owned(), Arc::new(RwLock::new(contents.to_vec())));
+        Ok(())
+    }
+
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        let mut files = self.files.lock()?;
+        if files.contains_key(name) {
+            return Ok(false);
+        }
+        files.insert(name.to_owned(), MemoryFile::default());
+        Ok(true)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(MemoryReader(self.file(name)?)))
+    }
+
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        Ok(Box::new(MemoryAppender {
+            file: self.file(name)?,
+            position: position as usize,
+        }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.lock()?;
+        match files.remove(from) {
+            Some(file) => {
+                files.insert(to.to_owned(), file);
+                Ok(())
+            }
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.files.lock()?.remove(name);
+        Ok(())
+    }
+}
+
+struct MemoryReader(MemoryFile);
+
+impl SegmentReader for MemoryReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let contents = self.0.read().map_err(|e| io::Error::other(e.to_string()))?;
+        let start = offset as usize;
+        match contents.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )),
+        }
+    }
+}
+
+struct MemoryAppender {
+    file: MemoryFile,
+    position: usize,
+}
+
+impl Write for MemoryAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut contents = self
+            .file
+            .write()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        contents.truncate(self.position);
+        contents.resize(self.position, 0);
+        contents.extend_from_slice(buf);
+        self.position += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SegmentAppender for MemoryAppender {
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Files kept as objects of an S3 bucket, enabled by the `s3` feature, see `S3Config`.
+///
+/// Objects can't be appended to, so a file being appended is kept whole in memory and uploaded
+/// again on every sync, which makes small segments and `SyncPolicy::Buffered` the better fit.
+/// Readers see the unsynced tail of such a file from memory and go to the bucket for the rest,
+/// one ranged request per read. Renames copy the object and then remove it, a crash in between
+/// leaves both.
+#[cfg(feature = "s3")]
+#[derive(Clone)]
+pub struct S3Storage {
+    shared: Arc<S3Shared>,
+}
+
+#[cfg(feature = "s3")]
+struct S3Shared {
+    config: S3Config,
+    // Contents of the files being appended to, by name
+    appending: Mutex<HashMap<String, MemoryFile>>,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    pub fn new(config: S3Config) -> S3Storage {
+        S3Storage {
+            shared: Arc::new(S3Shared {
+                config,
+                appending: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl S3Shared {
+    fn appending(&self, name: &str) -> Result<Option<MemoryFile>> {
+        Ok(self.appending.lock()?.get(name).cloned())
+    }
+
+    fn get(&self, name: &str, range: Option<String>) -> Result<Option<Vec<u8>>> {
+        let headers: Vec<(&str, &str)> = range.iter().map(|range| ("range", &**range)).collect();
+        let response = s3::request(&self.config, "GET", Some(name), &[], &headers, &[])?;
+        match response.status {
+            404 => Ok(None),
+            _ if response.is_success() => Ok(Some(response.body)),
+            _ => Err(s3::unexpected("GET", name, &response)),
+        }
+    }
+
+    fn put(&self, name: &str, headers: &[(&str, &str)], contents: &[u8]) -> Result<()> {
+        let response = s3::request(&self.config, "PUT", Some(name), &[], headers, contents)?;
+        match response.is_success() {
+            true => Ok(()),
+            false => Err(s3::unexpected("PUT", name, &response)),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl SegmentStorage for S3Storage {
+    fn list(&self) -> Result<Vec<String>> {
+        let config = &self.shared.config;
+        let mut names = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", config.prefix.as_str())];
+            if let Some(token) = &continuation {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = s3::request(config, "GET", None, &query, &[], &[])?;
+            if !response.is_success() {
+                return Err(s3::unexpected("GET", &config.bucket, &response));
+            }
+            let listing = String::from_utf8_lossy(&response.body);
+            for key in s3::xml_values(&listing, "Key") {
+                if let Some(name) = key.strip_prefix(&config.prefix) {
+                    names.push(name.to_owned());
+                }
+            }
+            continuation = s3::xml_values(&listing, "NextContinuationToken").pop();
+            if continuation.is_none() {
+                break;
+            }
+        }
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.shared.appending(name)? {
+            Some(file) => Ok(Some(file.read()?.clone())),
+            None => self.shared.get(name, None),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.shared.put(name, &[], contents)
+    }
+
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        let response = s3::request(
+            &self.shared.config,
+            "PUT",
+            Some(name),
+            &[],
+            &[("if-none-match", "*")],
+            &[],
+        )?;
+        match response.status {
+            // Already there, or being created by a request racing this one
+            412 | 409 => Ok(false),
+            _ if response.is_success() => Ok(true),
+            _ => Err(s3::unexpected("PUT", name, &response)),
+        }
+    }
+
+    fn len(&self, name: &str) -> Result<Option<u64>> {
+        if let Some(file) = self.shared.appending(name)? {
+            return Ok(Some(file.read()?.len() as u64));
+        }
+        let response = s3::request(&self.shared.config, "HEAD", Some(name), &[], &[], &[])?;
+        match response.status {
+            404 => Ok(None),
+            _ if response.is_success() => Ok(response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())),
+            _ => Err(s3::unexpected("HEAD", name, &response)),
+        }
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(S3Reader {
+            shared: self.shared.clone(),
+            name: name.to_owned(),
+        }))
+    }
+
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        let mut contents = self.read(name)?.unwrap_or_default();
+        contents.resize(position as usize, 0);
+        let file = Arc::new(RwLock::new(contents));
+        self.shared
+            .appending
+            .lock()?
+            .insert(name.to_owned(), file.clone());
+        Ok(Box::new(S3Appender {
+            shared: self.shared.clone(),
+            name: name.to_owned(),
+            file,
+            unsynced: AtomicBool::new(false),
+        }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let source = s3::object_path(&self.shared.config, Some(from));
+        self.shared
+            .put(to, &[("x-amz-copy-source", &source)], &[])?;
+        self.remove(from)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.shared.appending.lock()?.remove(name);
+        let response = s3::request(&self.shared.config, "DELETE", Some(name), &[], &[], &[])?;
+        match response.is_success() || response.status == 404 {
+            true => Ok(()),
+            false => Err(s3::unexpected("DELETE", name, &response)),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+struct S3Reader {
+    shared: Arc<S3Shared>,
+    name: String,
+}
+
+#[cfg(feature = "s3")]
+impl SegmentReader for S3Reader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let other = |e: KvsError| io::Error::other(e.to_string());
+        if let Some(file) = self.shared.appending(&self.name).map_err(other)? {
+            return MemoryReader(file).read_exact_at(buf, offset);
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let range = format!("bytes={}-{}", offset, offset + buf.len() as u64 - 1);
+        let contents = self
+            .shared
+            .get(&self.name, Some(range))
+            .map_err(other)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        match contents.len() == buf.len() {
+            true => {
+                buf.copy_from_slice(&contents);
+                Ok(())
+            }
+            false => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+struct S3Appender {
+    shared: Arc<S3Shared>,
+    name: String,
+    file: MemoryFile,
+    unsynced: AtomicBool,
+}
+
+#[cfg(feature = "s3")]
+impl Write for S3Appender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file
+            .write()
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .extend_from_slice(buf);
+        self.unsynced.store(true, Ordering::SeqCst);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+impl SegmentAppender for S3Appender {
+    fn sync_data(&self) -> io::Result<()> {
+        if !self.unsynced.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let contents = self
+            .file
+            .read()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.shared.put(&self.name, &[], &contents).map_err(|e| {
+            self.unsynced.store(true, Ordering::SeqCst);
+            io::Error::other(e.to_string())
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Drop for S3Appender {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_data() {
+            warn!("Could not upload {}: {:?}", self.name, e);
+        }
+        if let Ok(mut appending) = self.shared.appending.lock() {
+            if appending
+                .get(&self.name)
+                .is_some_and(|file| Arc::ptr_eq(file, &self.file))
+            {
+                appending.remove(&self.name);
+            }
+        }
+    }
+}
@@ -1,72 +1,860 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::fmt::Display;
-use std::fs;
-use std::fs::File;
-use std::fs::OpenOptions;
 use std::hash::Hash;
 use std::io;
 use std::io::BufWriter;
-use std::io::Cursor;
 use std::io::Write;
 use std::marker::PhantomData;
-use std::os::unix::prelude::FileExt;
+use std::ops::ControlFlow;
+use std::ops::RangeBounds;
 use std::path::Path;
-use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::MutexGuard;
 use std::sync::PoisonError;
 use std::sync::RwLock;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use dashmap::DashMap;
+use log::{info, warn};
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 
 use super::super::KvsError;
-use super::KvsEngine;
+use super::analyze::{AnalyzeOptions, KeyspaceAnalyzer, KeyspaceReport};
+use super::codec::{Codec, RecordCodec};
+use super::export::{Export, FilePins};
+use super::hasher::{KeyHashBuilder, KeyHasher};
+use super::manifest::{segment_name, value_log_name, FileChecksum, Manifest, LOG_EXTENSION};
+use super::read_txn::{ReadPins, ReadTxn};
+use super::scrub::ScrubReport;
+use super::storage::{LocalStorage, SegmentAppender, SegmentReader, SegmentStorage};
+use super::tail::{Change, ChangeLog, Tail};
 use super::Result;
+use super::{version_matches, KvsEngine, MergeOperator, SetCondition, SmallestKeys, ValueMeta};
+use crate::keys::KeyCodec;
+use crate::metrics::{Counter, Latency, Percentiles};
+use crate::{stable_hash, ErrorContext, ResultExt, StableHasher};
+/// In-memory map keyed by the keys of the store, hashed with `KvStoreOptions::key_hasher`
+type KeyMap<K, V> = DashMap<K, V, KeyHashBuilder>;
+
+fn key_map<K: Key, V>(options: &KvStoreOptions) -> KeyMap<K, V> {
+    DashMap::with_hasher_and_shard_amount(
+        KeyHashBuilder::new(options.key_hasher),
+        options.index_stripes,
+    )
+}
+
 pub trait Key:
     Debug + Display + Clone + Eq + Hash + Serialize + for<'de> Deserialize<'de> + Send + 'static
 {
 }
-pub trait Value:
-    Debug + Display + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
-{
-}
+pub trait Value: Debug + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static {}
 
 impl Key for String {}
 impl Value for String {}
 
+// Integer keys scan in numeric order, `KeyCodec` encodes them big endian for engines that order
+// keys by their bytes
+impl Key for u32 {}
+impl Key for u64 {}
+impl Key for i64 {}
+
+impl Value for Vec<u8> {}
+impl Value for bool {}
+impl Value for u8 {}
+impl Value for u16 {}
+impl Value for u32 {}
+impl Value for u64 {}
+impl Value for i8 {}
+impl Value for i16 {}
+impl Value for i32 {}
+impl Value for i64 {}
+impl Value for f32 {}
+impl Value for f64 {}
+
 #[derive(Serialize, Deserialize, Debug)]
-enum KvRecord<K, V> {
+pub(super) enum KvRecord<K, V> {
     Set((K, V)),
+    // Removal written before tombstones carried the time of the delete
     Rm(K),
+    // Removal along with its time in milliseconds since the unix epoch
+    Tombstone((K, u64)),
+    // Set along with its time in milliseconds since the unix epoch, written while history is kept
+    TimedSet((K, V, u64)),
+    // Operand for the merge operator, applied on top of the value before it
+    Merge((K, V)),
+    // Set of a value written to the value log, along with its time when history is kept
+    Pointer((K, ValuePointer, Option<u64>)),
+    // Record along with the seq and time in milliseconds since the unix epoch of its write,
+    // records are never stamped twice
+    Stamped((u64, u64, Box<KvRecord<K, V>>)),
+    // Count of the records right after it, written as one by `KvsEngine::write_batch`. Replay
+    // only applies them once all of them are read, it is never stamped.
+    Batch(u64),
+}
+
+impl<K, V> KvRecord<K, V> {
+    /// The record without its stamp, along with the stamp if it had one
+    pub(super) fn unstamp(self) -> (KvRecord<K, V>, Option<Stamp>) {
+        match self {
+            KvRecord::Stamped((seq, at, record)) => (*record, Some(Stamp { seq, at })),
+            record => (record, None),
+        }
+    }
+}
+
+// Borrowing twin of KvRecord used for writing, it serializes to the same bytes
+#[derive(Serialize, Debug)]
+#[serde(rename = "KvRecord")]
+enum KvRecordRef<'a, K, V> {
+    Set((&'a K, &'a V)),
+    // Never written anymore, but keeps the variants of both enums lined up
+    #[allow(dead_code)]
+    Rm(&'a K),
+    Tombstone((&'a K, u64)),
+    TimedSet((&'a K, &'a V, u64)),
+    Merge((&'a K, &'a V)),
+    Pointer((&'a K, ValuePointer, Option<u64>)),
+    Stamped((u64, u64, &'a KvRecordRef<'a, K, V>)),
+    Batch(u64),
+}
+
+/// Seq and time of a write, records written before they were stamped have none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Stamp {
+    pub(super) seq: u64,
+    /// Milliseconds since the unix epoch
+    pub(super) at: u64,
+}
+
+impl Stamp {
+    fn meta(self, size: u64) -> ValueMeta {
+        ValueMeta {
+            seq: self.seq,
+            written_at: self.at,
+            size,
+        }
+    }
+}
+
+/// Where a value written to the value log is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ValuePointer {
+    pub(super) log: u64,
+    pub(super) offset: u64,
+    pub(super) size: usize,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct ValueData {
+    pub(super) segment: u64,
+    pub(super) size: usize,
+    pub(super) offset: u64,
+    // Copy of the record when it is small enough to keep in memory, see
+    // `KvStoreOptions::inline_value_bytes`
+    pub(super) inline: Option<Arc<[u8]>>,
+    // Place of the value when the record only points at it in the value log
+    pub(super) pointer: Option<ValuePointer>,
+}
+
+impl ValueData {
+    /// Reads the record into `buf`, from the inline copy if there is one
+    fn read_into(
+        &self,
+        readers: &BTreeMap<u64, Box<dyn SegmentReader>>,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        buf.resize(self.size, 0);
+        match &self.inline {
+            Some(inline) => buf.copy_from_slice(inline),
+            None => readers[&self.segment]
+                .read_exact_at(buf, self.offset)
+                .context(|| self.read_context())?,
+        }
+        Ok(())
+    }
+
+    fn read_context(&self) -> ErrorContext {
+        ErrorContext::new("read record")
+            .with_file(segment_name(self.segment))
+            .with_offset(self.offset)
+    }
+}
+
+// Value of a key from some time on, kept for reads as of a past time
+#[derive(Debug, Clone)]
+struct Version {
+    // Milliseconds since the unix epoch, 0 for values written before history was kept
+    at: u64,
+    // Set or tombstone record of the version
+    record: ValueData,
+    removed: bool,
+}
+
+/// Drops the versions no read as of `cutoff` or later can see, returning whether the rest tell
+/// more than the index does
+fn prune_versions(versions: &mut Vec<Version>, cutoff: u64) -> bool {
+    let visible_from = versions
+        .iter()
+        .rposition(|version| version.at <= cutoff)
+        .unwrap_or(0);
+    versions.drain(..visible_from);
+    versions.len() > 1 || versions.first().is_some_and(|version| version.at > cutoff)
+}
+
+/// Adds a version of `key` replacing the record `previous`, which is taken as there from the
+/// start if the key has no history yet
+fn push_version<K: Key>(
+    history: &KeyMap<K, Vec<Version>>,
+    key: &K,
+    version: Version,
+    previous: Option<ValueData>,
+    cutoff: u64,
+) {
+    let mut versions = history.entry(key.clone()).or_insert_with(|| {
+        previous
+            .map(|record| Version {
+                at: 0,
+                record,
+                removed: false,
+            })
+            .into_iter()
+            .collect()
+    });
+    versions.push(version);
+    let keep = prune_versions(&mut versions, cutoff);
+    drop(versions);
+    if !keep {
+        history.remove(key);
+    }
+}
+
+/// Controls when buffered writes to the active log file are pushed to the OS and to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// Flush the write buffer to the OS after every write
+    Flush,
+    /// Flush the write buffer and fsync the file data after every write
+    Always,
+    /// Only flush when the buffer is full, before reading unflushed records, on compaction,
+    /// on `KvStore::flush` and when the store is dropped
+    Buffered,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    pub sync_policy: SyncPolicy,
+    /// Capacity in bytes of the buffer in front of the active log file
+    pub write_buffer_size: usize,
+    /// Number of bytes to reserve on disk up front for every new log file, 0 disables it
+    pub preallocate_bytes: u64,
+    /// Keep log files retired by compaction around and reuse them for the next new log file
+    pub reuse_files: bool,
+    /// Open the active log file with O_DSYNC so every write only returns once it is on disk
+    pub dsync: bool,
+    /// Open the active log file with O_DIRECT, bypassing the page cache. Only supported on Linux
+    pub direct_io: bool,
+    /// Read and write log files through io_uring, which batches the reads of replay and
+    /// `get_many` and links the writes of `SyncPolicy::Always` to their fsync. Needs Linux 5.6
+    /// and the `io-uring` feature. Not used for the active log file with `direct_io`.
+    pub io_uring: bool,
+    /// Size in bytes after which the active log file is sealed and a new segment is started
+    pub max_segment_bytes: u64,
+    /// Bytes of overwritten and removed records after which compaction runs
+    pub compaction_threshold: u64,
+    /// Adjust the compaction threshold and segment size after every compaction, based on the
+    /// write amplification and read latency observed since the previous one
+    pub adaptive: bool,
+    /// How long compaction keeps the tombstones of removed keys, so that replicas and change
+    /// consumers catching up can still see the delete
+    pub tombstone_grace_period: Duration,
+    /// Serialization of the records of new stores, existing stores keep the one they were
+    /// created with
+    pub codec: RecordCodec,
+    /// Bytes of records the segments may hold before sets fail with `KvsError::OutOfSpace`, 0
+    /// disables it. Sets over the limit compact first if that can free anything. Removes are
+    /// always let through, and compaction needs room for a copy of the live records on top.
+    pub max_disk_bytes: u64,
+    /// Latest writes kept in memory for `KvStore::tail`, 0 disables tailing
+    pub change_log_capacity: usize,
+    /// How far back `KvStore::get_as_of` can read, overwritten and removed values are kept
+    /// on disk that long. 0 disables history, which also leaves the time of sets unrecorded.
+    pub history_retention: Duration,
+    /// Records of at most this many bytes, key and value serialized together, are also kept in
+    /// the index so that reading them never touches disk, e.g. 64 for small hot values. 0
+    /// disables it.
+    pub inline_value_bytes: usize,
+    /// Sets whose record takes more than this many bytes write their value to a separate value
+    /// log, leaving a small record pointing at it, so that compaction doesn't copy large values
+    /// around. The value log is only rewritten by compactions that find more than
+    /// `compaction_threshold` bytes of it overwritten or removed. 0 keeps values in the records.
+    pub value_log_threshold: usize,
+    /// Number of stripes the in-memory key maps are split into, each behind its own lock, so
+    /// that threads working on different keys rarely wait on each other. Rounded up to a power
+    /// of two, 0 picks one from the number of CPUs.
+    pub index_stripes: usize,
+    /// Bytes of records kept in memory when the store is opened, on top of those small enough
+    /// for `inline_value_bytes`, so that the first reads after a restart don't all go to disk.
+    /// The newest records are kept first, and they stay until their key is written again. 0
+    /// keeps none.
+    pub warm_bytes: u64,
+    /// Repair damage a crash can leave behind when opening the store, like a torn record at
+    /// the end of the active segment or an unreadable hint file, logging what was fixed. When
+    /// off, opening fails with `KvsError::RepairNeeded` instead.
+    pub repair: bool,
+    /// Writes kept waiting longer than this on compaction, an fsync or the writer lock are
+    /// logged as a warning and counted in `StoreStats::write_stalls`. Zero disables it.
+    pub stall_threshold: Duration,
+    /// Hash function of the in-memory key maps. Fx is faster on small keys, but only SipHash,
+    /// the default, keeps clients that pick the keys from making them collide.
+    pub key_hasher: KeyHasher,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::Flush,
+            write_buffer_size: 64 * 1024,
+            preallocate_bytes: 0,
+            reuse_files: false,
+            dsync: false,
+            direct_io: false,
+            io_uring: false,
+            max_segment_bytes: 64 * 1024 * 1024,
+            compaction_threshold: 1000000,
+            adaptive: false,
+            tombstone_grace_period: Duration::ZERO,
+            codec: RecordCodec::Msgpack,
+            max_disk_bytes: 0,
+            change_log_capacity: 1024,
+            history_retention: Duration::ZERO,
+            inline_value_bytes: 0,
+            value_log_threshold: 0,
+            index_stripes: 0,
+            warm_bytes: 0,
+            repair: true,
+            stall_threshold: Duration::from_secs(1),
+            key_hasher: KeyHasher::SipHash,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StoreMetrics {
+    // Bytes of records written on behalf of set and remove
+    bytes_written: Counter,
+    // Bytes of records rewritten by compaction
+    compaction_bytes_written: Counter,
+    compactions: Counter,
+    write_stalls: Counter,
+    reads: Latency,
+    writes: Latency,
+    removes: Latency,
+}
+
+/// Point in time view of the metrics and limits of a store
+#[derive(Debug, Clone)]
+pub struct StoreStats {
+    pub bytes_written: u64,
+    pub compaction_bytes_written: u64,
+    pub compactions: u64,
+    /// Times a write waited longer than `KvStoreOptions::stall_threshold`
+    pub write_stalls: u64,
+    pub reads: u64,
+    pub mean_read_latency: Duration,
+    pub read_latency: Percentiles,
+    pub write_latency: Percentiles,
+    pub remove_latency: Percentiles,
+    pub compaction_threshold: u64,
+    pub max_segment_bytes: u64,
+    /// Bytes of records held by the segments
+    pub disk_bytes: u64,
+    /// Bytes of the value logs held by values that were overwritten or removed since they were
+    /// last rewritten
+    pub value_garbage: u64,
+    /// Stripes the key map is split into
+    pub index_stripes: usize,
+}
+
+impl StoreStats {
+    /// The stats as OpenTelemetry metrics
+    #[cfg(feature = "otel")]
+    pub fn otel_metrics(&self) -> Vec<crate::otel::Metric> {
+        use crate::otel::Metric;
+        let counter = |name: &str, value| Metric::Counter {
+            name: format!("kvs.store.{}", name),
+            value,
+        };
+        let gauge = |name: &str, value: u64| Metric::Gauge {
+            name: format!("kvs.store.{}", name),
+            value: value as i64,
+        };
+        let latency = |name: &str, percentiles| Metric::Latency {
+            name: format!("kvs.store.{}", name),
+            percentiles,
+        };
+        vec![
+            counter("bytes_written", self.bytes_written),
+            counter("compaction_bytes_written", self.compaction_bytes_written),
+            counter("compactions", self.compactions),
+            counter("write_stalls", self.write_stalls),
+            gauge("compaction_threshold", self.compaction_threshold),
+            gauge("max_segment_bytes", self.max_segment_bytes),
+            gauge("disk_bytes", self.disk_bytes),
+            gauge("value_garbage", self.value_garbage),
+            latency("read_latency", self.read_latency),
+            latency("write_latency", self.write_latency),
+            latency("remove_latency", self.remove_latency),
+        ]
+    }
+
+    /// Bytes written to disk for every byte written by set and remove
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_written == 0 {
+            return 1.0;
+        }
+        (self.bytes_written + self.compaction_bytes_written) as f64 / self.bytes_written as f64
+    }
 }
 
+// Writes failing with IO errors in a row after which the store stops taking writes
+const DEGRADE_AFTER_IO_ERRORS: u64 = 3;
+
+// Bounds the adaptive mode keeps the limits within
+const MIN_COMPACTION_THRESHOLD: u64 = 64 * 1024;
+const MAX_COMPACTION_THRESHOLD: u64 = 1024 * 1024 * 1024;
+const MIN_SEGMENT_BYTES: u64 = 1024 * 1024;
+const MAX_SEGMENT_BYTES: u64 = 256 * 1024 * 1024;
+// Write amplification the adaptive mode tries to stay between
+const HIGH_WRITE_AMPLIFICATION: f64 = 3.0;
+const LOW_WRITE_AMPLIFICATION: f64 = 1.5;
+
+/// Limits that start out from the options and only move in adaptive mode
 #[derive(Debug)]
-struct ValueData {
-    size: usize,
+struct Tuning {
+    compaction_threshold: AtomicU64,
+    max_segment_bytes: AtomicU64,
+    // Metrics at the previous adjustment
+    last_window: Mutex<TuningWindow>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TuningWindow {
+    bytes_written: u64,
+    compaction_bytes_written: u64,
+    reads: u64,
+    read_time: Duration,
+    mean_read_latency: Duration,
+}
+
+/// What kept a write waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StallCause {
+    Compaction,
+    Fsync,
+    // Another write holding the writer lock
+    Lock,
+}
+
+impl StallCause {
+    fn as_str(self) -> &'static str {
+        match self {
+            StallCause::Compaction => "compaction",
+            StallCause::Fsync => "fsync",
+            StallCause::Lock => "lock",
+        }
+    }
+}
+
+// Marks compaction as running until dropped, so writes waiting on it know why
+struct Compacting<'a>(&'a AtomicBool);
+
+impl<'a> Compacting<'a> {
+    fn start(flag: &'a AtomicBool) -> Compacting<'a> {
+        flag.store(true, Ordering::SeqCst);
+        Compacting(flag)
+    }
+}
+
+impl Drop for Compacting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// How far the active segment has been handed to the OS. Sealed segments are flushed in full
+/// before the writer moves on from them.
+struct Flushed {
+    segment: AtomicU64,
+    position: AtomicU64,
+}
+
+impl Flushed {
+    fn new(segment: u64, position: u64) -> Flushed {
+        Flushed {
+            segment: AtomicU64::new(segment),
+            position: AtomicU64::new(position),
+        }
+    }
+
+    /// Whether a record of `segment` ending at `end` was handed to the OS, records of the
+    /// active segment may still sit in the write buffer
+    fn covers(&self, segment: u64, end: u64) -> bool {
+        segment != self.segment.load(Ordering::SeqCst)
+            || end <= self.position.load(Ordering::SeqCst)
+    }
+
+    fn position(&self) -> u64 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    /// Notes that the active segment was flushed up to `position`
+    fn advance(&self, position: u64) {
+        self.position.store(position, Ordering::SeqCst);
+    }
+
+    /// Notes that the writer moved on to `segment`, flushed up to `position`. The position goes
+    /// first so that a reader seeing the new segment never compares against the old position.
+    fn moved_to(&self, segment: u64, position: u64) {
+        self.position.store(position, Ordering::SeqCst);
+        self.segment.store(segment, Ordering::SeqCst);
+    }
+}
+
+struct LogWriter {
+    buf_writer: BufWriter<Box<dyn SegmentAppender>>,
+    // Id of the segment currently appended to
+    segment: u64,
+    position: u64,
+    // Only ever changed while holding the writer lock
+    manifest: Manifest,
+    // Last value log, values are written straight through so readers always see them
+    value_log: Option<ValueLog>,
+    // Bytes of all the value logs
+    value_log_bytes: u64,
+}
+
+struct ValueLog {
+    file: Box<dyn SegmentAppender>,
+    id: u64,
+    position: u64,
+}
+
+impl LogWriter {
+    /// Syncs the last value log, which has to come before syncing any segment that may point
+    /// into it. Values are only flushed to the OS as they are written unless every write syncs.
+    fn sync_value_log(&self) -> Result<()> {
+        if let Some(log) = &self.value_log {
+            log.file.sync_data().context(|| {
+                ErrorContext::new("sync value log").with_file(value_log_name(log.id))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// Merge operands a key can have before they are folded into its value on write, which bounds
+// the records a read of it goes through
+const MAX_PENDING_OPERANDS: usize = 64;
+
+// Index entries of a segment written by bulk_load or offline compaction, read instead of
+// replaying the segment
+const HINT_EXTENSION: &str = "hint";
+
+/// Index entry of a record in a hint file: key, offset, size and where the value is for records
+/// that only point at it in the value log
+pub(super) type Hint<K> = (K, u64, usize, Option<ValuePointer>);
+
+// Index entry of hint files written before they held value pointers
+type LegacyHint<K> = (K, u64, usize);
+
+/// Index entries of a hint file, none for a hint file written before they held value pointers,
+/// whose segment is replayed instead so that overwriting its values counts them as garbage
+pub(super) fn decode_hints<K: Key>(
+    codec: RecordCodec,
+    hints: &[u8],
+) -> Result<Option<Vec<Hint<K>>>> {
+    match codec.decode::<Vec<Hint<K>>>(hints) {
+        Ok(hints) => Ok(Some(hints)),
+        Err(e) => match codec.decode::<Vec<LegacyHint<K>>>(hints) {
+            Ok(_) => Ok(None),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+type IndexEntry<K> = (K, ValueData);
+
+/// Copy of `serialized` to keep in the index, if it is small enough
+fn inline_copy(options: &KvStoreOptions, serialized: &[u8]) -> Option<Arc<[u8]>> {
+    if serialized.len() > options.inline_value_bytes {
+        return None;
+    }
+    Some(Arc::from(serialized))
+}
+
+pub(super) fn hint_name(segment: u64) -> String {
+    format!("{:020}.{}", segment, HINT_EXTENSION)
+}
+
+/// Creates the file for a new segment in `storage`, taking its id from the manifest. The caller
+/// is responsible for adding the segment to the manifest.
+fn allocate_segment(storage: &dyn SegmentStorage, manifest: &mut Manifest) -> Result<u64> {
+    loop {
+        let id = manifest.allocate_segment_id();
+        // A crash before the manifest was saved can leave a file behind under the next id
+        if storage.create_segment(&segment_name(id))? {
+            return Ok(id);
+        }
+    }
+}
+
+/// Creates an empty value log in `storage`, taking its id from the manifest. The caller is
+/// responsible for adding it to the manifest.
+fn create_value_log(storage: &dyn SegmentStorage, manifest: &mut Manifest) -> Result<u64> {
+    loop {
+        let id = manifest.allocate_segment_id();
+        if storage.len(&value_log_name(id))?.is_none() {
+            // Not created like segments, so that it is never preallocated nor a reused file
+            storage.write(&value_log_name(id), &[])?;
+            return Ok(id);
+        }
+    }
+}
+
+/// How far opening a store got, see `KvStore::open_with_progress`
+#[derive(Debug, Clone)]
+pub struct OpenProgress {
+    /// Segments replayed so far
+    pub segments_done: usize,
+    pub segments: usize,
+    /// Bytes of the segments replayed so far, segments with hints count in full
+    pub bytes_replayed: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl OpenProgress {
+    /// Time left if the rest replays as fast as what is done, none before any byte is replayed
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_replayed == 0 {
+            return None;
+        }
+        let left = self.bytes.saturating_sub(self.bytes_replayed);
+        Some(
+            self.elapsed
+                .mul_f64(left as f64 / self.bytes_replayed as f64),
+        )
+    }
+}
+
+// Most bytes read at once when walking records in the order they are laid out on disk
+const READ_AHEAD_BYTES: usize = 1024 * 1024;
+
+/// Records read before their turn, see `KvStore::copy_records`
+#[derive(Default)]
+struct ReadAhead {
+    segment: u64,
     offset: u64,
+    buf: Vec<u8>,
+}
+
+impl ReadAhead {
+    /// Bytes of `record`, if they were read already
+    fn get(&self, record: &ValueData) -> Option<&[u8]> {
+        if record.segment != self.segment || record.offset < self.offset {
+            return None;
+        }
+        let start = (record.offset - self.offset) as usize;
+        self.buf.get(start..start + record.size)
+    }
+}
+
+/// Number of stripes the key maps get for the `index_stripes` option, the same default as
+/// DashMap's
+fn stripe_count(index_stripes: usize) -> usize {
+    let stripes = match index_stripes {
+        0 => std::thread::available_parallelism().map_or(1, usize::from) * 4,
+        stripes => stripes,
+    };
+    stripes.max(2).next_power_of_two()
+}
+
+/// Bytes of the value log a record points at, which become garbage once it is replaced
+fn pointed_bytes(record: &ValueData) -> u64 {
+    record.pointer.map_or(0, |pointer| pointer.size as u64)
+}
+
+/// Error of a record that was found where the index points but doesn't decode
+fn corrupt(error: KvsError) -> KvsError {
+    match error {
+        KvsError::SerializationError(e) => KvsError::CorruptRecord(e),
+        error => error,
+    }
+}
+
+/// What is wrong with the sealed file `file`, if anything, going by its checksum
+fn check_file(file: &[u8], checksum: Option<&FileChecksum>) -> Option<String> {
+    let checksum = checksum?;
+    if (file.len() as u64) < checksum.len {
+        return Some(format!(
+            "is {} bytes, shorter than the {} it was sealed with",
+            file.len(),
+            checksum.len
+        ));
+    }
+    let hash = stable_hash(&file[..checksum.len as usize]);
+    (hash != checksum.hash).then(|| "doesn't match its checksum".to_owned())
+}
+
+/// Removes a segment that is no longer referenced along with its hint file
+fn retire_segment(storage: &dyn SegmentStorage, segment: u64) -> Result<()> {
+    storage.remove(&hint_name(segment))?;
+    storage.remove(&segment_name(segment))
+}
+
+/// Checksum of the first `len` bytes of the file `name`, read back a chunk at a time once it
+/// is sealed
+fn checksum_file(storage: &dyn SegmentStorage, name: &str, len: u64) -> Result<FileChecksum> {
+    let reader = storage.open(name)?;
+    let mut hasher = StableHasher::new();
+    let mut buf = vec![0u8; READ_AHEAD_BYTES.min(len as usize)];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = &mut buf[..(len - offset).min(READ_AHEAD_BYTES as u64) as usize];
+        reader.read_exact_at(chunk, offset).context(|| {
+            ErrorContext::new("checksum file")
+                .with_file(name)
+                .with_offset(offset)
+        })?;
+        hasher.write(chunk);
+        offset += chunk.len() as u64;
+    }
+    Ok(FileChecksum {
+        len,
+        hash: hasher.finish(),
+    })
 }
 
-struct BufWriterWithPosition<T: Write> {
-    buf_writer: BufWriter<T>,
-    path: PathBuf,
+/// Writes records to new segments that are sealed with a hint file once full. The segments
+/// aren't part of the store until the caller adds them to the manifest.
+struct SegmentWriter<'a, K> {
+    storage: &'a dyn SegmentStorage,
+    options: &'a KvStoreOptions,
+    max_segment_bytes: u64,
+    current: Option<(u64, BufWriter<Box<dyn SegmentAppender>>)>,
     position: u64,
+    // Index entries of every record written, those of the current segment start at `sealed`
+    written: Vec<IndexEntry<K>>,
+    sealed: usize,
+    segments: Vec<u64>,
 }
 
-fn get_new_file_path(dir_path: &Path) -> PathBuf {
-    dir_path.join(format!(
-        "{}.kvs",
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_nanos()
-            .to_string()
-    ))
+impl<'a, K: Key> SegmentWriter<'a, K> {
+    fn new(
+        storage: &'a dyn SegmentStorage,
+        options: &'a KvStoreOptions,
+        max_segment_bytes: u64,
+    ) -> Self {
+        SegmentWriter {
+            storage,
+            options,
+            max_segment_bytes,
+            current: None,
+            position: 0,
+            written: Vec::new(),
+            sealed: 0,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Appends the serialized record of `key`, starting a new segment when needed. `pointer` is
+    /// where the value is if the record only points at it.
+    fn write(
+        &mut self,
+        manifest: &mut Manifest,
+        key: K,
+        serialized: &[u8],
+        pointer: Option<ValuePointer>,
+    ) -> Result<()> {
+        if self.position >= self.max_segment_bytes {
+            self.seal(manifest)?;
+        }
+        let (segment, file) = match &mut self.current {
+            Some((segment, file)) => (*segment, file),
+            None => {
+                let segment = allocate_segment(self.storage, manifest)?;
+                self.segments.push(segment);
+                let file = BufWriter::with_capacity(
+                    self.options.write_buffer_size,
+                    self.storage.append(&segment_name(segment), 0)?,
+                );
+                let (_, file) = self.current.insert((segment, file));
+                (segment, file)
+            }
+        };
+        file.write_all(serialized)?;
+        self.written.push((
+            key,
+            ValueData {
+                segment,
+                offset: self.position,
+                size: serialized.len(),
+                inline: inline_copy(self.options, serialized),
+                pointer,
+            },
+        ));
+        self.position += serialized.len() as u64;
+        Ok(())
+    }
+
+    /// Syncs the current segment, records its checksum in `manifest` and writes its hint file
+    fn seal(&mut self, manifest: &mut Manifest) -> Result<()> {
+        let (segment, mut file) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        file.flush()?;
+        file.get_ref().sync_data()?;
+        let checksum = checksum_file(self.storage, &segment_name(segment), self.position)?;
+        manifest.checksums.insert(segment, checksum);
+        let hints: Vec<Hint<&K>> = self.written[self.sealed..]
+            .iter()
+            .map(|(key, value_data)| (key, value_data.offset, value_data.size, value_data.pointer))
+            .collect();
+        self.storage
+            .write(&hint_name(segment), &self.options.codec.encode(&hints)?)?;
+        self.sealed = self.written.len();
+        self.position = 0;
+        Ok(())
+    }
+
+    /// Seals the last segment, returning the new segments and the index entries of their records
+    fn finish(mut self, manifest: &mut Manifest) -> Result<(Vec<u64>, Vec<IndexEntry<K>>)> {
+        self.seal(manifest)?;
+        Ok((self.segments, self.written))
+    }
 }
 
 pub struct KvStore<K, V>
@@ -74,30 +862,78 @@ where
     K: Key,
     V: Value,
 {
-    path: Arc<PathBuf>,
-    writer: Arc<Mutex<BufWriterWithPosition<File>>>,
-    // All readers can read from the buffer even when performing writes or compaction
-    // However, when compaction is complete and we want to block reading as we flip to the new
-    // reader and index map
-    reader: Arc<RwLock<File>>,
-    index: Arc<DashMap<K, ValueData>>,
-    uncompressed_bytes: AtomicU64,
+    storage: Arc<dyn SegmentStorage>,
+    writer: Arc<Mutex<LogWriter>>,
+    // All readers can read from their segment even when performing writes or compaction
+    // However, when compaction is complete we want to block reading as we flip to the new
+    // segments
+    readers: Arc<RwLock<BTreeMap<u64, Box<dyn SegmentReader>>>>,
+    index: Arc<KeyMap<K, ValueData>>,
+    // Removed keys along with the time of their removal in milliseconds since the unix epoch
+    tombstones: Arc<KeyMap<K, u64>>,
+    // Versions of the keys written within the history retention, oldest first
+    history: Arc<KeyMap<K, Vec<Version>>>,
+    // Merge records of each key not folded into its value yet, oldest first
+    operands: Arc<KeyMap<K, Vec<ValueData>>>,
+    merge_operator: Option<Arc<dyn MergeOperator<K, V>>>,
+    uncompressed_bytes: Arc<AtomicU64>,
+    // Bytes of records in all segments, what max_disk_bytes limits
+    disk_bytes: Arc<AtomicU64>,
+    // Bytes of the value logs whose records were overwritten or removed
+    value_garbage: Arc<AtomicU64>,
+    metrics: Arc<StoreMetrics>,
+    tuning: Arc<Tuning>,
+    // Whether compaction holds the writer lock
+    compacting: Arc<AtomicBool>,
+    // Error that stopped the store from taking writes, until they are resumed
+    degraded: Arc<Mutex<Option<String>>>,
+    // Writes that failed with IO errors in a row
+    io_errors: Arc<AtomicU64>,
+    // Position in the active segment up to which records have been handed to the OS
+    flushed: Arc<Flushed>,
+    // Seq stamped on the next write. Seqs are handed out before taking the writer lock, so
+    // racing writes may be appended out of seq order.
+    next_seq: Arc<AtomicU64>,
+    // Epochs pinned by open read transactions and the values they may still read
+    pins: Arc<ReadPins<K>>,
+    // Files open exports read, which compaction leaves in place when it retires them
+    exports: Arc<FilePins>,
+    // Latest writes, in the order they were appended
+    changes: Arc<ChangeLog<K, V>>,
+    options: KvStoreOptions,
     phantom: PhantomData<V>,
 }
 
-impl <K,V> Clone for KvStore<K, V>
+impl<K, V> Clone for KvStore<K, V>
 where
     K: Key,
     V: Value,
 {
     fn clone(&self) -> Self {
         Self {
-            path: self.path.clone(),
+            storage: self.storage.clone(),
             writer: self.writer.clone(),
-            reader: self.reader.clone(),
+            readers: self.readers.clone(),
             index: self.index.clone(),
-            uncompressed_bytes: AtomicU64::new(self.uncompressed_bytes.load(Ordering::SeqCst)),
-            phantom: self.phantom.clone()
+            tombstones: self.tombstones.clone(),
+            history: self.history.clone(),
+            operands: self.operands.clone(),
+            merge_operator: self.merge_operator.clone(),
+            uncompressed_bytes: self.uncompressed_bytes.clone(),
+            disk_bytes: self.disk_bytes.clone(),
+            value_garbage: self.value_garbage.clone(),
+            metrics: self.metrics.clone(),
+            tuning: self.tuning.clone(),
+            compacting: self.compacting.clone(),
+            degraded: self.degraded.clone(),
+            io_errors: self.io_errors.clone(),
+            flushed: self.flushed.clone(),
+            next_seq: self.next_seq.clone(),
+            pins: self.pins.clone(),
+            exports: self.exports.clone(),
+            changes: self.changes.clone(),
+            options: self.options,
+            phantom: self.phantom,
         }
     }
 }
@@ -108,77 +944,285 @@ where
     V: Value,
 {
     fn set(&self, key: K, val: V) -> Result<()> {
-        let serialized = rmp_serde::to_vec(&KvRecord::Set((key.clone(), val)))?;
-        let mut writer = self.writer.lock()?;
-        let value_data = ValueData {
-            offset: writer.position,
-            size: serialized.len(),
-        };
-        writer.buf_writer.write_all(&serialized)?;
-        writer.buf_writer.flush()?;
-        writer.position += serialized.len() as u64;
-        if let Some(previous_value) = self.index.insert(key, value_data) {
-            // if we were over 10k then run compaction
-            if self.uncompressed_bytes.fetch_add(previous_value.size as u64, Ordering::SeqCst) > 1000000 {
-                drop(writer);
-                self.compact_file()?;
+        self.guarded(|| {
+            let start = Instant::now();
+            let stamp = self.stamp();
+            let serialized = self.encode_set(&key, &val, stamp)?;
+            self.reserve(serialized.len())?;
+            let writer = self.lock_writer()?;
+            self.write_set(writer, key, val, &serialized, stamp, start)
+        })
+    }
+    /// Writers hold the writer lock while changing the index, so it can't change between the
+    /// check and the write
+    fn set_if(&self, key: K, val: V, condition: SetCondition) -> Result<bool> {
+        self.guarded(|| {
+            let start = Instant::now();
+            let stamp = self.stamp();
+            let serialized = self.encode_set(&key, &val, stamp)?;
+            self.reserve(serialized.len())?;
+            let writer = self.lock_writer()?;
+            let present = self.index.contains_key(&key) || self.operands.contains_key(&key);
+            if present != (condition == SetCondition::Present) {
+                return Ok(false);
             }
-        }
-        Ok(())
+            self.write_set(writer, key, val, &serialized, stamp, start)?;
+            Ok(true)
+        })
     }
     fn get(&self, key: K) -> Result<Option<V>> {
-        if let Some(entry) = self.index.get(&key) {
-            let mut buf = vec![0u8; entry.value().size];
-            self.reader.read()?.read_exact_at(&mut buf, entry.value().offset)?;
-            match rmp_serde::from_slice(&buf)? {
-                KvRecord::Set(kv) => {
-                    let _key: K = kv.0;
-                    Ok(Some(kv.1))
-                }
-                _ => Ok(None),
-            }
-        } else {
-            Ok(None)
+        let start = Instant::now();
+        let value = self.current_value(&key)?;
+        if value.is_some() {
+            self.metrics.reads.record(start.elapsed());
         }
+        Ok(value.map(|(value, _)| value))
+    }
+    /// The size is that of the serialized value. Values written before records were stamped
+    /// have no metadata.
+    fn get_with_meta(&self, key: K) -> Result<Option<(V, Option<ValueMeta>)>> {
+        let start = Instant::now();
+        let (value, stamp) = match self.current_value(&key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let meta = match stamp {
+            Some(stamp) => Some(stamp.meta(self.options.codec.encode(&value)?.len() as u64)),
+            None => None,
+        };
+        self.metrics.reads.record(start.elapsed());
+        Ok(Some((value, meta)))
     }
     fn remove(&self, key: K) -> Result<()> {
-        let mut writer = self.writer.lock()?;
-        if let Some(previous_value) = self.index.remove(&key) {
-            let serialized = rmp_serde::to_vec(&KvRecord::<K, V>::Rm(key.clone()))?;
-            let value_data = ValueData {
-                offset: writer.position,
-                size: serialized.len(),
-            };
-            writer.buf_writer.write_all(&serialized)?;
-            writer.buf_writer.flush()?;
-            writer.position += serialized.len() as u64;
-            // if we were over 10k then run compaction
-            if self.uncompressed_bytes.fetch_add((previous_value.1.size + value_data.size) as u64, Ordering::SeqCst) > 1000000 {
+        self.guarded(|| {
+            // Missing keys fail without waiting on the writer lock
+            if !self.index.contains_key(&key) && !self.operands.contains_key(&key) {
+                return Err(KvsError::NonExistantKey);
+            }
+            let start = Instant::now();
+            let writer = self.lock_writer()?;
+            self.write_removal(writer, key, start)
+        })
+    }
+    /// The version is checked under the writer lock, so it can't change before the write
+    fn set_if_version(&self, key: K, val: V, expected_version: Option<u64>) -> Result<bool> {
+        self.guarded(|| {
+            let start = Instant::now();
+            let stamp = self.stamp();
+            let serialized = self.encode_set(&key, &val, stamp)?;
+            self.reserve(serialized.len())?;
+            let mut writer = self.lock_writer()?;
+            if !version_matches(self.version(&mut writer, &key)?, expected_version) {
+                return Ok(false);
+            }
+            self.write_set(writer, key, val, &serialized, stamp, start)?;
+            Ok(true)
+        })
+    }
+    fn remove_if_version(&self, key: K, expected_version: Option<u64>) -> Result<bool> {
+        self.guarded(|| {
+            let start = Instant::now();
+            let mut writer = self.lock_writer()?;
+            if !version_matches(self.version(&mut writer, &key)?, expected_version) {
+                return Ok(false);
+            }
+            self.write_removal(writer, key, start).map(|_| true)
+        })
+    }
+    fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.index.contains_key(&key) || self.operands.contains_key(&key))
+    }
+    /// The records follow a header counting them and go to the active segment in a single
+    /// write, replay drops them along with the torn end of the segment unless all of them made
+    /// it. Reads running meanwhile may see some of the writes before the others.
+    fn write_batch(&self, writes: Vec<(K, Option<V>)>) -> Result<()> {
+        self.guarded(|| {
+            let start = Instant::now();
+            let mut sets = Vec::with_capacity(writes.len());
+            for (key, value) in &writes {
+                let stamp = self.stamp();
+                let serialized = match value {
+                    Some(value) => Some(self.encode_set(key, value, stamp)?),
+                    None => None,
+                };
+                sets.push((stamp, serialized));
+            }
+            let bytes = sets
+                .iter()
+                .filter_map(|(_, serialized)| serialized.as_ref().map(Vec::len))
+                .sum();
+            self.reserve(bytes)?;
+            let mut writer = self.lock_writer()?;
+            let threshold = self.options.value_log_threshold;
+            // Whether each key written has a value as of the writes before
+            let mut present = HashMap::new();
+            let mut records = Vec::with_capacity(writes.len());
+            for ((key, value), (stamp, serialized)) in writes.into_iter().zip(sets) {
+                let had_value = *present.entry(key.clone()).or_insert_with(|| {
+                    self.index.contains_key(&key) || self.operands.contains_key(&key)
+                });
+                present.insert(key.clone(), value.is_some());
+                let (serialized, pointer) = match (&value, serialized) {
+                    (Some(value), Some(serialized))
+                        if threshold > 0 && serialized.len() > threshold =>
+                    {
+                        let pointer = self.append_value(&mut writer, value)?;
+                        let set_at = self.keeps_history().then_some(stamp.at);
+                        let record = KvRecordRef::Pointer((&key, pointer, set_at));
+                        (self.encode_stamped(&record, stamp)?, Some(pointer))
+                    }
+                    (Some(_), Some(serialized)) => (serialized, None),
+                    (_, _) if !had_value => continue,
+                    (_, _) => {
+                        let record = KvRecordRef::<K, V>::Tombstone((&key, stamp.at));
+                        (self.encode_stamped(&record, stamp)?, None)
+                    }
+                };
+                records.push((key, value, serialized, pointer, stamp));
+            }
+            if records.is_empty() {
+                return Ok(());
+            }
+            let header = KvRecordRef::<K, V>::Batch(records.len() as u64);
+            let mut batch = self.options.codec.encode(&header)?;
+            let header_size = batch.len() as u64;
+            for (_, _, serialized, _, _) in &records {
+                batch.extend_from_slice(serialized);
+            }
+            let written = self.write_command(&mut writer, &batch)?;
+            let mut offset = written.offset + header_size;
+            let mut compact = false;
+            for (key, value, serialized, pointer, stamp) in records {
+                let value_data = ValueData {
+                    segment: written.segment,
+                    offset,
+                    size: serialized.len(),
+                    inline: inline_copy(&self.options, &serialized),
+                    pointer,
+                };
+                offset += serialized.len() as u64;
+                compact |= match value {
+                    Some(value) => {
+                        let set_at = self.keeps_history().then_some(stamp.at);
+                        self.index_set(key, value, value_data, set_at)?
+                    }
+                    None => self.index_removal(key, value_data, stamp.at)?,
+                };
+            }
+            if compact {
                 drop(writer);
-                self.compact_file()?;
+                self.compact_files()?;
             }
+            self.metrics.writes.record(start.elapsed());
             Ok(())
-        } else {
-            Err(KvsError::NonExistantKey)
+        })
+    }
+    /// Operands are appended as merge records and folded into the value when it is read, by
+    /// compaction, and once a key has `MAX_PENDING_OPERANDS` of them
+    fn merge(&self, key: K, operand: V) -> Result<()> {
+        self.guarded(|| {
+            if self.merge_operator.is_none() {
+                return Err(KvsError::MergeUnsupported);
+            }
+            let start = Instant::now();
+            let serialized =
+                self.encode_stamped(&KvRecordRef::Merge((&key, &operand)), self.stamp())?;
+            self.reserve(serialized.len())?;
+            let writer = self.lock_writer()?;
+            self.append_operand(writer, key, operand, &serialized, start)
+        })
+    }
+    /// The operands of the key are folded first, under the same writer lock as the append
+    fn fetch_merge(&self, key: K, operand: V) -> Result<Option<V>> {
+        self.guarded(|| {
+            if self.merge_operator.is_none() {
+                return Err(KvsError::MergeUnsupported);
+            }
+            let start = Instant::now();
+            let serialized =
+                self.encode_stamped(&KvRecordRef::Merge((&key, &operand)), self.stamp())?;
+            self.reserve(serialized.len())?;
+            let mut writer = self.lock_writer()?;
+            let previous = self.fold(&mut writer, &key)?;
+            self.append_operand(writer, key, operand, &serialized, start)?;
+            Ok(previous.map(|(value, _)| value))
+        })
+    }
+    fn disk_usage(&self) -> Result<Option<u64>> {
+        Ok(Some(self.disk_bytes.load(Ordering::SeqCst)))
+    }
+    fn scrub(&self) -> Result<Option<ScrubReport<K>>> {
+        self.scrub_files().map(Some)
+    }
+    /// Fails like a write if the buffered records still can't be written out
+    fn resume_writes(&self) -> Result<Option<String>> {
+        let mut writer = self.lock_writer()?;
+        let mut degraded = self.degraded.lock().unwrap_or_else(PoisonError::into_inner);
+        if degraded.is_none() {
+            return Ok(None);
         }
+        self.reopen_writer(&mut writer)?;
+        self.io_errors.store(0, Ordering::SeqCst);
+        info!("Writes resumed");
+        Ok(degraded.take())
     }
-}
-
-impl From<rmp_serde::decode::Error> for KvsError {
-    fn from(serde_err: rmp_serde::decode::Error) -> Self {
-        KvsError::SerializationError(serde_err.to_string())
+    /// Straight from the index, without reading anything from disk
+    fn scan_keys(&self, after: Option<K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        let mut smallest = SmallestKeys::new(after.as_ref(), limit);
+        for entry in self.index.iter() {
+            smallest.offer(entry.key());
+        }
+        for entry in self.operands.iter() {
+            if !self.index.contains_key(entry.key()) {
+                smallest.offer(entry.key());
+            }
+        }
+        Ok(smallest.finish())
     }
-}
-
-impl From<rmp_serde::encode::Error> for KvsError {
-    fn from(serde_err: rmp_serde::encode::Error) -> Self {
-        KvsError::SerializationError(serde_err.to_string())
+    /// Writes wait while the snapshot is read, which holds every value in memory
+    fn snapshot(&self) -> Result<(u64, Vec<(K, V)>)> {
+        if !self.changes.is_enabled() {
+            return Err(KvsError::ChangesUnsupported);
+        }
+        let mut writer = self.lock_writer()?;
+        // Everything is read from the segments, without taking the writer lock again
+        writer.buf_writer.flush()?;
+        self.flushed.advance(writer.position);
+        let seq = self.changes.next_seq()?;
+        let mut entries = Vec::with_capacity(self.index.len());
+        let folded: Vec<K> = self
+            .operands
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in folded {
+            if let Some((value, _)) = self.fold(&mut writer, &key)? {
+                entries.push((key, value));
+            }
+        }
+        // The other records are read in the order they are laid out on disk
+        let mut records = Vec::with_capacity(self.index.len());
+        let live = self.live_records(|key| !self.operands.contains_key(key));
+        self.copy_records(live, |key, _, serialized| {
+            records.push((key, self.options.codec.decode(serialized)?));
+            Ok(())
+        })?;
+        let readers = self.readers();
+        for (key, record) in records {
+            if let Some(value) = self.value_of(record, &readers)? {
+                entries.push((key, value));
+            }
+        }
+        Ok((seq, entries))
     }
-}
-
-impl<T> From<PoisonError<T>> for KvsError {
-    fn from(poison_error: PoisonError<T>) -> Self {
-        KvsError::SerializationError(poison_error.to_string())
+    fn changes(&self, from_seq: u64) -> Result<Tail<K, V>> {
+        if !self.changes.is_enabled() {
+            return Err(KvsError::ChangesUnsupported);
+        }
+        Ok(self.tail(from_seq))
     }
 }
 
@@ -187,127 +1231,2268 @@ where
     K: Key,
     V: Value,
 {
-    fn compress_dir_files(db_path: &Path) -> Result<PathBuf> {
-        if !db_path.exists() {
-            fs::create_dir_all(&db_path)?;
-        }
-        let mut files_in_dir = fs::read_dir(&db_path)?;
-        let path = files_in_dir
-            .next()
-            .map(|f| f.unwrap().path())
-            .unwrap_or(get_new_file_path(db_path));
-        let mut final_file = fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&path)?;
-        for file in files_in_dir {
-            let file = file.unwrap();
-            if file
-                .path()
-                .extension()
-                .map(|osstr| (*osstr).to_str().map(|str| str == "kvs").unwrap_or(false))
-                .unwrap_or(false)
-            {
-                let mut to_copy = fs::OpenOptions::new().read(true).open(file.path())?;
-                io::copy(&mut final_file, &mut to_copy)?;
+    /// Loads the manifest of the store in `db_path`, creating one for new stores and for stores
+    /// written before segments had ids
+    fn load_manifest(storage: &dyn SegmentStorage, options: &KvStoreOptions) -> Result<Manifest> {
+        let mut manifest = match Manifest::load(storage)? {
+            Some(manifest) => {
+                for orphan in manifest.orphaned_segments(storage)? {
+                    storage.remove(&orphan)?;
+                }
+                manifest
+            }
+            None => {
+                let mut manifest = Manifest::default();
+                let mut legacy_files: Vec<String> = storage
+                    .list()?
+                    .into_iter()
+                    .filter(|name| name.ends_with(&format!(".{}", LOG_EXTENSION)))
+                    .collect();
+                // Legacy files are named after their creation time in nanoseconds, replay them
+                // oldest first rather than in the unspecified order of the listing
+                legacy_files.sort_by_key(|name| {
+                    let timestamp = name
+                        .split('.')
+                        .next()
+                        .and_then(|stem| stem.parse::<u128>().ok());
+                    (timestamp, name.clone())
+                });
+                // Legacy files predate the choice of codec, they are always msgpack
+                if legacy_files.is_empty() {
+                    manifest.codec = options.codec;
+                }
+                for legacy_file in legacy_files {
+                    let id = manifest.allocate_segment_id();
+                    storage.rename(&legacy_file, &segment_name(id))?;
+                    manifest.segments.push(id);
+                }
+                manifest
+            }
+        };
+        if manifest.segments.is_empty() {
+            let id = allocate_segment(storage, &mut manifest)?;
+            manifest.segments.push(id);
+        }
+        manifest.save(storage)?;
+        // Hints of segments that never made it into the manifest
+        for hint_name in storage
+            .list()?
+            .into_iter()
+            .filter(|name| name.ends_with(&format!(".{}", HINT_EXTENSION)))
+        {
+            let segment = hint_name
+                .split('.')
+                .next()
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if !segment.is_some_and(|segment| manifest.segments.contains(&segment)) {
+                storage.remove(&hint_name)?;
             }
         }
-        Ok(path)
+        Ok(manifest)
     }
 
+    /// Replays every record of a segment, returning the end position of the last record and
+    /// whether a torn record follows it. The records of a batch are only handed over once all
+    /// of them are read, a batch cut short by a crash counts as torn from its header on.
     fn deserialize_file(
-        file_path: &PathBuf,
-        mut f: impl FnMut(KvRecord<K, V>, ValueData) -> (),
-    ) -> Result<()> {
-        let file = fs::read(file_path)?;
-        let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(&file));
+        storage: &dyn SegmentStorage,
+        segment: u64,
+        options: &KvStoreOptions,
+        mut f: impl FnMut(KvRecord<K, V>, ValueData),
+    ) -> Result<(u64, bool)> {
+        let context = || ErrorContext::new("replay segment").with_file(segment_name(segment));
+        let file = match storage.read(&segment_name(segment)).context(context)? {
+            Some(file) => file,
+            None => {
+                return Err(KvsError::CorruptStore(format!(
+                    "segment {} is missing",
+                    segment
+                )))
+            }
+        };
         let mut position: u64 = 0;
-        while position < file.len() as u64 {
-            let deserialized: KvRecord<K, V> = serde::Deserialize::deserialize(&mut deserializer)?;
-            let new_position = rmp_serde::decode::Deserializer::position(&deserializer);
+        // Position of the header of the batch being read, its records and how many are left
+        let mut batch_start = None;
+        let mut batch = Vec::new();
+        let mut left = 0;
+        // A record never starts with a zero byte, so one marks the preallocated tail
+        while position < file.len() as u64 && file[position as usize] != 0 {
+            let rest = &file[position as usize..];
+            let (deserialized, size) = match options.codec.decode_prefix(rest) {
+                Ok(decoded) => decoded,
+                // What is left isn't even a whole value, a write cut short by a crash. Records
+                // that don't decode as K and V are left to fail.
+                Err(_) if options.codec.decode_prefix::<IgnoredAny>(rest).is_err() => {
+                    return Ok((batch_start.unwrap_or(position), true))
+                }
+                Err(e) => return Err(e.with_context(|| context().with_offset(position))),
+            };
             let value_data = ValueData {
+                segment,
                 offset: position,
-                size: (new_position - position) as usize,
+                size,
+                inline: inline_copy(options, &file[position as usize..position as usize + size]),
+                pointer: None,
             };
-            f(deserialized, value_data);
-            position = new_position;
-        }
-        Ok(())
+            match deserialized {
+                KvRecord::Batch(_) if left > 0 => {
+                    return Err(KvsError::CorruptStore(format!(
+                        "batch header within a batch at offset {} of segment {}",
+                        position, segment
+                    )))
+                }
+                KvRecord::Batch(len) => {
+                    batch_start = Some(position);
+                    left = len;
+                }
+                deserialized if left > 0 => {
+                    batch.push((deserialized, value_data));
+                    left -= 1;
+                }
+                deserialized => f(deserialized, value_data),
+            }
+            if left == 0 {
+                batch_start = None;
+                for (deserialized, value_data) in batch.drain(..) {
+                    f(deserialized, value_data);
+                }
+            }
+            position += size as u64;
+        }
+        match batch_start {
+            Some(start) => Ok((start, true)),
+            None => Ok((position, false)),
+        }
+    }
+
+    /// Fails with `KvsError::RepairNeeded` for `damage` unless the store repairs it
+    fn repairing(options: &KvStoreOptions, damage: String) -> Result<()> {
+        if !options.repair {
+            return Err(KvsError::RepairNeeded(damage));
+        }
+        warn!("Repairing {}", damage);
+        Ok(())
     }
 
     pub fn open(db_path: &Path) -> Result<KvStore<K, V>> {
-        let file_path = KvStore::<K, V>::compress_dir_files(db_path)?;
-        let index = Arc::new(DashMap::new());
-        KvStore::deserialize_file(&file_path, |deserialized: KvRecord<K, V>, value_data| {
-            match deserialized {
-                KvRecord::Set(kv) => {
-                    index.insert(kv.0, value_data);
+        KvStore::open_with_options(db_path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(db_path: &Path, options: KvStoreOptions) -> Result<KvStore<K, V>> {
+        #[cfg(feature = "otel")]
+        let _span = crate::otel::span("kvs.replay").with_attribute("path", db_path.display());
+        let storage = LocalStorage::new(db_path, options)?;
+        KvStore::open_with_storage(Arc::new(storage), options)
+    }
+
+    /// Same as `open_with_options`, calling `progress` after every segment replayed. Opening
+    /// stops with `KvsError::OpenCancelled` as soon as it returns `ControlFlow::Break`. Some
+    /// writes can happen before a cancel takes effect. Before replay starts, the manifest is
+    /// saved, which for a new store means creating its first segment and for a store from before
+    /// manifests means renaming its files. Segments and hint files the manifest doesn't list are
+    /// removed. While replaying, a repair zeroes the torn tail of the last segment and removes
+    /// unreadable hint files, see `KvStoreOptions::repair`. A cancelled store opens just the same
+    /// the next time.
+    pub fn open_with_progress(
+        db_path: &Path,
+        options: KvStoreOptions,
+        mut progress: impl FnMut(&OpenProgress) -> ControlFlow<()>,
+    ) -> Result<KvStore<K, V>> {
+        let storage = LocalStorage::new(db_path, options)?;
+        KvStore::open_storage(Arc::new(storage), options, &mut progress)
+    }
+
+    /// Opens the store kept in `storage`, which is used instead of the local file system
+    pub fn open_with_storage(
+        storage: Arc<dyn SegmentStorage>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore<K, V>> {
+        KvStore::open_storage(storage, options, &mut |_| ControlFlow::Continue(()))
+    }
+
+    fn open_storage(
+        storage: Arc<dyn SegmentStorage>,
+        options: KvStoreOptions,
+        progress: &mut dyn FnMut(&OpenProgress) -> ControlFlow<()>,
+    ) -> Result<KvStore<K, V>> {
+        let manifest = KvStore::<K, V>::load_manifest(storage.as_ref(), &options)?;
+        let options = KvStoreOptions {
+            codec: manifest.codec,
+            index_stripes: stripe_count(options.index_stripes),
+            ..options
+        };
+        let index = Arc::new(key_map(&options));
+        let tombstones = Arc::new(key_map(&options));
+        let history = Arc::new(key_map(&options));
+        let operands: Arc<KeyMap<K, Vec<ValueData>>> = Arc::new(key_map(&options));
+        let keeps_history = !options.history_retention.is_zero();
+        let cutoff = now_millis().saturating_sub(options.history_retention.as_millis() as u64);
+        let mut readers = BTreeMap::new();
+        let mut position = 0;
+        // Bytes of records that have been overwritten or removed, compaction reclaims them
+        let mut uncompressed_bytes = 0;
+        let mut value_garbage = 0;
+        let mut disk_bytes = 0;
+        // Segments with hints aren't replayed, the manifest has a seq past theirs
+        let mut next_seq = manifest.next_seq.unwrap_or(0);
+        let sizes = manifest
+            .segments
+            .iter()
+            .map(|&segment| Ok(storage.len(&segment_name(segment))?.unwrap_or(0)))
+            .collect::<Result<Vec<u64>>>()?;
+        let mut report = OpenProgress {
+            segments_done: 0,
+            segments: sizes.len(),
+            bytes_replayed: 0,
+            bytes: sizes.iter().sum(),
+            elapsed: Duration::ZERO,
+        };
+        let started = Instant::now();
+        let mut segment_done = |segment: u64| {
+            report.bytes_replayed += sizes[report.segments_done];
+            report.segments_done += 1;
+            report.elapsed = started.elapsed();
+            info!(
+                "Replayed segment {} ({} of {}, {} of {} bytes)",
+                segment, report.segments_done, report.segments, report.bytes_replayed, report.bytes
+            );
+            match progress(&report) {
+                ControlFlow::Continue(()) => Ok(()),
+                ControlFlow::Break(()) => Err(KvsError::OpenCancelled),
+            }
+        };
+        for &segment in &manifest.segments {
+            let hints = match storage.read(&hint_name(segment))? {
+                Some(hints) => match decode_hints::<K>(options.codec, &hints) {
+                    Ok(hints) => hints,
+                    // The segment holds the same, it is replayed instead
+                    Err(e) => {
+                        let damage = format!("unreadable hints of segment {}: {:?}", segment, e);
+                        KvStore::<K, V>::repairing(&options, damage)?;
+                        storage.remove(&hint_name(segment))?;
+                        None
+                    }
+                },
+                None => None,
+            };
+            if let Some(hints) = hints {
+                // Segments with hints only hold the records the hints point at
+                disk_bytes += hints
+                    .iter()
+                    .map(|(_, offset, size, _)| offset + *size as u64)
+                    .max()
+                    .unwrap_or(0);
+                let reader = storage.open(&segment_name(segment))?;
+                // Hints don't hold the records, small ones are read to keep them inline
+                let mut inlines: Vec<Option<Vec<u8>>> = hints
+                    .iter()
+                    .map(|(_, _, size, _)| {
+                        (*size <= options.inline_value_bytes).then(|| vec![0u8; *size])
+                    })
+                    .collect();
+                let mut reads: Vec<(u64, &mut [u8])> = hints
+                    .iter()
+                    .zip(&mut inlines)
+                    .filter_map(|((_, offset, _, _), buf)| {
+                        Some((*offset, buf.as_mut()?.as_mut_slice()))
+                    })
+                    .collect();
+                reader.read_many(&mut reads)?;
+                for ((key, offset, size, pointer), inline) in hints.into_iter().zip(inlines) {
+                    tombstones.remove(&key);
+                    history.remove(&key);
+                    operands.remove(&key);
+                    let value_data = ValueData {
+                        segment,
+                        offset,
+                        size,
+                        inline: inline.map(Arc::from),
+                        pointer,
+                    };
+                    if let Some(previous_value) = index.insert(key, value_data) {
+                        uncompressed_bytes += previous_value.size as u64;
+                        value_garbage += pointed_bytes(&previous_value);
+                    }
                 }
-                KvRecord::Rm(key) => {
-                    index.insert(key, value_data);
+                readers.insert(segment, reader);
+                segment_done(segment)?;
+                continue;
+            }
+            let torn;
+            (position, torn) = KvStore::deserialize_file(
+                storage.as_ref(),
+                segment,
+                &options,
+                |deserialized: KvRecord<K, V>, value_data| {
+                    let (deserialized, stamp) = deserialized.unstamp();
+                    if let Some(stamp) = stamp {
+                        next_seq = next_seq.max(stamp.seq + 1);
+                    }
+                    let (key, deleted_at) = match deserialized {
+                        KvRecord::Set((key, _)) => {
+                            tombstones.remove(&key);
+                            operands.remove(&key);
+                            // Its time is unknown, so whatever came before can't be told apart
+                            history.remove(&key);
+                            if let Some(previous_value) = index.insert(key, value_data) {
+                                uncompressed_bytes += previous_value.size as u64;
+                                value_garbage += pointed_bytes(&previous_value);
+                            }
+                            return;
+                        }
+                        KvRecord::Pointer((key, pointer, set_at)) => {
+                            tombstones.remove(&key);
+                            operands.remove(&key);
+                            let value_data = ValueData {
+                                pointer: Some(pointer),
+                                ..value_data
+                            };
+                            let previous = index.insert(key.clone(), value_data.clone());
+                            match set_at {
+                                Some(set_at) if keeps_history => {
+                                    let version = Version {
+                                        at: set_at,
+                                        record: value_data,
+                                        removed: false,
+                                    };
+                                    push_version(&history, &key, version, previous.clone(), cutoff);
+                                }
+                                _ => {
+                                    history.remove(&key);
+                                }
+                            }
+                            if let Some(previous_value) = previous {
+                                uncompressed_bytes += previous_value.size as u64;
+                                value_garbage += pointed_bytes(&previous_value);
+                            }
+                            return;
+                        }
+                        KvRecord::TimedSet((key, _, set_at)) => {
+                            tombstones.remove(&key);
+                            operands.remove(&key);
+                            let previous = index.insert(key.clone(), value_data.clone());
+                            if keeps_history {
+                                let version = Version {
+                                    at: set_at,
+                                    record: value_data,
+                                    removed: false,
+                                };
+                                push_version(&history, &key, version, previous.clone(), cutoff);
+                            }
+                            if let Some(previous_value) = previous {
+                                uncompressed_bytes += previous_value.size as u64;
+                                value_garbage += pointed_bytes(&previous_value);
+                            }
+                            return;
+                        }
+                        KvRecord::Merge((key, _)) => {
+                            tombstones.remove(&key);
+                            uncompressed_bytes += value_data.size as u64;
+                            operands.entry(key).or_default().push(value_data);
+                            return;
+                        }
+                        // The age of legacy removals is unknown, so they don't keep a tombstone
+                        KvRecord::Rm(key) => (key, None),
+                        KvRecord::Tombstone((key, deleted_at)) => (key, Some(deleted_at)),
+                        // Never written, stamps aren't nested
+                        KvRecord::Stamped(_) => return,
+                        // Never handed over, `deserialize_file` holds on to the batch instead
+                        KvRecord::Batch(_) => return,
+                    };
+                    // Removed keys don't need an index entry, get and remove can tell they are
+                    // gone without reading anything
+                    uncompressed_bytes += value_data.size as u64;
+                    operands.remove(&key);
+                    let previous = index.remove(&key).map(|(_, previous_value)| previous_value);
+                    if let Some(previous_value) = &previous {
+                        uncompressed_bytes += previous_value.size as u64;
+                        value_garbage += pointed_bytes(previous_value);
+                    }
+                    match deleted_at {
+                        Some(deleted_at) if keeps_history => {
+                            let version = Version {
+                                at: deleted_at,
+                                record: value_data,
+                                removed: true,
+                            };
+                            push_version(&history, &key, version, previous, cutoff);
+                        }
+                        _ => {
+                            history.remove(&key);
+                        }
+                    }
+                    if let Some(deleted_at) = deleted_at {
+                        tombstones.insert(key, deleted_at);
+                    }
+                },
+            )?;
+            if torn {
+                let damage = format!("torn record at offset {} of segment {}", position, segment);
+                // Only the active segment is written to when a crash happens
+                if Some(&segment) != manifest.segments.last() {
+                    return Err(KvsError::CorruptStore(damage));
                 }
+                KvStore::<K, V>::repairing(&options, damage)?;
+                // Zeroed like a preallocated tail, so that the torn bytes never follow new records
+                let len = storage.len(&segment_name(segment))?.unwrap_or(position);
+                let mut file = storage.append(&segment_name(segment), position)?;
+                file.write_all(&vec![0u8; (len - position) as usize])?;
+                file.flush()?;
+                file.sync_data()?;
             }
-        })?;
-        let write_buf = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(&file_path)?;
-        Ok(KvStore {
-            path: Arc::new(db_path.to_path_buf()),
+            disk_bytes += position;
+            readers.insert(segment, storage.open(&segment_name(segment))?);
+            segment_done(segment)?;
+        }
+        let mut value_log_bytes = 0;
+        for &log in &manifest.value_logs {
+            value_log_bytes += storage.len(&value_log_name(log))?.unwrap_or(0);
+            readers.insert(log, storage.open(&value_log_name(log))?);
+        }
+        disk_bytes += value_log_bytes;
+        // Values past the last record pointing at them are garbage left by a crash
+        let value_log = match manifest.value_logs.last() {
+            Some(&id) => {
+                let position = storage.len(&value_log_name(id))?.unwrap_or(0);
+                Some(ValueLog {
+                    file: storage.append(&value_log_name(id), position)?,
+                    id,
+                    position,
+                })
+            }
+            None => None,
+        };
+        let active = *manifest
+            .segments
+            .last()
+            .expect("the manifest always has an active segment");
+        let write_buf = storage.append(&segment_name(active), position)?;
+        let store = KvStore {
+            storage,
             index,
-            reader: Arc::new(RwLock::new(OpenOptions::new().read(true).open(&file_path)?)),
-            writer: Arc::new(Mutex::new(BufWriterWithPosition {
-                path: file_path,
-                position: (write_buf.metadata()?.len()),
-                buf_writer: BufWriter::new(write_buf),
+            tombstones,
+            history,
+            operands,
+            merge_operator: None,
+            readers: Arc::new(RwLock::new(readers)),
+            writer: Arc::new(Mutex::new(LogWriter {
+                buf_writer: BufWriter::with_capacity(options.write_buffer_size, write_buf),
+                segment: active,
+                position,
+                manifest,
+                value_log,
+                value_log_bytes,
             })),
-            uncompressed_bytes: AtomicU64::new(0),
+            uncompressed_bytes: Arc::new(AtomicU64::new(uncompressed_bytes)),
+            disk_bytes: Arc::new(AtomicU64::new(disk_bytes)),
+            value_garbage: Arc::new(AtomicU64::new(value_garbage)),
+            metrics: Arc::new(StoreMetrics::default()),
+            tuning: Arc::new(Tuning {
+                compaction_threshold: AtomicU64::new(options.compaction_threshold),
+                max_segment_bytes: AtomicU64::new(options.max_segment_bytes),
+                last_window: Mutex::new(TuningWindow::default()),
+            }),
+            compacting: Arc::new(AtomicBool::new(false)),
+            degraded: Arc::new(Mutex::new(None)),
+            io_errors: Arc::new(AtomicU64::new(0)),
+            flushed: Arc::new(Flushed::new(active, position)),
+            next_seq: Arc::new(AtomicU64::new(next_seq)),
+            pins: Arc::new(ReadPins::new()),
+            exports: Arc::new(FilePins::new()),
+            changes: Arc::new(ChangeLog::new(options.change_log_capacity)),
+            options,
             phantom: PhantomData,
+        };
+        store.warm_up()?;
+        Ok(store)
+    }
+
+    /// Keeps copies of the newest records in the index, up to `warm_bytes`. They are read in
+    /// the order they are laid out on disk, right after replay read the same segments.
+    fn warm_up(&self) -> Result<()> {
+        let mut budget = self.options.warm_bytes;
+        if budget == 0 {
+            return Ok(());
+        }
+        // Records pointing at the value log would only save reading the pointer
+        let mut records = self.live_records(|_| true);
+        records.retain(|(_, record)| record.inline.is_none() && record.pointer.is_none());
+        records.sort_unstable_by_key(|(_, record)| Reverse((record.segment, record.offset)));
+        records.retain(|(_, record)| match budget.checked_sub(record.size as u64) {
+            Some(left) => {
+                budget = left;
+                true
+            }
+            None => false,
+        });
+        self.copy_records(records, |key, record, serialized| {
+            if let Some(mut entry) = self.index.get_mut(&key) {
+                if (entry.segment, entry.offset) == (record.segment, record.offset) {
+                    entry.inline = Some(Arc::from(serialized));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes sure `len` more bytes of records fit within `max_disk_bytes`, compacting first if
+    /// they don't. Writes racing with this can overshoot the limit by a record each.
+    fn reserve(&self, len: usize) -> Result<()> {
+        let max_disk_bytes = self.options.max_disk_bytes;
+        let fits = || self.disk_bytes.load(Ordering::SeqCst) + len as u64 <= max_disk_bytes;
+        if max_disk_bytes == 0 || fits() {
+            return Ok(());
+        }
+        if self.uncompressed_bytes.load(Ordering::SeqCst) > 0 {
+            info!(
+                "Over the disk limit of {} bytes, compacting",
+                max_disk_bytes
+            );
+            self.compact_files()?;
+        }
+        if fits() {
+            Ok(())
+        } else {
+            Err(KvsError::OutOfSpace)
+        }
+    }
+
+    /// Seq and time of a write about to be made
+    fn stamp(&self) -> Stamp {
+        Stamp {
+            seq: self.next_seq.fetch_add(1, Ordering::SeqCst),
+            at: now_millis(),
+        }
+    }
+
+    fn encode_stamped(&self, record: &KvRecordRef<K, V>, stamp: Stamp) -> Result<Vec<u8>> {
+        self.options
+            .codec
+            .encode(&KvRecordRef::Stamped((stamp.seq, stamp.at, record)))
+    }
+
+    /// Encodes the set of `key` to `val` written with `stamp`, repeating its time in the set
+    /// when history is kept
+    fn encode_set(&self, key: &K, val: &V, stamp: Stamp) -> Result<Vec<u8>> {
+        match self.keeps_history() {
+            true => self.encode_stamped(&KvRecordRef::TimedSet((key, val, stamp.at)), stamp),
+            false => self.encode_stamped(&KvRecordRef::Set((key, val)), stamp),
+        }
+    }
+
+    /// Appends the encoded set of `key` to `value`, written with `stamp`, and points the index
+    /// at it
+    fn write_set(
+        &self,
+        mut writer: MutexGuard<LogWriter>,
+        key: K,
+        value: V,
+        serialized: &[u8],
+        stamp: Stamp,
+        start: Instant,
+    ) -> Result<()> {
+        let set_at = self.keeps_history().then_some(stamp.at);
+        // Large values go to the value log, leaving a record that only points at them
+        let threshold = self.options.value_log_threshold;
+        let pointed;
+        let (serialized, pointer) = if threshold > 0 && serialized.len() > threshold {
+            let pointer = self.append_value(&mut writer, &value)?;
+            pointed = self.encode_stamped(&KvRecordRef::Pointer((&key, pointer, set_at)), stamp)?;
+            (pointed.as_slice(), Some(pointer))
+        } else {
+            (serialized, None)
+        };
+        let mut value_data = self.write_command(&mut writer, serialized)?;
+        value_data.pointer = pointer;
+        if self.index_set(key, value, value_data, set_at)? {
+            drop(writer);
+            self.compact_files()?;
+        }
+        self.metrics.writes.record(start.elapsed());
+        Ok(())
+    }
+
+    /// Points the index at the set of `key` to `value` just written, returning whether
+    /// compaction is due. The caller holds the writer lock.
+    fn index_set(
+        &self,
+        key: K,
+        value: V,
+        value_data: ValueData,
+        set_at: Option<u64>,
+    ) -> Result<bool> {
+        if self.changes.is_enabled() {
+            self.changes.push(Change::Set((key.clone(), value)))?;
+        }
+        self.keep_prior(&key);
+        self.tombstones.remove(&key);
+        self.operands.remove(&key);
+        if let Some(set_at) = set_at {
+            let version = Version {
+                at: set_at,
+                record: value_data.clone(),
+                removed: false,
+            };
+            let previous = self.index.get(&key).map(|entry| entry.clone());
+            push_version(
+                &self.history,
+                &key,
+                version,
+                previous,
+                self.history_cutoff(),
+            );
+        }
+        Ok(match self.index.insert(key, value_data) {
+            Some(previous_value) => {
+                self.uncompressed_bytes
+                    .fetch_add(previous_value.size as u64, Ordering::SeqCst)
+                    > self.tuning.compaction_threshold.load(Ordering::SeqCst)
+                    || self.add_value_garbage(pointed_bytes(&previous_value))
+            }
+            None => false,
         })
     }
 
-    fn compact_file(&self) -> Result<()> {
-        let mut value_map = HashMap::new();
-        let new_path = get_new_file_path(&self.path);
-        let mut new_file = fs::File::create(&new_path)?;
-        let mut writer = self.writer.lock()?;
-        KvStore::deserialize_file(&writer.path,
-            |deserialized: KvRecord<K, V>, _| match deserialized {
-                KvRecord::Set(kv) => {
-                    value_map.insert(kv.0, kv.1);
+    /// Appends a tombstone for `key` and takes it out of the index
+    fn write_removal(
+        &self,
+        mut writer: MutexGuard<LogWriter>,
+        key: K,
+        start: Instant,
+    ) -> Result<()> {
+        if !self.index.contains_key(&key) && !self.operands.contains_key(&key) {
+            return Err(KvsError::NonExistantKey);
+        }
+        let stamp = self.stamp();
+        let serialized =
+            self.encode_stamped(&KvRecordRef::<K, V>::Tombstone((&key, stamp.at)), stamp)?;
+        let value_data = self.write_command(&mut writer, &serialized)?;
+        if self.index_removal(key, value_data, stamp.at)? {
+            drop(writer);
+            self.compact_files()?;
+        }
+        self.metrics.removes.record(start.elapsed());
+        Ok(())
+    }
+
+    /// Takes `key` out of the index after its tombstone was written, returning whether
+    /// compaction is due. The caller holds the writer lock.
+    fn index_removal(&self, key: K, value_data: ValueData, deleted_at: u64) -> Result<bool> {
+        self.keep_prior(&key);
+        let previous = self
+            .index
+            .remove(&key)
+            .map(|(_, previous_value)| previous_value);
+        self.operands.remove(&key);
+        if self.changes.is_enabled() {
+            self.changes.push(Change::Removed(key.clone()))?;
+        }
+        if self.keeps_history() {
+            let version = Version {
+                at: deleted_at,
+                record: value_data.clone(),
+                removed: true,
+            };
+            let cutoff = self.history_cutoff();
+            push_version(&self.history, &key, version, previous.clone(), cutoff);
+        }
+        self.tombstones.insert(key, deleted_at);
+        let previous_size = previous
+            .as_ref()
+            .map_or(0, |previous_value| previous_value.size);
+        let garbage = previous.as_ref().map_or(0, pointed_bytes);
+        Ok(self
+            .uncompressed_bytes
+            .fetch_add((previous_size + value_data.size) as u64, Ordering::SeqCst)
+            > self.tuning.compaction_threshold.load(Ordering::SeqCst)
+            || self.add_value_garbage(garbage))
+    }
+
+    /// Version of the value of `key` if it has one, see `KvsEngine::set_if_version`. The caller
+    /// holds the writer lock, so it can't change meanwhile.
+    fn version(&self, writer: &mut LogWriter, key: &K) -> Result<Option<Option<u64>>> {
+        let found = if self.operands.contains_key(key) {
+            self.fold(writer, key)?
+        } else {
+            // Reads of records still in the write buffer would take the writer lock again
+            writer.buf_writer.flush()?;
+            self.flushed.advance(writer.position);
+            self.read_value(key, || self.index.get(key).map(|entry| entry.clone()))?
+        };
+        Ok(found.map(|(_, stamp)| stamp.map(|stamp| stamp.seq)))
+    }
+
+    /// Counts `bytes` more of the value logs as garbage, returning whether compaction should
+    /// rewrite them
+    fn add_value_garbage(&self, bytes: u64) -> bool {
+        bytes > 0
+            && self.value_garbage.fetch_add(bytes, Ordering::SeqCst) + bytes
+                > self.tuning.compaction_threshold.load(Ordering::SeqCst)
+    }
+
+    /// Appends `value` to the last value log, starting a new one once it is full
+    fn append_value(&self, writer: &mut LogWriter, value: &V) -> Result<ValuePointer> {
+        let serialized = self.options.codec.encode(value)?;
+        let max_bytes = self.tuning.max_segment_bytes.load(Ordering::SeqCst);
+        if writer
+            .value_log
+            .as_ref()
+            .is_none_or(|log| log.position >= max_bytes)
+        {
+            if let Some(full) = &writer.value_log {
+                full.file.sync_data()?;
+                let (id, len) = (full.id, full.position);
+                let checksum = checksum_file(self.storage.as_ref(), &value_log_name(id), len)?;
+                writer.manifest.checksums.insert(id, checksum);
+            }
+            let id = create_value_log(self.storage.as_ref(), &mut writer.manifest)?;
+            let name = value_log_name(id);
+            self.readers_mut().insert(id, self.storage.open(&name)?);
+            writer.manifest.value_logs.push(id);
+            writer.manifest.next_seq = Some(self.next_seq.load(Ordering::SeqCst));
+            writer.manifest.save(self.storage.as_ref())?;
+            writer.value_log = Some(ValueLog {
+                file: self.storage.append(&name, 0)?,
+                id,
+                position: 0,
+            });
+        }
+        let log = writer
+            .value_log
+            .as_mut()
+            .expect("a value log was just started");
+        let context = || {
+            ErrorContext::new("append value")
+                .with_file(value_log_name(log.id))
+                .with_offset(log.position)
+        };
+        if self.options.sync_policy == SyncPolicy::Always {
+            let synced = Instant::now();
+            log.file.write_synced(&serialized).context(context)?;
+            self.check_stall(StallCause::Fsync, synced.elapsed());
+        } else {
+            log.file.write_all(&serialized).context(context)?;
+            log.file.flush().context(context)?;
+        }
+        let pointer = ValuePointer {
+            log: log.id,
+            offset: log.position,
+            size: serialized.len(),
+        };
+        log.position += serialized.len() as u64;
+        writer.value_log_bytes += serialized.len() as u64;
+        self.metrics.bytes_written.add(serialized.len() as u64);
+        self.disk_bytes
+            .fetch_add(serialized.len() as u64, Ordering::SeqCst);
+        Ok(pointer)
+    }
+
+    /// Appends a serialized record to the active segment, flushing according to the sync policy
+    /// and starting a new segment once the active one is full
+    fn write_command(&self, writer: &mut LogWriter, serialized: &[u8]) -> Result<ValueData> {
+        let value_data = ValueData {
+            segment: writer.segment,
+            offset: writer.position,
+            size: serialized.len(),
+            inline: inline_copy(&self.options, serialized),
+            pointer: None,
+        };
+        let context = || {
+            ErrorContext::new("append record")
+                .with_file(segment_name(value_data.segment))
+                .with_offset(value_data.offset)
+        };
+        if self.options.sync_policy == SyncPolicy::Always {
+            // Nothing else is buffered with this policy, the record and the sync go together
+            writer.buf_writer.flush().context(context)?;
+            let synced = Instant::now();
+            writer
+                .buf_writer
+                .get_mut()
+                .write_synced(serialized)
+                .context(context)?;
+            self.check_stall(StallCause::Fsync, synced.elapsed());
+        } else {
+            writer.buf_writer.write_all(serialized).context(context)?;
+            if self.options.sync_policy == SyncPolicy::Flush {
+                // Before moving past the record, a failed flush leaves it to be written over
+                writer.buf_writer.flush().context(context)?;
+            }
+        }
+        writer.position += serialized.len() as u64;
+        self.metrics.bytes_written.add(serialized.len() as u64);
+        self.disk_bytes
+            .fetch_add(serialized.len() as u64, Ordering::SeqCst);
+        match self.options.sync_policy {
+            SyncPolicy::Flush | SyncPolicy::Always => {}
+            SyncPolicy::Buffered => {
+                if writer.buf_writer.buffer().is_empty() {
+                    // The BufWriter already passed everything through to the file
+                    self.flushed.advance(writer.position);
                 }
-                KvRecord::Rm(k) => {
-                    // I dont think this should ever happen, but just to be sure
-                    value_map.remove(&k);
+                if writer.position >= self.tuning.max_segment_bytes.load(Ordering::SeqCst) {
+                    self.roll_segment(writer)?;
                 }
-            },
+                return Ok(value_data);
+            }
+        }
+        self.flushed.advance(writer.position);
+        if writer.position >= self.tuning.max_segment_bytes.load(Ordering::SeqCst) {
+            self.roll_segment(writer)?;
+        }
+        Ok(value_data)
+    }
+
+    /// Seals the active segment and continues writing to a new one
+    fn roll_segment(&self, writer: &mut LogWriter) -> Result<()> {
+        writer.buf_writer.flush()?;
+        let synced = Instant::now();
+        writer.sync_value_log()?;
+        writer.buf_writer.get_ref().sync_data()?;
+        self.check_stall(StallCause::Fsync, synced.elapsed());
+        let sealed = writer.segment;
+        let checksum = checksum_file(
+            self.storage.as_ref(),
+            &segment_name(sealed),
+            writer.position,
         )?;
+        writer.manifest.checksums.insert(sealed, checksum);
+        let id = allocate_segment(self.storage.as_ref(), &mut writer.manifest)?;
+        let name = segment_name(id);
+        self.readers_mut().insert(id, self.storage.open(&name)?);
+        writer.manifest.segments.push(id);
+        writer.manifest.next_seq = Some(self.next_seq.load(Ordering::SeqCst));
+        writer.manifest.save(self.storage.as_ref())?;
+        writer.buf_writer = BufWriter::with_capacity(
+            self.options.write_buffer_size,
+            self.storage.append(&name, 0)?,
+        );
+        writer.segment = id;
+        writer.position = 0;
+        self.flushed.moved_to(id, 0);
+        Ok(())
+    }
+
+    /// Flushes any buffered records of the active segment to the OS
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        writer.buf_writer.flush()?;
+        self.flushed.advance(writer.position);
+        Ok(())
+    }
+
+    /// Sets every pair, with later pairs for the same key winning. The pairs are sorted and
+    /// written straight to new segments along with hint files holding their index entries, so
+    /// that neither writing nor reopening goes through records one by one. The new segments
+    /// are installed all at once, a crash before that leaves the store as it was. Writes are
+    /// blocked until the load is done. Returns the number of keys set. Loads that don't fit
+    /// within `KvStoreOptions::max_disk_bytes`, even after compacting, fail with
+    /// `KvsError::OutOfSpace` without setting anything.
+    pub fn bulk_load(&self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<usize>
+    where
+        K: Ord,
+    {
+        self.guarded(|| {
+            let mut pairs: Vec<(K, V)> = pairs.into_iter().collect();
+            // Stable, so the last pair for a key stays last
+            pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut pairs = pairs.into_iter().peekable();
+            // Encoded before taking the writer lock, which compacting to make room for them takes.
+            // Values are only kept for the change feed.
+            let mut records = Vec::new();
+            while let Some((key, val)) = pairs.next() {
+                if pairs.peek().is_some_and(|(next_key, _)| *next_key == key) {
+                    continue;
+                }
+                let serialized =
+                    self.encode_stamped(&KvRecordRef::Set((&key, &val)), self.stamp())?;
+                records.push((key, self.changes.is_enabled().then_some(val), serialized));
+            }
+            self.reserve(
+                records
+                    .iter()
+                    .map(|(_, _, serialized)| serialized.len())
+                    .sum(),
+            )?;
+
+            let mut writer = self.lock_writer()?;
+            writer.buf_writer.flush()?;
+            let mut loaded_changes = Vec::new();
+            let mut segment_writer = SegmentWriter::new(
+                self.storage.as_ref(),
+                &self.options,
+                self.tuning.max_segment_bytes.load(Ordering::SeqCst),
+            );
+            for (key, val, serialized) in records {
+                self.metrics.bytes_written.add(serialized.len() as u64);
+                if let Some(val) = val {
+                    loaded_changes.push(Change::Set((key.clone(), val)));
+                }
+                segment_writer.write(&mut writer.manifest, key, &serialized, None)?;
+            }
+            let (segments, loaded) = segment_writer.finish(&mut writer.manifest)?;
+            if segments.is_empty() {
+                return Ok(0);
+            }
+
+            let mut readers = self.readers_mut();
+            for &segment in &segments {
+                readers.insert(segment, self.storage.open(&segment_name(segment))?);
+            }
+            drop(readers);
+            // Saved along with a new active segment, so that later writes replay after the load
+            writer.manifest.segments.extend(&segments);
+            self.roll_segment(&mut writer)?;
+
+            let count = loaded.len();
+            let loaded_bytes: u64 = loaded
+                .iter()
+                .map(|(_, value_data)| value_data.size as u64)
+                .sum();
+            self.disk_bytes.fetch_add(loaded_bytes, Ordering::SeqCst);
+            for (key, value_data) in loaded {
+                self.keep_prior(&key);
+                self.tombstones.remove(&key);
+                self.history.remove(&key);
+                self.operands.remove(&key);
+                if let Some(previous_value) = self.index.insert(key, value_data) {
+                    self.uncompressed_bytes
+                        .fetch_add(previous_value.size as u64, Ordering::SeqCst);
+                }
+            }
+            for change in loaded_changes {
+                self.changes.push(change)?;
+            }
+            Ok(count)
+        })
+    }
+
+    /// Fully merges the segments of the store in `db_path`, which must not be open anywhere
+    /// else. Only the latest record of each key is kept and every tombstone is dropped, whatever
+    /// the compaction settings, which suits a dataset that is about to be shipped. Records are
+    /// copied as they are in the order they appear on disk, so values are never held in memory
+    /// all at once. The merged segments get hint files so that opening the store is quick.
+    /// Stores with merge operands still pending fail with `KvsError::MergeUnsupported`.
+    pub fn compact_offline(db_path: &Path) -> Result<()> {
+        KvStore::<K, V>::compact_offline_with_options(db_path, KvStoreOptions::default())
+    }
+
+    /// Same as `compact_offline`, with the segment size and file handling taken from `options`
+    pub fn compact_offline_with_options(db_path: &Path, options: KvStoreOptions) -> Result<()> {
+        let storage = LocalStorage::new(db_path, options)?;
+        KvStore::<K, V>::compact_offline_with_storage(Arc::new(storage), options)
+    }
+
+    /// Same as `compact_offline`, for the store kept in `storage`
+    pub fn compact_offline_with_storage(
+        storage: Arc<dyn SegmentStorage>,
+        options: KvStoreOptions,
+    ) -> Result<()> {
+        let store = KvStore::<K, V>::open_with_storage(storage, options)?;
+        // Folding operands takes the merge operator, which this store doesn't have
+        if !store.operands.is_empty() {
+            return Err(KvsError::MergeUnsupported);
+        }
+        let mut writer = store.lock_writer()?;
+        writer.buf_writer.flush()?;
+        let mut segment_writer = SegmentWriter::new(
+            store.storage.as_ref(),
+            &store.options,
+            store.options.max_segment_bytes,
+        );
+        store.copy_records(store.live_records(|_| true), |key, record, serialized| {
+            segment_writer.write(&mut writer.manifest, key, serialized, record.pointer)
+        })?;
+        writer.sync_value_log()?;
+        let (segments, _) = segment_writer.finish(&mut writer.manifest)?;
+
+        // Followed by an empty active segment, segments with hints are never written to again
+        let old_segments = std::mem::replace(&mut writer.manifest.segments, segments);
+        store.roll_segment(&mut writer)?;
+        let mut readers = store.readers_mut();
+        for segment in old_segments {
+            readers.remove(&segment);
+            retire_segment(store.storage.as_ref(), segment)?;
+        }
+        Ok(())
+    }
+
+    /// Reports on the values held by the store, see `KeyspaceReport`. Value sizes are those of
+    /// the serialized values. Records are decoded one at a time in the order they are laid out on
+    /// disk, so only the largest values seen so far and the counts per prefix are kept in memory.
+    /// Writes are blocked until the scan is done.
+    pub fn analyze(&self, options: AnalyzeOptions) -> Result<KeyspaceReport> {
+        let mut writer = self.lock_writer()?;
+        writer.buf_writer.flush()?;
+        let mut analyzer = KeyspaceAnalyzer::new(options);
+        self.copy_live_records(|key, serialized| {
+            let (record, _) = self
+                .options
+                .codec
+                .decode::<KvRecord<K, V>>(serialized)?
+                .unstamp();
+            let value_size = match record {
+                KvRecord::Set((_, value)) | KvRecord::TimedSet((_, value, _)) => {
+                    self.options.codec.encode(&value)?.len() as u64
+                }
+                KvRecord::Pointer((_, pointer, _)) => pointer.size as u64,
+                _ => return Ok(()),
+            };
+            analyzer.add(key.to_string(), value_size);
+            Ok(())
+        })?;
+        Ok(analyzer.finish(self.tombstones.len() as u64))
+    }
+
+    /// Reads back every segment and sealed value log, see `KvsEngine::scrub`. Sealed files are
+    /// compared against the checksum recorded when they were sealed, and the records of each
+    /// segment are decoded and matched against the index. Files are read one at a time without
+    /// holding a lock, so the store keeps serving reads and writes meanwhile.
+    fn scrub_files(&self) -> Result<ScrubReport<K>> {
+        let (manifest, active, flushed) = {
+            let writer = self.lock_writer()?;
+            (
+                writer.manifest.clone(),
+                writer.segment,
+                self.flushed.position(),
+            )
+        };
+        let mut report = ScrubReport::default();
+        // Bytes of each segment read, records past them were written after the scrub started
+        let mut scrubbed = HashMap::new();
+        let mut damaged = HashSet::new();
+        // Segment and offset of the records keys were found at, as the index has them
+        let mut verified = HashSet::new();
+        for &segment in &manifest.segments {
+            let file = match self.read_scrubbed(&mut report, segment, &segment_name(segment)) {
+                Some(file) => file,
+                None => {
+                    damaged.insert(segment);
+                    scrubbed.insert(segment, u64::MAX);
+                    continue;
+                }
+            };
+            let checksum = manifest.checksums.get(&segment);
+            let end = match checksum {
+                Some(checksum) => checksum.len,
+                None if segment == active => flushed,
+                None => file.len() as u64,
+            };
+            scrubbed.insert(segment, end);
+            if let Some(damage) = check_file(&file, checksum) {
+                damaged.insert(segment);
+                report
+                    .damage
+                    .push(format!("segment {} {}", segment, damage));
+                continue;
+            }
+            let end = end.min(file.len() as u64) as usize;
+            let mut position = 0;
+            // Zeroes mark the preallocated tail, like when the store is replayed
+            while position < end && file[position] != 0 {
+                let (record, size) = match self
+                    .options
+                    .codec
+                    .decode_prefix::<KvRecord<K, IgnoredAny>>(&file[position..end])
+                {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        damaged.insert(segment);
+                        report.damage.push(format!(
+                            "segment {} has an unreadable record at offset {}: {}",
+                            segment, position, e
+                        ));
+                        break;
+                    }
+                };
+                report.records += 1;
+                let key = match record.unstamp().0 {
+                    KvRecord::Set((key, _))
+                    | KvRecord::TimedSet((key, _, _))
+                    | KvRecord::Pointer((key, _, _)) => key,
+                    _ => {
+                        position += size;
+                        continue;
+                    }
+                };
+                if self.index.get(&key).is_some_and(|record| {
+                    record.segment == segment && record.offset == position as u64
+                }) {
+                    verified.insert((segment, position as u64));
+                }
+                position += size;
+            }
+        }
+        let mut damaged_logs = HashSet::new();
+        for &log in &manifest.value_logs {
+            // The last value log is still appended to, it has no checksum to go by yet
+            let checksum = match manifest.checksums.get(&log) {
+                Some(checksum) => checksum,
+                None => continue,
+            };
+            let damage = match self.read_scrubbed(&mut report, log, &value_log_name(log)) {
+                Some(file) => check_file(&file, Some(checksum)),
+                None => Some("is missing".to_owned()),
+            };
+            if let Some(damage) = damage {
+                damaged_logs.insert(log);
+                report.damage.push(format!("value log {} {}", log, damage));
+            }
+        }
+        for entry in self.index.iter() {
+            let record = entry.value();
+            let in_damaged_log = record
+                .pointer
+                .is_some_and(|pointer| damaged_logs.contains(&pointer.log));
+            let unverified = scrubbed.get(&record.segment).is_some_and(|&end| {
+                record.offset < end && !verified.contains(&(record.segment, record.offset))
+            });
+            if !in_damaged_log && !unverified {
+                continue;
+            }
+            if unverified && !damaged.contains(&record.segment) {
+                report.damage.push(format!(
+                    "segment {} has no record of key {} at offset {}",
+                    record.segment,
+                    entry.key(),
+                    record.offset
+                ));
+            }
+            report.bad_keys.push(entry.key().clone());
+        }
+        for damage in &report.damage {
+            warn!("Scrub found damage: {}", damage);
+        }
+        Ok(report)
+    }
+
+    /// Contents of the segment or value log `id` for scrubbing, counted in `report`. None when
+    /// it can't be read, which is damage unless compaction retired it since the scrub started.
+    fn read_scrubbed(&self, report: &mut ScrubReport<K>, id: u64, name: &str) -> Option<Vec<u8>> {
+        let damage = match self.storage.read(name) {
+            Ok(Some(file)) => {
+                report.files += 1;
+                report.bytes += file.len() as u64;
+                return Some(file);
+            }
+            Ok(None) => "is missing".to_owned(),
+            Err(e) => format!("could not be read: {}", e),
+        };
+        if self.readers().contains_key(&id) {
+            report.damage.push(format!("{} {}", name, damage));
+        }
+        None
+    }
+
+    /// Reads the current record of every key in the order they are laid out on disk, handing
+    /// each to `f` still serialized. Only one record is held at a time. The caller holds the
+    /// writer lock with the write buffer flushed, so the index can't change meanwhile.
+    fn copy_live_records(&self, mut f: impl FnMut(K, &[u8]) -> Result<()>) -> Result<()> {
+        self.copy_records(self.live_records(|_| true), |key, _, serialized| {
+            f(key, serialized)
+        })
+    }
+
+    /// Current records of the keys `filter` lets through, from the index
+    fn live_records(&self, filter: impl Fn(&K) -> bool) -> Vec<(K, ValueData)> {
+        self.index
+            .iter()
+            .filter(|entry| filter(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Reads `records` in the order they are laid out on disk, handing each to `f` along with
+    /// where it is, still serialized. Records of a segment are read up to `READ_AHEAD_BYTES` at
+    /// a time, so walking many records takes a few large reads rather than one per record.
+    fn copy_records(
+        &self,
+        mut records: Vec<(K, ValueData)>,
+        mut f: impl FnMut(K, ValueData, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        records.sort_unstable_by_key(|(_, record)| (record.segment, record.offset));
+        // Segment and end of every record, to size the reads ahead
+        let ends: Vec<(u64, Option<u64>)> = records
+            .iter()
+            .map(|(_, record)| {
+                let end = record.offset + record.size as u64;
+                (record.segment, record.inline.is_none().then_some(end))
+            })
+            .collect();
+        let readers = self.readers();
+        let mut ahead = ReadAhead::default();
+        let mut buf = Vec::new();
+        for (i, (key, record)) in records.into_iter().enumerate() {
+            let needs_read = record.inline.is_none() && ahead.get(&record).is_none();
+            if needs_read && record.size < READ_AHEAD_BYTES {
+                // Up to the end of the last record of the segment that fits
+                let window_end = ends[i..]
+                    .iter()
+                    .take_while(|(segment, _)| *segment == record.segment)
+                    .filter_map(|(_, end)| *end)
+                    .take_while(|end| end - record.offset <= READ_AHEAD_BYTES as u64)
+                    .last()
+                    .unwrap_or(record.offset + record.size as u64);
+                ahead.segment = record.segment;
+                ahead.offset = record.offset;
+                ahead.buf.resize((window_end - record.offset) as usize, 0);
+                readers[&record.segment].read_exact_at(&mut ahead.buf, record.offset)?;
+            }
+            match ahead.get(&record).filter(|_| record.inline.is_none()) {
+                Some(serialized) => f(key, record, serialized)?,
+                None => {
+                    record.read_into(&readers, &mut buf)?;
+                    f(key, record, &buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Values of `keys`, in the same order. Records that are on disk are read with one batch per
+    /// segment, which `KvStoreOptions::io_uring` turns into a single submission.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let mut values: Vec<Option<V>> = (0..keys.len()).map(|_| None).collect();
+        let mut batched = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            // Everything else takes the path of get
+            match self.index.get(key).map(|entry| entry.clone()) {
+                Some(record)
+                    if !self.operands.contains_key(key)
+                        && record.inline.is_none()
+                        && record.pointer.is_none()
+                        && self
+                            .flushed
+                            .covers(record.segment, record.offset + record.size as u64) =>
+                {
+                    batched.push((i, record))
+                }
+                _ => values[i] = self.current_value(key)?.map(|(value, _)| value),
+            }
+        }
+        batched.sort_unstable_by_key(|(_, record)| (record.segment, record.offset));
+        let mut bufs: Vec<Vec<u8>> = batched
+            .iter()
+            .map(|(_, record)| vec![0u8; record.size])
+            .collect();
+        let readers = self.readers();
+        let mut retired = Vec::new();
+        let mut start = 0;
+        for group in batched.chunk_by(|(_, a), (_, b)| a.segment == b.segment) {
+            let group_bufs = &mut bufs[start..start + group.len()];
+            start += group.len();
+            let reader = match readers.get(&group[0].1.segment) {
+                Some(reader) => reader,
+                // Compaction retired the segment since we looked at the index
+                None => {
+                    retired.extend(group.iter().map(|(i, _)| *i));
+                    continue;
+                }
+            };
+            let mut reads: Vec<(u64, &mut [u8])> = group
+                .iter()
+                .zip(group_bufs.iter_mut())
+                .map(|((_, record), buf)| (record.offset, buf.as_mut_slice()))
+                .collect();
+            reader.read_many(&mut reads)?;
+        }
+        for ((i, _), buf) in batched.iter().zip(&bufs) {
+            if !retired.contains(i) {
+                values[*i] = self.value_of(self.options.codec.decode(buf)?, &readers)?;
+            }
+        }
+        drop(readers);
+        for i in retired {
+            values[i] = self.current_value(&keys[i])?.map(|(value, _)| value);
+        }
+        Ok(values)
+    }
+
+    /// Warns and counts a stall if a write waited on `cause` for longer than the stall threshold
+    fn check_stall(&self, cause: StallCause, waited: Duration) {
+        let threshold = self.options.stall_threshold;
+        if threshold.is_zero() || waited <= threshold {
+            return;
+        }
+        self.metrics.write_stalls.add(1);
+        warn!(
+            "write stall cause={} waited={:?} threshold={:?}",
+            cause.as_str(),
+            waited,
+            threshold
+        );
+    }
+
+    /// Locks the writer. A writer that panicked, like in a merge operator, may have left part of
+    /// a record after the position, so once the buffered records are written the segment and
+    /// value log are reopened at the end of the last complete write, and whatever came after it
+    /// is written over.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, LogWriter>> {
+        let cause = if self.compacting.load(Ordering::SeqCst) {
+            StallCause::Compaction
+        } else {
+            StallCause::Lock
+        };
+        let waiting = Instant::now();
+        let locked = self.writer.lock();
+        self.check_stall(cause, waiting.elapsed());
+        let poisoned = match locked {
+            Ok(writer) => return Ok(writer),
+            Err(poisoned) => poisoned,
+        };
+        let mut writer = poisoned.into_inner();
+        warn!(
+            "A write panicked, dropping what it left after {} in {}",
+            writer.position,
+            segment_name(writer.segment)
+        );
+        self.reopen_writer(&mut writer)?;
+        // Only once the writer is whole again, a failure above leaves it to the next writer
+        self.writer.clear_poison();
+        Ok(writer)
+    }
+
+    /// Writes out the buffered records and reopens the active segment and value log at the end
+    /// of the last complete write, so that whatever a failed write left after it is written over
+    fn reopen_writer(&self, writer: &mut LogWriter) -> Result<()> {
+        writer.buf_writer.flush()?;
+        writer.buf_writer = BufWriter::with_capacity(
+            self.options.write_buffer_size,
+            self.storage
+                .append(&segment_name(writer.segment), writer.position)?,
+        );
+        if let Some(log) = &mut writer.value_log {
+            log.file = self.storage.append(&value_log_name(log.id), log.position)?;
+        }
+        self.flushed.advance(writer.position);
+        Ok(())
+    }
+
+    /// Runs `write`, failing fast with `KvsError::Degraded` instead while writes are stopped.
+    /// After an IO error the writer is reopened so that the next write doesn't land after a
+    /// partial record. Writes are stopped once the disk is full, once `DEGRADE_AFTER_IO_ERRORS`
+    /// writes in a row have failed, or if the writer can't be reopened.
+    fn guarded<T>(&self, write: impl FnOnce() -> Result<T>) -> Result<T> {
+        if let Some(error) = self
+            .degraded
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            return Err(KvsError::Degraded(error.clone()));
+        }
+        let result = write();
+        match &result {
+            Ok(_) => self.io_errors.store(0, Ordering::SeqCst),
+            Err(e) if matches!(e.root(), KvsError::IOError(_) | KvsError::DiskFull) => {
+                let failures = self.io_errors.fetch_add(1, Ordering::SeqCst) + 1;
+                let reopened = self
+                    .lock_writer()
+                    .and_then(|mut writer| self.reopen_writer(&mut writer));
+                if matches!(e.root(), KvsError::DiskFull)
+                    || failures >= DEGRADE_AFTER_IO_ERRORS
+                    || reopened.is_err()
+                {
+                    self.degrade(e);
+                }
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn degrade(&self, error: &KvsError) {
+        let mut degraded = self.degraded.lock().unwrap_or_else(PoisonError::into_inner);
+        if degraded.is_none() {
+            warn!(
+                "Writes failed with {}, only serving reads until they are resumed",
+                error
+            );
+            *degraded = Some(error.to_string());
+        }
+    }
+
+    // The segment map is only changed by single inserts and removes, a panic can't leave it
+    // half changed
+    fn readers(&self) -> RwLockReadGuard<'_, BTreeMap<u64, Box<dyn SegmentReader>>> {
+        self.readers.read().unwrap_or_else(|poisoned| {
+            self.readers.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn readers_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<u64, Box<dyn SegmentReader>>> {
+        self.readers.write().unwrap_or_else(|poisoned| {
+            self.readers.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// Value of `key` along with the stamp of its last write, folding its merge operands if it
+    /// has any
+    fn current_value(&self, key: &K) -> Result<Option<(V, Option<Stamp>)>> {
+        if self.operands.contains_key(key) {
+            let mut writer = self.lock_writer()?;
+            self.fold(&mut writer, key)
+        } else {
+            self.read_value(key, || self.index.get(key).map(|entry| entry.clone()))
+        }
+        .context(|| ErrorContext::new("get").with_key(key))
+    }
+
+    /// Reads the value of the record `lookup` finds for `key`, along with its stamp, looking
+    /// again if compaction moved it meanwhile. Records that don't decode or hold another key
+    /// fail with `KvsError::CorruptRecord`.
+    fn read_value(
+        &self,
+        key: &K,
+        lookup: impl Fn() -> Option<ValueData>,
+    ) -> Result<Option<(V, Option<Stamp>)>> {
+        loop {
+            let record = match lookup() {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            if record.inline.is_none()
+                && !self
+                    .flushed
+                    .covers(record.segment, record.offset + record.size as u64)
+            {
+                // The record may still be sitting in the write buffer
+                let mut writer = self.lock_writer()?;
+                writer.buf_writer.flush()?;
+                self.flushed.advance(writer.position);
+            }
+            let readers = self.readers();
+            // Records pointing at the value log are read too, for their stamp. Compaction
+            // retired the segment or value log since we looked at the index if it is missing,
+            // look again.
+            let decoded: KvRecord<K, V> = match &record.inline {
+                Some(inline) => self.options.codec.decode(inline),
+                None => {
+                    let mut buf = vec![0u8; record.size];
+                    match readers.get(&record.segment) {
+                        Some(reader) => reader
+                            .read_exact_at(&mut buf, record.offset)
+                            .context(|| record.read_context())?,
+                        None => continue,
+                    }
+                    self.options.codec.decode(&buf)
+                }
+            }
+            .map_err(corrupt)
+            .context(|| record.read_context())?;
+            let (decoded, stamp) = decoded.unstamp();
+            let found = match &decoded {
+                KvRecord::Set((found, _))
+                | KvRecord::Rm(found)
+                | KvRecord::Tombstone((found, _))
+                | KvRecord::TimedSet((found, _, _))
+                | KvRecord::Merge((found, _))
+                | KvRecord::Pointer((found, _, _)) => found,
+                KvRecord::Stamped(_) => {
+                    return Err(KvsError::CorruptRecord("stamped twice".to_owned())
+                        .with_context(|| record.read_context()))
+                }
+                KvRecord::Batch(_) => {
+                    return Err(KvsError::CorruptRecord("batch header".to_owned())
+                        .with_context(|| record.read_context()))
+                }
+            };
+            if found != key {
+                return Err(KvsError::CorruptRecord(format!("holds key {}", found))
+                    .with_context(|| record.read_context()));
+            }
+            if let KvRecord::Pointer((_, pointer, _)) = &decoded {
+                if !readers.contains_key(&pointer.log) {
+                    continue;
+                }
+            }
+            let value = self.value_of(decoded, &readers)?;
+            return Ok(value.map(|value| (value, stamp)));
+        }
+    }
+
+    /// Value a record sets, read from the value log if the record only points at it
+    fn value_of(
+        &self,
+        record: KvRecord<K, V>,
+        readers: &BTreeMap<u64, Box<dyn SegmentReader>>,
+    ) -> Result<Option<V>> {
+        match record {
+            KvRecord::Set((_, value)) | KvRecord::TimedSet((_, value, _)) => Ok(Some(value)),
+            KvRecord::Stamped((_, _, record)) => self.value_of(*record, readers),
+            KvRecord::Pointer((_, pointer, _)) => {
+                let reader = readers
+                    .get(&pointer.log)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                self.read_pointed(reader.as_ref(), &pointer).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn read_pointed(&self, reader: &dyn SegmentReader, pointer: &ValuePointer) -> Result<V> {
+        let context = || {
+            ErrorContext::new("read value")
+                .with_file(value_log_name(pointer.log))
+                .with_offset(pointer.offset)
+        };
+        let mut buf = vec![0u8; pointer.size];
+        reader
+            .read_exact_at(&mut buf, pointer.offset)
+            .context(context)?;
+        self.options
+            .codec
+            .decode(&buf)
+            .map_err(corrupt)
+            .context(context)
+    }
+
+    fn keeps_history(&self) -> bool {
+        !self.options.history_retention.is_zero()
+    }
+
+    /// Earliest time reads as of a past time can go back to, in milliseconds since the epoch
+    fn history_cutoff(&self) -> u64 {
+        now_millis().saturating_sub(self.options.history_retention.as_millis() as u64)
+    }
+
+    /// Value `key` had at `at`, which can go back as far as the history retention or fails with
+    /// `KvsError::HistoryUnavailable`. Values written before history was kept are taken as
+    /// there from the start.
+    pub fn get_as_of(&self, key: K, at: SystemTime) -> Result<Option<V>> {
+        let at = self.history_millis(at)?;
+        self.value_as_of(&key, at)
+    }
+
+    /// Pairs within `range` as of `at`, in ascending key order, see `get_as_of`
+    pub fn scan_as_of(&self, range: impl RangeBounds<K>, at: SystemTime) -> Result<Vec<(K, V)>>
+    where
+        K: Ord,
+    {
+        let at = self.history_millis(at)?;
+        let mut keys: Vec<K> = self
+            .index
+            .iter()
+            .map(|entry| entry.key().clone())
+            .chain(self.history.iter().map(|entry| entry.key().clone()))
+            .filter(|key| range.contains(key))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if let Some(value) = self.value_as_of(&key, at)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Pairs whose key begins with the fields of `prefix`, in ascending key order, see
+    /// `KeyCodec::has_prefix`. Keys are matched in the index, only the values are read.
+    pub fn scan_prefix<P: KeyCodec>(&self, prefix: &P) -> Result<Vec<(K, V)>>
+    where
+        K: KeyCodec,
+    {
+        let prefix = prefix.to_key_bytes();
+        let mut keys: Vec<K> = self
+            .keys()
+            .filter(|key| key.to_key_bytes().as_slice().starts_with(&prefix))
+            .collect();
+        keys.sort_unstable();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if let Some((value, _)) = self.current_value(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// `at` in milliseconds since the epoch, if the history goes back that far
+    fn history_millis(&self, at: SystemTime) -> Result<u64> {
+        let at = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64;
+        if !self.keeps_history() || at < self.history_cutoff() {
+            return Err(KvsError::HistoryUnavailable);
+        }
+        Ok(at)
+    }
+
+    fn value_as_of(&self, key: &K, at: u64) -> Result<Option<V>> {
+        self.read_value(key, || match self.history.get(key) {
+            Some(versions) => versions
+                .iter()
+                .rev()
+                .find(|version| version.at <= at)
+                .filter(|version| !version.removed)
+                .map(|version| version.record.clone()),
+            None => self.index.get(key).map(|entry| entry.clone()),
+        })
+        .map(|found| found.map(|(value, _)| value))
+    }
+
+    /// Opens a read-only transaction seeing the store as it is now, whatever is written after.
+    /// Compaction keeps the records of the values it may read until it is dropped.
+    pub fn read_txn(&self) -> Result<ReadTxn<K, V>> {
+        // No write is halfway done while the writer lock is held
+        let _writer = self.lock_writer()?;
+        Ok(ReadTxn::new(self.clone(), self.pins.pin()))
+    }
+
+    /// Value `key` had at `epoch` of the read transactions
+    pub(super) fn value_at(&self, key: &K, epoch: u64) -> Result<Option<V>> {
+        let folds = match self.pins.prior(key, epoch) {
+            Some(prior) => !prior.operands.is_empty(),
+            None => self.operands.contains_key(key),
+        };
+        if folds {
+            let mut writer = self.lock_writer()?;
+            let (base, operands) = match self.pins.prior(key, epoch) {
+                Some(prior) => (prior.record, prior.operands),
+                None => self.current_records(key),
+            };
+            self.fold_records(&mut writer, key, base, &operands)
+        } else {
+            // A write since the check keeps the record as the prior of the key
+            self.read_value(key, || match self.pins.prior(key, epoch) {
+                Some(prior) => prior.record,
+                None => self.index.get(key).map(|entry| entry.clone()),
+            })
+        }
+        .map(|found| found.map(|(value, _)| value))
+        .context(|| ErrorContext::new("get").with_key(key))
+    }
+
+    /// Pairs within `range` at `epoch` of the read transactions, in ascending key order
+    pub(super) fn scan_at(&self, range: impl RangeBounds<K>, epoch: u64) -> Result<Vec<(K, V)>>
+    where
+        K: Ord,
+    {
+        let mut keys: Vec<K> = self
+            .index
+            .iter()
+            .map(|entry| entry.key().clone())
+            .chain(self.operands.iter().map(|entry| entry.key().clone()))
+            .chain(self.pins.keys())
+            .filter(|key| range.contains(key))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if let Some(value) = self.value_at(&key, epoch)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub(super) fn unpin(&self, epoch: u64) {
+        self.pins.unpin(epoch);
+    }
+
+    /// Record and pending merge operands of `key`
+    fn current_records(&self, key: &K) -> (Option<ValueData>, Vec<ValueData>) {
+        let base = self.index.get(key).map(|entry| entry.clone());
+        let operands = self
+            .operands
+            .get(key)
+            .map(|operands| operands.clone())
+            .unwrap_or_default();
+        (base, operands)
+    }
+
+    /// Keeps the value of `key` for open read transactions, right before a write changes it.
+    /// The caller holds the writer lock.
+    fn keep_prior(&self, key: &K) {
+        self.pins.written(key, || self.current_records(key));
+    }
+
+    /// Rewrites the live records of all segments into a single new segment. Records are copied
+    /// straight from the old segments, so values never have to fit in memory.
+    fn compact_files(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        let started = Instant::now();
+        let _compacting = Compacting::start(&self.compacting);
+        writer.buf_writer.flush()?;
+        #[cfg(feature = "otel")]
+        let _span = crate::otel::span("kvs.compaction")
+            .with_attribute("segments", writer.manifest.segments.len());
+        let new_segment = allocate_segment(self.storage.as_ref(), &mut writer.manifest)?;
+        let new_name = segment_name(new_segment);
+        let mut new_file = BufWriter::with_capacity(
+            self.options.write_buffer_size,
+            self.storage.append(&new_name, 0)?,
+        );
         let mut next_offset = 0;
-        let mut new_index = HashMap::new();
-        for (key, val) in value_map {
-            let serialized = rmp_serde::to_vec(&KvRecord::Set((key.clone(), val)))?;
-            let value_data = ValueData {
+        // New place of every copied record by its old segment and offset
+        let mut relocated = HashMap::with_capacity(self.index.len());
+        let history_cutoff = self.history_cutoff();
+        self.history
+            .retain(|_, versions| prune_versions(versions, history_cutoff));
+        // Pending operands are folded into plain values, or carried over as they are if the
+        // store was opened without a merge operator
+        let folding = self.merge_operator.is_some();
+        let mut folded = Vec::new();
+        if folding {
+            for key in self.operands.iter().map(|entry| entry.key().clone()) {
+                let found = self.fold(&mut writer, &key)?;
+                folded.push((key, found));
+            }
+        }
+        // Keys with history have their current record among their versions
+        let mut records = self.live_records(|key| {
+            !(self.history.contains_key(key) || (folding && self.operands.contains_key(key)))
+        });
+        for entry in self.history.iter() {
+            for version in entry.value() {
+                records.push((entry.key().clone(), version.record.clone()));
+            }
+        }
+        if !folding {
+            for entry in self.operands.iter() {
+                for operand in entry.value() {
+                    records.push((entry.key().clone(), operand.clone()));
+                }
+            }
+        }
+        // Values open read transactions may still read, unless they are live as well
+        let mut copied: HashSet<(u64, u64)> = records
+            .iter()
+            .map(|(_, record)| (record.segment, record.offset))
+            .collect();
+        for (key, record) in self.pins.records() {
+            if copied.insert((record.segment, record.offset)) {
+                records.push((key, record));
+            }
+        }
+        // Values move to a new value log once enough of the old ones are garbage, otherwise
+        // records keep pointing at the same values
+        let rewrite_values = !writer.manifest.value_logs.is_empty()
+            && self.value_garbage.load(Ordering::SeqCst)
+                > self.tuning.compaction_threshold.load(Ordering::SeqCst);
+        let new_value_log = match rewrite_values {
+            true => Some(create_value_log(
+                self.storage.as_ref(),
+                &mut writer.manifest,
+            )?),
+            false => None,
+        };
+        // Old and new place of every moved value, in the order they go into the new value log
+        let mut moved_values = Vec::new();
+        let mut next_value_offset = 0;
+        self.copy_records(records, |key, old, serialized| {
+            let mut new = ValueData {
+                segment: new_segment,
                 offset: next_offset,
                 size: serialized.len(),
+                inline: old.inline,
+                pointer: old.pointer,
+            };
+            let rewritten;
+            let mut serialized = serialized;
+            if let Some(log) = new_value_log {
+                let (record, stamp) = self
+                    .options
+                    .codec
+                    .decode::<KvRecord<K, V>>(serialized)?
+                    .unstamp();
+                if let KvRecord::Pointer((_, pointer, set_at)) = record {
+                    let moved = ValuePointer {
+                        log,
+                        offset: next_value_offset,
+                        size: pointer.size,
+                    };
+                    next_value_offset += pointer.size as u64;
+                    moved_values.push((pointer, moved));
+                    let moved_record = KvRecordRef::<K, V>::Pointer((&key, moved, set_at));
+                    rewritten = match stamp {
+                        Some(stamp) => self.encode_stamped(&moved_record, stamp)?,
+                        None => self.options.codec.encode(&moved_record)?,
+                    };
+                    serialized = &rewritten;
+                    new.size = serialized.len();
+                    new.inline = inline_copy(&self.options, serialized);
+                    new.pointer = Some(moved);
+                }
+            }
+            relocated.insert((old.segment, old.offset), new);
+            new_file.write_all(serialized)?;
+            next_offset += serialized.len() as u64;
+            Ok(())
+        })?;
+        if let Some(log) = new_value_log {
+            let name = value_log_name(log);
+            let mut file = self.storage.append(&name, 0)?;
+            let readers = self.readers();
+            let mut buf = Vec::new();
+            for (old, _) in &moved_values {
+                buf.resize(old.size, 0);
+                readers[&old.log].read_exact_at(&mut buf, old.offset)?;
+                file.write_all(&buf)?;
+            }
+            drop(readers);
+            file.flush()?;
+            file.sync_data()?;
+            self.metrics.compaction_bytes_written.add(next_value_offset);
+        }
+        // Tombstones within their grace period move along to the new segment, the rest go away.
+        // Those of keys with history were copied along with their versions.
+        let cutoff =
+            now_millis().saturating_sub(self.options.tombstone_grace_period.as_millis() as u64);
+        self.tombstones.retain(|_, deleted_at| *deleted_at > cutoff);
+        for tombstone in self.tombstones.iter() {
+            if self.history.contains_key(tombstone.key()) {
+                continue;
+            }
+            let serialized = self.options.codec.encode(&KvRecordRef::<K, V>::Tombstone((
+                tombstone.key(),
+                *tombstone.value(),
+            )))?;
+            new_file.write_all(&serialized)?;
+            next_offset += serialized.len() as u64;
+        }
+        let mut folded_records = Vec::with_capacity(folded.len());
+        for (key, found) in folded {
+            let (value, stamp) = match found {
+                Some(found) => found,
+                None => {
+                    folded_records.push((key, None));
+                    continue;
+                }
+            };
+            // Folded values keep the stamp of the last operand
+            let record = KvRecordRef::Set((&key, &value));
+            let serialized = match stamp {
+                Some(stamp) => self.encode_stamped(&record, stamp)?,
+                None => self.options.codec.encode(&record)?,
             };
-            new_index.insert(key, value_data);
             new_file.write_all(&serialized)?;
-            new_file.flush()?;
+            let new = ValueData {
+                segment: new_segment,
+                offset: next_offset,
+                size: serialized.len(),
+                inline: inline_copy(&self.options, &serialized),
+                pointer: None,
+            };
             next_offset += serialized.len() as u64;
+            folded_records.push((key, Some(new)));
+        }
+        new_file.flush()?;
+        // Records that kept their values point into the old value logs
+        writer.sync_value_log()?;
+        new_file.get_ref().sync_data()?;
+        drop(new_file);
+        // The swap, a crash before the new manifest is renamed into place leaves the store as it
+        // was with the new files as orphans, and one after it leaves the old files as orphans.
+        // Nothing in memory changes until then, so a failure leaves the store working as well.
+        let mut manifest = writer.manifest.clone();
+        manifest.segments = vec![new_segment];
+        if let Some(log) = new_value_log {
+            manifest.value_logs = vec![log];
+        }
+        // Records compaction drops may have had the latest seqs
+        manifest.next_seq = Some(self.next_seq.load(Ordering::SeqCst));
+        manifest.save(self.storage.as_ref())?;
+        let old_manifest = std::mem::replace(&mut writer.manifest, manifest);
+        let old_segments = old_manifest.segments;
+        let old_value_logs = match new_value_log {
+            Some(_) => old_manifest.value_logs,
+            None => Vec::new(),
+        };
+        let mut readers = self.readers_mut();
+        readers.insert(new_segment, self.storage.open(&new_name)?);
+        if let Some(log) = new_value_log {
+            readers.insert(log, self.storage.open(&value_log_name(log))?);
+        }
+        drop(readers);
+        // Writes are blocked by the writer lock, so the index only changes here
+        let relocate = |record: &mut ValueData| {
+            if let Some(new) = relocated.get(&(record.segment, record.offset)) {
+                *record = new.clone();
+            }
+        };
+        for mut entry in self.index.iter_mut() {
+            relocate(entry.value_mut());
         }
-        let old_path = writer.path.clone();
-        writer.buf_writer = BufWriter::new(new_file);
+        for mut entry in self.history.iter_mut() {
+            for version in entry.value_mut() {
+                relocate(&mut version.record);
+            }
+        }
+        for mut entry in self.operands.iter_mut() {
+            for operand in entry.value_mut() {
+                relocate(operand);
+            }
+        }
+        self.pins.relocate(relocate);
+        for (key, record) in folded_records {
+            self.operands.remove(&key);
+            match record {
+                Some(record) => self.index.insert(key, record),
+                None => self.index.remove(&key).map(|(_, record)| record),
+            };
+        }
+        if let Some(log) = new_value_log {
+            writer.value_log = Some(ValueLog {
+                file: self
+                    .storage
+                    .append(&value_log_name(log), next_value_offset)?,
+                id: log,
+                position: next_value_offset,
+            });
+            writer.value_log_bytes = next_value_offset;
+            self.value_garbage.store(0, Ordering::SeqCst);
+        }
+        writer.buf_writer = BufWriter::with_capacity(
+            self.options.write_buffer_size,
+            self.storage.append(&new_name, next_offset)?,
+        );
+        writer.segment = new_segment;
         writer.position = next_offset;
-        writer.path = new_path.clone();
+        self.flushed.moved_to(new_segment, next_offset);
         self.uncompressed_bytes.store(0, Ordering::SeqCst);
-        let mut reader = self.reader.write()?;
-        *reader = OpenOptions::new().read(true).open(&new_path)?;
-        new_index.clear();
-        for (key, value) in new_index {
-            self.index.insert(key, value);
+        self.disk_bytes
+            .store(next_offset + writer.value_log_bytes, Ordering::SeqCst);
+        let mut readers = self.readers_mut();
+        for segment in old_segments {
+            readers.remove(&segment);
+            self.storage.remove(&hint_name(segment))?;
+            self.retire_file(&segment_name(segment))?;
         }
-        fs::remove_file(&old_path)?;
+        for log in old_value_logs {
+            readers.remove(&log);
+            self.retire_file(&value_log_name(log))?;
+        }
+        drop(readers);
+        self.metrics.compaction_bytes_written.add(next_offset);
+        self.metrics.compactions.add(1);
+        // Compaction always runs in place of a write that crossed the threshold
+        self.check_stall(StallCause::Compaction, started.elapsed());
+        if self.options.adaptive {
+            self.tune()?;
+        }
+        Ok(())
+    }
+
+    /// Keys removed at or after `since` whose tombstones are still retained, along with the
+    /// time of their removal
+    pub fn tombstones_since(&self, since: SystemTime) -> Vec<(K, SystemTime)> {
+        let since = since
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64;
+        self.tombstones
+            .iter()
+            .filter(|tombstone| *tombstone.value() >= since)
+            .map(|tombstone| {
+                (
+                    tombstone.key().clone(),
+                    UNIX_EPOCH + Duration::from_millis(*tombstone.value()),
+                )
+            })
+            .collect()
+    }
+
+    /// Blocking iterator over the writes made to the store from `from_seq` on, in the order
+    /// they were appended, waiting for new ones once caught up. Only the latest
+    /// `change_log_capacity` writes are kept, see the `tail` module.
+    pub fn tail(&self, from_seq: u64) -> Tail<K, V> {
+        Tail::new(self.changes.clone(), from_seq)
+    }
+
+    /// Seq the next write to the store will get, for tailing only what comes next
+    pub fn next_change_seq(&self) -> Result<u64> {
+        self.changes.next_seq()
+    }
+
+    /// Every live key in no particular order, straight from the index without reading anything
+    /// from disk. The keys are copied out up front, so the store can be written while iterating.
+    pub fn keys(&self) -> impl Iterator<Item = K> {
+        let mut keys: Vec<K> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.extend(
+            self.operands
+                .iter()
+                .filter(|entry| !self.index.contains_key(entry.key()))
+                .map(|entry| entry.key().clone()),
+        );
+        keys.into_iter()
+    }
+
+    /// Every live pair, as of the call, see `Export`. Writes wait while values with merge
+    /// operands are folded, the other values are only read as the export is iterated, from files
+    /// compaction leaves in place until the export is dropped.
+    pub fn export(&self) -> Result<Export<K, V>> {
+        let mut writer = self.lock_writer()?;
+        writer.buf_writer.flush()?;
+        self.flushed.advance(writer.position);
+        let mut folded = Vec::new();
+        let keys: Vec<K> = self
+            .operands
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in keys {
+            if let Some((value, _)) = self.fold(&mut writer, &key)? {
+                folded.push((key, value));
+            }
+        }
+        let mut records = self.live_records(|key| !self.operands.contains_key(key));
+        records.sort_unstable_by_key(|(_, record)| (record.segment, record.offset));
+        // The export reads through its own handles, the store drops those of retired files
+        let mut names = BTreeMap::new();
+        for (_, record) in &records {
+            if record.inline.is_none() {
+                names.insert(record.segment, segment_name(record.segment));
+            }
+            if let Some(pointer) = &record.pointer {
+                names.insert(pointer.log, value_log_name(pointer.log));
+            }
+        }
+        let mut readers = BTreeMap::new();
+        for (&id, name) in &names {
+            readers.insert(id, self.storage.open(name)?);
+        }
+        let files: Vec<String> = names.into_values().collect();
+        self.exports.pin(&files);
+        Ok(Export::new(self.clone(), readers, files, folded, records))
+    }
+
+    /// Value the record of `key` sets, read through the `readers` of an export
+    pub(super) fn exported_value(
+        &self,
+        readers: &BTreeMap<u64, Box<dyn SegmentReader>>,
+        key: &K,
+        record: &ValueData,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<V>> {
+        record.read_into(readers, buf)?;
+        let decoded: KvRecord<K, V> = self
+            .options
+            .codec
+            .decode(buf)
+            .map_err(corrupt)
+            .context(|| record.read_context())?;
+        self.value_of(decoded, readers)
+            .context(|| ErrorContext::new("export").with_key(key))
+    }
+
+    pub(super) fn unpin_files(&self, files: &[String]) -> Vec<String> {
+        self.exports.unpin(files)
+    }
+
+    pub(super) fn remove_file(&self, name: &str) -> Result<()> {
+        self.storage.remove(name)
+    }
+
+    /// Removes a file compaction retired, unless an export still reads it
+    fn retire_file(&self, name: &str) -> Result<()> {
+        if self.exports.retire(name) {
+            return Ok(());
+        }
+        self.storage.remove(name)
+    }
+
+    /// Applies merges with `operator`, see `KvsEngine::merge`. Merge operands aren't kept in the
+    /// history, only the values they are folded into once a key has many of them, and
+    /// followers only see them once they are folded.
+    pub fn with_merge_operator(self, operator: impl MergeOperator<K, V>) -> KvStore<K, V> {
+        KvStore {
+            merge_operator: Some(Arc::new(operator)),
+            ..self
+        }
+    }
+
+    /// Appends the merge record of `operand`, already serialized
+    fn append_operand(
+        &self,
+        mut writer: MutexGuard<LogWriter>,
+        key: K,
+        operand: V,
+        serialized: &[u8],
+        start: Instant,
+    ) -> Result<()> {
+        let value_data = self.write_command(&mut writer, serialized)?;
+        if self.changes.is_enabled() {
+            self.changes.push(Change::Merged((key.clone(), operand)))?;
+        }
+        self.keep_prior(&key);
+        self.tombstones.remove(&key);
+        let pending = {
+            let mut operands = self.operands.entry(key.clone()).or_default();
+            operands.push(value_data.clone());
+            operands.len()
+        };
+        if pending >= MAX_PENDING_OPERANDS {
+            if let Some((value, stamp)) = self.fold(&mut writer, &key)? {
+                // The value is as of the operand just appended, it keeps its stamp
+                let stamp = stamp.unwrap_or_else(|| self.stamp());
+                let serialized = self.encode_set(&key, &value, stamp)?;
+                self.write_set(writer, key, value, &serialized, stamp, start)?;
+                return Ok(());
+            }
+        }
+        // Folding the operands reclaims them
+        if self
+            .uncompressed_bytes
+            .fetch_add(value_data.size as u64, Ordering::SeqCst)
+            > self.tuning.compaction_threshold.load(Ordering::SeqCst)
+        {
+            drop(writer);
+            self.compact_files()?;
+        }
+        self.metrics.writes.record(start.elapsed());
+        Ok(())
+    }
+
+    /// Value of `key` with its pending merge operands folded in, along with the stamp of the
+    /// last record folded. The caller holds the writer lock, so the records can't move or change
+    /// meanwhile.
+    fn fold(&self, writer: &mut LogWriter, key: &K) -> Result<Option<(V, Option<Stamp>)>> {
+        let (base, operands) = self.current_records(key);
+        self.fold_records(writer, key, base, &operands)
+    }
+
+    /// Value of `key` folded from the record `base` and the merge `operands` on top of it, see
+    /// `fold`
+    fn fold_records(
+        &self,
+        writer: &mut LogWriter,
+        key: &K,
+        base: Option<ValueData>,
+        operands: &[ValueData],
+    ) -> Result<Option<(V, Option<Stamp>)>> {
+        writer.buf_writer.flush()?;
+        self.flushed.advance(writer.position);
+        let readers = self.readers();
+        let read = |record: &ValueData| -> Result<(KvRecord<K, V>, Option<Stamp>)> {
+            let mut buf = Vec::new();
+            record.read_into(&readers, &mut buf)?;
+            Ok(self.options.codec.decode::<KvRecord<K, V>>(&buf)?.unstamp())
+        };
+        let (mut value, mut stamp) = match base.as_ref().map(read).transpose()? {
+            Some((record, stamp)) => (self.value_of(record, &readers)?, stamp),
+            None => (None, None),
+        };
+        for operand in operands {
+            let (record, operand_stamp) = read(operand)?;
+            if let KvRecord::Merge((_, operand)) = record {
+                value = match &self.merge_operator {
+                    Some(operator) => operator.merge(key, value, operand),
+                    None => return Err(KvsError::MergeUnsupported),
+                };
+                stamp = operand_stamp;
+            }
+        }
+        Ok(value.map(|value| (value, stamp)))
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            bytes_written: self.metrics.bytes_written.get(),
+            compaction_bytes_written: self.metrics.compaction_bytes_written.get(),
+            compactions: self.metrics.compactions.get(),
+            write_stalls: self.metrics.write_stalls.get(),
+            reads: self.metrics.reads.count(),
+            mean_read_latency: self.metrics.reads.mean(),
+            read_latency: self.metrics.reads.percentiles(),
+            write_latency: self.metrics.writes.percentiles(),
+            remove_latency: self.metrics.removes.percentiles(),
+            compaction_threshold: self.tuning.compaction_threshold.load(Ordering::SeqCst),
+            max_segment_bytes: self.tuning.max_segment_bytes.load(Ordering::SeqCst),
+            disk_bytes: self.disk_bytes.load(Ordering::SeqCst),
+            value_garbage: self.value_garbage.load(Ordering::SeqCst),
+            index_stripes: self.options.index_stripes,
+        }
+    }
+
+    /// Moves the compaction threshold and segment size based on what happened since the last
+    /// adjustment. Compaction runs less often when it rewrites a lot compared to new writes, and
+    /// more often when it is cheap or reads slow down.
+    fn tune(&self) -> Result<()> {
+        let mut last = self
+            .tuning
+            .last_window
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let window = TuningWindow {
+            bytes_written: self.metrics.bytes_written.get(),
+            compaction_bytes_written: self.metrics.compaction_bytes_written.get(),
+            reads: self.metrics.reads.count(),
+            read_time: self.metrics.reads.total(),
+            mean_read_latency: last.mean_read_latency,
+        };
+        let written = window.bytes_written - last.bytes_written;
+        let rewritten = window.compaction_bytes_written - last.compaction_bytes_written;
+        let reads = window.reads - last.reads;
+        let mean_read_latency = match reads {
+            0 => last.mean_read_latency,
+            reads => {
+                Duration::from_nanos((window.read_time - last.read_time).as_nanos() as u64 / reads)
+            }
+        };
+        if written == 0 {
+            return Ok(());
+        }
+        let write_amplification = (written + rewritten) as f64 / written as f64;
+
+        let threshold = self.tuning.compaction_threshold.load(Ordering::SeqCst);
+        let new_threshold = if !last.mean_read_latency.is_zero()
+            && mean_read_latency > last.mean_read_latency * 3 / 2
+        {
+            info!(
+                "Mean read latency rose from {:?} to {:?}, compacting sooner",
+                last.mean_read_latency, mean_read_latency
+            );
+            threshold / 2
+        } else if write_amplification > HIGH_WRITE_AMPLIFICATION {
+            info!(
+                "Write amplification of {:.2} since last compaction, compacting less often",
+                write_amplification
+            );
+            threshold * 2
+        } else if write_amplification < LOW_WRITE_AMPLIFICATION {
+            info!(
+                "Write amplification of {:.2} since last compaction, compacting more often",
+                write_amplification
+            );
+            threshold / 2
+        } else {
+            threshold
+        }
+        .clamp(MIN_COMPACTION_THRESHOLD, MAX_COMPACTION_THRESHOLD);
+        if new_threshold != threshold {
+            info!(
+                "Compaction threshold changed from {} to {} bytes",
+                threshold, new_threshold
+            );
+            self.tuning
+                .compaction_threshold
+                .store(new_threshold, Ordering::SeqCst);
+        }
+
+        // Segments hold a few compaction cycles worth of writes
+        let segment_bytes = self.tuning.max_segment_bytes.load(Ordering::SeqCst);
+        let new_segment_bytes = (new_threshold * 4).clamp(MIN_SEGMENT_BYTES, MAX_SEGMENT_BYTES);
+        if new_segment_bytes != segment_bytes {
+            info!(
+                "Maximum segment size changed from {} to {} bytes",
+                segment_bytes, new_segment_bytes
+            );
+            self.tuning
+                .max_segment_bytes
+                .store(new_segment_bytes, Ordering::SeqCst);
+        }
+
+        *last = TuningWindow {
+            mean_read_latency,
+            ..window
+        };
         Ok(())
     }
 }
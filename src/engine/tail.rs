@@ -0,0 +1,133 @@
+//! Feed of the writes made to a store, for processes building projections, search indexes or
+//! replicas out of it.
+//!
+//! Writes are numbered in the order they reach the log, starting from 0 every time the store is
+//! opened. Only the latest ones are kept in memory, tails falling further behind get
+//! `KvsError::ChangesDropped` and have to resync from the store itself.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub use crate::protocol::{Change, ChangeEvent};
+use crate::{KvsError, Result};
+
+struct Retained<K, V> {
+    // Seq of the first retained change
+    first_seq: u64,
+    changes: VecDeque<Change<K, V>>,
+}
+
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+pub(crate) struct ChangeLog<K, V> {
+    capacity: usize,
+    retained: Mutex<Retained<K, V>>,
+    appended: Condvar,
+}
+
+#[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+impl<K: Clone, V: Clone> ChangeLog<K, V> {
+    pub(crate) fn new(capacity: usize) -> ChangeLog<K, V> {
+        ChangeLog {
+            capacity,
+            retained: Mutex::new(Retained {
+                first_seq: 0,
+                changes: VecDeque::new(),
+            }),
+            appended: Condvar::new(),
+        }
+    }
+
+    /// Whether changes are kept at all, so that writers can skip building them
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Appends a change, dropping the oldest one once over capacity
+    pub(crate) fn push(&self, change: Change<K, V>) -> Result<()> {
+        let mut retained = self.retained.lock()?;
+        retained.changes.push_back(change);
+        if retained.changes.len() > self.capacity {
+            retained.changes.pop_front();
+            retained.first_seq += 1;
+        }
+        self.appended.notify_all();
+        Ok(())
+    }
+
+    /// Seq the next change will get
+    pub(crate) fn next_seq(&self) -> Result<u64> {
+        let retained = self.retained.lock()?;
+        Ok(retained.first_seq + retained.changes.len() as u64)
+    }
+
+    /// Waits for change `seq` until `deadline`, or for good if there is none
+    fn wait(&self, seq: u64, deadline: Option<Instant>) -> Result<Option<ChangeEvent<K, V>>> {
+        let mut retained = self.retained.lock()?;
+        loop {
+            if seq < retained.first_seq {
+                return Err(KvsError::ChangesDropped(retained.first_seq));
+            }
+            let index = (seq - retained.first_seq) as usize;
+            if let Some(change) = retained.changes.get(index) {
+                return Ok(Some(ChangeEvent {
+                    seq,
+                    change: change.clone(),
+                }));
+            }
+            retained = match deadline {
+                None => self.appended.wait(retained)?,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.appended.wait_timeout(retained, deadline - now)?.0
+                }
+            };
+        }
+    }
+}
+
+/// Blocking iterator over the changes made to a store from some seq on, see `KvStore::tail`
+pub struct Tail<K, V> {
+    log: Arc<ChangeLog<K, V>>,
+    next: u64,
+}
+
+impl<K: Clone, V: Clone> Tail<K, V> {
+    #[cfg_attr(not(feature = "engine-kvs"), allow(dead_code))]
+    pub(crate) fn new(log: Arc<ChangeLog<K, V>>, from_seq: u64) -> Tail<K, V> {
+        Tail {
+            log,
+            next: from_seq,
+        }
+    }
+
+    /// Seq of the next change this tail returns
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+
+    /// Next change, or none if it wasn't made within `timeout`
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent<K, V>>> {
+        let event = self.log.wait(self.next, Some(Instant::now() + timeout))?;
+        if event.is_some() {
+            self.next += 1;
+        }
+        Ok(event)
+    }
+}
+
+impl<K: Clone, V: Clone> Iterator for Tail<K, V> {
+    type Item = Result<ChangeEvent<K, V>>;
+
+    /// Blocks until the next change is made
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.log.wait(self.next, None).transpose()?;
+        if event.is_ok() {
+            self.next += 1;
+        }
+        Some(event)
+    }
+}
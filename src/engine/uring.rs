@@ -0,0 +1,510 @@
+//! Reads and writes of log files through io_uring, see `KvStoreOptions::io_uring`.
+//!
+//! One ring is shared by the files of a storage. Submitting takes a lock and waits for every
+//! completion, so the gain comes from handing the kernel many operations at once: the reads of
+//! `SegmentReader::read_many`, or a write linked to the fsync that makes it durable.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::{error, warn};
+
+use super::storage::{SegmentAppender, SegmentReader};
+
+// Operations in flight at once, larger submissions go in several rounds
+const ENTRIES: u32 = 64;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+
+// Layouts of linux/io_uring.h
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// Memory shared with the kernel, unmapped on drop
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Mmap> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    // Pointer to the field `offset` bytes into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// One operation of a submission, pointing at a buffer that outlives the submission
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    opcode: u8,
+    fd: RawFd,
+    addr: u64,
+    len: u32,
+    offset: u64,
+    op_flags: u32,
+    // The next operation only starts once this one succeeded in full
+    linked: bool,
+}
+
+impl Op {
+    fn read(fd: RawFd, buf: &mut [u8], offset: u64) -> Op {
+        Op {
+            opcode: IORING_OP_READ,
+            fd,
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len().min(u32::MAX as usize) as u32,
+            offset,
+            op_flags: 0,
+            linked: false,
+        }
+    }
+
+    fn write(fd: RawFd, buf: &[u8], offset: u64) -> Op {
+        Op {
+            opcode: IORING_OP_WRITE,
+            fd,
+            addr: buf.as_ptr() as u64,
+            len: buf.len().min(u32::MAX as usize) as u32,
+            offset,
+            op_flags: 0,
+            linked: false,
+        }
+    }
+
+    fn fdatasync(fd: RawFd) -> Op {
+        Op {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            addr: 0,
+            len: 0,
+            offset: 0,
+            op_flags: IORING_FSYNC_DATASYNC,
+            linked: false,
+        }
+    }
+}
+
+/// Bytes transferred by a completed operation
+fn completed(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        return Err(io::Error::from_raw_os_error(-res));
+    }
+    Ok(res as usize)
+}
+
+struct RingState {
+    fd: OwnedFd,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    entries: u32,
+}
+
+// The mappings are only touched with the lock of the ring held
+unsafe impl Send for RingState {}
+
+/// Submission and completion queues shared with the kernel
+pub(crate) struct Ring {
+    state: Mutex<RingState>,
+}
+
+impl fmt::Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Ring")
+    }
+}
+
+impl Ring {
+    /// Sets up a ring, failing on kernels without io_uring or where it is disabled
+    pub(crate) fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let raw = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        Ok(Ring {
+            state: Mutex::new(RingState {
+                sq: Mmap::new(raw, sq_len, IORING_OFF_SQ_RING)?,
+                cq: Mmap::new(raw, cq_len, IORING_OFF_CQ_RING)?,
+                sqes: Mmap::new(raw, sqes_len, IORING_OFF_SQES)?,
+                fd,
+                sq_off: params.sq_off,
+                cq_off: params.cq_off,
+                entries: params.sq_entries,
+            }),
+        })
+    }
+
+    /// Submits `ops` and waits for all of them, returning what each completed with: bytes
+    /// transferred or a negated errno. Linked operations are never split across rounds.
+    fn submit(&self, ops: &[Op]) -> io::Result<Vec<i32>> {
+        // Every submission is waited for in full, so a panic can't leave the ring half used
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut results = vec![0; ops.len()];
+        let mut start = 0;
+        while start < ops.len() {
+            let mut end = ops.len().min(start + state.entries as usize);
+            while end < ops.len() && end > start + 1 && ops[end - 1].linked {
+                end -= 1;
+            }
+            state.push(&ops[start..end], start);
+            state.wait(end - start, &mut results)?;
+            start = end;
+        }
+        Ok(results)
+    }
+}
+
+impl RingState {
+    // Queues `ops`, tagged with their index in the whole submission
+    fn push(&self, ops: &[Op], first: usize) {
+        unsafe {
+            let tail = &*self.sq.at::<AtomicU32>(self.sq_off.tail);
+            let mask = *self.sq.at::<u32>(self.sq_off.ring_mask);
+            let array = self.sq.at::<u32>(self.sq_off.array);
+            let sqes = self.sqes.ptr.cast::<Sqe>();
+            let mut next = tail.load(Ordering::Relaxed);
+            for (i, op) in ops.iter().enumerate() {
+                let index = next & mask;
+                let linked = op.linked && i + 1 < ops.len();
+                ptr::write(
+                    sqes.add(index as usize),
+                    Sqe {
+                        opcode: op.opcode,
+                        flags: if linked { IOSQE_IO_LINK } else { 0 },
+                        ioprio: 0,
+                        fd: op.fd,
+                        off: op.offset,
+                        addr: op.addr,
+                        len: op.len,
+                        op_flags: op.op_flags,
+                        user_data: (first + i) as u64,
+                        buf_index: 0,
+                        personality: 0,
+                        splice_fd_in: 0,
+                        addr3: 0,
+                        pad: 0,
+                    },
+                );
+                *array.add(index as usize) = index;
+                next = next.wrapping_add(1);
+            }
+            tail.store(next, Ordering::Release);
+        }
+    }
+
+    // Submits the `count` queued operations and reaps their completions into `results`. The
+    // operations point into buffers of the caller, so this never returns while the kernel may
+    // still use one of them.
+    fn wait(&self, count: usize, results: &mut [i32]) -> io::Result<()> {
+        let mut to_submit = count;
+        let mut pending = count;
+        let mut failure = None;
+        while pending > 0 {
+            let entered = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    to_submit as libc::c_uint,
+                    1 as libc::c_uint,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::c_void>(),
+                    0usize,
+                )
+            };
+            if entered >= 0 {
+                to_submit -= entered as usize;
+            } else {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    // Interrupted, or out of room until the completions below are reaped
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {}
+                    // The kernel took none of the operations left to submit. They are taken
+                    // back so that the next submission doesn't carry them, and the ones in
+                    // flight are still waited for.
+                    _ if to_submit > 0 => {
+                        self.unqueue();
+                        pending -= to_submit;
+                        to_submit = 0;
+                        failure = Some(error);
+                    }
+                    // Returning would let the kernel write into freed buffers
+                    _ => {
+                        error!("io_uring failed with operations in flight: {}", error);
+                        process::abort();
+                    }
+                }
+            }
+            self.reap(results, &mut pending);
+        }
+        match failure {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // Drops the operations queued but not submitted yet
+    fn unqueue(&self) {
+        unsafe {
+            let head = &*self.sq.at::<AtomicU32>(self.sq_off.head);
+            let tail = &*self.sq.at::<AtomicU32>(self.sq_off.tail);
+            tail.store(head.load(Ordering::Acquire), Ordering::Release);
+        }
+    }
+
+    // Moves the completions posted so far into `results`
+    fn reap(&self, results: &mut [i32], pending: &mut usize) {
+        unsafe {
+            let head = &*self.cq.at::<AtomicU32>(self.cq_off.head);
+            let tail = &*self.cq.at::<AtomicU32>(self.cq_off.tail);
+            let mask = *self.cq.at::<u32>(self.cq_off.ring_mask);
+            let cqes = self.cq.at::<Cqe>(self.cq_off.cqes);
+            let mut next = head.load(Ordering::Relaxed);
+            let end = tail.load(Ordering::Acquire);
+            while next != end {
+                let cqe = &*cqes.add((next & mask) as usize);
+                match results.get_mut(cqe.user_data as usize) {
+                    Some(result) if *pending > 0 => {
+                        *result = cqe.res;
+                        *pending -= 1;
+                    }
+                    _ => warn!("ignoring completion of unknown operation {}", cqe.user_data),
+                }
+                next = next.wrapping_add(1);
+            }
+            head.store(next, Ordering::Release);
+        }
+    }
+}
+
+/// Reads a log file through a ring
+pub(crate) struct UringReader {
+    file: File,
+    ring: Arc<Ring>,
+}
+
+impl UringReader {
+    pub(crate) fn new(file: File, ring: Arc<Ring>) -> UringReader {
+        UringReader { file, ring }
+    }
+}
+
+impl SegmentReader for UringReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_many(&mut [(offset, buf)])
+    }
+
+    /// Every read goes in the same submission, reads that come back short are submitted again
+    /// for what is left
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let mut remaining: Vec<(u64, &mut [u8])> = reads
+            .iter_mut()
+            .map(|(offset, buf)| (*offset, &mut **buf))
+            .filter(|(_, buf)| !buf.is_empty())
+            .collect();
+        while !remaining.is_empty() {
+            let ops: Vec<Op> = remaining
+                .iter_mut()
+                .map(|(offset, buf)| Op::read(fd, buf, *offset))
+                .collect();
+            let results = self.ring.submit(&ops)?;
+            let mut short = Vec::new();
+            for ((offset, buf), res) in remaining.into_iter().zip(results) {
+                let read = completed(res)?;
+                if read == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                if read < buf.len() {
+                    short.push((offset + read as u64, &mut buf[read..]));
+                }
+            }
+            remaining = short;
+        }
+        Ok(())
+    }
+}
+
+/// Appends to a log file through a ring
+pub(crate) struct UringAppender {
+    file: File,
+    ring: Arc<Ring>,
+    position: u64,
+}
+
+impl UringAppender {
+    /// Appends to `file` after its first `position` bytes
+    pub(crate) fn new(file: File, ring: Arc<Ring>, position: u64) -> UringAppender {
+        UringAppender {
+            file,
+            ring,
+            position,
+        }
+    }
+}
+
+impl Write for UringAppender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let op = Op::write(self.file.as_raw_fd(), data, self.position);
+        let written = completed(self.ring.submit(&[op])?[0])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SegmentAppender for UringAppender {
+    fn sync_data(&self) -> io::Result<()> {
+        completed(self.ring.submit(&[Op::fdatasync(self.file.as_raw_fd())])?[0])?;
+        Ok(())
+    }
+
+    /// The write and the fsync go in one submission, the fsync only starting once the whole
+    /// write is done
+    fn write_synced(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        loop {
+            let write = Op {
+                linked: true,
+                ..Op::write(fd, data, self.position)
+            };
+            let results = self.ring.submit(&[write, Op::fdatasync(fd)])?;
+            let written = completed(results[0])?;
+            self.position += written as u64;
+            if written == data.len() {
+                completed(results[1])?;
+                return Ok(());
+            }
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            // A short write cancels the fsync, both go again for the rest
+            data = &data[written..];
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! C API for embedding a `KvStore`, enabled by the `ffi` feature. The matching header is
+//! `include/kvs.h`. Handles can also be opened on a sled database or a connection to a server,
+//! which the Python module in `python` builds on as well.
+//!
+//! Keys and values are NUL terminated UTF-8 strings. Every function returns a `KvsStatus` code,
+//! results are handed back through out pointers.
+
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use crate::client::KvsClient;
+use crate::engine::sled::SledKvsEngine;
+use crate::engine::store::KvStore;
+use crate::engine::KvsEngine;
+use crate::{KvsError, Result};
+
+/// Status codes returned by every function of the C API
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvsStatus {
+    Ok = 0,
+    NotFound = 1,
+    /// A null pointer, or a string that isn't UTF-8 or can't hold the value
+    InvalidArgument = 2,
+    IoError = 3,
+    SerializationError = 4,
+    Error = 5,
+}
+
+impl From<&KvsError> for KvsStatus {
+    fn from(err: &KvsError) -> Self {
+        match err.root() {
+            KvsError::NonExistantKey => KvsStatus::NotFound,
+            KvsError::IOError(_) => KvsStatus::IoError,
+            KvsError::SerializationError(_) => KvsStatus::SerializationError,
+            _ => KvsStatus::Error,
+        }
+    }
+}
+
+pub(crate) enum Engine {
+    Kvs(KvStore<String, String>),
+    Sled(SledKvsEngine),
+    Client(KvsClient),
+}
+
+/// Opaque handle on an open store or a connection to a server
+pub struct KvsHandle {
+    engine: Engine,
+}
+
+impl KvsHandle {
+    pub(crate) fn new(engine: Engine) -> KvsHandle {
+        KvsHandle { engine }
+    }
+
+    pub(crate) fn set(&self, key: String, value: String) -> Result<()> {
+        match &self.engine {
+            Engine::Kvs(store) => store.set(key, value),
+            Engine::Sled(sled) => sled.set(key, value),
+            Engine::Client(client) => client.set(key, value),
+        }
+    }
+
+    pub(crate) fn get(&self, key: String) -> Result<Option<String>> {
+        match &self.engine {
+            Engine::Kvs(store) => store.get(key),
+            Engine::Sled(sled) => sled.get(key),
+            Engine::Client(client) => client.get(key),
+        }
+    }
+
+    pub(crate) fn remove(&self, key: String) -> Result<()> {
+        match &self.engine {
+            Engine::Kvs(store) => store.remove(key),
+            Engine::Sled(sled) => sled.remove(key),
+            Engine::Client(client) => client.remove(key),
+        }
+    }
+
+    pub(crate) fn close(self) -> Result<()> {
+        match self.engine {
+            Engine::Kvs(store) => store.flush(),
+            // Sled flushes once the last handle is dropped
+            Engine::Sled(_) | Engine::Client(_) => Ok(()),
+        }
+    }
+}
+
+/// Stores a handle on `engine` in `out`
+///
+/// # Safety
+/// `out` must be null or valid for a write.
+unsafe fn open_handle(
+    out: *mut *mut KvsHandle,
+    engine: impl FnOnce() -> Result<Engine>,
+) -> std::result::Result<(), KvsStatus> {
+    if out.is_null() {
+        return Err(KvsStatus::InvalidArgument);
+    }
+    let engine = check(engine())?;
+    *out = Box::into_raw(Box::new(KvsHandle::new(engine)));
+    Ok(())
+}
+
+/// Runs `f`, turning errors and panics into status codes so neither crosses into C
+fn status(f: impl FnOnce() -> std::result::Result<(), KvsStatus>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => KvsStatus::Ok as c_int,
+        Ok(Err(status)) => status as c_int,
+        Err(_) => KvsStatus::Error as c_int,
+    }
+}
+
+fn check<T>(result: Result<T>) -> std::result::Result<T, KvsStatus> {
+    result.map_err(|e| KvsStatus::from(&e))
+}
+
+/// Borrows a C string as UTF-8
+///
+/// # Safety
+/// `s` must be null or point to a NUL terminated string that outlives the returned borrow.
+unsafe fn str_arg<'a>(s: *const c_char) -> std::result::Result<&'a str, KvsStatus> {
+    if s.is_null() {
+        return Err(KvsStatus::InvalidArgument);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| KvsStatus::InvalidArgument)
+}
+
+/// Opens the store in the directory `path`, creating it if needed, and stores a handle on it in
+/// `out`. The handle must be closed with `kvs_close`.
+///
+/// # Safety
+/// `path` must be a NUL terminated string and `out` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char, out: *mut *mut KvsHandle) -> c_int {
+    status(|| {
+        let path = str_arg(path)?;
+        open_handle(out, || Ok(Engine::Kvs(KvStore::open(Path::new(path))?)))
+    })
+}
+
+/// Same as `kvs_open`, for a sled database in the directory `path`
+///
+/// # Safety
+/// `path` must be a NUL terminated string and `out` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open_sled(path: *const c_char, out: *mut *mut KvsHandle) -> c_int {
+    status(|| {
+        let path = str_arg(path)?;
+        open_handle(out, || {
+            Ok(Engine::Sled(SledKvsEngine::new(Path::new(path))?))
+        })
+    })
+}
+
+/// Stores a handle in `out` that sends every call to the server at `addr`, given as
+/// `<ip>:<port>`. Connections are made per call, so this only fails on a malformed address.
+///
+/// # Safety
+/// `addr` must be a NUL terminated string and `out` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_connect(addr: *const c_char, out: *mut *mut KvsHandle) -> c_int {
+    status(|| {
+        let addr: SocketAddr = str_arg(addr)?
+            .parse()
+            .map_err(|_| KvsStatus::InvalidArgument)?;
+        open_handle(out, || Ok(Engine::Client(KvsClient::new(addr))))
+    })
+}
+
+/// Sets `key` to `value`
+///
+/// # Safety
+/// `handle` must come from `kvs_open` and not be closed yet, `key` and `value` must be NUL
+/// terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    handle: *const KvsHandle,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    status(|| {
+        let handle = handle.as_ref().ok_or(KvsStatus::InvalidArgument)?;
+        let (key, value) = (str_arg(key)?, str_arg(value)?);
+        check(handle.set(key.to_owned(), value.to_owned()))
+    })
+}
+
+/// Stores the value of `key` in `value_out`, to be freed with `kvs_free_string`. Returns
+/// `KVS_NOT_FOUND` and leaves `value_out` null if the key has no value.
+///
+/// # Safety
+/// `handle` must come from `kvs_open` and not be closed yet, `key` must be a NUL terminated
+/// string and `value_out` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    handle: *const KvsHandle,
+    key: *const c_char,
+    value_out: *mut *mut c_char,
+) -> c_int {
+    status(|| {
+        let handle = handle.as_ref().ok_or(KvsStatus::InvalidArgument)?;
+        let key = str_arg(key)?;
+        if value_out.is_null() {
+            return Err(KvsStatus::InvalidArgument);
+        }
+        *value_out = ptr::null_mut();
+        let value = check(handle.get(key.to_owned()))?.ok_or(KvsStatus::NotFound)?;
+        // Values holding a NUL byte can't be handed out as C strings
+        let value = CString::new(value).map_err(|_| KvsStatus::InvalidArgument)?;
+        *value_out = value.into_raw();
+        Ok(())
+    })
+}
+
+/// Removes `key`, returning `KVS_NOT_FOUND` if it has no value
+///
+/// # Safety
+/// `handle` must come from `kvs_open` and not be closed yet, `key` must be a NUL terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(handle: *const KvsHandle, key: *const c_char) -> c_int {
+    status(|| {
+        let handle = handle.as_ref().ok_or(KvsStatus::InvalidArgument)?;
+        let key = str_arg(key)?;
+        check(handle.remove(key.to_owned()))
+    })
+}
+
+/// Frees a value returned by `kvs_get`, null is ignored
+///
+/// # Safety
+/// `value` must be null or come from `kvs_get` and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free_string(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Flushes and closes the store or connection, null is ignored
+///
+/// # Safety
+/// `handle` must be null or come from `kvs_open` and not be closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(handle: *mut KvsHandle) -> c_int {
+    status(|| {
+        if handle.is_null() {
+            return Ok(());
+        }
+        check(Box::from_raw(handle).close())
+    })
+}
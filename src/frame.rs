@@ -0,0 +1,156 @@
+//! Reading and writing protocol messages on a connection.
+//!
+//! The handshake and everything on connections that skip it are plain JSON values with nothing
+//! in between, so that a frame can directly follow the handshake. After a handshake every message
+//! is sent as a frame: a byte naming the codec the body was compressed with, the length of the
+//! body as a big endian u32, and the body itself. Bodies are msgpack, or JSON if both sides
+//! agreed to `Feature::Json`, and are only compressed when they are large enough to be worth it.
+//! Bodies are at most `MAX_FRAME_LEN` bytes, before and after decompression, and so are plain JSON
+//! messages.
+
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::{frame_header, parse_frame_header, HEADER_LEN, UNCOMPRESSED};
+pub use crate::protocol::{Compression, Encoding, Framing, COMPRESSION_THRESHOLD, MAX_FRAME_LEN};
+use crate::{KvsError, Result};
+
+impl Compression {
+    fn compress(self, body: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(body),
+            Compression::Zstd => zstd::encode_all(body, 0)?,
+        })
+    }
+
+    /// Fails for bodies that decompress to more than `MAX_FRAME_LEN` bytes, without
+    /// decompressing more than that
+    fn decompress(self, body: &[u8]) -> Result<Vec<u8>> {
+        let too_long = || KvsError::SerializationError("decompressed frame too long".to_owned());
+        match self {
+            Compression::Lz4 => {
+                let (size, compressed) = body
+                    .split_first_chunk()
+                    .ok_or_else(|| KvsError::SerializationError("truncated lz4 body".to_owned()))?;
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > MAX_FRAME_LEN {
+                    return Err(too_long());
+                }
+                let mut decompressed = vec![0; size];
+                let len = lz4_flex::block::decompress_into(compressed, &mut decompressed)?;
+                decompressed.truncate(len);
+                Ok(decompressed)
+            }
+            Compression::Zstd => {
+                let mut decompressed = Vec::new();
+                zstd::Decoder::new(body)?
+                    .take(MAX_FRAME_LEN as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > MAX_FRAME_LEN {
+                    return Err(too_long());
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+impl Encoding {
+    fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Msgpack => rmp_serde::to_vec_named(message)?,
+            Encoding::Json => serde_json::to_vec(message)?,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Msgpack => rmp_serde::from_slice(body)?,
+            Encoding::Json => serde_json::from_slice(body)?,
+        })
+    }
+}
+
+impl From<lz4_flex::block::DecompressError> for KvsError {
+    fn from(lz4_err: lz4_flex::block::DecompressError) -> Self {
+        KvsError::SerializationError(lz4_err.to_string())
+    }
+}
+
+/// Writes `message` as plain JSON, or as a frame if `framing` is set
+pub fn write_message<T: Serialize>(
+    mut writer: impl Write,
+    message: &T,
+    framing: Option<Framing>,
+) -> Result<()> {
+    match framing {
+        None => serde_json::to_writer(&mut writer, message)?,
+        Some(framing) => {
+            let body = framing.encoding.encode(message)?;
+            match framing.compression {
+                Some(compression) if body.len() >= COMPRESSION_THRESHOLD => {
+                    let compressed = compression.compress(&body)?;
+                    write_header(&mut writer, compression.tag(), compressed.len())?;
+                    writer.write_all(&compressed)?;
+                }
+                _ => {
+                    write_header(&mut writer, UNCOMPRESSED, body.len())?;
+                    writer.write_all(&body)?;
+                }
+            }
+        }
+    }
+    Ok(writer.flush()?)
+}
+
+fn write_header(writer: &mut impl Write, tag: u8, len: usize) -> Result<()> {
+    let header = frame_header(tag, len)
+        .ok_or_else(|| KvsError::SerializationError(format!("message of {} bytes", len)))?;
+    Ok(writer.write_all(&header)?)
+}
+
+/// Reads the next message, or `None` if the other side finished writing
+pub fn read_message<T: DeserializeOwned>(
+    mut reader: impl Read,
+    framing: Option<Framing>,
+) -> Result<Option<T>> {
+    let framing = match framing {
+        Some(framing) => framing,
+        None => {
+            // Plain JSON messages are held to the same length as frame bodies
+            return Ok(
+                serde_json::Deserializer::from_reader(reader.take(MAX_FRAME_LEN as u64))
+                    .into_iter()
+                    .next()
+                    .transpose()?,
+            );
+        }
+    };
+    let mut header = [0; HEADER_LEN];
+    match reader.read_exact(&mut header[..1]) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    reader.read_exact(&mut header[1..])?;
+    let (tag, len) = parse_frame_header(header);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::SerializationError(format!(
+            "frame of {} bytes",
+            len
+        )));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    let body = match Compression::from_tag(tag) {
+        Ok(None) => body,
+        Ok(Some(compression)) => compression.decompress(&body)?,
+        Err(tag) => {
+            return Err(KvsError::SerializationError(format!(
+                "unknown compression {}",
+                tag
+            )))
+        }
+    };
+    Ok(Some(framing.encoding.decode(&body)?))
+}
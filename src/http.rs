@@ -0,0 +1,231 @@
+//! REST gateway in front of an engine, enabled by the `http` feature.
+//!
+//! `GET`, `PUT` and `DELETE` on `/keys/{key}` get, set and remove a key. Values travel as JSON
+//! bodies of the form `{"value": "..."}`. Keys are percent-decoded. Every connection serves a
+//! single request, which is all curl and browsers need for debugging.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::KvsEngine;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+// Bodies are values, anything larger is refused rather than buffered
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const KEYS_PATH: &str = "/keys/";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValueBody {
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyValueBody {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Option<String>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Response {
+        Response {
+            status,
+            body: serde_json::to_string(body).ok(),
+        }
+    }
+
+    fn error(status: u16, error: impl ToString) -> Response {
+        Response::json(
+            status,
+            &ErrorBody {
+                error: error.to_string(),
+            },
+        )
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: None,
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decodes `%XX` escapes, failing on malformed ones or on bytes that aren't UTF-8
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Reads the request line, headers and body, or returns the response refusing the request
+fn read_request(stream: &TcpStream) -> Result<std::result::Result<Request, Response>> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Ok(Err(Response::error(400, "malformed request line"))),
+    };
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse() {
+                    Ok(length) => length,
+                    Err(_) => return Ok(Err(Response::error(400, "malformed Content-Length"))),
+                };
+            }
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Ok(Err(Response::error(413, "body too large")));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request { method, path, body }))
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> Result<()> {
+    let body = response.body.as_deref().unwrap_or("");
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        body.len()
+    );
+    if response.body.is_some() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    if response.status == 405 {
+        head.push_str("Allow: GET, PUT, DELETE\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Serves `GET`, `PUT` and `DELETE` on `/keys/{key}` against an engine. Requests go straight to
+/// the engine, so they skip what a `kvs-server` does on top of it like notifying watchers.
+#[derive(Clone)]
+pub struct HttpGateway<E> {
+    engine: E,
+}
+
+impl<E: KvsEngine<String, String>> HttpGateway<E> {
+    pub fn new(engine: E) -> HttpGateway<E> {
+        HttpGateway { engine }
+    }
+
+    /// Answers connections accepted on `listener` on `pool` until accepting fails
+    pub fn serve<P: ThreadPool>(&self, listener: TcpListener, pool: &P) -> Result<()> {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let gateway = self.clone();
+                    pool.spawn(move || {
+                        if let Err(e) = gateway.handle_connection(&stream) {
+                            info!("Could not serve HTTP connection: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Errored in HTTP stream: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: &TcpStream) -> Result<()> {
+        let response = match read_request(stream)? {
+            Ok(request) => self.handle(request),
+            Err(response) => response,
+        };
+        write_response(stream, &response)
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let key = match request
+            .path
+            .strip_prefix(KEYS_PATH)
+            .filter(|key| !key.is_empty())
+            .map(percent_decode)
+        {
+            Some(Some(key)) => key,
+            Some(None) => return Response::error(400, "malformed key"),
+            None => return Response::error(404, "no such path"),
+        };
+        match request.method.as_str() {
+            "GET" => match self.engine.get(key.clone()) {
+                Ok(Some(value)) => Response::json(200, &KeyValueBody { key, value }),
+                Ok(None) => Response::error(404, "Key not found"),
+                Err(e) => Response::error(500, format!("{:?}", e)),
+            },
+            "PUT" => match serde_json::from_slice::<ValueBody>(&request.body) {
+                Ok(body) => match self.engine.set(key, body.value) {
+                    Ok(()) => Response::no_content(),
+                    Err(KvsError::OutOfSpace) => Response::error(507, "out of space"),
+                    Err(e @ KvsError::Degraded(_)) => Response::error(503, e),
+                    Err(KvsError::QuotaExceeded(namespace)) => {
+                        Response::error(507, format!("namespace {:?} over its quota", namespace))
+                    }
+                    Err(e) => Response::error(500, format!("{:?}", e)),
+                },
+                Err(e) => Response::error(400, e),
+            },
+            "DELETE" => match self.engine.remove(key) {
+                Ok(()) => Response::no_content(),
+                Err(KvsError::NonExistantKey) => Response::error(404, "Key not found"),
+                Err(e @ KvsError::Degraded(_)) => Response::error(503, e),
+                Err(e) => Response::error(500, format!("{:?}", e)),
+            },
+            _ => Response::error(405, "method not allowed"),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Results of recent idempotent writes, so that retries of them aren't applied twice.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::Result;
+
+/// Number of results kept by default, older ones are forgotten first
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+enum Entry<V> {
+    // The write is being run, retries wait for it to finish
+    Running,
+    Done(Option<V>),
+}
+
+struct Results<V> {
+    by_token: HashMap<u64, Entry<V>>,
+    // Tokens of finished writes from oldest to newest
+    order: VecDeque<u64>,
+}
+
+pub struct IdempotencyCache<V> {
+    capacity: usize,
+    results: Mutex<Results<V>>,
+    // Notified whenever a running write finishes
+    finished: Condvar,
+}
+
+/// Forgets the running write of `token` when dropped, unless it finished successfully, so that
+/// retries waiting on a write that failed or panicked run it again
+struct Running<'a, V> {
+    cache: &'a IdempotencyCache<V>,
+    token: u64,
+}
+
+impl<V> Drop for Running<'_, V> {
+    fn drop(&mut self) {
+        let mut results = self.cache.lock();
+        if matches!(results.by_token.get(&self.token), Some(Entry::Running)) {
+            results.by_token.remove(&self.token);
+        }
+        drop(results);
+        self.cache.finished.notify_all();
+    }
+}
+
+impl<V> IdempotencyCache<V> {
+    /// The results stay consistent through a panic, which only ever happens in `write`, run
+    /// without the lock
+    fn lock(&self) -> MutexGuard<'_, Results<V>> {
+        self.results.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<V: Clone> IdempotencyCache<V> {
+    pub fn new(capacity: usize) -> IdempotencyCache<V> {
+        IdempotencyCache {
+            capacity,
+            results: Mutex::new(Results {
+                by_token: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Runs `write` unless a write with `token` already succeeded, in which case its result is
+    /// returned instead. Failed writes aren't remembered so that retrying them runs them again.
+    /// Writes with different tokens run concurrently, a retry arriving while its write runs
+    /// waits for it to finish.
+    pub fn run(&self, token: u64, write: impl FnOnce() -> Result<Option<V>>) -> Result<Option<V>> {
+        let mut results = self.lock();
+        loop {
+            match results.by_token.get(&token) {
+                Some(Entry::Done(result)) => return Ok(result.clone()),
+                Some(Entry::Running) => {
+                    results = self
+                        .finished
+                        .wait(results)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+                None => break,
+            }
+        }
+        results.by_token.insert(token, Entry::Running);
+        drop(results);
+        let running = Running { cache: self, token };
+        let result = write()?;
+        let mut results = self.lock();
+        if results.order.len() >= self.capacity {
+            if let Some(oldest) = results.order.pop_front() {
+                results.by_token.remove(&oldest);
+            }
+        }
+        results.by_token.insert(token, Entry::Done(result.clone()));
+        results.order.push_back(token);
+        drop(results);
+        drop(running);
+        Ok(result)
+    }
+}
+
+impl<V: Clone> Default for IdempotencyCache<V> {
+    fn default() -> Self {
+        IdempotencyCache::new(DEFAULT_CAPACITY)
+    }
+}
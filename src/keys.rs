@@ -0,0 +1,206 @@
+//! Byte encodings of typed keys that sort in the same order as the keys, so that range scans over
+//! keys like `(tenant_id, timestamp)` come out in key order from engines that order keys by their
+//! bytes, like sled, as well as from those that order them with `Ord`.
+//!
+//! Integers are big endian, with the sign bit of signed ones flipped so that negative numbers
+//! come first. Strings and byte strings have their zero bytes escaped as `00 ff` and end with
+//! `00 01`, so that no encoding is a prefix of another and a shorter string sorts first. Tuples
+//! are their fields one after the other.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Keys with an order-preserving byte encoding: comparing the encodings of two keys bytewise
+/// gives the same order as comparing the keys with `Ord`, and no encoding is a prefix of another
+pub trait KeyCodec: Ord + Sized {
+    /// Appends the encoding of the key to `out`
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decodes a key from the front of `bytes`, moving `bytes` past it
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self>;
+
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_key(&mut out);
+        out
+    }
+
+    /// Whether the leading fields of the key are `prefix`, like the user of a `(user, item)`
+    /// key. Strings only match whole, `"ab"` doesn't begin with `"a"`.
+    fn has_prefix<P: KeyCodec>(&self, prefix: &P) -> bool {
+        self.to_key_bytes()
+            .as_slice()
+            .starts_with(&prefix.to_key_bytes())
+    }
+
+    /// Decodes a key that takes up all of `bytes`
+    fn from_key_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let key = Self::decode_key(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(key)
+    }
+}
+
+fn invalid(problem: &str) -> KvsError {
+    KvsError::SerializationError(format!("invalid key encoding: {}", problem))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(invalid("too short"));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn encode_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == 0 {
+            out.push(0xff);
+        }
+    }
+    out.extend_from_slice(&[0, 1]);
+}
+
+fn decode_escaped(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        match take(bytes, 1)?[0] {
+            0 => match take(bytes, 1)?[0] {
+                0xff => decoded.push(0),
+                1 => return Ok(decoded),
+                _ => return Err(invalid("bad escape")),
+            },
+            byte => decoded.push(byte),
+        }
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self, out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        decode_escaped(bytes)
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(decode_escaped(bytes)?).map_err(|_| invalid("not UTF-8"))
+    }
+}
+
+macro_rules! unsigned_key_codec {
+    ($($int:ty),*) => {$(
+        impl KeyCodec for $int {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                let taken = take(bytes, std::mem::size_of::<$int>())?;
+                Ok(<$int>::from_be_bytes(taken.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_key_codec {
+    ($($int:ty => $unsigned:ty),*) => {$(
+        impl KeyCodec for $int {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))).encode_key(out);
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                let flipped = <$unsigned>::decode_key(bytes)?;
+                Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $int)
+            }
+        }
+    )*};
+}
+
+unsigned_key_codec!(u8, u16, u32, u64, u128);
+signed_key_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+macro_rules! tuple_key_codec {
+    ($($field:ident $index:tt),+) => {
+        impl<$($field: KeyCodec),+> KeyCodec for ($($field,)+) {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                $(self.$index.encode_key(out);)+
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($field::decode_key(bytes)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key_codec!(A 0);
+tuple_key_codec!(A 0, B 1);
+tuple_key_codec!(A 0, B 1, C 2);
+tuple_key_codec!(A 0, B 1, C 2, D 3);
+
+/// Key made of several fields, like `(user, item)`, ordered field by field. Keys sharing their
+/// leading fields sort next to each other, so the items of a user can be listed with a prefix
+/// scan like `KvStore::scan_prefix(&user)`. It derives what stores need of keys from the tuple
+/// and displays as its fields separated by slashes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CompositeKey<T>(pub T);
+
+impl<T> From<T> for CompositeKey<T> {
+    fn from(fields: T) -> Self {
+        CompositeKey(fields)
+    }
+}
+
+impl<T: KeyCodec> KeyCodec for CompositeKey<T> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.0.encode_key(out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        T::decode_key(bytes).map(CompositeKey)
+    }
+}
+
+macro_rules! composite_key_display {
+    ($first:ident 0 $(, $field:ident $index:tt)*) => {
+        impl<$first: fmt::Display $(, $field: fmt::Display)*> fmt::Display
+            for CompositeKey<($first, $($field,)*)>
+        {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0 .0)?;
+                $(write!(f, "/{}", self.0 .$index)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+composite_key_display!(A 0, B 1);
+composite_key_display!(A 0, B 1, C 2);
+composite_key_display!(A 0, B 1, C 2, D 3);
+
+#[cfg(feature = "engine-kvs")]
+impl<T> crate::engine::store::Key for CompositeKey<T>
+where
+    T: fmt::Debug + Clone + Eq + std::hash::Hash + Serialize + Send + 'static,
+    T: for<'de> Deserialize<'de>,
+    CompositeKey<T>: fmt::Display,
+{
+}
@@ -0,0 +1,258 @@
+//! Checks an engine against the contract of `KvsEngine`, for implementors of other backends.
+//!
+//! `run_engine_conformance` is meant to be called from a test. Every check opens the engine on
+//! a fresh directory through the factory, and opens it on the same directory again to check
+//! what survives a restart, so engines that keep nothing on disk can't pass. Broken contracts
+//! panic with the check that failed, errors of the engine are returned.
+
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use crate::engine::KvsEngine;
+use crate::{KvsError, Result};
+
+/// Threads writing at the same time in the concurrency check
+const THREADS: usize = 8;
+/// Keys every thread writes in the concurrency check
+const KEYS_PER_THREAD: usize = 100;
+
+/// Runs every check against engines opened by `factory`, which opens the engine stored in the
+/// directory it is given
+pub fn run_engine_conformance<E, F>(factory: F) -> Result<()>
+where
+    E: KvsEngine<String, String>,
+    F: Fn(&Path) -> Result<E>,
+{
+    let checks: [(&str, Check<E>); 6] = [
+        ("persistence", persistence),
+        ("overwrite", overwrite),
+        ("remove", remove),
+        ("remove nonexistent", remove_nonexistent),
+        ("concurrent access", concurrent_access),
+        ("restart", restart),
+    ];
+    for (name, check) in checks {
+        let dir = ScratchDir::new(name)?;
+        check(&factory, &dir.0)?;
+    }
+    Ok(())
+}
+
+type Factory<'a, E> = &'a dyn Fn(&Path) -> Result<E>;
+
+type Check<E> = fn(Factory<'_, E>, &Path) -> Result<()>;
+
+// Removed once the check is done, also when it panics
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new(check: &str) -> Result<ScratchDir> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst),
+            check.replace(' ', "-")
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(ScratchDir(path))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn persistence<E: KvsEngine<String, String>>(factory: Factory<'_, E>, dir: &Path) -> Result<()> {
+    let engine = factory(dir)?;
+    assert_eq!(engine.get("key1".to_owned())?, None, "persistence: empty");
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value1".to_owned()),
+        "persistence: read back"
+    );
+    assert!(
+        engine.contains_key("key2".to_owned())?,
+        "persistence: contains"
+    );
+    drop(engine);
+
+    let engine = factory(dir)?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value1".to_owned()),
+        "persistence: reopened"
+    );
+    assert_eq!(
+        engine.get("key2".to_owned())?,
+        Some("value2".to_owned()),
+        "persistence: reopened"
+    );
+    assert_eq!(
+        engine.scan_keys(None, 10)?,
+        vec!["key1".to_owned(), "key2".to_owned()],
+        "persistence: scan"
+    );
+    Ok(())
+}
+
+fn overwrite<E: KvsEngine<String, String>>(factory: Factory<'_, E>, dir: &Path) -> Result<()> {
+    let engine = factory(dir)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value2".to_owned()),
+        "overwrite: newest value"
+    );
+    assert!(
+        !engine.set_nx("key1".to_owned(), "value3".to_owned())?,
+        "overwrite: set_nx on a present key"
+    );
+    assert!(
+        engine.set_xx("key1".to_owned(), "value4".to_owned())?,
+        "overwrite: set_xx on a present key"
+    );
+    assert!(
+        !engine.set_xx("key2".to_owned(), "value1".to_owned())?,
+        "overwrite: set_xx on an absent key"
+    );
+    drop(engine);
+
+    let engine = factory(dir)?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value4".to_owned()),
+        "overwrite: reopened"
+    );
+    assert_eq!(engine.get("key2".to_owned())?, None, "overwrite: reopened");
+    Ok(())
+}
+
+fn remove<E: KvsEngine<String, String>>(factory: Factory<'_, E>, dir: &Path) -> Result<()> {
+    let engine = factory(dir)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None, "remove: gone");
+    assert!(!engine.contains_key("key1".to_owned())?, "remove: contains");
+    drop(engine);
+
+    let engine = factory(dir)?;
+    assert_eq!(engine.get("key1".to_owned())?, None, "remove: reopened");
+    assert_eq!(
+        engine.scan_keys(None, 10)?,
+        vec!["key2".to_owned()],
+        "remove: scan"
+    );
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value3".to_owned()),
+        "remove: set again"
+    );
+    Ok(())
+}
+
+fn remove_nonexistent<E: KvsEngine<String, String>>(
+    factory: Factory<'_, E>,
+    dir: &Path,
+) -> Result<()> {
+    let engine = factory(dir)?;
+    let removed = engine.remove("key1".to_owned());
+    assert!(
+        matches!(&removed, Err(e) if matches!(e.root(), KvsError::NonExistantKey)),
+        "remove nonexistent: got {:?}",
+        removed
+    );
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    let removed = engine.remove("key1".to_owned());
+    assert!(
+        matches!(&removed, Err(e) if matches!(e.root(), KvsError::NonExistantKey)),
+        "remove nonexistent: removed twice, got {:?}",
+        removed
+    );
+    Ok(())
+}
+
+fn concurrent_access<E: KvsEngine<String, String>>(
+    factory: Factory<'_, E>,
+    dir: &Path,
+) -> Result<()> {
+    let engine = factory(dir)?;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS_PER_THREAD {
+                    let key = format!("key{}-{}", thread, i);
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(
+                        engine.get(key)?,
+                        Some(format!("value{}", i)),
+                        "concurrent access: own write"
+                    );
+                    // Every thread also contends on the same key
+                    engine.set("shared".to_owned(), format!("value{}", thread))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))?;
+    }
+
+    let check = |engine: &E, when: &str| -> Result<()> {
+        for thread in 0..THREADS {
+            for i in 0..KEYS_PER_THREAD {
+                assert_eq!(
+                    engine.get(format!("key{}-{}", thread, i))?,
+                    Some(format!("value{}", i)),
+                    "concurrent access: {}",
+                    when
+                );
+            }
+        }
+        let shared = engine.get("shared".to_owned())?;
+        assert!(
+            shared.is_some_and(|value| (0..THREADS).any(|t| value == format!("value{}", t))),
+            "concurrent access: shared key {}",
+            when
+        );
+        Ok(())
+    };
+    check(&engine, "after joining")?;
+    drop(engine);
+    check(&factory(dir)?, "reopened")
+}
+
+fn restart<E: KvsEngine<String, String>>(factory: Factory<'_, E>, dir: &Path) -> Result<()> {
+    // Every session overwrites the keys of the one before and removes one of them
+    for session in 0..5 {
+        let engine = factory(dir)?;
+        for i in 0..20 {
+            let expected =
+                (session > 0 && i != session - 1).then(|| format!("{}-{}", session - 1, i));
+            assert_eq!(
+                engine.get(format!("key{}", i))?,
+                expected,
+                "restart: session {}",
+                session
+            );
+            engine.set(format!("key{}", i), format!("{}-{}", session, i))?;
+        }
+        engine.remove(format!("key{}", session))?;
+    }
+    Ok(())
+}
@@ -1,16 +1,18 @@
-use serde::{Deserialize, Serialize};
+extern crate alloc;
 
-pub type Result<T> = std::result::Result<T, KvsError>;
+pub use protocol::{ErrorContext, KvsError, Result};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum KvsError {
-    FileListEmpty,
-    WrongEngine,
-    SerializationError(String),
-    IOError(String),
-    NonExistantKey,
-    ThreadPoolBuildError(String),
-    Other,
+/// Adds context to the IO and serialization errors of a result, see `KvsError::with_context`
+#[cfg(feature = "engine-kvs")]
+pub(crate) trait ResultExt<T> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+#[cfg(feature = "engine-kvs")]
+impl<T, E: Into<KvsError>> ResultExt<T> for std::result::Result<T, E> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T> {
+        self.map_err(|e| e.into().with_context(context))
+    }
 }
 
 impl From<serde_json::Error> for KvsError {
@@ -21,32 +23,117 @@ impl From<serde_json::Error> for KvsError {
 
 impl From<std::io::Error> for KvsError {
     fn from(io_err: std::io::Error) -> Self {
-        KvsError::IOError(io_err.to_string())
+        match io_err.kind() {
+            std::io::ErrorKind::StorageFull => KvsError::DiskFull,
+            _ => KvsError::IOError(io_err.to_string()),
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for KvsError {
+    fn from(serde_err: rmp_serde::decode::Error) -> Self {
+        KvsError::SerializationError(serde_err.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for KvsError {
+    fn from(serde_err: rmp_serde::encode::Error) -> Self {
+        KvsError::SerializationError(serde_err.to_string())
     }
 }
 
+impl<T> From<std::sync::PoisonError<T>> for KvsError {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        KvsError::Poisoned
+    }
+}
+
+#[cfg(feature = "server")]
 impl From<rayon::ThreadPoolBuildError> for KvsError {
     fn from(rayon_err: rayon::ThreadPoolBuildError) -> Self {
         KvsError::IOError(rayon_err.to_string())
     }
 }
 
-pub mod protocol {
-    use crate::Result;
-    use serde::{Deserialize, Serialize};
+/// FNV-1a mixed with the splitmix64 finalizer, so that hashes agree across processes and builds
+pub fn stable_hash(item: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(item);
+    hasher.finish()
+}
+
+/// `stable_hash` of bytes fed a piece at a time, like a file read in chunks
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher {
+    hash: u64,
+}
 
-    #[derive(Serialize, Deserialize, Debug)]
-    pub enum KvRequest<K, V> {
-        Set((K, V)),
-        Rm(K),
-        Get(K),
+impl StableHasher {
+    pub fn new() -> StableHasher {
+        StableHasher {
+            hash: 0xcbf29ce484222325,
+        }
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
-    pub struct KvResponse<V> {
-        pub value: Result<Option<V>>,
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Hash of every byte written so far
+    pub fn finish(&self) -> u64 {
+        let hash = self.hash;
+        let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^ (hash >> 31)
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher::new()
     }
 }
 
+#[cfg(feature = "server")]
+pub mod audit;
+pub mod bench;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod cluster;
+#[cfg(feature = "client")]
+pub mod config;
+#[cfg(feature = "client")]
+pub mod discovery;
 pub mod engine;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "server")]
+pub mod idempotency;
+pub mod keys;
+pub mod kvs_test_suite;
+pub mod merkle;
+pub mod metrics;
+#[cfg(feature = "client")]
+pub mod net;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod protocol;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "server")]
+pub mod session;
+#[cfg(feature = "client")]
+pub mod sharded;
+#[cfg(feature = "server")]
+pub mod sim;
 pub mod thread_pool;
+pub mod txn;
+pub mod values;
+pub mod watch;
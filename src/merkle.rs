@@ -0,0 +1,167 @@
+//! Hash tree over the keys of a store, for replicas to find which of their keys differ without
+//! exchanging them all.
+//!
+//! Keys are split into `LEAVES` buckets by the hash of the key. A leaf hashes every key of its
+//! bucket along with its value, and every node above hashes its two children, so two replicas
+//! holding the same keys have the same root. Replicas compare nodes level by level from the root,
+//! only descending into the ones that differ, and then exchange the keys of the leaves that do.
+//!
+//! Writes only mark the leaf of their key as changed, and changed leaves are hashed again when
+//! the tree is next asked for, from a scan of the keys of the store.
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::engine::KvsEngine;
+use crate::{stable_hash, Result};
+
+/// Levels below the root, the leaves are on the last one
+pub const DEPTH: u32 = 10;
+/// Buckets keys are split into
+pub const LEAVES: u64 = 1 << DEPTH;
+// Keys scanned at a time while hashing leaves
+const SCAN_BATCH: usize = 1024;
+
+struct State {
+    leaves: Vec<u64>,
+    // Leaves written since they were last hashed
+    changed: Vec<bool>,
+}
+
+pub struct MerkleTree {
+    state: Mutex<State>,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        // Nothing is known about the store yet
+        MerkleTree {
+            state: Mutex::new(State {
+                leaves: vec![0; LEAVES as usize],
+                changed: vec![true; LEAVES as usize],
+            }),
+        }
+    }
+}
+
+/// Leaf the bucket of `key` hangs from
+pub fn leaf_of<K: Serialize>(key: &K) -> Result<u64> {
+    Ok(stable_hash(&serde_json::to_vec(key)?) >> (64 - DEPTH))
+}
+
+impl MerkleTree {
+    /// Must be called for every write to the store, so that the leaf of `key` is hashed again
+    pub fn written<K: Serialize>(&self, key: &K) {
+        if let Ok(leaf) = leaf_of(key) {
+            self.state.lock().unwrap().changed[leaf as usize] = true;
+        }
+    }
+
+    /// Hashes of `nodes` at `level`, counting from the root at level 0 and from the left
+    pub fn hashes<K, V, E>(&self, engine: &E, level: u32, nodes: &[u64]) -> Result<Vec<u64>>
+    where
+        K: Serialize + Ord + Clone,
+        V: Serialize,
+        E: KvsEngine<K, V>,
+    {
+        let leaves = self.leaves(engine)?;
+        let mut hashes = leaves;
+        for _ in level..DEPTH {
+            hashes = hashes
+                .chunks(2)
+                .map(|children| {
+                    let mut bytes = children[0].to_le_bytes().to_vec();
+                    bytes.extend_from_slice(&children[1].to_le_bytes());
+                    stable_hash(&bytes)
+                })
+                .collect();
+        }
+        Ok(nodes
+            .iter()
+            .map(|node| hashes.get(*node as usize).copied().unwrap_or_default())
+            .collect())
+    }
+
+    /// Keys and values in the bucket of `leaf`
+    pub fn entries<K, V, E>(&self, engine: &E, leaf: u64) -> Result<Vec<(K, V)>>
+    where
+        K: Serialize + Ord + Clone,
+        E: KvsEngine<K, V>,
+    {
+        let mut entries = Vec::new();
+        scan(engine, |key| {
+            if leaf_of(key)? == leaf {
+                if let Some(value) = engine.get(key.clone())? {
+                    entries.push((key.clone(), value));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Hashes of every leaf, hashing the changed ones again first
+    fn leaves<K, V, E>(&self, engine: &E) -> Result<Vec<u64>>
+    where
+        K: Serialize + Ord + Clone,
+        V: Serialize,
+        E: KvsEngine<K, V>,
+    {
+        // Writes made while hashing mark their leaf changed again for the next time
+        let changed = {
+            let mut state = self.state.lock().unwrap();
+            std::mem::replace(&mut state.changed, vec![false; LEAVES as usize])
+        };
+        if !changed.contains(&true) {
+            return Ok(self.state.lock().unwrap().leaves.clone());
+        }
+        let mut hashed = vec![0u64; LEAVES as usize];
+        let result = scan(engine, |key| {
+            let leaf = leaf_of(key)? as usize;
+            if changed[leaf] {
+                if let Some(value) = engine.get(key.clone())? {
+                    let mut bytes = serde_json::to_vec(key)?;
+                    bytes.push(0);
+                    bytes.extend(serde_json::to_vec(&value)?);
+                    // Summed so that the order keys are scanned in doesn't matter
+                    hashed[leaf] = hashed[leaf].wrapping_add(stable_hash(&bytes));
+                }
+            }
+            Ok(())
+        });
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = result {
+            for (leaf, changed) in changed.into_iter().enumerate() {
+                state.changed[leaf] |= changed;
+            }
+            return Err(e);
+        }
+        for (leaf, changed) in changed.into_iter().enumerate() {
+            if changed {
+                state.leaves[leaf] = hashed[leaf];
+            }
+        }
+        Ok(state.leaves.clone())
+    }
+}
+
+/// Calls `f` with every key of `engine`, in batches of `SCAN_BATCH`
+fn scan<K, V, E, F>(engine: &E, mut f: F) -> Result<()>
+where
+    K: Ord + Clone,
+    E: KvsEngine<K, V>,
+    F: FnMut(&K) -> Result<()>,
+{
+    let mut after = None;
+    loop {
+        let keys = engine.scan_keys(after, SCAN_BATCH)?;
+        for key in &keys {
+            f(key)?;
+        }
+        if keys.len() < SCAN_BATCH {
+            return Ok(());
+        }
+        after = keys.last().cloned();
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+pub use crate::protocol::{HotKey, Percentiles};
+use crate::stable_hash;
+
+/// Monotonic counter that can be bumped from any thread
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Current amount of something that comes and goes, like jobs waiting in a queue
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Values below this are counted exactly, above it every power of two is split into half as
+// many equal buckets, which keeps every bucket within 1% of the values counted in it
+const SUB_BUCKET_BITS: u32 = 8;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const HALF_SUB_BUCKETS: u64 = SUB_BUCKETS / 2;
+/// Latencies above this are counted as this, an hour in nanoseconds is well below it
+const MAX_TRACKABLE_NANOS: u64 = (1 << 42) - 1;
+
+fn bucket_index(value: u64) -> usize {
+    let value = value.min(MAX_TRACKABLE_NANOS);
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - (SUB_BUCKET_BITS - 1);
+    (SUB_BUCKETS + (shift as u64 - 1) * HALF_SUB_BUCKETS + (value >> shift) - HALF_SUB_BUCKETS)
+        as usize
+}
+
+/// Highest value counted in the bucket at `index`
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let offset = index - SUB_BUCKETS;
+    let shift = offset / HALF_SUB_BUCKETS + 1;
+    let sub_bucket = offset % HALF_SUB_BUCKETS + HALF_SUB_BUCKETS;
+    ((sub_bucket + 1) << shift) - 1
+}
+
+/// Distribution of latencies in the manner of an HDR histogram: buckets grow with the values
+/// they hold so that any percentile is within 1% of the real one, using a fixed amount of
+/// memory no matter how many values are recorded. Recording never takes a lock.
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: Counter,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..=bucket_index(MAX_TRACKABLE_NANOS))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            count: Counter::default(),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count.get())
+            .field("max", &self.max.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.add(1);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Smallest latency that `quantile` of the recorded ones are at or below
+    pub fn value_at_quantile(&self, quantile: f64) -> Duration {
+        let count = self.count.get();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                let max = self.max.load(Ordering::Relaxed);
+                return Duration::from_nanos(bucket_value(index).min(max));
+            }
+        }
+        Duration::from_nanos(self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            count: self.count.get(),
+            p50: self.value_at_quantile(0.5),
+            p95: self.value_at_quantile(0.95),
+            p99: self.value_at_quantile(0.99),
+            p999: self.value_at_quantile(0.999),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Number of times an operation ran, the total time spent in it and how that time was spread
+#[derive(Debug, Default)]
+pub struct Latency {
+    count: Counter,
+    total_nanos: Counter,
+    histogram: Histogram,
+}
+
+impl Latency {
+    pub fn record(&self, elapsed: Duration) {
+        self.count.add(1);
+        self.total_nanos.add(elapsed.as_nanos() as u64);
+        self.histogram.record(elapsed);
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        self.histogram.percentiles()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.total_nanos.get())
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos(self.total_nanos.get() / count),
+        }
+    }
+}
+
+fn halve(counter: &AtomicU64) {
+    // Can't fail, the update always gives a value
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+        Some(count / 2)
+    });
+}
+
+// Rows and counters per row of the sketch, which overestimates a count by more than
+// 2 / SKETCH_WIDTH of every access recorded with a chance of 1 in 2^SKETCH_DEPTH
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 4096;
+/// Keys the top keys are picked from
+const HOT_KEY_CANDIDATES: usize = 64;
+/// Accesses after which every count is halved, so that the counts favour recent accesses
+const DECAY_INTERVAL: u64 = 1 << 20;
+
+/// Keys accessed the most, counted in a count-min sketch: a fixed amount of counters that
+/// estimate the count of any key with some error, which can only be too high. The keys with the
+/// highest estimates are kept as candidates for `top_keys`. Recording only takes a lock for keys
+/// estimated to be accessed as often as the least accessed candidate.
+pub struct HotKeys {
+    counters: Box<[AtomicU64]>,
+    accesses: AtomicU64,
+    candidates: Mutex<HashMap<String, u64>>,
+    /// Lowest count of the candidates once there are as many as can be kept, zero before
+    threshold: AtomicU64,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys {
+            counters: (0..SKETCH_DEPTH * SKETCH_WIDTH)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            accesses: AtomicU64::new(0),
+            candidates: Mutex::new(HashMap::new()),
+            threshold: AtomicU64::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for HotKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HotKeys")
+            .field("accesses", &self.accesses.load(Ordering::Relaxed))
+            .field("threshold", &self.threshold.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl HotKeys {
+    pub fn record(&self, key: &str) {
+        // Each row picks its counter with its own combination of two halves of one hash
+        let hash = stable_hash(key.as_bytes());
+        let (first, step) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let estimate = (0..SKETCH_DEPTH)
+            .map(|row| {
+                let column = first.wrapping_add(row as u64 * step) as usize % SKETCH_WIDTH;
+                self.counters[row * SKETCH_WIDTH + column].fetch_add(1, Ordering::Relaxed) + 1
+            })
+            .min()
+            .unwrap_or(0);
+        if estimate >= self.threshold.load(Ordering::Relaxed) {
+            self.offer(key, estimate);
+        }
+        if (self.accesses.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(DECAY_INTERVAL) {
+            self.decay();
+        }
+    }
+
+    fn offer(&self, key: &str, estimate: u64) {
+        let mut candidates = self
+            .candidates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match candidates.get_mut(key) {
+            Some(count) => *count = estimate,
+            None => {
+                candidates.insert(key.to_owned(), estimate);
+            }
+        }
+        if candidates.len() > HOT_KEY_CANDIDATES {
+            let coldest = candidates
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, _)| key.clone());
+            if let Some(coldest) = coldest {
+                candidates.remove(&coldest);
+            }
+            let threshold = candidates.values().min().copied().unwrap_or(0);
+            self.threshold.store(threshold, Ordering::Relaxed);
+        }
+    }
+
+    fn decay(&self) {
+        self.counters.iter().for_each(halve);
+        let mut candidates = self
+            .candidates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        candidates.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+        halve(&self.threshold);
+    }
+
+    /// Up to `n` of the keys accessed the most, most accessed first
+    pub fn top_keys(&self, n: usize) -> Vec<HotKey> {
+        let candidates = self
+            .candidates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut top: Vec<HotKey> = candidates
+            .iter()
+            .map(|(key, &count)| HotKey {
+                key: key.clone(),
+                count,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        top.truncate(n);
+        top
+    }
+}
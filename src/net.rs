@@ -0,0 +1,165 @@
+//! Addresses and sockets shared by the server, the client and the command line tools.
+//!
+//! IPv6 listeners only accept IPv6 connections, so that a server can listen on both `0.0.0.0`
+//! and `[::]` with the same port, which a dual-stack IPv6 socket would already have taken.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Port used when an address is given without one
+pub const DEFAULT_PORT: u16 = 4000;
+
+/// Address the server listens on and clients connect to by default
+pub fn default_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT)
+}
+
+/// Parses `127.0.0.1:4000`, `[::1]:4000` or an IP address alone like `::1` or `[::1]`, which
+/// gets `DEFAULT_PORT`
+pub fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(addr);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+        .map_err(|_| {
+            format!(
+                "invalid address {}, expected <ip>:<port> or [<ipv6>]:<port>",
+                addr
+            )
+        })
+}
+
+/// Listens on `addr`, only accepting IPv6 connections if it is an IPv6 address
+#[cfg(unix)]
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let addr = match addr {
+        SocketAddr::V4(_) => return TcpListener::bind(addr),
+        SocketAddr::V6(addr) => addr,
+    };
+    // std can't set IPV6_V6ONLY before binding, so the socket is made by hand
+    unsafe {
+        let fd = libc::socket(libc::AF_INET6, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owned right away so that the socket is closed on errors
+        let listener = TcpListener::from_raw_fd(fd);
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        let mut sockaddr: libc::sockaddr_in6 = std::mem::zeroed();
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sockaddr.sin6_port = addr.port().to_be();
+        sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+        sockaddr.sin6_flowinfo = addr.flowinfo();
+        sockaddr.sin6_scope_id = addr.scope_id();
+        let bound = libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        );
+        if bound < 0 || libc::listen(fd, 128) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(listener)
+    }
+}
+
+/// Listens on `addr`, Windows sockets only accept IPv6 connections on IPv6 addresses already
+#[cfg(not(unix))]
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+/// Options of the TCP connections between clients and servers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends small messages right away instead of waiting to fill a packet (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Probes idle connections after this long, to find peers that went away
+    pub keepalive: Option<Duration>,
+    /// Size of the receive buffer of the kernel, left to the system if not given
+    pub recv_buffer: Option<usize>,
+    /// Size of the send buffer of the kernel, left to the system if not given
+    pub send_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options on `stream`
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if self.keepalive.is_none() && self.recv_buffer.is_none() && self.send_buffer.is_none() {
+            return Ok(());
+        }
+        self.apply_sockopts(stream)
+    }
+
+    #[cfg(unix)]
+    fn apply_sockopts(&self, stream: &TcpStream) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = stream.as_raw_fd();
+        if let Some(idle) = self.keepalive {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            let secs = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs)?;
+            // Elsewhere probes start after the idle time of the system
+            let _ = secs;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, buffer_size(size))?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, buffer_size(size))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_sockopts(&self, _stream: &TcpStream) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "keepalive and buffer sizes are only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn buffer_size(size: usize) -> libc::c_int {
+    size.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+#[cfg(unix)]
+fn set_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let set = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! OpenTelemetry export, enabled by the `otel` feature.
+//!
+//! Spans and metrics are sent to a collector as OTLP over HTTP with JSON bodies, to `/v1/traces`
+//! and `/v1/metrics` on the configured endpoint. Nothing is recorded until `start` is called, so
+//! spans in library code cost next to nothing when no exporter runs.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::metrics::Percentiles;
+use crate::{KvsError, Result};
+
+/// Spans waiting for the next export beyond this are dropped
+const MAX_PENDING_SPANS: usize = 10_000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct OtelConfig {
+    /// OTLP/HTTP endpoint of the collector, usually port 4318
+    pub endpoint: SocketAddr,
+    /// Reported as the `service.name` resource attribute
+    pub service_name: String,
+    /// Time between exports
+    pub interval: Duration,
+}
+
+impl OtelConfig {
+    pub fn new(endpoint: SocketAddr) -> OtelConfig {
+        OtelConfig {
+            endpoint,
+            service_name: "kvs".to_owned(),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Value of a metric at export time
+#[derive(Debug, Clone)]
+pub enum Metric {
+    /// Total that only ever grows, like the number of compactions
+    Counter { name: String, value: u64 },
+    /// Current amount of something, like jobs waiting in a queue
+    Gauge { name: String, value: i64 },
+    /// Distribution of latencies, exported as a summary in seconds
+    Latency {
+        name: String,
+        percentiles: Percentiles,
+    },
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    trace_id: u128,
+    span_id: u64,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+static PENDING_SPANS: OnceLock<Mutex<Vec<FinishedSpan>>> = OnceLock::new();
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Operation being timed, exported once dropped if an exporter is running
+pub struct Span {
+    name: &'static str,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+/// Starts a span named `name`
+pub fn span(name: &'static str) -> Span {
+    Span {
+        name,
+        start: SystemTime::now(),
+        attributes: Vec::new(),
+        error: None,
+    }
+}
+
+impl Span {
+    pub fn with_attribute(mut self, key: &'static str, value: impl ToString) -> Span {
+        self.attributes.push((key, value.to_string()));
+        self
+    }
+
+    /// Marks the span as failed if `result` is an error
+    pub fn record_result<T>(&mut self, result: &Result<T>) {
+        if let Err(e) = result {
+            self.error = Some(format!("{:?}", e));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let pending = match PENDING_SPANS.get() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.len() < MAX_PENDING_SPANS {
+            pending.push(FinishedSpan {
+                name: self.name,
+                trace_id: (u128::from(random_u64()) << 64) | u128::from(random_u64()),
+                span_id: random_u64(),
+                start: self.start,
+                end: SystemTime::now(),
+                attributes: std::mem::take(&mut self.attributes),
+                error: self.error.take(),
+            });
+        }
+    }
+}
+
+fn attributes(attributes: &[(&'static str, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+fn resource(config: &OtelConfig) -> Value {
+    json!({"attributes": attributes(&[("service.name", config.service_name.clone())])})
+}
+
+fn traces_body(config: &OtelConfig, spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                // Internal
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes(&span.attributes),
+                "status": match &span.error {
+                    Some(message) => json!({"code": 2, "message": message}),
+                    None => json!({"code": 1}),
+                },
+            })
+        })
+        .collect();
+    json!({"resourceSpans": [{
+        "resource": resource(config),
+        "scopeSpans": [{"scope": {"name": "kvs"}, "spans": spans}],
+    }]})
+}
+
+fn metrics_body(config: &OtelConfig, metrics: &[Metric], start: SystemTime) -> Value {
+    let now = unix_nanos(SystemTime::now());
+    let start = unix_nanos(start);
+    let metrics: Vec<Value> = metrics
+        .iter()
+        .map(|metric| match metric {
+            Metric::Counter { name, value } => json!({
+                "name": name,
+                "sum": {
+                    "dataPoints": [{
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                        "asInt": value.to_string(),
+                    }],
+                    // Cumulative
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                },
+            }),
+            Metric::Gauge { name, value } => json!({
+                "name": name,
+                "gauge": {"dataPoints": [{"timeUnixNano": now, "asInt": value.to_string()}]},
+            }),
+            Metric::Latency { name, percentiles } => json!({
+                "name": name,
+                "unit": "s",
+                "summary": {"dataPoints": [{
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": percentiles.count.to_string(),
+                    "quantileValues": [
+                        {"quantile": 0.5, "value": percentiles.p50.as_secs_f64()},
+                        {"quantile": 0.95, "value": percentiles.p95.as_secs_f64()},
+                        {"quantile": 0.99, "value": percentiles.p99.as_secs_f64()},
+                        {"quantile": 0.999, "value": percentiles.p999.as_secs_f64()},
+                        {"quantile": 1.0, "value": percentiles.max.as_secs_f64()},
+                    ],
+                }]},
+            }),
+        })
+        .collect();
+    json!({"resourceMetrics": [{
+        "resource": resource(config),
+        "scopeMetrics": [{"scope": {"name": "kvs"}, "metrics": metrics}],
+    }]})
+}
+
+/// Posts `body` as JSON to `path` on the collector, failing unless it answers with a 2xx
+fn post(endpoint: SocketAddr, path: &str, body: &Value) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut stream = TcpStream::connect_timeout(&endpoint, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        endpoint,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(KvsError::IOError(format!(
+            "collector answered {}",
+            status_line.trim_end()
+        ))),
+    }
+}
+
+/// Starts recording spans and exporting them every `config.interval`, along with the metrics
+/// returned by `metrics`. Only the first call has an effect.
+pub fn start(config: OtelConfig, metrics: impl Fn() -> Vec<Metric> + Send + 'static) {
+    if PENDING_SPANS.set(Mutex::new(Vec::new())).is_err() {
+        return;
+    }
+    let config = Arc::new(config);
+    let started = SystemTime::now();
+    thread::spawn(move || loop {
+        thread::sleep(config.interval);
+        let spans = match PENDING_SPANS.get() {
+            Some(pending) => {
+                std::mem::take(&mut *pending.lock().unwrap_or_else(|e| e.into_inner()))
+            }
+            None => Vec::new(),
+        };
+        if !spans.is_empty() {
+            if let Err(e) = post(config.endpoint, "/v1/traces", &traces_body(&config, &spans)) {
+                warn!("Could not export {} spans: {:?}", spans.len(), e);
+            }
+        }
+        let body = metrics_body(&config, &metrics(), started);
+        if let Err(e) = post(config.endpoint, "/v1/metrics", &body) {
+            warn!("Could not export metrics: {:?}", e);
+        }
+    });
+}
@@ -0,0 +1,255 @@
+//! Errors of every part of the crate, carried in responses so that clients get the error the
+//! server ran into.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use core::error::Error;
+use core::fmt;
+use core::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::Feature;
+
+pub type Result<T> = core::result::Result<T, KvsError>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KvsError {
+    FileListEmpty,
+    WrongEngine,
+    SerializationError(String),
+    IOError(String),
+    NonExistantKey,
+    ThreadPoolBuildError(String),
+    /// The node isn't the cluster leader, carries the address of the leader if it is known
+    NotLeader(Option<SocketAddr>),
+    /// A cluster message was from an older term, carries the current term
+    StaleTerm(u64),
+    VoteDenied,
+    /// A write couldn't be replicated to a majority of the cluster
+    NoQuorum,
+    /// No protocol version is supported by both sides, carries the version of the other side
+    UnsupportedVersion(u32),
+    /// The other side of the connection didn't agree to use a feature
+    UnsupportedFeature(Feature),
+    /// A job run on a thread pool panicked before finishing
+    JobPanicked,
+    /// A key listing cursor that wasn't handed out by a server
+    InvalidCursor,
+    /// A conditional write wasn't applied because the key was in the wrong state
+    ConditionNotMet,
+    /// The session ended or was never opened on this server
+    UnknownSession,
+    /// A write would take the store past its disk limit
+    OutOfSpace,
+    /// A write would take the namespace it carries over its quota
+    QuotaExceeded(String),
+    /// The server doesn't account for namespaces
+    NamespacesDisabled,
+    /// The engine only serves reads, like a follower of a store written by another process
+    ReadOnly,
+    /// Changes a tail asked for are no longer kept, carries the seq of the oldest one kept
+    ChangesDropped(u64),
+    /// A read as of a time further back than the store keeps history for
+    HistoryUnavailable,
+    /// The engine has no merge operator to apply merges with
+    MergeUnsupported,
+    /// A value or merge operand that isn't of the expected type, like a counter that isn't a
+    /// number
+    InvalidValue,
+    /// A key the transaction read was written before it committed, the transaction can be retried
+    Conflict,
+    /// The transaction ended or was never begun on this server
+    UnknownTransaction,
+    /// A replica is further behind than the read allows, carries how many writes
+    ReplicaLagging(u64),
+    /// The engine keeps no change feed to take snapshots from or tail
+    ChangesUnsupported,
+    /// Opening the store was cancelled by its progress callback
+    OpenCancelled,
+    /// Opening the store found damage a crash can leave behind, which can be repaired without
+    /// losing acknowledged writes, like a torn record at the end of the active segment. Carries
+    /// what was found. Stores fix it themselves unless `KvStoreOptions::repair` is off.
+    RepairNeeded(String),
+    /// Opening the store found damage that can't be repaired without losing data, like a
+    /// manifest that fails its checksum or a segment it lists that is missing. Carries what was
+    /// found.
+    CorruptStore(String),
+    /// The store was written in a newer format than this build reads, carries that format
+    UnsupportedFormat(u32),
+    /// An IO or serialization error, along with what was being done when it happened
+    WithContext(ErrorContext, Box<KvsError>),
+    /// A thread panicked while holding a lock the request needed
+    Poisoned,
+    /// Settings that can't be used, alone or together, carries what is wrong with them
+    InvalidConfig(String),
+    /// The disk filled up during a write
+    DiskFull,
+    /// The store stopped taking writes after the disk filled up or writes kept failing,
+    /// carries the error that stopped it. Reads keep working, `kvs-admin resume` lets writes
+    /// through again once the disk is fixed.
+    Degraded(String),
+    /// A record the index points at didn't decode or held another key, like after bit rot,
+    /// carries what was wrong with it. Servers in a cluster repair it from another node.
+    CorruptRecord(String),
+    /// The engine can't write several keys as one
+    BatchUnsupported,
+    /// The engine can't list its keys in order
+    ScanUnsupported,
+    Other,
+}
+
+/// What was being done when an IO or serialization error happened
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorContext {
+    /// Like "read record"
+    pub operation: String,
+    /// Name of the file within the store directory
+    pub file: Option<String>,
+    pub offset: Option<u64>,
+    /// The key involved, as displayed
+    pub key: Option<String>,
+}
+
+impl ErrorContext {
+    pub fn new(operation: &str) -> ErrorContext {
+        ErrorContext {
+            operation: operation.to_owned(),
+            ..ErrorContext::default()
+        }
+    }
+
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn with_key(mut self, key: impl fmt::Display) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+}
+
+impl KvsError {
+    /// Whether the error is damage that opening the store again with repair on fixes
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, KvsError::RepairNeeded(_))
+    }
+
+    /// The error without the context around it
+    pub fn root(&self) -> &KvsError {
+        match self {
+            KvsError::WithContext(_, error) => error.root(),
+            error => error,
+        }
+    }
+
+    /// Wraps IO and serialization errors in `context`. Errors that have context already only
+    /// get the file, offset and key they were missing, the innermost operation is kept. Other
+    /// errors are about the request rather than the store and are left as they are.
+    pub fn with_context(self, context: impl FnOnce() -> ErrorContext) -> KvsError {
+        match self {
+            KvsError::IOError(_)
+            | KvsError::SerializationError(_)
+            | KvsError::DiskFull
+            | KvsError::CorruptRecord(_) => KvsError::WithContext(context(), Box::new(self)),
+            KvsError::WithContext(mut inner, error) => {
+                let outer = context();
+                inner.file = inner.file.or(outer.file);
+                inner.offset = inner.offset.or(outer.offset);
+                inner.key = inner.key.or(outer.key);
+                KvsError::WithContext(inner, error)
+            }
+            error => error,
+        }
+    }
+}
+
+// Messages are for people, the wire format is the serde derive above and doesn't depend on them
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::FileListEmpty => write!(f, "no log files"),
+            KvsError::WrongEngine => write!(f, "the directory was written by another engine"),
+            KvsError::SerializationError(e) => write!(f, "serialization error: {}", e),
+            KvsError::IOError(e) => write!(f, "IO error: {}", e),
+            KvsError::NonExistantKey => write!(f, "key not found"),
+            KvsError::ThreadPoolBuildError(e) => write!(f, "could not build thread pool: {}", e),
+            KvsError::NotLeader(Some(leader)) => write!(f, "not the leader, {} is", leader),
+            KvsError::NotLeader(None) => write!(f, "not the leader"),
+            KvsError::StaleTerm(term) => write!(f, "stale term, the current one is {}", term),
+            KvsError::VoteDenied => write!(f, "vote denied"),
+            KvsError::NoQuorum => write!(f, "write not replicated to a majority"),
+            KvsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            KvsError::UnsupportedFeature(feature) => {
+                write!(f, "protocol feature {:?} not agreed to", feature)
+            }
+            KvsError::JobPanicked => write!(f, "job panicked"),
+            KvsError::InvalidCursor => write!(f, "invalid cursor"),
+            KvsError::ConditionNotMet => write!(f, "condition not met"),
+            KvsError::UnknownSession => write!(f, "unknown session"),
+            KvsError::OutOfSpace => write!(f, "out of disk space"),
+            KvsError::QuotaExceeded(namespace) => {
+                write!(f, "quota of namespace {} exceeded", namespace)
+            }
+            KvsError::NamespacesDisabled => write!(f, "namespaces are disabled"),
+            KvsError::ReadOnly => write!(f, "read only"),
+            KvsError::ChangesDropped(oldest) => {
+                write!(f, "changes dropped, the oldest kept is {}", oldest)
+            }
+            KvsError::HistoryUnavailable => write!(f, "history unavailable"),
+            KvsError::MergeUnsupported => write!(f, "no merge operator"),
+            KvsError::InvalidValue => write!(f, "invalid value"),
+            KvsError::Conflict => write!(f, "transaction conflict"),
+            KvsError::UnknownTransaction => write!(f, "unknown transaction"),
+            KvsError::ReplicaLagging(lag) => write!(f, "replica {} writes behind", lag),
+            KvsError::ChangesUnsupported => write!(f, "no change feed"),
+            KvsError::OpenCancelled => write!(f, "open cancelled"),
+            KvsError::RepairNeeded(damage) => write!(f, "store needs repair: {}", damage),
+            KvsError::CorruptStore(damage) => write!(f, "store is corrupt: {}", damage),
+            KvsError::UnsupportedFormat(format) => write!(f, "unsupported store format {}", format),
+            KvsError::WithContext(context, _) => write!(f, "{}", context),
+            KvsError::Poisoned => write!(f, "a thread panicked holding a lock"),
+            KvsError::InvalidConfig(problem) => write!(f, "invalid configuration: {}", problem),
+            KvsError::DiskFull => write!(f, "disk full"),
+            KvsError::Degraded(error) => write!(f, "writes stopped after: {}", error),
+            KvsError::CorruptRecord(damage) => write!(f, "corrupt record: {}", damage),
+            KvsError::BatchUnsupported => write!(f, "no atomic batches"),
+            KvsError::ScanUnsupported => write!(f, "no key scans"),
+            KvsError::Other => write!(f, "unknown error"),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::WithContext(_, error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not {}", self.operation)?;
+        if let Some(key) = &self.key {
+            write!(f, " of key {}", key)?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
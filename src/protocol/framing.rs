@@ -0,0 +1,74 @@
+//! How messages are laid out on a connection after the handshake, see `frame` for reading and
+//! writing them.
+
+use serde::{Deserialize, Serialize};
+
+/// Bodies smaller than this are sent uncompressed
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Header byte of a frame with an uncompressed body
+pub const UNCOMPRESSED: u8 = 0;
+pub const LZ4: u8 = 1;
+pub const ZSTD: u8 = 2;
+
+/// Longest frame body, compressed or not, that is read or written. Longer ones fail with
+/// `KvsError::SerializationError` rather than having a peer make the reader allocate them.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// Length of the header in front of every frame body: the compression byte and the length of
+/// the body as a big endian u32
+pub const HEADER_LEN: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Header byte of frames compressed with this codec
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::Lz4 => LZ4,
+            Compression::Zstd => ZSTD,
+        }
+    }
+
+    /// Codec named by a header byte, none for uncompressed bodies
+    pub fn from_tag(tag: u8) -> Result<Option<Compression>, u8> {
+        match tag {
+            UNCOMPRESSED => Ok(None),
+            LZ4 => Ok(Some(Compression::Lz4)),
+            ZSTD => Ok(Some(Compression::Zstd)),
+            tag => Err(tag),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Msgpack,
+    Json,
+}
+
+/// How messages are sent on a connection after the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub encoding: Encoding,
+    pub compression: Option<Compression>,
+}
+
+/// Header of a frame whose body is `len` bytes, failing for bodies longer than `MAX_FRAME_LEN`
+pub fn frame_header(tag: u8, len: usize) -> Option<[u8; HEADER_LEN]> {
+    if len > MAX_FRAME_LEN {
+        return None;
+    }
+    let len = u32::try_from(len).ok()?.to_be_bytes();
+    Some([tag, len[0], len[1], len[2], len[3]])
+}
+
+/// Compression byte and body length of a frame header
+pub fn parse_frame_header(header: [u8; HEADER_LEN]) -> (u8, usize) {
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    (header[0], len as usize)
+}
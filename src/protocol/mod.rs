@@ -0,0 +1,570 @@
+//! Messages between clients and servers, the errors they carry and how they are framed.
+//!
+//! Nothing here touches files or sockets, only `core` and `alloc` are used, so that lightweight
+//! clients (embedded, WASM) can build on the wire types alone. The rest of the crate re-exports
+//! them where they were always found, like `KvsError` at the root or `Compression` in `frame`.
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::SocketAddr;
+use core::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+mod error;
+mod framing;
+
+pub use self::error::{ErrorContext, KvsError, Result};
+pub use self::framing::{
+    frame_header, parse_frame_header, Compression, Encoding, Framing, COMPRESSION_THRESHOLD,
+    HEADER_LEN, LZ4, MAX_FRAME_LEN, UNCOMPRESSED, ZSTD,
+};
+
+/// Version of the wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of the wire protocol this build can still negotiate. Version 1 is the plain
+/// JSON protocol spoken by clients that skip the handshake, or that ask for it in one.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional parts of the protocol, only used once both sides agreed to in the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Compression,
+    Batching,
+    Subscriptions,
+    /// Frame bodies are JSON instead of msgpack, for debugging
+    Json,
+}
+
+/// First message on a connection, answered with the negotiated `Result<Handshake>`. Both are
+/// always JSON, the messages after them are framed as the handshake says. Clients that skip it
+/// get version 1 without any optional features.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub features: Vec<Feature>,
+    /// Codecs in order of preference, only used with `Feature::Compression`
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+}
+
+impl Handshake {
+    pub fn new(features: Vec<Feature>) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            features,
+            compression: Vec::new(),
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Vec<Compression>) -> Handshake {
+        self.compression = compression;
+        self
+    }
+
+    /// Picks the highest version and the features supported by both this side and `other`,
+    /// along with the codec this side prefers most out of those both support. Version 1 has no
+    /// optional features.
+    pub fn negotiate(&self, other: &Handshake) -> Result<Handshake> {
+        let version = self.version.min(other.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(KvsError::UnsupportedVersion(other.version));
+        }
+        if version == 1 {
+            return Ok(Handshake {
+                version,
+                features: Vec::new(),
+                compression: Vec::new(),
+            });
+        }
+        let compression: Vec<Compression> = self
+            .compression
+            .iter()
+            .filter(|codec| other.compression.contains(codec))
+            .take(1)
+            .copied()
+            .collect();
+        Ok(Handshake {
+            version,
+            features: self
+                .features
+                .iter()
+                .filter(|feature| other.features.contains(feature))
+                .filter(|feature| **feature != Feature::Compression || !compression.is_empty())
+                .copied()
+                .collect(),
+            compression,
+        })
+    }
+
+    /// Codec messages are compressed with after this handshake, if any
+    pub fn compression_codec(&self) -> Option<Compression> {
+        if self.supports(Feature::Compression) {
+            self.compression.first().copied()
+        } else {
+            None
+        }
+    }
+
+    /// How messages are framed after this handshake, none for the plain JSON of version 1
+    pub fn framing(&self) -> Option<Framing> {
+        if self.version < 2 {
+            return None;
+        }
+        Some(Framing {
+            encoding: if self.supports(Feature::Json) {
+                Encoding::Json
+            } else {
+                Encoding::Msgpack
+            },
+            compression: self.compression_codec(),
+        })
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KvRequest<K, V> {
+    Handshake(Handshake),
+    Set((K, V)),
+    Rm(K),
+    Get(K),
+    /// Set that removes the key once the ttl runs out
+    SetEx((K, V, Duration)),
+    /// Set only applied if the key is in the expected state, failing with
+    /// `KvsError::ConditionNotMet` otherwise
+    SetIf((K, V, SetCondition)),
+    /// Takes the lock named by the key for the lease, answered with its fencing token. Fails
+    /// with `KvsError::ConditionNotMet` while someone else holds it.
+    Lock((K, Duration)),
+    /// Releases the lock named by the key if it is still held with the fencing token, failing
+    /// with `KvsError::ConditionNotMet` otherwise
+    Unlock((K, u64)),
+    /// Opens a session ending once it goes without a heartbeat for the timeout, answered
+    /// with its id
+    OpenSession(Duration),
+    /// Keeps the session alive
+    Heartbeat(u64),
+    /// Ends the session, removing its ephemeral keys
+    CloseSession(u64),
+    /// Set of a key removed once the session ends, unless it is set or removed before
+    SetEphemeral((K, V, u64)),
+    /// Merges the operand into the value of the key with the merge operator of the engine,
+    /// see `values::ValueOp` for the operands servers understand
+    Merge((K, V)),
+    /// Merge answered with the value the key had right before, like the item a pop took
+    FetchMerge((K, V)),
+    /// Begins an optimistic transaction, answered with its id
+    Begin,
+    /// Get within the transaction, seeing the writes it buffered
+    TxnGet((u64, K)),
+    /// Set or remove buffered until the transaction commits
+    TxnWrite {
+        txn: u64,
+        request: Box<KvRequest<K, V>>,
+    },
+    /// First phase of a commit spanning servers: checks the transaction like a commit would
+    /// and persists its writes, so that the following commit can't fail on a conflict
+    Prepare(u64),
+    /// Applies the writes of the transaction, failing with `KvsError::Conflict` if a key it
+    /// read was written since
+    Commit(u64),
+    /// Ends the transaction without applying its writes
+    Abort(u64),
+    /// Sets the quota of a namespace, on servers accounting for namespaces
+    SetQuota {
+        namespace: String,
+        quota: NamespaceQuota,
+    },
+    /// Subscribes to changes to a key, or to every key if not given. The server answers with
+    /// a response and then keeps sending `WatchEvent`s on the connection.
+    Watch(Option<K>),
+    /// Election and heartbeat traffic between cluster members
+    Cluster(ClusterMessage),
+    /// Write forwarded by the leader of `term` to its followers
+    Replicate {
+        term: u64,
+        leader: u64,
+        /// Number of the write, counting up within the term
+        #[serde(default)]
+        seq: u64,
+        request: Box<KvRequest<K, V>>,
+    },
+    /// Get answered by a follower only if it is at most `max_lag` writes behind the leader,
+    /// failing with `KvsError::ReplicaLagging` otherwise
+    ReplicaGet {
+        key: K,
+        max_lag: u64,
+    },
+    /// Write tagged with a token unique to it, so that the server answers retries of it with
+    /// the result of the first attempt instead of applying it again
+    Idempotent {
+        token: u64,
+        request: Box<KvRequest<K, V>>,
+    },
+    /// Asks for the request latencies of the server, answered with a `ServerStats` as JSON
+    Stats,
+    /// Asks for up to `limit` keys in ascending order, continuing from the `next` cursor of
+    /// the previous page or starting at the first key. Answered with a `KeysPage` as JSON.
+    Keys {
+        cursor: Option<String>,
+        limit: u32,
+    },
+    /// Asks for the `limit` keys the server was asked for most often of late, answered with
+    /// `HotKey`s as JSON, most requested first
+    TopKeys {
+        limit: u32,
+    },
+    /// Asks for the hashes of `nodes` at `level` of the server's `merkle::MerkleTree`,
+    /// answered with them in the same order as JSON
+    MerkleHashes {
+        level: u32,
+        nodes: Vec<u64>,
+    },
+    /// Asks for the keys and values under a leaf of the server's `merkle::MerkleTree`,
+    /// answered with them as JSON
+    MerkleLeaf(u64),
+    /// Asks for every key and value of the store, followed by every change made after them,
+    /// for bootstrapping a replica. Answered like `Watch`, then with `SnapshotMessage`s.
+    Snapshot,
+    /// Asks which node of the cluster takes writes and which serve reads, answered with a
+    /// `Topology` as JSON
+    Topology,
+    /// Changes the level of the messages the server logs, one of off, error, warn, info, debug
+    /// or trace, answered with the level it logged at before
+    SetLogLevel(String),
+    /// Lets writes through again after the store stopped taking them, answered with the error
+    /// that stopped them or nothing if they weren't
+    ResumeWrites,
+    /// Reads back everything the store keeps on disk to find damage done to it since it was
+    /// written, rewriting the damaged keys with their value from another node of the cluster if
+    /// `repair` is set. Answered with a `ScrubReport` as JSON, or nothing if the engine can't
+    /// tell.
+    Scrub {
+        repair: bool,
+    },
+    /// Get answered with the value along with its `ValueMeta` as JSON, or nothing if the key
+    /// has no value. The meta is null for engines that don't keep track of it.
+    GetWithMeta(K),
+    /// Set or remove only applied if the key is at `expected_version`, the seq of the write of
+    /// its value in `ValueMeta`, or only if it has no value when none is expected, like HTTP
+    /// `If-Match` and `If-None-Match`. Fails with `KvsError::ConditionNotMet` otherwise.
+    /// Versions are those of the node answering, a new cluster leader has versions of its own.
+    IfVersion {
+        expected_version: Option<u64>,
+        request: Box<KvRequest<K, V>>,
+    },
+    /// Several requests answered in one round trip, with a `KvResponse` for each of them in
+    /// order as JSON. With `atomic`, only gets, sets and removes can be batched: gets see the
+    /// writes before them, the writes are applied as one `WriteBatch` once every request was
+    /// answered, and nothing else is written meanwhile. The batch fails as a whole instead of
+    /// answering them if any of them fails.
+    Batch {
+        requests: Vec<KvRequest<K, V>>,
+        atomic: bool,
+    },
+    /// Sets of the keys with a value and removes of those without, written by the engine as
+    /// one, see `KvsEngine::write_batch`. Servers apply and replicate atomic batches as one.
+    WriteBatch(Vec<(K, Option<V>)>),
+}
+
+/// Nodes of a cluster, as seen by the node answering `KvRequest::Topology`. A server on its
+/// own is its own primary.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Node taking writes, none while there is no leader
+    pub primary: Option<SocketAddr>,
+    pub replicas: Vec<SocketAddr>,
+}
+
+/// Message streamed in answer to `KvRequest::Snapshot`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SnapshotMessage<K, V> {
+    /// Keys and values of the snapshot, a batch at a time
+    Entries(Vec<(K, V)>),
+    /// Every entry was sent, changes made from `seq` on follow
+    Complete {
+        seq: u64,
+    },
+    Change(ChangeEvent<K, V>),
+    /// The stream ends, like when the replica fell further behind than the store keeps
+    /// changes for
+    Failed(KvsError),
+}
+
+/// Page of keys answering `KvRequest::Keys`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeysPage<K> {
+    pub keys: Vec<K>,
+    /// Opaque cursor to ask for the next page with, none once every key has been listed
+    pub next: Option<String>,
+    /// Whether writes were applied since the first page was listed. Keys present the whole
+    /// time are listed exactly once either way, others may or may not show up.
+    pub stale: bool,
+}
+
+/// Position in a key listing, handed to clients as an opaque token so that servers don't
+/// keep any state between pages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeysCursor<K> {
+    /// Last key listed so far
+    pub after: K,
+    /// Count of writes the server had applied when the first page was listed
+    pub seq: u64,
+}
+
+impl<K: Serialize + DeserializeOwned> KeysCursor<K> {
+    /// Token standing for this cursor, hex encoded JSON
+    pub fn encode(&self) -> Result<String> {
+        let json = serde_json::to_vec(self)?;
+        Ok(json.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    pub fn decode(token: &str) -> Result<KeysCursor<K>> {
+        if !token.len().is_multiple_of(2) {
+            return Err(KvsError::InvalidCursor);
+        }
+        let json = (0..token.len())
+            .step_by(2)
+            .map(|i| {
+                token
+                    .get(i..i + 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(KvsError::InvalidCursor)
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        serde_json::from_slice(&json).map_err(|_| KvsError::InvalidCursor)
+    }
+}
+
+/// Latency percentiles of the requests a server answered, by kind of request
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub get: Percentiles,
+    pub set: Percentiles,
+    pub remove: Percentiles,
+    pub other: Percentiles,
+    /// Bytes the engine takes up on disk, if it can tell
+    #[serde(default)]
+    pub disk_bytes: Option<u64>,
+    /// Usage and quota by namespace, on servers accounting for namespaces
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, NamespaceStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClusterMessage {
+    RequestVote {
+        term: u64,
+        candidate: u64,
+        /// Term and seq of the last write the candidate applied, see `Heartbeat::seq`
+        #[serde(default)]
+        applied_term: u64,
+        #[serde(default)]
+        applied_seq: u64,
+    },
+    Heartbeat {
+        term: u64,
+        leader: u64,
+        /// Seq of the latest write of the leader
+        #[serde(default)]
+        seq: u64,
+    },
+}
+
+impl<K, V> KvRequest<K, V> {
+    /// Whether this changes the store
+    pub fn is_write(&self) -> bool {
+        match self {
+            KvRequest::Set(_)
+            | KvRequest::SetEx(_)
+            | KvRequest::SetIf(_)
+            | KvRequest::Rm(_)
+            | KvRequest::Merge(_)
+            | KvRequest::FetchMerge(_)
+            | KvRequest::Lock(_)
+            | KvRequest::Unlock(_)
+            | KvRequest::SetEphemeral(_)
+            | KvRequest::WriteBatch(_) => true,
+            KvRequest::Idempotent { request, .. } | KvRequest::IfVersion { request, .. } => {
+                request.is_write()
+            }
+            _ => false,
+        }
+    }
+
+    /// Key this request reads or changes, if it is about a single key
+    pub fn key(&self) -> Option<&K> {
+        match self {
+            KvRequest::Set((key, _))
+            | KvRequest::SetEx((key, _, _))
+            | KvRequest::SetIf((key, _, _))
+            | KvRequest::Merge((key, _))
+            | KvRequest::FetchMerge((key, _))
+            | KvRequest::Lock((key, _))
+            | KvRequest::Unlock((key, _))
+            | KvRequest::SetEphemeral((key, _, _))
+            | KvRequest::Rm(key)
+            | KvRequest::Get(key)
+            | KvRequest::GetWithMeta(key)
+            | KvRequest::TxnGet((_, key))
+            | KvRequest::ReplicaGet { key, .. } => Some(key),
+            KvRequest::Watch(key) => key.as_ref(),
+            KvRequest::Idempotent { request, .. }
+            | KvRequest::IfVersion { request, .. }
+            | KvRequest::Replicate { request, .. }
+            | KvRequest::TxnWrite { request, .. } => request.key(),
+            KvRequest::Handshake(_)
+            | KvRequest::Cluster(_)
+            | KvRequest::Stats
+            | KvRequest::Keys { .. }
+            | KvRequest::TopKeys { .. }
+            | KvRequest::MerkleHashes { .. }
+            | KvRequest::MerkleLeaf(_)
+            | KvRequest::Snapshot
+            | KvRequest::Topology
+            | KvRequest::SetLogLevel(_)
+            | KvRequest::ResumeWrites
+            | KvRequest::Scrub { .. }
+            | KvRequest::OpenSession(_)
+            | KvRequest::Heartbeat(_)
+            | KvRequest::CloseSession(_)
+            | KvRequest::Begin
+            | KvRequest::Prepare(_)
+            | KvRequest::Commit(_)
+            | KvRequest::Abort(_)
+            | KvRequest::SetQuota { .. }
+            | KvRequest::Batch { .. }
+            | KvRequest::WriteBatch(_) => None,
+        }
+    }
+
+    /// Keys this request reads or changes, like `key` but with every key of a `WriteBatch`
+    pub fn touched_keys(&self) -> Vec<&K> {
+        match self {
+            KvRequest::WriteBatch(writes) => writes.iter().map(|(key, _)| key).collect(),
+            KvRequest::Idempotent { request, .. } | KvRequest::Replicate { request, .. } => {
+                request.touched_keys()
+            }
+            request => request.key().into_iter().collect(),
+        }
+    }
+
+    /// Idempotency token of this request or of the write it carries
+    pub fn idempotency_token(&self) -> Option<u64> {
+        match self {
+            KvRequest::Idempotent { token, .. } => Some(*token),
+            KvRequest::Replicate { request, .. } => request.idempotency_token(),
+            _ => None,
+        }
+    }
+
+    /// This request without the version checks in it, for replicas to apply a write the
+    /// leader checked against versions of its own
+    pub fn without_version_check(self) -> KvRequest<K, V> {
+        match self {
+            KvRequest::IfVersion { request, .. } => request.without_version_check(),
+            KvRequest::Idempotent { token, request } => KvRequest::Idempotent {
+                token,
+                request: Box::new(request.without_version_check()),
+            },
+            request => request,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KvResponse<V> {
+    pub value: Result<Option<V>>,
+}
+
+/// What a conditional set expects of the key it sets
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// The key has no value
+    Absent,
+    /// The key has a value
+    Present,
+}
+
+/// What the engine knows of the last write of a value, answering `KvRequest::GetWithMeta`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// Number of the write, counting up across the whole store
+    pub seq: u64,
+    /// Milliseconds since the unix epoch
+    pub written_at: u64,
+    /// Bytes of the value serialized
+    pub size: u64,
+}
+
+/// Write made to the store
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change<K, V> {
+    Set((K, V)),
+    Removed(K),
+    /// Operand merged into the value of the key, see `KvsEngine::merge`
+    Merged((K, V)),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent<K, V> {
+    pub seq: u64,
+    pub change: Change<K, V>,
+}
+
+/// Limits of a namespace, unset ones don't apply
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceQuota {
+    pub max_keys: Option<u64>,
+    /// Limit on the bytes of the keys and values of the namespace
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub keys: u64,
+    /// Bytes of the keys and values of the namespace
+    pub bytes: u64,
+    pub quota: NamespaceQuota,
+}
+
+/// Key a server was asked for often, with about how many times it was lately. Counts are
+/// estimates that can only be too high, and halve every so often so that keys that cooled off
+/// drop out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HotKey {
+    pub key: String,
+    pub count: u64,
+}
+
+/// Latency percentiles at a point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ops, p50 {:?}, p95 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            self.count, self.p50, self.p95, self.p99, self.p999, self.max
+        )
+    }
+}
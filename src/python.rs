@@ -0,0 +1,207 @@
+//! Python module `kvs`, enabled by the `python` feature. It opens the same handles as the C API,
+//! whose classes behave like dicts of strings:
+//!
+//! ```python
+//! import kvs
+//!
+//! with kvs.KvStore("./db") as store:
+//!     store["key"] = "value"
+//!     print(store["key"], "key" in store)
+//!     del store["key"]
+//! ```
+//!
+//! Calls release the GIL while they wait on the disk or the network.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock};
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::client::KvsClient;
+use crate::engine::sled::SledKvsEngine;
+use crate::engine::store::KvStore;
+use crate::ffi::{Engine, KvsHandle};
+use crate::Result;
+
+create_exception!(
+    kvs,
+    KvsError,
+    PyException,
+    "Raised when a call to a store or a server fails"
+);
+
+fn error(err: crate::KvsError) -> PyErr {
+    KvsError::new_err(err.to_string())
+}
+
+/// Dict-like access to a store or a server, the base class of `KvStore`, `SledKvsEngine` and
+/// `KvsClient`
+#[pyclass(subclass, frozen, module = "kvs")]
+pub struct Handle {
+    // None once closed
+    handle: RwLock<Option<KvsHandle>>,
+}
+
+impl Handle {
+    fn open(engine: Result<Engine>) -> PyResult<Handle> {
+        Ok(Handle {
+            handle: RwLock::new(Some(KvsHandle::new(engine.map_err(error)?))),
+        })
+    }
+
+    /// Runs `call` on the handle with the GIL released, failing if it's closed
+    fn call<T: Send>(
+        &self,
+        py: Python<'_>,
+        call: impl FnOnce(&KvsHandle) -> Result<T> + Send,
+    ) -> PyResult<Result<T>> {
+        py.detach(|| {
+            let handle = self.handle.read().unwrap_or_else(PoisonError::into_inner);
+            match &*handle {
+                Some(handle) => Ok(call(handle)),
+                None => Err(PyValueError::new_err("call on a closed handle")),
+            }
+        })
+    }
+}
+
+#[pymethods]
+impl Handle {
+    fn __setitem__(&self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        self.call(py, |handle| handle.set(key, value))?
+            .map_err(error)
+    }
+
+    fn __getitem__(&self, py: Python<'_>, key: String) -> PyResult<String> {
+        self.call(py, |handle| handle.get(key.clone()))?
+            .map_err(error)?
+            .ok_or_else(|| PyKeyError::new_err(key))
+    }
+
+    fn __delitem__(&self, py: Python<'_>, key: String) -> PyResult<()> {
+        match self.call(py, |handle| handle.remove(key.clone()))? {
+            Ok(()) => Ok(()),
+            Err(e) if matches!(e.root(), crate::KvsError::NonExistantKey) => {
+                Err(PyKeyError::new_err(key))
+            }
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: String) -> PyResult<bool> {
+        let value = self.call(py, |handle| handle.get(key))?.map_err(error)?;
+        Ok(value.is_some())
+    }
+
+    /// The value of `key`, or `default` if it has none
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, py: Python<'_>, key: String, default: Option<Py<PyAny>>) -> PyResult<Py<PyAny>> {
+        let value = self.call(py, |handle| handle.get(key))?.map_err(error)?;
+        Ok(match value {
+            Some(value) => value.into_pyobject(py)?.into_any().unbind(),
+            None => default.unwrap_or_else(|| py.None()),
+        })
+    }
+
+    /// Flushes and closes the store or connection, closing it again does nothing
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| {
+            let handle = self
+                .handle
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            match handle {
+                Some(handle) => handle.close(),
+                None => Ok(()),
+            }
+        })
+        .map_err(error)
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Py<PyAny>,
+        _exc: Py<PyAny>,
+        _traceback: Py<PyAny>,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let handle = self
+            .handle
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(handle) = handle {
+            // Nothing to raise a failed flush to once Python let go of the handle
+            let _ = handle.close();
+        }
+    }
+}
+
+/// The log-structured store in the directory `path`, created if needed
+#[pyclass(name = "KvStore", extends = Handle, frozen, module = "kvs")]
+pub struct PyKvStore;
+
+#[pymethods]
+impl PyKvStore {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<(Self, Handle)> {
+        let handle = Handle::open(KvStore::open(&path).map(Engine::Kvs))?;
+        Ok((PyKvStore, handle))
+    }
+}
+
+/// A sled database in the directory `path`
+#[pyclass(name = "SledKvsEngine", extends = Handle, frozen, module = "kvs")]
+pub struct PySledKvsEngine;
+
+#[pymethods]
+impl PySledKvsEngine {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<(Self, Handle)> {
+        let handle = Handle::open(SledKvsEngine::new(&path).map(Engine::Sled))?;
+        Ok((PySledKvsEngine, handle))
+    }
+}
+
+/// Connection to the server at `addr`, given as `<ip>:<port>`. Connections are made per call.
+#[pyclass(name = "KvsClient", extends = Handle, frozen, module = "kvs")]
+pub struct PyKvsClient;
+
+#[pymethods]
+impl PyKvsClient {
+    #[new]
+    #[pyo3(signature = (addr = "127.0.0.1:4000"))]
+    fn new(addr: &str) -> PyResult<(Self, Handle)> {
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| PyValueError::new_err(format!("invalid address {:?}", addr)))?;
+        let handle = Handle::open(Ok(Engine::Client(KvsClient::new(addr))))?;
+        Ok((PyKvsClient, handle))
+    }
+}
+
+/// Entry point of the module, called when Python imports `kvs`
+#[pymodule]
+pub fn kvs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Handle>()?;
+    module.add_class::<PyKvStore>()?;
+    module.add_class::<PySledKvsEngine>()?;
+    module.add_class::<PyKvsClient>()?;
+    module.add("KvsError", module.py().get_type::<KvsError>())?;
+    Ok(())
+}
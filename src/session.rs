@@ -0,0 +1,138 @@
+//! Client sessions kept alive by heartbeats, along with the ephemeral keys removed once their
+//! session ends.
+//!
+//! Sessions are only kept in memory by the server that opened them. Ephemeral keys outlive a
+//! restart of the server, as nothing is left to remove them.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{KvsError, Result};
+
+struct Session<K> {
+    timeout: Duration,
+    deadline: SystemTime,
+    keys: HashSet<K>,
+}
+
+struct State<K> {
+    last_id: u64,
+    sessions: HashMap<u64, Session<K>>,
+    // Session owning each ephemeral key
+    owners: HashMap<K, u64>,
+}
+
+pub struct Sessions<K> {
+    state: Mutex<State<K>>,
+}
+
+impl<K> Default for Sessions<K> {
+    fn default() -> Self {
+        Sessions {
+            state: Mutex::new(State {
+                last_id: 0,
+                sessions: HashMap::new(),
+                owners: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Sessions<K> {
+    /// Opens a session ending `timeout` after the last heartbeat, returning its id. Ids count up
+    /// from the clock in microseconds, so a restarted server doesn't reuse the ids of sessions it
+    /// lost.
+    pub fn open(&self, timeout: Duration) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        let clock = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let id = clock.max(state.last_id + 1);
+        state.last_id = id;
+        state.sessions.insert(
+            id,
+            Session {
+                timeout,
+                deadline: now + timeout,
+                keys: HashSet::new(),
+            },
+        );
+        id
+    }
+
+    /// Pushes back the end of session `id`
+    pub fn heartbeat(&self, id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let session = state
+            .sessions
+            .get_mut(&id)
+            .ok_or(KvsError::UnknownSession)?;
+        session.deadline = SystemTime::now() + session.timeout;
+        Ok(())
+    }
+
+    /// Makes `key` ephemeral to session `id`, taking it from any session it belonged to
+    pub fn attach(&self, id: u64, key: K) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let session = state
+            .sessions
+            .get_mut(&id)
+            .ok_or(KvsError::UnknownSession)?;
+        session.keys.insert(key.clone());
+        if let Some(previous) = state.owners.insert(key.clone(), id) {
+            if previous != id {
+                if let Some(session) = state.sessions.get_mut(&previous) {
+                    session.keys.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether session `id` is open
+    pub fn is_open(&self, id: u64) -> bool {
+        self.state.lock().unwrap().sessions.contains_key(&id)
+    }
+
+    /// Makes `key` outlive its session, after it was overwritten or removed
+    pub fn detach(&self, key: &K) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(id) = state.owners.remove(key) {
+            if let Some(session) = state.sessions.get_mut(&id) {
+                session.keys.remove(key);
+            }
+        }
+    }
+
+    /// Ends session `id`, returning the ephemeral keys to remove
+    pub fn close(&self, id: u64) -> Result<Vec<K>> {
+        let mut state = self.state.lock().unwrap();
+        let session = state.sessions.remove(&id).ok_or(KvsError::UnknownSession)?;
+        for key in &session.keys {
+            state.owners.remove(key);
+        }
+        Ok(session.keys.into_iter().collect())
+    }
+
+    /// Ends the sessions that went without a heartbeat for their timeout as of `now`, returning
+    /// their ephemeral keys to remove
+    pub fn take_expired(&self, now: SystemTime) -> Vec<K> {
+        let expired: Vec<u64> = self
+            .state
+            .lock()
+            .unwrap()
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired
+            .into_iter()
+            .flat_map(|id| self.close(id).unwrap_or_default())
+            .collect()
+    }
+}
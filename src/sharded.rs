@@ -0,0 +1,555 @@
+//! Client spreading keys over servers that don't know about each other, each owning the keys
+//! consistent hashing maps to it.
+//!
+//! Every server is placed on a ring at several points hashed from its address, and a key
+//! belongs to the first server found going around the ring from the hash of the key. Adding or
+//! removing a server only moves the keys next to its points.
+//!
+//! Transactions writing to several servers are committed in two phases: every server prepares
+//! its part first, persisting the writes in its own log, and only once all of them did is any
+//! committed. A coordinator failing between the phases leaves prepared transactions behind,
+//! which hold on to their keys until committed or aborted by id.
+//!
+//! Keys can also be kept on several servers, the ones found next going around the ring, and
+//! written and read through quorums: a quorum write succeeds once W of them have the value and a
+//! quorum read takes the latest value R of them have. Values written this way are stamped with
+//! the time they were written, and the one stamped last wins. Removes leave a stamped tombstone
+//! behind, so that an older value on another server doesn't win over them.
+//!
+//! Replicas that missed quorum writes are brought up to date by `repair`, which compares the
+//! Merkle trees of two of them to find the keys they differ on and copies the latest value over.
+//!
+//! Servers are added with `add_shard` while the client is in use. The keys moving to the new
+//! server are copied over from a scan of every other server, catching up on the writes made in
+//! the meantime through a subscription to their changes. Routing is then switched over at once,
+//! while requests through the client wait, and the moved keys are removed from where they were.
+//! Clients made with `discover` add the servers a DNS name comes to resolve to this way.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::client::{random_u64, KvsClient, Transaction};
+use crate::discovery::Discovery;
+use crate::merkle;
+use crate::txn::PREPARED_PREFIX;
+use crate::watch::{ExpiredEvent, WatchEvent};
+use crate::{stable_hash, KvsError, Result};
+
+/// Points every server takes on the ring, more of them spread keys more evenly
+const VIRTUAL_NODES: usize = 64;
+/// Prefix of values written by quorum writes, followed by a `Stamped` as JSON
+const STAMPED_PREFIX: &str = "lww:";
+/// Prefix of the keys set on a server to tell when every change before them was seen while
+/// adding a shard, followed by a random id
+const REBALANCE_PREFIX: &str = "__kvs_rebalance/";
+
+/// Clones share their routing, so shards added through one are used by all of them
+#[derive(Clone)]
+pub struct ShardedClient {
+    routing: Arc<RwLock<Routing>>,
+    // Shards every key is kept on by quorum writes
+    replication: usize,
+}
+
+struct Routing {
+    shards: Vec<KvsClient>,
+    // Shard at each point of the ring
+    ring: BTreeMap<u64, usize>,
+}
+
+impl Routing {
+    fn new(shards: Vec<KvsClient>) -> Routing {
+        let mut ring = BTreeMap::new();
+        for (index, shard) in shards.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                // Placed by the address given, so that failing over doesn't move keys around
+                let point = stable_hash(format!("{}#{}", shard.given_addr(), node).as_bytes());
+                ring.insert(point, index);
+            }
+        }
+        Routing { shards, ring }
+    }
+
+    /// Distinct shards found going around the ring from the hash of `key`, up to `count`
+    fn replicas(&self, key: &str, count: usize) -> Vec<usize> {
+        let hash = stable_hash(key.as_bytes());
+        let mut replicas = Vec::new();
+        for (_, shard) in self.ring.range(hash..).chain(self.ring.iter()) {
+            if replicas.len() == count.min(self.shards.len()) {
+                break;
+            }
+            if !replicas.contains(shard) {
+                replicas.push(*shard);
+            }
+        }
+        replicas
+    }
+
+    fn owner(&self, key: &str) -> Result<usize> {
+        self.replicas(key, 1).pop().ok_or(KvsError::Other)
+    }
+}
+
+/// Value of a quorum write with the time it was written, none for a remove
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Stamped {
+    // Microseconds since the epoch
+    time: u64,
+    // Picked at random by the writer, breaks ties between writes in the same microsecond
+    writer: u64,
+    value: Option<String>,
+}
+
+impl Stamped {
+    fn now(value: Option<String>) -> Stamped {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        Stamped {
+            time,
+            writer: random_u64(),
+            value,
+        }
+    }
+
+    fn encode(&self) -> Result<String> {
+        Ok(format!(
+            "{}{}",
+            STAMPED_PREFIX,
+            serde_json::to_string(self)?
+        ))
+    }
+
+    fn decode(value: &str) -> Result<Stamped> {
+        let stamped = value
+            .strip_prefix(STAMPED_PREFIX)
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(serde_json::from_str(stamped)?)
+    }
+}
+
+impl ShardedClient {
+    /// Client for the servers `shards` talk to. Every client of the same servers has to be
+    /// given them with the same addresses to agree on where keys belong.
+    pub fn new(shards: Vec<KvsClient>) -> ShardedClient {
+        ShardedClient {
+            routing: Arc::new(RwLock::new(Routing::new(shards))),
+            replication: 1,
+        }
+    }
+
+    /// Client for the servers `name` resolves to, see `Discovery`. A thread resolves the name
+    /// again every `refresh_interval` for as long as the client or a clone of it is around, and
+    /// adds the servers it finds with `add_shard`. Servers the name no longer resolves to are
+    /// kept, their keys can't be moved anywhere without them.
+    pub fn discover(name: &str, refresh_interval: Duration) -> Result<ShardedClient> {
+        let discovery = Discovery::new(name, refresh_interval)?;
+        let shards = discovery.addrs().into_iter().map(KvsClient::new).collect();
+        let client = ShardedClient::new(shards);
+        let routing = Arc::downgrade(&client.routing);
+        thread::spawn(move || follow_discovery(discovery, routing));
+        Ok(client)
+    }
+
+    /// Keeps every key written by quorum writes on `replication` shards, or on all of them if
+    /// there are fewer
+    pub fn with_replication(mut self, replication: usize) -> ShardedClient {
+        self.replication = replication.max(1);
+        self
+    }
+
+    /// Indexes of the shards keeping `key` for quorum writes, the owner of the key first
+    pub fn replicas_of(&self, key: &str) -> Vec<usize> {
+        self.routing.read().unwrap().replicas(key, self.replication)
+    }
+
+    /// Index of the shard owning `key`
+    pub fn shard_of(&self, key: &str) -> Result<usize> {
+        self.routing.read().unwrap().owner(key)
+    }
+
+    /// Client of the shard owning `key`
+    pub fn shard(&self, key: &str) -> Result<KvsClient> {
+        let routing = self.routing.read().unwrap();
+        Ok(routing.shards[routing.owner(key)?].clone())
+    }
+
+    pub fn shards(&self) -> Vec<KvsClient> {
+        self.routing.read().unwrap().shards.clone()
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_owner(&key, |shard| shard.set(key.clone(), value))
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_owner(&key, |shard| shard.get(key.clone()))
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_owner(&key, |shard| shard.remove(key.clone()))
+    }
+
+    /// Runs `op` on the shard owning `key`, holding on to the routing so that it isn't switched
+    /// over by `add_shard` in the middle
+    fn with_owner<T>(&self, key: &str, op: impl FnOnce(&KvsClient) -> Result<T>) -> Result<T> {
+        let routing = self.routing.read().unwrap();
+        op(&routing.shards[routing.owner(key)?])
+    }
+
+    /// Adds `shard` to the shards keys are spread over, moving the keys that now belong to it
+    /// over while the client stays in use. Returns how many keys were moved. Every server has to
+    /// support subscriptions. On failure the routing is left as it was, along with whatever keys
+    /// were already copied to `shard`.
+    pub fn add_shard(&self, shard: KvsClient) -> Result<usize> {
+        let (sources, next) = {
+            let routing = self.routing.read().unwrap();
+            let mut shards = routing.shards.clone();
+            shards.push(shard.clone());
+            (routing.shards.clone(), Routing::new(shards))
+        };
+        let index = sources.len();
+        let moving = |key: &str| {
+            !key.starts_with(PREPARED_PREFIX)
+                && !key.starts_with(REBALANCE_PREFIX)
+                && next.owner(key).ok() == Some(index)
+        };
+        // Subscribing before the scan means no write made during it goes unseen
+        let mut migrations = sources
+            .into_iter()
+            .map(Migration::start)
+            .collect::<Result<Vec<_>>>()?;
+        for migration in &mut migrations {
+            for key in migration.source.keys() {
+                let key = key?;
+                if !moving(&key) {
+                    continue;
+                }
+                if let Some(value) = migration.source.get(key.clone())? {
+                    shard.set(key.clone(), value)?;
+                    migration.moved.insert(key);
+                }
+            }
+            migration.catch_up(&shard, moving, None)?;
+        }
+        {
+            let mut routing = self.routing.write().unwrap();
+            for migration in &mut migrations {
+                migration.finish(&shard, moving)?;
+            }
+            *routing = next;
+        }
+        let mut moved = 0;
+        for migration in migrations {
+            moved += migration.moved.len();
+            for key in migration.moved {
+                match migration.source.remove(key) {
+                    Ok(()) | Err(KvsError::NonExistantKey) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Sets `key` on every shard keeping it, succeeding once `w` of them did. Fails with
+    /// `KvsError::NoQuorum` if fewer could, leaving the value on those that did.
+    pub fn quorum_set(&self, key: String, value: String, w: usize) -> Result<()> {
+        self.quorum_write(key, Stamped::now(Some(value)), w)
+    }
+
+    /// Removes `key` from every shard keeping it like `quorum_set` sets it
+    pub fn quorum_remove(&self, key: String, w: usize) -> Result<()> {
+        self.quorum_write(key, Stamped::now(None), w)
+    }
+
+    /// Gets `key` from the shards keeping it, returning the value written last of the first `r`
+    /// to answer. Fails with `KvsError::NoQuorum` if fewer could.
+    pub fn quorum_get(&self, key: String, r: usize) -> Result<Option<String>> {
+        let responses = self.quorum(&key, r, move |shard, key| match shard.get(key)? {
+            Some(value) => Stamped::decode(&value).map(Some),
+            None => Ok(None),
+        })?;
+        let latest = responses
+            .into_iter()
+            .flatten()
+            .max_by_key(|stamped| (stamped.time, stamped.writer));
+        Ok(latest.and_then(|stamped| stamped.value))
+    }
+
+    fn quorum_write(&self, key: String, stamped: Stamped, w: usize) -> Result<()> {
+        let value = stamped.encode()?;
+        self.quorum(&key, w, move |shard, key| shard.set(key, value.clone()))?;
+        Ok(())
+    }
+
+    /// Runs `op` for `key` on every shard keeping it at once, returning the results of the first
+    /// `count` to succeed. The others are left running.
+    fn quorum<T, F>(&self, key: &str, count: usize, op: F) -> Result<Vec<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&KvsClient, String) -> Result<T> + Clone + Send + 'static,
+    {
+        let replicas = self.replicas_of(key);
+        let count = count.max(1);
+        if count > replicas.len() {
+            return Err(KvsError::NoQuorum);
+        }
+        let (tx, rx) = mpsc::channel();
+        for replica in &replicas {
+            let (shard, key, op, tx) = (
+                self.routing.read().unwrap().shards[*replica].clone(),
+                key.to_owned(),
+                op.clone(),
+                tx.clone(),
+            );
+            thread::spawn(move || tx.send(op(&shard, key)));
+        }
+        drop(tx);
+        let mut results = Vec::new();
+        for result in rx {
+            match result {
+                Ok(result) => results.push(result),
+                Err(e) => debug!("quorum request for {} failed: {:?}", key, e),
+            }
+            if results.len() == count {
+                return Ok(results);
+            }
+        }
+        Err(KvsError::NoQuorum)
+    }
+
+    /// Brings shards `a` and `b` up to date with each other on the keys quorum writes keep on
+    /// both, returning how many keys were copied. Only the buckets of keys whose Merkle tree
+    /// hashes differ are exchanged. Keys not written by quorum writes are left alone.
+    pub fn repair(&self, a: usize, b: usize) -> Result<usize> {
+        let shards = self.shards();
+        let (shard_a, shard_b) = (&shards[a], &shards[b]);
+        let mut nodes = vec![0];
+        for level in 0..=merkle::DEPTH {
+            let hashes_a = shard_a.merkle_hashes(level, nodes.clone())?;
+            let hashes_b = shard_b.merkle_hashes(level, nodes.clone())?;
+            let differing = nodes
+                .into_iter()
+                .zip(hashes_a.into_iter().zip(hashes_b))
+                .filter(|(_, (hash_a, hash_b))| hash_a != hash_b)
+                .map(|(node, _)| node);
+            nodes = match level {
+                merkle::DEPTH => differing.collect(),
+                _ => differing
+                    .flat_map(|node| [node * 2, node * 2 + 1])
+                    .collect(),
+            };
+        }
+        let mut copied = 0;
+        for leaf in nodes {
+            let entries_a: HashMap<String, String> =
+                shard_a.merkle_leaf(leaf)?.into_iter().collect();
+            let entries_b: HashMap<String, String> =
+                shard_b.merkle_leaf(leaf)?.into_iter().collect();
+            let keys: BTreeSet<&String> = entries_a.keys().chain(entries_b.keys()).collect();
+            for key in keys {
+                let replicas = self.replicas_of(key);
+                if !replicas.contains(&a) || !replicas.contains(&b) {
+                    continue;
+                }
+                let (value_a, value_b) = (entries_a.get(key), entries_b.get(key));
+                let stamp = |value: Option<&String>| match value {
+                    Some(value) => Stamped::decode(value).map(|s| Some((s.time, s.writer))),
+                    None => Ok(None),
+                };
+                let (stamp_a, stamp_b) = match (stamp(value_a), stamp(value_b)) {
+                    (Ok(stamp_a), Ok(stamp_b)) => (stamp_a, stamp_b),
+                    _ => continue,
+                };
+                let (from, to) = match stamp_a.cmp(&stamp_b) {
+                    std::cmp::Ordering::Greater => (value_a, shard_b),
+                    std::cmp::Ordering::Less => (value_b, shard_a),
+                    std::cmp::Ordering::Equal => continue,
+                };
+                if let Some(value) = from {
+                    to.set(key.clone(), value.clone())?;
+                    copied += 1;
+                }
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Begins a transaction over every shard, each of which is only asked to take part once
+    /// the transaction touches one of its keys
+    pub fn begin(&self) -> ShardedTransaction<'_> {
+        ShardedTransaction {
+            client: self,
+            transactions: HashMap::new(),
+        }
+    }
+}
+
+/// Transaction begun by `ShardedClient::begin`, aborted when dropped unless committed
+pub struct ShardedTransaction<'a> {
+    client: &'a ShardedClient,
+    // Transaction of every shard taking part, by index
+    transactions: HashMap<usize, Transaction>,
+}
+
+impl ShardedTransaction<'_> {
+    fn transaction(&mut self, key: &str) -> Result<&mut Transaction> {
+        let shard = self.client.shard_of(key)?;
+        if !self.transactions.contains_key(&shard) {
+            let transaction = self.client.shards()[shard].begin()?;
+            self.transactions.insert(shard, transaction);
+        }
+        Ok(self.transactions.get_mut(&shard).unwrap())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.transaction(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.transaction(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.transaction(&key)?.remove(key)
+    }
+
+    /// Commits the transaction on every shard taking part, in two phases if there are several.
+    /// Fails with `KvsError::Conflict` without writing anything if a key it read was written
+    /// since. An error in the second phase leaves the shards that didn't commit with their part
+    /// prepared.
+    pub fn commit(mut self) -> Result<()> {
+        let mut transactions: Vec<Transaction> =
+            self.transactions.drain().map(|(_, txn)| txn).collect();
+        if transactions.len() <= 1 {
+            return transactions.pop().map_or(Ok(()), Transaction::commit);
+        }
+        // Dropping the transactions aborts every part, prepared or not
+        for transaction in &mut transactions {
+            transaction.prepare()?;
+        }
+        let mut result = Ok(());
+        for transaction in transactions {
+            if let Err(e) = transaction.commit() {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    pub fn abort(mut self) -> Result<()> {
+        for (_, transaction) in self.transactions.drain() {
+            transaction.abort()?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the servers `discovery` comes to resolve to as shards, until the client is dropped
+fn follow_discovery(discovery: Discovery, routing: Weak<RwLock<Routing>>) {
+    loop {
+        thread::sleep(discovery.refresh_interval());
+        let client = match routing.upgrade() {
+            Some(routing) => ShardedClient {
+                routing,
+                replication: 1,
+            },
+            None => return,
+        };
+        let known: Vec<SocketAddr> = client.shards().iter().map(KvsClient::given_addr).collect();
+        let addrs = discovery.addrs();
+        for addr in addrs.iter().filter(|addr| !known.contains(addr)) {
+            match client.add_shard(KvsClient::new(*addr)) {
+                Ok(moved) => info!("Added shard {}, moving {} keys", addr, moved),
+                Err(e) => warn!("Could not add shard {}: {:?}", addr, e),
+            }
+        }
+        for addr in known.iter().filter(|addr| !addrs.contains(addr)) {
+            warn!("{} no longer resolves to shard {}", discovery.name(), addr);
+        }
+    }
+}
+
+/// Keys of one server moving to a shard being added, and the changes to them not caught up on
+struct Migration {
+    source: KvsClient,
+    events: mpsc::Receiver<Result<WatchEvent<String, String>>>,
+    // Key set and removed on the source to tell when every change before was seen
+    barrier: String,
+    // Keys copied to the new shard
+    moved: BTreeSet<String>,
+}
+
+impl Migration {
+    fn start(source: KvsClient) -> Result<Migration> {
+        let subscription = source.watch(None)?;
+        let barrier = format!("{}{}", REBALANCE_PREFIX, random_u64());
+        let (tx, events) = mpsc::channel();
+        let last = barrier.clone();
+        thread::spawn(move || {
+            for event in subscription {
+                let done = matches!(&event, Ok(WatchEvent::Removed(key)) if *key == last);
+                if tx.send(event).is_err() || done {
+                    return;
+                }
+            }
+        });
+        Ok(Migration {
+            source,
+            events,
+            barrier,
+            moved: BTreeSet::new(),
+        })
+    }
+
+    /// Applies the changes to moving keys seen so far to `shard`, or up to the barrier if given
+    fn catch_up(
+        &mut self,
+        shard: &KvsClient,
+        moving: impl Fn(&str) -> bool,
+        barrier: Option<&str>,
+    ) -> Result<()> {
+        loop {
+            let event = match barrier {
+                Some(_) => self.events.recv().map_err(|_| KvsError::Other)??,
+                None => match self.events.try_recv() {
+                    Ok(event) => event?,
+                    Err(_) => return Ok(()),
+                },
+            };
+            let key = event.key().clone();
+            if barrier == Some(key.as_str()) {
+                return Ok(());
+            }
+            if !moving(&key) {
+                continue;
+            }
+            match event {
+                WatchEvent::Set((key, value)) => shard.set(key, value)?,
+                WatchEvent::Removed(key) | WatchEvent::Expired(ExpiredEvent { key, .. }) => {
+                    match shard.remove(key) {
+                        Ok(()) | Err(KvsError::NonExistantKey) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            self.moved.insert(key);
+        }
+    }
+
+    /// Catches up on every change made to the source before now, ending the subscription
+    fn finish(&mut self, shard: &KvsClient, moving: impl Fn(&str) -> bool) -> Result<()> {
+        self.source.set(self.barrier.clone(), String::new())?;
+        let barrier = self.barrier.clone();
+        self.catch_up(shard, moving, Some(&barrier))?;
+        self.source.remove(barrier)
+    }
+}
@@ -0,0 +1,276 @@
+//! Deterministic simulation of a cluster, for testing replication and timeouts without sockets
+//! or sleeps.
+//!
+//! Every node runs the cluster logic and answers requests like the server does, against a
+//! `KvStore` kept in memory. Time only moves when the simulation is advanced, then the timer of
+//! every running node fires at each heartbeat interval in the order of node ids, and messages
+//! are delivered right away by a network that can be cut or drop messages. The same script
+//! always plays out the same way.
+//!
+//! Requests are sent to a node as the JSON protocol, but without going through `KvsClient`, and
+//! the parts of the server that aren't about the cluster, like sessions or expiry, aren't run.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::cluster::{Clock, Cluster, ClusterConfig, MemoryTermStore, Role, Transport};
+use crate::engine::storage::MemoryStorage;
+use crate::engine::store::{KvStore, KvStoreOptions};
+use crate::protocol::{KvRequest, KvResponse};
+use crate::{KvsError, Result};
+
+/// A clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    now: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    // Nothing else runs meanwhile, so sleeping is moving the time
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+struct Node {
+    cluster: Arc<Cluster>,
+    store: KvStore<String, String>,
+}
+
+impl Node {
+    // Same dispatch as the server
+    fn handle(&self, request: KvRequest<String, String>) -> Result<Option<String>> {
+        match request {
+            KvRequest::Cluster(message) => self.cluster.handle_message(message).map(|_| None),
+            KvRequest::Replicate {
+                term,
+                leader,
+                seq,
+                request,
+            } => self
+                .cluster
+                .handle_replicate(&self.store, term, leader, seq, *request),
+            request if request.is_write() => self.cluster.handle_write(&self.store, request),
+            KvRequest::ReplicaGet { max_lag, .. } if self.cluster.lag() > max_lag => {
+                Err(KvsError::ReplicaLagging(self.cluster.lag()))
+            }
+            request => request.apply(&self.store),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Network {
+    // Nodes that are running
+    nodes: Mutex<BTreeMap<SocketAddr, Arc<Node>>>,
+    // Pairs of addresses that can't reach each other, both ways
+    cut: Mutex<BTreeSet<(SocketAddr, SocketAddr)>>,
+    // Messages between nodes still to be dropped, whichever nodes they are between
+    drops: Mutex<usize>,
+}
+
+impl Network {
+    // Messages from no node are from clients, which reach every running node
+    fn deliver(&self, from: Option<SocketAddr>, to: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+        let unreachable = || KvsError::IOError(format!("{:?} can't reach {}", from, to));
+        if let Some(from) = from {
+            if self.cut.lock().unwrap().contains(&(from, to)) {
+                return Err(unreachable());
+            }
+            let mut drops = self.drops.lock().unwrap();
+            if *drops > 0 {
+                *drops -= 1;
+                debug!("Dropping message from {} to {}", from, to);
+                return Err(unreachable());
+            }
+        }
+        // Not held while the node answers, which can send messages of its own
+        let node = self.nodes.lock().unwrap().get(&to).cloned();
+        let node = node.ok_or_else(unreachable)?;
+        let value = serde_json::from_slice(request)
+            .map_err(KvsError::from)
+            .and_then(|request| node.handle(request));
+        Ok(serde_json::to_vec(&KvResponse { value })?)
+    }
+}
+
+// The network as seen by one node
+struct NodeTransport {
+    addr: SocketAddr,
+    network: Arc<Network>,
+}
+
+impl Transport for NodeTransport {
+    fn call(&self, addr: SocketAddr, request: &[u8], _timeout: Duration) -> Result<Vec<u8>> {
+        self.network.deliver(Some(self.addr), addr, request)
+    }
+}
+
+/// A cluster of nodes numbered from 1, each keeping its store across crashes
+pub struct Simulation {
+    clock: Arc<VirtualClock>,
+    network: Arc<Network>,
+    configs: BTreeMap<u64, ClusterConfig>,
+    storages: BTreeMap<u64, MemoryStorage>,
+    terms: BTreeMap<u64, Arc<MemoryTermStore>>,
+    heartbeat_interval: Duration,
+}
+
+impl Simulation {
+    /// Starts `nodes` nodes with the default timeouts of `ClusterConfig`
+    pub fn new(nodes: u64) -> Result<Simulation> {
+        let addrs: BTreeMap<u64, SocketAddr> = (1..=nodes).map(|id| (id, addr(id))).collect();
+        let configs: BTreeMap<u64, ClusterConfig> = addrs
+            .keys()
+            .map(|&id| {
+                let mut peers = addrs.clone();
+                peers.remove(&id);
+                (id, ClusterConfig::new(id, peers.into_iter().collect()))
+            })
+            .collect();
+        let mut simulation = Simulation {
+            clock: Arc::new(VirtualClock::new()),
+            network: Arc::new(Network::default()),
+            heartbeat_interval: ClusterConfig::new(0, Default::default()).heartbeat_interval,
+            storages: addrs.keys().map(|&id| (id, MemoryStorage::new())).collect(),
+            terms: addrs.keys().map(|&id| (id, Arc::default())).collect(),
+            configs,
+        };
+        for id in 1..=nodes {
+            simulation.restart(id)?;
+        }
+        Ok(simulation)
+    }
+
+    /// Time since the simulation started
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Moves the time forward a heartbeat interval at a time, running the timers of the nodes
+    pub fn advance(&self, duration: Duration) {
+        let end = self.clock.now() + duration;
+        while self.clock.now() + self.heartbeat_interval <= end {
+            self.clock.advance(self.heartbeat_interval);
+            let nodes: Vec<_> = self
+                .network
+                .nodes
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect();
+            for node in nodes {
+                node.cluster.tick();
+            }
+        }
+        self.clock.advance(end - self.clock.now());
+    }
+
+    /// Sends `request` to node `id` from a client that can reach every node
+    pub fn request(&self, id: u64, request: KvRequest<String, String>) -> Result<Option<String>> {
+        let response = self
+            .network
+            .deliver(None, addr(id), &serde_json::to_vec(&request)?)?;
+        serde_json::from_slice::<KvResponse<String>>(&response)?.value
+    }
+
+    /// Role of a running node
+    pub fn role(&self, id: u64) -> Option<Role> {
+        self.node(id).map(|node| node.cluster.role())
+    }
+
+    pub fn term(&self, id: u64) -> Option<u64> {
+        self.node(id).map(|node| node.cluster.term())
+    }
+
+    /// Writes of the leader a running node hasn't applied, see `Cluster::lag`
+    pub fn lag(&self, id: u64) -> Option<u64> {
+        self.node(id).map(|node| node.cluster.lag())
+    }
+
+    /// The running leader of the newest term, if there is one
+    pub fn leader(&self) -> Option<u64> {
+        self.configs
+            .keys()
+            .filter(|&&id| self.role(id) == Some(Role::Leader))
+            .filter_map(|&id| Some((self.term(id)?, id)))
+            .max()
+            .map(|(_, id)| id)
+    }
+
+    /// Stops a node, it keeps what its store had written along with its term and vote
+    pub fn crash(&self, id: u64) {
+        self.network.nodes.lock().unwrap().remove(&addr(id));
+    }
+
+    /// Starts a node again on its store, as a follower that has to learn of the leader anew
+    pub fn restart(&mut self, id: u64) -> Result<()> {
+        self.crash(id);
+        let store = KvStore::open_with_storage(
+            Arc::new(self.storages[&id].clone()),
+            KvStoreOptions::default(),
+        )?;
+        let transport = NodeTransport {
+            addr: addr(id),
+            network: self.network.clone(),
+        };
+        let cluster = Cluster::new(
+            self.configs[&id].clone(),
+            self.clock.clone(),
+            Arc::new(transport),
+            self.terms[&id].clone(),
+        )?;
+        let node = Arc::new(Node { cluster, store });
+        self.network.nodes.lock().unwrap().insert(addr(id), node);
+        Ok(())
+    }
+
+    /// Cuts the nodes of `group` off from the others, clients still reach every node
+    pub fn partition(&self, group: &[u64]) {
+        let mut cut = self.network.cut.lock().unwrap();
+        for &inside in group {
+            for &outside in self.configs.keys().filter(|id| !group.contains(id)) {
+                cut.insert((addr(inside), addr(outside)));
+                cut.insert((addr(outside), addr(inside)));
+            }
+        }
+    }
+
+    /// Lets every node reach the others again
+    pub fn heal(&self) {
+        self.network.cut.lock().unwrap().clear();
+    }
+
+    /// Drops the next `count` messages between nodes
+    pub fn drop_next(&self, count: usize) {
+        *self.network.drops.lock().unwrap() += count;
+    }
+
+    fn node(&self, id: u64) -> Option<Arc<Node>> {
+        self.network.nodes.lock().unwrap().get(&addr(id)).cloned()
+    }
+}
+
+// Made up, nothing listens on it
+fn addr(id: u64) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, id as u8)), 4000)
+}
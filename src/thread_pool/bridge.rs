@@ -0,0 +1,198 @@
+//! Running pool jobs from async code.
+//!
+//! `AsyncPool` hands blocking work or whole futures to a pool and returns a future of their
+//! result, so that an async server can keep blocking engine calls off its own threads.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+enum State<T> {
+    Waiting(Option<Waker>),
+    Done(thread::Result<T>),
+    // The job was dropped without producing a result
+    Dropped,
+    Taken,
+}
+
+/// Sending half of a `JobFuture`
+struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    fn finish(&self, state: State<T>) {
+        let mut current = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // Only the first of sending and dropping counts
+        if let State::Waiting(waker) = &mut *current {
+            let waker = waker.take();
+            *current = state;
+            drop(current);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    fn send(self, result: thread::Result<T>) {
+        self.finish(State::Done(result));
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.finish(State::Dropped);
+    }
+}
+
+/// Result of a job handed to an `AsyncPool`, resolves to an error if the job panicked or was
+/// dropped without running
+pub struct JobFuture<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+fn oneshot<T>() -> (Sender<T>, JobFuture<T>) {
+    let state = Arc::new(Mutex::new(State::Waiting(None)));
+    (
+        Sender {
+            state: Arc::clone(&state),
+        },
+        JobFuture { state },
+    )
+}
+
+impl<T> Future for JobFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match std::mem::replace(&mut *state, State::Taken) {
+            State::Waiting(_) => {
+                *state = State::Waiting(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            State::Done(Ok(result)) => Poll::Ready(Ok(result)),
+            State::Done(Err(_)) => Poll::Ready(Err(KvsError::JobPanicked)),
+            State::Dropped => Poll::Ready(Err(KvsError::Other)),
+            State::Taken => panic!("JobFuture polled after it completed"),
+        }
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawned future that sends its output, or its panic, to a `JobFuture`
+struct Spawned<Fut: Future> {
+    future: Pin<Box<Fut>>,
+    sender: Option<Sender<Fut::Output>>,
+}
+
+impl<Fut: Future> Future for Spawned<Fut> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| self.future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(output)) => Ok(output),
+            Err(e) => Err(e),
+        };
+        if let Some(sender) = self.sender.take() {
+            sender.send(result);
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Future spawned on a pool, polled by a new job every time it is woken
+struct Task<P> {
+    // Empty once the future completed or panicked
+    future: Mutex<Option<BoxFuture>>,
+    pool: Weak<P>,
+}
+
+impl<P: ThreadPool + Send + Sync + 'static> Task<P> {
+    fn poll(self: Arc<Self>) {
+        let waker = Waker::from(Arc::clone(&self));
+        let mut slot = self.future.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(mut future) = slot.take() {
+            if future
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+            {
+                *slot = Some(future);
+            }
+        }
+    }
+}
+
+impl<P: ThreadPool + Send + Sync + 'static> Wake for Task<P> {
+    fn wake(self: Arc<Self>) {
+        // Futures woken after the pool went away are dropped, failing their JobFuture
+        if let Some(pool) = self.pool.upgrade() {
+            pool.spawn(move || self.poll());
+        }
+    }
+}
+
+/// Wraps a pool so that async code can hand it blocking jobs and futures
+pub struct AsyncPool<P> {
+    pool: Arc<P>,
+}
+
+impl<P> Clone for AsyncPool<P> {
+    fn clone(&self) -> Self {
+        AsyncPool {
+            pool: Arc::clone(&self.pool),
+        }
+    }
+}
+
+impl<P: ThreadPool + Send + Sync + 'static> AsyncPool<P> {
+    pub fn new(pool: P) -> AsyncPool<P> {
+        AsyncPool {
+            pool: Arc::new(pool),
+        }
+    }
+
+    pub fn pool(&self) -> &P {
+        &self.pool
+    }
+
+    /// Runs the blocking `job` on the pool, the returned future resolves once it finishes
+    pub fn spawn_blocking<F, T>(&self, job: F) -> JobFuture<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot();
+        self.pool
+            .spawn(move || sender.send(panic::catch_unwind(AssertUnwindSafe(job))));
+        receiver
+    }
+
+    /// Drives `future` to completion on the pool, polling it on a worker whenever it is woken
+    pub fn spawn<Fut>(&self, future: Fut) -> JobFuture<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (sender, receiver) = oneshot();
+        let future = Spawned {
+            future: Box::pin(future),
+            sender: Some(sender),
+        };
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future) as BoxFuture)),
+            pool: Arc::downgrade(&self.pool),
+        });
+        task.wake();
+        receiver
+    }
+}
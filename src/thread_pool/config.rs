@@ -0,0 +1,102 @@
+use std::io;
+use std::thread::{self, JoinHandle};
+
+use crate::Result;
+
+/// How a pool sizes and sets up its workers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadPoolConfig {
+    /// Number of workers, 0 starts one per cpu available to the process
+    pub threads: u32,
+    /// Pins each worker to its own cpu, wrapping around when there are more workers than cpus.
+    /// Only has an effect on linux.
+    pub pin_cores: bool,
+    /// Workers are named `<name>-<index>` when set, which shows up in debuggers and profiles
+    pub name: Option<String>,
+}
+
+impl ThreadPoolConfig {
+    pub fn new(threads: u32) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            threads,
+            ..ThreadPoolConfig::default()
+        }
+    }
+
+    pub fn with_pinned_cores(self) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            pin_cores: true,
+            ..self
+        }
+    }
+
+    pub fn with_name(self, name: impl Into<String>) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            name: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Number of workers to start, resolving 0 to the available parallelism
+    pub fn thread_count(&self) -> u32 {
+        match self.threads {
+            0 => available_cpus() as u32,
+            threads => threads,
+        }
+    }
+
+    pub(crate) fn thread_name(&self, index: u32) -> Option<String> {
+        self.name.as_ref().map(|name| format!("{}-{}", name, index))
+    }
+
+    /// Cpu for the worker with `index` if workers are pinned
+    pub(crate) fn core(&self, index: u32) -> Option<usize> {
+        self.pin_cores.then(|| index as usize % available_cpus())
+    }
+
+    /// Starts the worker with `index`, named and pinned as configured
+    pub(crate) fn spawn_worker<F>(&self, index: u32, f: F) -> Result<JoinHandle<()>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = self.thread_name(index) {
+            builder = builder.name(name);
+        }
+        let core = self.core(index);
+        Ok(builder.spawn(move || {
+            if let Some(core) = core {
+                if let Err(e) = pin_to_core(core) {
+                    println!("Failed to pin worker {} to cpu {}: {:?}", index, core, e);
+                }
+            }
+            f()
+        })?)
+    }
+}
+
+fn available_cpus() -> usize {
+    thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1)
+}
+
+/// Restricts the calling thread to running on `core`
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_to_core(core: usize) -> io::Result<()> {
+    // SAFETY: the set is plain data, zeroed is a valid empty set, and it outlives the call
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_to_core(_core: usize) -> io::Result<()> {
+    Ok(())
+}
@@ -1,14 +1,198 @@
-use crate::Result;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::metrics::{Counter, Gauge};
+use crate::{KvsError, Result};
 
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::with_config(ThreadPoolConfig::new(threads))
+    }
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
     where
         Self: Sized;
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// Handle to the live metrics of the pool
+    fn metrics(&self) -> ThreadPoolMetrics;
+
+    /// Runs `job` on the pool, returning a handle to wait for its result
+    fn spawn_with_handle<F, T>(&self, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        self.spawn(move || {
+            // Nobody is waiting for the result if the handle was dropped
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        JoinHandle { receiver }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolCounters {
+    queued: Gauge,
+    busy: Gauge,
+    completed: Counter,
+    panics: Counter,
+}
+
+/// Live counts of what a pool is doing, clones share the same counts. Jobs waiting while
+/// workers are all busy point at queueing rather than the jobs themselves being slow.
+#[derive(Debug, Default, Clone)]
+pub struct ThreadPoolMetrics(Arc<PoolCounters>);
+
+impl ThreadPoolMetrics {
+    /// Jobs spawned that no worker has started yet
+    pub fn queued(&self) -> u64 {
+        self.0.queued.get().max(0) as u64
+    }
+
+    /// Workers currently running a job
+    pub fn busy(&self) -> u64 {
+        self.0.busy.get().max(0) as u64
+    }
+
+    /// Jobs that ran to the end, including those that panicked
+    pub fn completed(&self) -> u64 {
+        self.0.completed.get()
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.0.panics.get()
+    }
+
+    /// Wraps a job being spawned so that it is counted while queued and while running. Panics
+    /// are counted and then carry on as they would have without the wrapper.
+    pub(crate) fn track<F>(&self, job: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let counters = Arc::clone(&self.0);
+        counters.queued.increment();
+        move || {
+            counters.queued.decrement();
+            counters.busy.increment();
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            counters.busy.decrement();
+            counters.completed.add(1);
+            if let Err(e) = result {
+                counters.panics.add(1);
+                panic::resume_unwind(e);
+            }
+        }
+    }
+}
+
+/// Result of a job spawned on a pool
+pub struct JoinHandle<T> {
+    receiver: Receiver<thread::Result<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits for the job to finish, failing if it panicked or was dropped without running
+    pub fn join(self) -> Result<T> {
+        match self.receiver.recv() {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(KvsError::JobPanicked),
+            Err(_) => Err(KvsError::Other),
+        }
+    }
+}
+
+/// Lets jobs borrow from the stack of the caller of `scope`
+pub struct Scope<'scope, 'env: 'scope, P> {
+    pool: &'scope P,
+    // Jobs spawned in the scope that haven't finished yet
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// Counts a job as finished when dropped, whether or not the job got to run
+struct PendingGuard(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let (pending, finished) = &*self.0;
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        *pending -= 1;
+        if *pending == 0 {
+            finished.notify_all();
+        }
+    }
 }
 
+impl<'scope, 'env, P: ThreadPool> Scope<'scope, 'env, P> {
+    /// Runs `job` on the pool, it may borrow anything that outlives the scope
+    pub fn spawn<F, T>(&'scope self, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        *self.pending.0.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        let guard = PendingGuard(Arc::clone(&self.pending));
+        let (sender, receiver) = channel();
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let guard = guard;
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+            drop(sender);
+            drop(guard);
+        });
+        // SAFETY: `scope` doesn't return until every job spawned in it has finished or been
+        // dropped, so nothing the job borrows is used after the scope ends
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(job) };
+        self.pool.spawn(job);
+        JoinHandle { receiver }
+    }
+
+    fn wait(&self) {
+        let (pending, finished) = &*self.pending;
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        while *pending > 0 {
+            pending = finished.wait(pending).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Calls `f` with a scope whose jobs may borrow non-static data, returning once all of them
+/// have finished. Must not be called from a job of the same pool, which could leave every
+/// worker waiting on jobs that no worker is free to run.
+pub fn scope<'env, P, F, R>(pool: &P, f: F) -> R
+where
+    P: ThreadPool,
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env, P>) -> R,
+{
+    let scope = Scope {
+        pool,
+        pending: Arc::new((Mutex::new(0), Condvar::new())),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.wait();
+    match result {
+        Ok(result) => result,
+        Err(e) => panic::resume_unwind(e),
+    }
+}
+
+pub use config::ThreadPoolConfig;
+
+pub mod bridge;
+mod config;
 pub mod naive;
+pub mod priority;
+#[cfg(feature = "server")]
 pub mod rayon;
 pub mod shared_queue;
+pub mod work_stealing;
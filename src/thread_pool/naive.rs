@@ -1,24 +1,42 @@
-use std::thread;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use super::Result;
 use super::ThreadPool;
-pub struct NaiveThreadPool {}
+use super::ThreadPoolConfig;
+use super::ThreadPoolMetrics;
+pub struct NaiveThreadPool {
+    config: ThreadPoolConfig,
+    // Index of the next thread, used to name and pin it
+    next_thread: AtomicU32,
+    metrics: ThreadPoolMetrics,
+}
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self>
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
     where
         Self: Sized,
     {
         println!(
             "Naive thread pool will just spin up unlimited threads regardless of param {}",
-            threads
+            config.threads
         );
-        Ok(NaiveThreadPool {})
+        Ok(NaiveThreadPool {
+            config,
+            next_thread: AtomicU32::new(0),
+            metrics: ThreadPoolMetrics::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let index = self.next_thread.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.config.spawn_worker(index, self.metrics.track(job)) {
+            println!("Error spawning thread for job: {:?}", e);
+        }
+    }
+
+    fn metrics(&self) -> ThreadPoolMetrics {
+        self.metrics.clone()
     }
 }
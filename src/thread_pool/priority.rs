@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use super::Result;
+use super::ThreadPool;
+use super::ThreadPoolConfig;
+use super::ThreadPoolMetrics;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How urgently a job should run, workers always take the most urgent job waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+struct Queues {
+    // Indexed by priority, most urgent first
+    jobs: [VecDeque<Job>; 3],
+    shutdown: bool,
+}
+
+impl Queues {
+    fn pop(&mut self) -> Option<Job> {
+        self.jobs.iter_mut().find_map(|queue| queue.pop_front())
+    }
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    available: Condvar,
+}
+
+struct Worker {
+    id: u32,
+    join_handle: Option<JoinHandle<()>>,
+}
+impl Worker {
+    fn new(id: u32, config: &ThreadPoolConfig, shared: Arc<Shared>) -> Result<Self> {
+        let join_handle = config.spawn_worker(id, move || loop {
+            let job = {
+                let mut queues = match shared.queues.lock() {
+                    Ok(queues) => queues,
+                    Err(e) => {
+                        println!("Worker {} failed to lock queues: {:?}", id, e);
+                        return;
+                    }
+                };
+                loop {
+                    if let Some(job) = queues.pop() {
+                        break job;
+                    }
+                    if queues.shutdown {
+                        println!("Worker {} received message to shutdown", id);
+                        return;
+                    }
+                    queues = match shared.available.wait(queues) {
+                        Ok(queues) => queues,
+                        Err(e) => {
+                            println!("Worker {} failed to wait for jobs: {:?}", id, e);
+                            return;
+                        }
+                    };
+                }
+            };
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                println!("Worker {} panicked running job {:?}", id, e);
+            }
+        })?;
+        Ok(Worker {
+            id,
+            join_handle: Some(join_handle),
+        })
+    }
+}
+
+/// Pool with a queue per priority, so that latency sensitive jobs can get ahead of expensive
+/// ones when it is loaded. Low priority jobs only run once nothing more urgent is waiting.
+/// Jobs spawned through `ThreadPool::spawn` are normal priority. Jobs still queued when the
+/// pool is dropped are run before the workers exit.
+pub struct PriorityThreadPool {
+    workers: Vec<Worker>,
+    shared: Arc<Shared>,
+    metrics: ThreadPoolMetrics,
+}
+
+impl PriorityThreadPool {
+    pub fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.shared.queues.lock() {
+            Ok(mut queues) => {
+                queues.jobs[priority as usize].push_back(Box::new(self.metrics.track(job)))
+            }
+            Err(e) => {
+                println!("Error queueing job: {:?}", e);
+                return;
+            }
+        }
+        self.shared.available.notify_one();
+    }
+}
+
+impl ThreadPool for PriorityThreadPool {
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues {
+                jobs: Default::default(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+        let workers = (0..config.thread_count())
+            .map(|i| Worker::new(i, &config, Arc::clone(&shared)))
+            .collect::<Result<_>>()?;
+        Ok(PriorityThreadPool {
+            workers,
+            shared,
+            metrics: ThreadPoolMetrics::default(),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, job)
+    }
+
+    fn metrics(&self) -> ThreadPoolMetrics {
+        self.metrics.clone()
+    }
+}
+
+impl Drop for PriorityThreadPool {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("dropped while unwinding panic");
+            return;
+        }
+        match self.shared.queues.lock() {
+            Ok(mut queues) => queues.shutdown = true,
+            Err(e) => println!("Failed to lock queues while shutting down: {:?}", e),
+        }
+        self.shared.available.notify_all();
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.join_handle.take() {
+                if let Err(e) = thread.join() {
+                    println!(
+                        "Failed to join worker {} while shutting down: {:?}",
+                        worker.id, e
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,18 +1,37 @@
+use super::config::pin_to_core;
 use super::Result;
 use super::ThreadPool;
+use super::ThreadPoolConfig;
+use super::ThreadPoolMetrics;
 
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    metrics: ThreadPoolMetrics,
 }
 impl ThreadPool for RayonThreadPool {
-    fn new(threads: u32) -> Result<Self>
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
     where
         Self: Sized,
     {
+        let names = config.clone();
+        let cores = config.clone();
         Ok(RayonThreadPool {
             pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(threads as usize)
+                .num_threads(config.thread_count() as usize)
+                .thread_name(move |index| {
+                    names
+                        .thread_name(index as u32)
+                        .unwrap_or_else(|| format!("rayon-{}", index))
+                })
+                .start_handler(move |index| {
+                    if let Some(core) = cores.core(index as u32) {
+                        if let Err(e) = pin_to_core(core) {
+                            println!("Failed to pin worker {} to cpu {}: {:?}", index, core, e);
+                        }
+                    }
+                })
                 .build()?,
+            metrics: ThreadPoolMetrics::default(),
         })
     }
 
@@ -20,6 +39,10 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.install(job);
+        self.pool.install(self.metrics.track(job));
+    }
+
+    fn metrics(&self) -> ThreadPoolMetrics {
+        self.metrics.clone()
     }
 }
@@ -9,6 +9,8 @@ use std::{
 
 use super::Result;
 use super::ThreadPool;
+use super::ThreadPoolConfig;
+use super::ThreadPoolMetrics;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 enum ThreadPoolMessage {
@@ -20,8 +22,12 @@ struct Worker {
     join_handle: Option<JoinHandle<()>>,
 }
 impl Worker {
-    fn new(id: u32, receiver: Arc<Mutex<Receiver<ThreadPoolMessage>>>) -> Self {
-        let join_handle = thread::spawn(move || loop {
+    fn new(
+        id: u32,
+        config: &ThreadPoolConfig,
+        receiver: Arc<Mutex<Receiver<ThreadPoolMessage>>>,
+    ) -> Result<Self> {
+        let join_handle = config.spawn_worker(id, move || loop {
             match receiver.lock() {
                 Ok(receiver) => match receiver.recv() {
                     Ok(message) => match message {
@@ -43,43 +49,53 @@ impl Worker {
                     println!("Worker {} failed to lock receiver: {:?}", id, e);
                 }
             }
-        });
-        Worker {
+        })?;
+        Ok(Worker {
             id,
             join_handle: Some(join_handle),
-        }
+        })
     }
 }
 
 pub struct SharedQueueThreadPool {
     workers: Vec<Worker>,
     sender: Sender<ThreadPoolMessage>,
+    metrics: ThreadPoolMetrics,
 }
 impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self>
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
     where
         Self: Sized,
     {
         let (sender, receiver) = channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let threads = config.thread_count();
         let mut workers = Vec::with_capacity(threads as usize);
         for i in 0..threads {
-            workers.push(Worker::new(i, Arc::clone(&receiver)));
+            workers.push(Worker::new(i, &config, Arc::clone(&receiver))?);
         }
-        Ok(SharedQueueThreadPool { workers, sender })
+        Ok(SharedQueueThreadPool {
+            workers,
+            sender,
+            metrics: ThreadPoolMetrics::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        match self.sender.send(ThreadPoolMessage::Run(Box::new(job))) {
-            Err(e) => {
-                println!("Error sending job to worker channel: {:?}", e);
-            }
-            Ok(_) => {}
+        if let Err(e) = self
+            .sender
+            .send(ThreadPoolMessage::Run(Box::new(self.metrics.track(job))))
+        {
+            println!("Error sending job to worker channel: {:?}", e);
         }
     }
+
+    fn metrics(&self) -> ThreadPoolMetrics {
+        self.metrics.clone()
+    }
 }
 
 impl Drop for SharedQueueThreadPool {
@@ -96,7 +112,10 @@ impl Drop for SharedQueueThreadPool {
         for worker in &mut self.workers {
             if let Some(thread) = worker.join_handle.take() {
                 if let Err(e) = thread.join() {
-                    println!("Failed to join while shutting down: {:?}", e);
+                    println!(
+                        "Failed to join worker {} while shutting down: {:?}",
+                        worker.id, e
+                    );
                 }
             }
         }
@@ -0,0 +1,177 @@
+use std::{
+    cell::RefCell,
+    iter,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam_deque::{Injector, Stealer, Worker as Deque};
+
+use super::Result;
+use super::ThreadPool;
+use super::ThreadPoolConfig;
+use super::ThreadPoolMetrics;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Idle workers look for work at least this often, in case they missed a wakeup
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    shutdown: AtomicBool,
+    idle: Mutex<()>,
+    available: Condvar,
+}
+
+impl Shared {
+    /// Takes a job from the local deque, then from the jobs spawned outside the pool, then from
+    /// the other workers
+    fn find_job(&self, local: &Deque<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(|s| s.steal()).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })
+    }
+}
+
+thread_local! {
+    // Deque of the worker running on this thread, along with the pool it belongs to
+    static LOCAL: RefCell<Option<(usize, Rc<Deque<Job>>)>> = const { RefCell::new(None) };
+}
+
+fn pool_id(shared: &Arc<Shared>) -> usize {
+    Arc::as_ptr(shared) as usize
+}
+
+struct Worker {
+    id: u32,
+    join_handle: Option<JoinHandle<()>>,
+}
+impl Worker {
+    fn new(
+        id: u32,
+        config: &ThreadPoolConfig,
+        shared: Arc<Shared>,
+        local: Deque<Job>,
+    ) -> Result<Self> {
+        let join_handle = config.spawn_worker(id, move || {
+            let local = Rc::new(local);
+            LOCAL.with(|cell| *cell.borrow_mut() = Some((pool_id(&shared), Rc::clone(&local))));
+            loop {
+                match shared.find_job(&local) {
+                    Some(job) => {
+                        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            println!("Worker {} panicked running job {:?}", id, e);
+                        }
+                    }
+                    None if shared.shutdown.load(Ordering::SeqCst) => {
+                        println!("Worker {} received message to shutdown", id);
+                        return;
+                    }
+                    None => {
+                        let idle = shared.idle.lock().unwrap_or_else(|e| e.into_inner());
+                        let _ = shared.available.wait_timeout(idle, IDLE_TIMEOUT);
+                    }
+                }
+            }
+        })?;
+        Ok(Worker {
+            id,
+            join_handle: Some(join_handle),
+        })
+    }
+}
+
+/// Pool where every worker has its own deque of jobs and steals from the others once it runs
+/// out, so uneven jobs like compression or checksumming balance across workers. Jobs spawned
+/// from a worker go to that worker's deque, the rest go to a queue shared by all workers.
+pub struct WorkStealingThreadPool {
+    workers: Vec<Worker>,
+    shared: Arc<Shared>,
+    metrics: ThreadPoolMetrics,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn with_config(config: ThreadPoolConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let deques: Vec<Deque<Job>> = (0..config.thread_count())
+            .map(|_| Deque::new_fifo())
+            .collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: deques.iter().map(|deque| deque.stealer()).collect(),
+            shutdown: AtomicBool::new(false),
+            idle: Mutex::new(()),
+            available: Condvar::new(),
+        });
+        let workers = deques
+            .into_iter()
+            .zip(0..)
+            .map(|(deque, i)| Worker::new(i, &config, Arc::clone(&shared), deque))
+            .collect::<Result<_>>()?;
+        Ok(WorkStealingThreadPool {
+            workers,
+            shared,
+            metrics: ThreadPoolMetrics::default(),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(self.metrics.track(job));
+        let id = pool_id(&self.shared);
+        let job = LOCAL.with(|cell| match &*cell.borrow() {
+            Some((pool, local)) if *pool == id => {
+                local.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.shared.injector.push(job);
+        }
+        self.shared.available.notify_one();
+    }
+
+    fn metrics(&self) -> ThreadPoolMetrics {
+        self.metrics.clone()
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("dropped while unwinding panic");
+            return;
+        }
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.available.notify_all();
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.join_handle.take() {
+                if let Err(e) = thread.join() {
+                    println!(
+                        "Failed to join worker {} while shutting down: {:?}",
+                        worker.id, e
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Optimistic transactions run by a server for its clients.
+//!
+//! Writes of a transaction are buffered by the server until it commits. Reads note the version
+//! of the key they saw, and the commit fails with `KvsError::Conflict` if any of those keys was
+//! written since, for the client to retry. Versions are only kept for the keys open transactions
+//! read, and transactions are only kept in memory by the server that began them.
+//!
+//! Transactions spanning servers are committed in two phases. Preparing checks a transaction
+//! like a commit would and holds on to its writes, which the server persists under
+//! `PREPARED_PREFIX` so that they outlive a restart. Other transactions touching the keys of a
+//! prepared transaction conflict until it is committed or aborted.
+//!
+//! Writes outside of transactions hold `Transactions::write_guard` until they are noted with
+//! `written`, so a commit can't check its reads between a write and its note.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::KvRequest;
+use crate::{KvsError, Result};
+
+/// Transactions idle for longer than this are dropped, unless they were prepared
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Prefix of the keys prepared transactions are persisted under, followed by their id
+pub const PREPARED_PREFIX: &str = "__kvs_prepared/";
+
+struct Transaction<K, V> {
+    // Last time the transaction was begun, read from or written to
+    last_active: Instant,
+    // Version of every key read, as it was when first read
+    reads: HashMap<K, u64>,
+    writes: Vec<KvRequest<K, V>>,
+}
+
+struct Watched {
+    // Open transactions that read the key
+    readers: usize,
+    version: u64,
+}
+
+struct State<K, V> {
+    last_id: u64,
+    // Bumped on every write to a watched key
+    last_version: u64,
+    transactions: HashMap<u64, Transaction<K, V>>,
+    watched: HashMap<K, Watched>,
+    prepared: HashMap<u64, Vec<KvRequest<K, V>>>,
+    // Prepared transaction writing each key
+    prepared_keys: HashMap<K, u64>,
+}
+
+impl<K: Eq + Hash + Clone, V> State<K, V> {
+    /// Takes transaction `id` out, failing if a key it read was written since or if it touches
+    /// the keys of a prepared transaction
+    fn take_checked(&mut self, id: u64) -> Result<Transaction<K, V>> {
+        let mut transaction = self
+            .transactions
+            .remove(&id)
+            .ok_or(KvsError::UnknownTransaction)?;
+        let stale = transaction.reads.iter().any(|(key, version)| {
+            self.watched
+                .get(key)
+                .is_some_and(|watched| watched.version != *version)
+        });
+        let locked = transaction
+            .reads
+            .keys()
+            .chain(transaction.writes.iter().filter_map(|write| write.key()))
+            .any(|key| self.prepared_keys.contains_key(key));
+        self.unwatch(std::mem::take(&mut transaction.reads));
+        if stale || locked {
+            return Err(KvsError::Conflict);
+        }
+        Ok(transaction)
+    }
+
+    fn hold(&mut self, id: u64, writes: Vec<KvRequest<K, V>>) {
+        for key in writes.iter().filter_map(|write| write.key()) {
+            self.prepared_keys.insert(key.clone(), id);
+        }
+        self.prepared.insert(id, writes);
+    }
+
+    fn release(&mut self, id: u64) -> Option<Vec<KvRequest<K, V>>> {
+        let writes = self.prepared.remove(&id)?;
+        for key in writes.iter().filter_map(|write| write.key()) {
+            self.prepared_keys.remove(key);
+        }
+        Some(writes)
+    }
+
+    fn unwatch(&mut self, reads: HashMap<K, u64>) {
+        for key in reads.into_keys() {
+            if let Some(watched) = self.watched.get_mut(&key) {
+                watched.readers -= 1;
+                if watched.readers == 0 {
+                    self.watched.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Writes of a transaction being committed, other commits wait until this is dropped
+pub struct Commit<'a, K, V> {
+    pub writes: Vec<KvRequest<K, V>>,
+    /// Whether the transaction was prepared, so its persisted writes are to be removed
+    pub prepared: bool,
+    _committing: MutexGuard<'a, ()>,
+}
+
+pub struct Transactions<K, V> {
+    state: Mutex<State<K, V>>,
+    // Held while a transaction is checked and applied, so commits don't interleave with each
+    // other or with writes outside of transactions
+    committing: Mutex<()>,
+}
+
+impl<K, V> Default for Transactions<K, V> {
+    fn default() -> Self {
+        Transactions {
+            state: Mutex::new(State {
+                last_id: 0,
+                last_version: 0,
+                transactions: HashMap::new(),
+                watched: HashMap::new(),
+                prepared: HashMap::new(),
+                prepared_keys: HashMap::new(),
+            }),
+            committing: Mutex::new(()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Transactions<K, V> {
+    /// Begins a transaction, returning its id. Ids count up from the clock in microseconds, so a
+    /// restarted server doesn't take commits meant for transactions it lost.
+    pub fn begin(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<u64> = state
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.last_active.elapsed() > TRANSACTION_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(transaction) = state.transactions.remove(&id) {
+                state.unwatch(transaction.reads);
+            }
+        }
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let id = clock.max(state.last_id + 1);
+        state.last_id = id;
+        state.transactions.insert(
+            id,
+            Transaction {
+                last_active: Instant::now(),
+                reads: HashMap::new(),
+                writes: Vec::new(),
+            },
+        );
+        id
+    }
+
+    /// Notes that transaction `id` reads `key`, which the caller reads right after. Returns the
+    /// write the transaction buffered for the key if any, to be answered instead.
+    pub fn read(&self, id: u64, key: &K) -> Result<Option<KvRequest<K, V>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let transaction = state
+            .transactions
+            .get_mut(&id)
+            .ok_or(KvsError::UnknownTransaction)?;
+        transaction.last_active = Instant::now();
+        let buffered = transaction
+            .writes
+            .iter()
+            .rev()
+            .find(|write| write.key() == Some(key));
+        if let Some(buffered) = buffered {
+            return Ok(Some(buffered.clone()));
+        }
+        if !transaction.reads.contains_key(key) {
+            let watched = state.watched.entry(key.clone()).or_insert(Watched {
+                readers: 0,
+                version: 0,
+            });
+            watched.readers += 1;
+            transaction.reads.insert(key.clone(), watched.version);
+        }
+        Ok(None)
+    }
+
+    /// Buffers a set or remove until transaction `id` commits
+    pub fn write(&self, id: u64, request: KvRequest<K, V>) -> Result<()> {
+        if !matches!(request, KvRequest::Set(_) | KvRequest::Rm(_)) {
+            return Err(KvsError::Other);
+        }
+        let mut state = self.state.lock().unwrap();
+        let transaction = state
+            .transactions
+            .get_mut(&id)
+            .ok_or(KvsError::UnknownTransaction)?;
+        transaction.last_active = Instant::now();
+        transaction.writes.push(request);
+        Ok(())
+    }
+
+    /// Held by writes outside of transactions until they are noted with `written`, commits wait
+    /// for it to be dropped
+    pub fn write_guard(&self) -> MutexGuard<'_, ()> {
+        self.committing.lock().unwrap()
+    }
+
+    /// Must be called for every write to the store, so that transactions that read the key
+    /// before can't commit
+    pub fn written(&self, key: &K) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(watched) = state.watched.get_mut(key) {
+            state.last_version += 1;
+            watched.version = state.last_version;
+        }
+    }
+
+    /// Ends transaction `id`, returning its writes for the caller to apply before dropping the
+    /// commit. Fails with `KvsError::Conflict` if a key it read was written since. Prepared
+    /// transactions always commit.
+    pub fn commit(&self, id: u64) -> Result<Commit<'_, K, V>> {
+        let committing = self.committing.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        if let Some(writes) = state.release(id) {
+            return Ok(Commit {
+                writes,
+                prepared: true,
+                _committing: committing,
+            });
+        }
+        let transaction = state.take_checked(id)?;
+        Ok(Commit {
+            writes: transaction.writes,
+            prepared: false,
+            _committing: committing,
+        })
+    }
+
+    /// Checks transaction `id` like a commit would and holds on to its writes until it is
+    /// committed or aborted, returning them for the caller to persist
+    pub fn prepare(&self, id: u64) -> Result<Vec<KvRequest<K, V>>> {
+        let _committing = self.committing.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let transaction = state.take_checked(id)?;
+        state.hold(id, transaction.writes.clone());
+        Ok(transaction.writes)
+    }
+
+    /// Holds on to the writes of a transaction prepared before a restart
+    pub fn restore(&self, id: u64, writes: Vec<KvRequest<K, V>>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_id = state.last_id.max(id);
+        state.hold(id, writes);
+    }
+
+    /// Ends transaction `id` without applying its writes, returning whether it was prepared
+    pub fn abort(&self, id: u64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.release(id).is_some() {
+            return Ok(true);
+        }
+        let transaction = state
+            .transactions
+            .remove(&id)
+            .ok_or(KvsError::UnknownTransaction)?;
+        state.unwatch(transaction.reads);
+        Ok(false)
+    }
+}
@@ -0,0 +1,363 @@
+//! Values built up by merging operands into them instead of being read, changed and set again:
+//! counters, HyperLogLog sketches for counting distinct items, bitmaps, lists, sets and maps.
+//!
+//! Operands are `ValueOp`s encoded as JSON, merged by `ValueMerge` on stores of strings opened
+//! with it as their merge operator. Values stay plain strings, so they can be read with a get and
+//! decoded with `HyperLogLog::decode`, `Bitmap::decode`, `List::decode`, `Set::decode` or
+//! `Map::decode`.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::engine::MergeOperator;
+use crate::{stable_hash, KvsError, Result};
+
+const HLL_PREFIX: &str = "hll:";
+const BITMAP_PREFIX: &str = "bitmap:";
+const LIST_PREFIX: &str = "list:";
+const SET_PREFIX: &str = "set:";
+const MAP_PREFIX: &str = "map:";
+// Bits of the hash picking the register, 1024 registers for a standard error of about 3%
+const HLL_PRECISION: u32 = 10;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+/// Bits a bitmap can hold, setting bits past it fails
+pub const MAX_BITMAP_BITS: u64 = 1 << 23;
+
+/// Operand merged into a value
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ValueOp {
+    /// Adds to a counter, which starts at 0
+    Incr(i64),
+    /// Adds items to a HyperLogLog sketch
+    PfAdd(Vec<String>),
+    /// Sets or clears a bit of a bitmap
+    SetBit((u64, bool)),
+    /// Pushes items to the front of a list, each in turn
+    LPush(Vec<String>),
+    /// Pushes items to the back of a list
+    RPush(Vec<String>),
+    /// Takes the item at the front of a list off, removing the list once empty
+    LPop,
+    /// Takes the item at the back of a list off, removing the list once empty
+    RPop,
+    /// Adds members to a set
+    SAdd(Vec<String>),
+    /// Removes members from a set, removing the set once empty
+    SRem(Vec<String>),
+    /// Sets one field of a map, leaving the others as they are
+    HSet((String, String)),
+    /// Removes fields from a map, removing the map once empty
+    HDel(Vec<String>),
+}
+
+impl ValueOp {
+    pub fn encode(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn decode(operand: &str) -> Result<ValueOp> {
+        Ok(serde_json::from_str(operand)?)
+    }
+
+    /// Value after applying this to `existing`, none if it leaves nothing
+    pub fn apply(&self, existing: Option<&str>) -> Result<Option<String>> {
+        let value = match self {
+            ValueOp::Incr(by) => {
+                let count: i64 = match existing {
+                    Some(count) => count.parse().map_err(|_| KvsError::InvalidValue)?,
+                    None => 0,
+                };
+                count.saturating_add(*by).to_string()
+            }
+            ValueOp::PfAdd(items) => {
+                let mut hll = match existing {
+                    Some(hll) => HyperLogLog::decode(hll)?,
+                    None => HyperLogLog::new(),
+                };
+                for item in items {
+                    hll.add(item.as_bytes());
+                }
+                hll.encode()
+            }
+            ValueOp::SetBit((bit, value)) => {
+                let mut bitmap = match existing {
+                    Some(bitmap) => Bitmap::decode(bitmap)?,
+                    None => Bitmap::default(),
+                };
+                bitmap.set(*bit, *value)?;
+                bitmap.encode()
+            }
+            ValueOp::LPush(items) | ValueOp::RPush(items) => {
+                let mut list = existing.map(List::decode).transpose()?.unwrap_or_default();
+                for item in items {
+                    match self {
+                        ValueOp::LPush(_) => list.items.push_front(item.clone()),
+                        _ => list.items.push_back(item.clone()),
+                    }
+                }
+                list.encode()
+            }
+            ValueOp::LPop | ValueOp::RPop => {
+                let mut list = existing.map(List::decode).transpose()?.unwrap_or_default();
+                match self {
+                    ValueOp::LPop => list.items.pop_front(),
+                    _ => list.items.pop_back(),
+                };
+                if list.items.is_empty() {
+                    return Ok(None);
+                }
+                list.encode()
+            }
+            ValueOp::SAdd(members) | ValueOp::SRem(members) => {
+                let mut set = existing.map(Set::decode).transpose()?.unwrap_or_default();
+                for member in members {
+                    match self {
+                        ValueOp::SAdd(_) => set.members.insert(member.clone()),
+                        _ => set.members.remove(member),
+                    };
+                }
+                if set.members.is_empty() {
+                    return Ok(None);
+                }
+                set.encode()
+            }
+            ValueOp::HSet((field, value)) => {
+                let mut map = existing.map(Map::decode).transpose()?.unwrap_or_default();
+                map.fields.insert(field.clone(), value.clone());
+                map.encode()
+            }
+            ValueOp::HDel(fields) => {
+                let mut map = existing.map(Map::decode).transpose()?.unwrap_or_default();
+                for field in fields {
+                    map.fields.remove(field);
+                }
+                if map.fields.is_empty() {
+                    return Ok(None);
+                }
+                map.encode()
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Merge operator applying `ValueOp`s. Operands that can't be decoded or don't fit the value,
+/// like an increment of something that isn't a number, are logged and leave the value as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValueMerge;
+
+impl MergeOperator<String, String> for ValueMerge {
+    fn merge(&self, key: &String, existing: Option<String>, operand: String) -> Option<String> {
+        match ValueOp::decode(&operand).and_then(|op| op.apply(existing.as_deref())) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Skipping operand {} of {}: {:?}", operand, key, e);
+                existing
+            }
+        }
+    }
+}
+
+/// Sketch estimating how many distinct items were added to it in a fixed kilobyte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+
+    pub fn add(&mut self, item: &[u8]) {
+        let hash = stable_hash(item);
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION).leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    /// Adds the items of `other`, as if they had been added to this sketch
+    pub fn union(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    /// Estimate of the distinct items added
+    pub fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-(*register as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        // Linear counting is more accurate while few registers were hit
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}{}", HLL_PREFIX, to_hex(&self.registers))
+    }
+
+    pub fn decode(value: &str) -> Result<HyperLogLog> {
+        let registers = value
+            .strip_prefix(HLL_PREFIX)
+            .and_then(from_hex)
+            .filter(|registers| registers.len() == HLL_REGISTERS)
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(HyperLogLog { registers })
+    }
+}
+
+/// Bits addressed by their index, growing as higher bits are set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    bytes: Vec<u8>,
+}
+
+impl Bitmap {
+    /// Fails with `KvsError::InvalidValue` past `MAX_BITMAP_BITS`
+    pub fn set(&mut self, bit: u64, value: bool) -> Result<()> {
+        if bit >= MAX_BITMAP_BITS {
+            return Err(KvsError::InvalidValue);
+        }
+        let byte = (bit / 8) as usize;
+        if byte >= self.bytes.len() {
+            if !value {
+                return Ok(());
+            }
+            self.bytes.resize(byte + 1, 0);
+        }
+        let mask = 1 << (bit % 8);
+        if value {
+            self.bytes[byte] |= mask;
+        } else {
+            self.bytes[byte] &= !mask;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, bit: u64) -> bool {
+        self.bytes
+            .get((bit / 8) as usize)
+            .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Number of bits set
+    pub fn count(&self) -> u64 {
+        self.bytes.iter().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}{}", BITMAP_PREFIX, to_hex(&self.bytes))
+    }
+
+    pub fn decode(value: &str) -> Result<Bitmap> {
+        let bytes = value
+            .strip_prefix(BITMAP_PREFIX)
+            .and_then(from_hex)
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(Bitmap { bytes })
+    }
+}
+
+/// Items in order, encoded as a JSON array
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct List {
+    pub items: VecDeque<String>,
+}
+
+impl List {
+    pub fn encode(&self) -> String {
+        // Serializing strings can't fail
+        let items = serde_json::to_string(&self.items).unwrap_or_default();
+        format!("{}{}", LIST_PREFIX, items)
+    }
+
+    pub fn decode(value: &str) -> Result<List> {
+        let items = value
+            .strip_prefix(LIST_PREFIX)
+            .and_then(|items| serde_json::from_str(items).ok())
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(List { items })
+    }
+}
+
+/// Distinct members in ascending order, encoded as a JSON array
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Set {
+    pub members: BTreeSet<String>,
+}
+
+impl Set {
+    pub fn encode(&self) -> String {
+        // Serializing strings can't fail
+        let members = serde_json::to_string(&self.members).unwrap_or_default();
+        format!("{}{}", SET_PREFIX, members)
+    }
+
+    pub fn decode(value: &str) -> Result<Set> {
+        let members = value
+            .strip_prefix(SET_PREFIX)
+            .and_then(|members| serde_json::from_str(members).ok())
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(Set { members })
+    }
+}
+
+/// Fields and their values in ascending order of field, encoded as a JSON object
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Map {
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Map {
+    pub fn encode(&self) -> String {
+        // Serializing strings can't fail
+        let fields = serde_json::to_string(&self.fields).unwrap_or_default();
+        format!("{}{}", MAP_PREFIX, fields)
+    }
+
+    pub fn decode(value: &str) -> Result<Map> {
+        let fields = value
+            .strip_prefix(MAP_PREFIX)
+            .and_then(|fields| serde_json::from_str(fields).ok())
+            .ok_or(KvsError::InvalidValue)?;
+        Ok(Map { fields })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
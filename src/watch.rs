@@ -0,0 +1,266 @@
+//! Key expiry and change notifications for the server.
+//!
+//! Expiry deadlines are only kept in memory, so keys set with a ttl stay around for good if the
+//! server restarts before they expire.
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::frame::{self, Framing};
+use crate::protocol::{KvRequest, KvResponse};
+use crate::Result;
+
+/// How long a subscriber gets to take an event before it is dropped
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredEvent<K> {
+    pub key: K,
+    /// When the ttl of the key ran out, the removal can happen a little after
+    pub expired_at: SystemTime,
+}
+
+/// Change to a key sent to the subscribers watching it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent<K, V> {
+    Set((K, V)),
+    Removed(K),
+    Expired(ExpiredEvent<K>),
+}
+
+impl<K, V> WatchEvent<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            WatchEvent::Set((key, _)) => key,
+            WatchEvent::Removed(key) => key,
+            WatchEvent::Expired(expired) => &expired.key,
+        }
+    }
+}
+
+struct Subscriber<K> {
+    /// Key being watched, or every key if not set
+    key: Option<K>,
+    /// Locked while an event is sent, so that events sent concurrently don't interleave
+    stream: Mutex<TcpStream>,
+    framing: Option<Framing>,
+}
+
+struct Expiries<K> {
+    deadlines: HashMap<K, SystemTime>,
+    by_deadline: BTreeSet<(SystemTime, K)>,
+}
+
+pub struct Watcher<K, V> {
+    subscribers: Mutex<Vec<Arc<Subscriber<K>>>>,
+    expiries: Mutex<Expiries<K>>,
+    _value: PhantomData<fn(V)>,
+}
+
+impl<K, V> Default for Watcher<K, V>
+where
+    K: Ord + Hash + Clone,
+{
+    fn default() -> Self {
+        Watcher {
+            subscribers: Mutex::new(Vec::new()),
+            expiries: Mutex::new(Expiries {
+                deadlines: HashMap::new(),
+                by_deadline: BTreeSet::new(),
+            }),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Watcher<K, V>
+where
+    K: Ord + Hash + Clone + Serialize,
+    V: Clone + Serialize,
+{
+    /// Answers the watch request on `stream`, then sends it events for `key`, or for every key,
+    /// until writing to it fails. No event can fall between the answer and the first event sent.
+    pub fn subscribe(
+        &self,
+        key: Option<K>,
+        stream: TcpStream,
+        framing: Option<Framing>,
+    ) -> Result<()> {
+        stream.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT))?;
+        let subscriber = Arc::new(Subscriber {
+            key,
+            stream: Mutex::new(stream),
+            framing,
+        });
+        // Events published from now on wait for the stream until the answer is sent
+        let stream = subscriber.stream.lock().unwrap();
+        self.subscribers.lock().unwrap().push(subscriber.clone());
+        let answered =
+            frame::write_message(&*stream, &KvResponse::<V> { value: Ok(None) }, framing);
+        drop(stream);
+        if answered.is_err() {
+            self.unsubscribe(&[subscriber]);
+        }
+        answered
+    }
+
+    /// Sends `event` to the subscribers watching its key, dropping those that can't take it.
+    /// Sending can take up to the write timeout, so it happens outside of the subscribers lock.
+    pub fn publish(&self, event: &WatchEvent<K, V>) {
+        let watching: Vec<_> = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|subscriber| subscriber.key.as_ref().is_none_or(|key| key == event.key()))
+            .cloned()
+            .collect();
+        let failed: Vec<_> = watching
+            .into_iter()
+            .filter(|subscriber| {
+                let stream = subscriber.stream.lock().unwrap();
+                match frame::write_message(&*stream, event, subscriber.framing) {
+                    Ok(_) => false,
+                    Err(e) => {
+                        debug!("Dropping subscriber: {:?}", e);
+                        true
+                    }
+                }
+            })
+            .collect();
+        if !failed.is_empty() {
+            self.unsubscribe(&failed);
+        }
+    }
+
+    fn unsubscribe(&self, dropped: &[Arc<Subscriber<K>>]) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            !dropped
+                .iter()
+                .any(|dropped| Arc::ptr_eq(subscriber, dropped))
+        });
+    }
+
+    /// Updates expiries and notifies subscribers after `request` was applied. Writes forwarded by
+    /// a cluster leader are handled like the write they carry.
+    pub fn applied(&self, request: &KvRequest<K, V>) {
+        match request {
+            KvRequest::Set((key, value)) | KvRequest::SetIf((key, value, _)) => {
+                self.clear_expiry(key);
+                self.publish(&WatchEvent::Set((key.clone(), value.clone())));
+            }
+            KvRequest::SetEx((key, value, ttl)) => {
+                self.expire_at(key.clone(), SystemTime::now() + *ttl);
+                self.publish(&WatchEvent::Set((key.clone(), value.clone())));
+            }
+            KvRequest::Rm(key) => {
+                self.clear_expiry(key);
+                self.publish(&WatchEvent::Removed(key.clone()));
+            }
+            KvRequest::Replicate { request, .. }
+            | KvRequest::Idempotent { request, .. }
+            | KvRequest::IfVersion { request, .. } => self.applied(request),
+            KvRequest::WriteBatch(writes) => {
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => self.applied(&KvRequest::Set((key.clone(), value.clone()))),
+                        None => self.applied(&KvRequest::Rm(key.clone())),
+                    }
+                }
+            }
+            KvRequest::Handshake(_)
+            | KvRequest::Get(_)
+            | KvRequest::GetWithMeta(_)
+            | KvRequest::ReplicaGet { .. }
+            | KvRequest::Watch(_)
+            | KvRequest::Cluster(_)
+            | KvRequest::Stats
+            | KvRequest::Keys { .. }
+            | KvRequest::TopKeys { .. }
+            | KvRequest::MerkleHashes { .. }
+            | KvRequest::MerkleLeaf(_)
+            | KvRequest::Snapshot
+            | KvRequest::Topology
+            | KvRequest::SetLogLevel(_)
+            | KvRequest::ResumeWrites
+            | KvRequest::Scrub { .. }
+            // Locks and ephemeral keys are made of sets and removes, which are published
+            | KvRequest::Lock(_)
+            | KvRequest::Unlock(_)
+            | KvRequest::OpenSession(_)
+            | KvRequest::Heartbeat(_)
+            | KvRequest::CloseSession(_)
+            | KvRequest::SetEphemeral(_)
+            // Transactions are made of sets and removes, which are published once committed
+            | KvRequest::Begin
+            | KvRequest::TxnGet(_)
+            | KvRequest::TxnWrite { .. }
+            | KvRequest::Prepare(_)
+            | KvRequest::Commit(_)
+            | KvRequest::Abort(_)
+            | KvRequest::SetQuota { .. } => {}
+            // The requests of a batch are applied one at a time, each published on its own
+            KvRequest::Batch { .. } => {}
+            // The merged value isn't known without reading it back, so merges aren't published
+            KvRequest::Merge(_) | KvRequest::FetchMerge(_) => {}
+        }
+    }
+
+    pub fn expire_at(&self, key: K, at: SystemTime) {
+        let mut expiries = self.expiries.lock().unwrap();
+        if let Some(previous) = expiries.deadlines.insert(key.clone(), at) {
+            expiries.by_deadline.remove(&(previous, key.clone()));
+        }
+        expiries.by_deadline.insert((at, key));
+    }
+
+    pub fn clear_expiry(&self, key: &K) {
+        let mut expiries = self.expiries.lock().unwrap();
+        if let Some(previous) = expiries.deadlines.remove(key) {
+            expiries.by_deadline.remove(&(previous, key.clone()));
+        }
+    }
+
+    /// Whether the ttl of `key` has run out, even if it hasn't been removed yet
+    pub fn is_expired(&self, key: &K) -> bool {
+        self.expiries
+            .lock()
+            .unwrap()
+            .deadlines
+            .get(key)
+            .is_some_and(|at| *at <= SystemTime::now())
+    }
+
+    /// Keys whose ttl ran out by `now`, soonest first. They are only removed by `take_expired`.
+    pub fn expired(&self, now: SystemTime) -> Vec<K> {
+        self.expiries
+            .lock()
+            .unwrap()
+            .by_deadline
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// Forgets the deadline of `key` and returns it if it ran out by `now`. A write since the key
+    /// was listed by `expired` clears or moves its deadline, so this tells whether it still has
+    /// to be removed.
+    pub fn take_expired(&self, key: &K, now: SystemTime) -> Option<ExpiredEvent<K>> {
+        let mut expiries = self.expiries.lock().unwrap();
+        let at = *expiries.deadlines.get(key).filter(|at| **at <= now)?;
+        expiries.deadlines.remove(key);
+        expiries.by_deadline.remove(&(at, key.clone()));
+        Some(ExpiredEvent {
+            key: key.clone(),
+            expired_at: at,
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use kvs::audit::{AuditLog, AuditRecord};
+use kvs::protocol::KvRequest;
+use kvs::Result;
+use std::fs;
+use tempfile::TempDir;
+
+// Should attribute writes, skip reads and carry the token of idempotent writes
+#[test]
+fn audit_record_of_request() {
+    let set = KvRequest::<String, String>::Set(("key".to_owned(), "value".to_owned()));
+    let record = AuditRecord::of(&set, "127.0.0.1:5000".to_owned()).unwrap();
+    assert_eq!(record.op, "set");
+    assert_eq!(record.key, "key");
+    assert_eq!(record.principal, "127.0.0.1:5000");
+    assert_eq!(record.request_id, None);
+
+    let rm = KvRequest::<String, String>::Idempotent {
+        token: 7,
+        request: Box::new(KvRequest::Rm("key".to_owned())),
+    };
+    let record = AuditRecord::of(&rm, "client".to_owned()).unwrap();
+    assert_eq!(record.op, "rm");
+    assert_eq!(record.request_id, Some(7));
+
+    let get = KvRequest::<String, String>::Get("key".to_owned());
+    assert_eq!(AuditRecord::of(&get, "client".to_owned()), None);
+}
+
+// Should rotate files, keep only the latest ones and read back across reopening
+#[test]
+fn audit_log_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set =
+        |key: usize| KvRequest::<String, String>::Set((format!("key{}", key), "v".to_owned()));
+    let log = AuditLog::open(temp_dir.path(), 1024, 3)?;
+    for key in 0..50 {
+        log.append(&AuditRecord::of(&set(key), "client".to_owned()).unwrap())?;
+    }
+    drop(log);
+    let log = AuditLog::open(temp_dir.path(), 1024, 3)?;
+    log.append(&AuditRecord::of(&set(50), "other".to_owned()).unwrap())?;
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 3);
+
+    let mut records = Vec::new();
+    AuditLog::read(temp_dir.path(), |record| records.push(record))?;
+    assert!(records.len() < 51);
+    assert_eq!(records.last().unwrap().key, "key50");
+    assert_eq!(records.last().unwrap().principal, "other");
+    let keys: Vec<usize> = records
+        .iter()
+        .map(|record| record.key["key".len()..].parse().unwrap())
+        .collect();
+    assert!(keys.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use assert_cmd::prelude::*;
+use kvs::bench::{self, Target, Workload};
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use kvs::Result;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should load every key and split the operations between reads and writes
+#[test]
+fn run_workload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let workload = Workload {
+        keys: 100,
+        value_size: 10,
+        operations: 1001,
+        read_ratio: 0.5,
+        concurrency: 4,
+        seed: None,
+    };
+    let report = bench::run(&pool, store.clone(), &workload)?;
+    assert_eq!(report.reads.count + report.writes.count, 1001);
+    assert!(report.reads.count > 0 && report.writes.count > 0);
+    assert!(report.reads.p50 <= report.reads.p99 && report.reads.p99 <= report.reads.max);
+    assert_eq!(store.get("key0000000099".to_owned())?.unwrap().len(), 10);
+
+    let report = bench::run(
+        &pool,
+        store,
+        &Workload {
+            read_ratio: 1.0,
+            ..workload
+        },
+    )?;
+    assert_eq!(report.writes.count, 0);
+    Ok(())
+}
+
+// Every target gets the same operations, and the report has a row for each
+#[test]
+fn compare_targets() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(1)?;
+    let addr = "127.0.0.1:4324";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(server_dir.path())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let workload = Workload {
+        keys: 50,
+        value_size: 10,
+        operations: 200,
+        read_ratio: 0.5,
+        concurrency: 1,
+        seed: None,
+    };
+    let targets = [
+        Target::Kvs(kvs_dir.path().to_owned()),
+        Target::Sled(sled_dir.path().to_owned()),
+        Target::Remote(addr.parse().unwrap()),
+    ];
+    let comparison = bench::compare(&pool, &targets, &workload);
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+    let comparison = comparison?;
+
+    let rows = comparison.rows();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[2].target, "remote 127.0.0.1:4324");
+    assert!(rows.iter().all(|row| row.operations == 200));
+    assert_eq!(rows[0].relative_throughput, 1.0);
+    let csv = comparison.to_csv();
+    assert_eq!(csv.lines().count(), 4);
+    assert!(csv.lines().nth(2).unwrap().starts_with("sled,200,"));
+    let json: serde_json::Value = serde_json::from_str(&comparison.to_json()?)?;
+    assert_eq!(json[1]["target"], "sled");
+
+    let store = KvStore::<String, String>::open(kvs_dir.path())?;
+    let sled = SledKvsEngine::new(sled_dir.path())?;
+    for index in 0..50 {
+        let key = format!("key{:010}", index);
+        assert_eq!(store.get(key.clone())?, sled.get(key)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use kvs::engine::cache::{CacheEngine, Lfu, Lru, TtlFirst};
+use kvs::engine::KvsEngine;
+use kvs::{KvsError, Result};
+use std::thread;
+use std::time::Duration;
+
+// Each entry weighs 10 bytes, so a 30 byte cache holds three of them
+fn entry(id: u32) -> (String, String) {
+    (format!("key{}", id), format!("value{}", id))
+}
+
+// Should evict the least recently used entry, reads counting as use
+#[test]
+fn lru_eviction() -> Result<()> {
+    let cache = CacheEngine::new(30, Lru::default());
+    for id in 1..=3 {
+        let (key, value) = entry(id);
+        cache.set(key, value)?;
+    }
+    assert_eq!(cache.get("key1".to_owned())?, Some("value1".to_owned()));
+    let (key, value) = entry(4);
+    cache.set(key, value)?;
+    assert_eq!(cache.get("key2".to_owned())?, None);
+    assert!(cache.contains_key("key1".to_owned())?);
+    assert!(cache.contains_key("key3".to_owned())?);
+
+    let stats = cache.stats()?;
+    assert_eq!(stats.entries, 3);
+    assert_eq!(stats.bytes, 30);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+
+    assert!(matches!(
+        cache.set("big".to_owned(), "v".repeat(100)),
+        Err(KvsError::OutOfSpace)
+    ));
+    cache.remove("key1".to_owned())?;
+    assert_eq!(cache.stats()?.bytes, 20);
+    Ok(())
+}
+
+// Should evict the least frequently read entry
+#[test]
+fn lfu_eviction() -> Result<()> {
+    let cache = CacheEngine::new(30, Lfu::default());
+    for id in 1..=3 {
+        let (key, value) = entry(id);
+        cache.set(key, value)?;
+    }
+    for _ in 0..3 {
+        cache.get("key1".to_owned())?;
+        cache.get("key3".to_owned())?;
+    }
+    cache.get("key2".to_owned())?;
+    let (key, value) = entry(4);
+    cache.set(key, value)?;
+    assert!(!cache.contains_key("key2".to_owned())?);
+    assert!(cache.contains_key("key1".to_owned())?);
+    Ok(())
+}
+
+// Should evict entries with a ttl first, and drop them once it runs out
+#[test]
+fn ttl_first_eviction() -> Result<()> {
+    let cache = CacheEngine::new(30, TtlFirst::default());
+    let (key, value) = entry(1);
+    cache.set(key, value)?;
+    let (key, value) = entry(2);
+    cache.set_with_ttl(key, value, Duration::from_secs(60))?;
+    let (key, value) = entry(3);
+    cache.set_with_ttl(key, value, Duration::from_millis(10))?;
+    let (key, value) = entry(4);
+    cache.set(key, value)?;
+    assert!(!cache.contains_key("key3".to_owned())?);
+    assert!(cache.contains_key("key1".to_owned())?);
+    assert!(cache.contains_key("key2".to_owned())?);
+
+    let (key, value) = entry(5);
+    cache.set(key, value)?;
+    assert!(!cache.contains_key("key2".to_owned())?);
+    cache.set_with_ttl(
+        "key6".to_owned(),
+        "value6".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(cache.get("key6".to_owned())?, None);
+    assert_eq!(
+        cache.scan_keys(None, 10)?,
+        vec!["key4".to_owned(), "key5".to_owned()]
+    );
+    Ok(())
+}
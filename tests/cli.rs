@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server to exit");
 
     let content = fs::read_to_string(&stderr_path)
         .expect("unable to read from stderr file")
@@ -180,15 +182,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server to exit");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -199,15 +202,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server to exit");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -219,19 +223,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--addr", addr, "--engine", engine])
+        .args(["--addr", addr, "--engine", engine])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server to exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -239,7 +244,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -247,7 +252,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -255,7 +260,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -263,7 +268,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -271,7 +276,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -279,7 +284,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -287,7 +292,23 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "set", "--nx", "key2", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ConditionNotMet"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "--xx", "key2", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -300,26 +321,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--addr", addr, "--engine", engine])
+        .args(["--addr", addr, "--engine", engine])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server to exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -337,3 +359,263 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Watchers should see sets and expiries, and expired keys should be gone
+#[test]
+fn cli_watch_key_expiry() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "watch", "key1"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key2", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1", "--ttl", "1"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let mut events = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    assert_eq!(events.next().unwrap().unwrap(), "set key1 value1");
+    assert_eq!(events.next().unwrap().unwrap(), "expired key1");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().expect("failed to wait for watcher to exit");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+}
+
+// Flags should override the config file, which should override the defaults
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "addr = [\"127.0.0.1:4007\"]\ndata_dir = \"data\"\nsync_policy = \"always\"\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008", "set", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4007", "get", "key1"])
+        .assert()
+        .failure();
+    assert!(temp_dir.path().join("data").join("store").exists());
+    assert!(!temp_dir.path().join("db").exists());
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+}
+
+// Config that can't be used should be reported before anything is opened
+#[test]
+fn cli_invalid_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    for (file, config, problem) in [
+        (
+            "unknown.toml",
+            "adress = \"127.0.0.1:4009\"\n",
+            "unknown field",
+        ),
+        ("cluster.toml", "node_id = 1\n", "node_id"),
+        (
+            "rate.toml",
+            "trace_sample_rate = 2.0\n",
+            "trace_sample_rate",
+        ),
+        ("sync.yaml", "sync_policy: sometimes\n", "sync_policy"),
+        (
+            "peers.yml",
+            "node_id: 1\npeers:\n  - id: 1\n    addr: 127.0.0.1:4010\n",
+            "peers",
+        ),
+    ] {
+        fs::write(temp_dir.path().join(file), config).unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--config", file])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(problem));
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "missing.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("missing.toml"));
+    assert!(!temp_dir.path().join("db").exists());
+}
+
+// The server, client and admin should all pick up the KVS_ variables
+#[test]
+fn cli_env_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "addr = [\"127.0.0.1:4011\"]\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_CONFIG", "kvs.toml")
+        .env("KVS_ADDR", "127.0.0.1:4012")
+        .env("KVS_DATA_DIR", "data")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4012")
+        .args(["set", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4011")
+        .args(["--addr", "127.0.0.1:4012", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4012")
+        .arg("stats")
+        .assert()
+        .success()
+        .stdout(contains("set:"));
+    assert!(temp_dir.path().join("data").join("store").exists());
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .env("KVS_DATA_DIR", "data")
+        .arg("compact")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+// Debug messages should only be logged once the level is turned up
+#[test]
+fn cli_log_level() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let get = |key: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "get", key])
+            .assert()
+            .success();
+    };
+
+    get("before");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["log-level", "--addr", addr, "debug"])
+        .assert()
+        .success()
+        .stdout("info -> debug\n");
+    get("after");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["log-level", "--addr", addr, "loud"])
+        .assert()
+        .failure();
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+
+    // Only the second get is logged, with a hash of its key instead of the key
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    assert_eq!(content.matches("request op=get").count(), 1);
+    assert!(!content.contains("after"));
+}
+
+// Sampled requests should be logged with their key at the default level
+#[test]
+fn cli_trace_sampling() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--trace-sample-rate", "1"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server to exit");
+
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    assert!(content.contains("request op=set key=\"key1\""));
+    assert!(content.contains("bytes=10"));
+    assert!(content.contains("outcome=ok"));
+}
@@ -0,0 +1,1033 @@
+use assert_cmd::prelude::*;
+use kvs::client::{ClientBuilder, KvsClient, RetryPolicy};
+use kvs::discovery::Discovery;
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::frame::{self, Compression, Encoding};
+use kvs::net::{parse_addr, SocketOptions, DEFAULT_PORT};
+use kvs::protocol::{Feature, Handshake, KvRequest, KvResponse};
+use kvs::sharded::ShardedClient;
+use kvs::values::ValueMerge;
+use kvs::KvsError;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, dir: &Path) -> Child {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap()
+}
+
+fn stop_server(mut child: Child) {
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server to exit");
+}
+
+// Should keep retrying until the server comes up
+#[test]
+fn retry_until_server_starts() {
+    let addr = "127.0.0.1:4300";
+    let temp_dir = TempDir::new().unwrap();
+    let client = KvsClient::new(addr.parse().unwrap()).with_retry_policy(RetryPolicy {
+        max_retries: 20,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(500),
+    });
+    assert!(matches!(
+        client
+            .clone()
+            .with_retry_policy(RetryPolicy::none())
+            .get("key1".to_owned()),
+        Err(KvsError::IOError(_))
+    ));
+
+    let dir = temp_dir.path().to_owned();
+    let starter = thread::spawn(move || {
+        thread::sleep(Duration::from_secs(1));
+        start_server(addr, &dir)
+    });
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    stop_server(starter.join().unwrap());
+}
+
+// A write sent again with the same token should get the first result instead of running again
+#[test]
+fn idempotent_retry() {
+    let addr = "127.0.0.1:4301";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let remove = KvRequest::Idempotent {
+        token: 42,
+        request: Box::new(KvRequest::Rm("key1".to_owned())),
+    };
+    assert_eq!(client.request(remove.clone()).unwrap(), None);
+    assert_eq!(client.request(remove).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::NonExistantKey)
+    ));
+    stop_server(server);
+}
+
+// Cached gets should see writes made through other clients
+#[test]
+fn cache_invalidation() {
+    let addr = "127.0.0.1:4302";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let cached = KvsClient::new(addr.parse().unwrap()).with_cache(100);
+    let other = KvsClient::new(addr.parse().unwrap());
+    // Give the cache time to subscribe
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(cached.get("key1".to_owned()).unwrap(), None);
+    other.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        cached.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    other.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        cached.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    // Its own writes are seen right away
+    cached.remove("key1".to_owned()).unwrap();
+    assert_eq!(cached.get("key1".to_owned()).unwrap(), None);
+    stop_server(server);
+}
+
+// Stats should count the requests the server answered by kind
+#[test]
+fn server_stats() {
+    let addr = "127.0.0.1:4303";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    for _ in 0..3 {
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    client.get("key1".to_owned()).unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.set.count, 3);
+    assert_eq!(stats.get.count, 1);
+    assert_eq!(stats.remove.count, 0);
+    assert!(stats.set.p50 > Duration::ZERO && stats.set.p50 <= stats.set.max);
+    assert!(stats.disk_bytes.is_some_and(|bytes| bytes > 0));
+    stop_server(server);
+}
+
+// Should page through every key of the server in order
+#[test]
+fn list_keys() {
+    let addr = "127.0.0.1:4304";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    for key_id in 0..2500 {
+        client
+            .set(format!("key{:04}", key_id), "value".to_owned())
+            .unwrap();
+    }
+    client.remove("key0000".to_owned()).unwrap();
+
+    let page = client.keys_page(None, 2).unwrap();
+    assert_eq!(page.keys, vec!["key0001".to_owned(), "key0002".to_owned()]);
+    assert!(!page.stale);
+    let page = client.keys_page(page.next, 2).unwrap();
+    assert_eq!(page.keys, vec!["key0003".to_owned(), "key0004".to_owned()]);
+    assert!(!page.stale);
+
+    // Cursors outlive writes, which mark the rest of the listing as stale
+    client.remove("key0005".to_owned()).unwrap();
+    let page = client.keys_page(page.next, 2).unwrap();
+    assert_eq!(page.keys, vec!["key0006".to_owned(), "key0007".to_owned()]);
+    assert!(page.stale);
+    assert!(matches!(
+        client.keys_page(Some("not a cursor".to_owned()), 2),
+        Err(KvsError::InvalidCursor)
+    ));
+
+    let keys: Vec<String> = client.keys().map(|key| key.unwrap()).collect();
+    assert_eq!(keys.len(), 2498);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    stop_server(server);
+}
+
+// Should hand a lock to one holder at a time with growing fencing tokens
+#[test]
+fn lock_with_lease() {
+    let addr = "127.0.0.1:4305";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    let lease = Duration::from_secs(60);
+    let first = client.lock("lock".to_owned(), lease).unwrap().unwrap();
+    assert_eq!(client.lock("lock".to_owned(), lease).unwrap(), None);
+    assert!(!client.unlock("lock".to_owned(), first + 1).unwrap());
+    assert!(client.unlock("lock".to_owned(), first).unwrap());
+    assert!(!client.unlock("lock".to_owned(), first).unwrap());
+
+    // Leases running out release the lock
+    let second = client
+        .lock("lock".to_owned(), Duration::from_millis(200))
+        .unwrap()
+        .unwrap();
+    assert!(second > first);
+    thread::sleep(Duration::from_millis(500));
+    let third = client.lock("lock".to_owned(), lease).unwrap().unwrap();
+    assert!(third > second);
+    assert!(!client.unlock("lock".to_owned(), second).unwrap());
+    assert_eq!(
+        client.get("lock".to_owned()).unwrap(),
+        Some(third.to_string())
+    );
+    stop_server(server);
+}
+
+// Should remove the ephemeral keys of sessions once they are closed or miss their heartbeats
+#[test]
+fn ephemeral_keys() {
+    let addr = "127.0.0.1:4306";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    let session = client.session(Duration::from_millis(600)).unwrap();
+    session.set("svc1".to_owned(), "addr1".to_owned()).unwrap();
+
+    let lapsed = client.open_session(Duration::from_millis(300)).unwrap();
+    client
+        .set_ephemeral("svc2".to_owned(), "addr2".to_owned(), lapsed)
+        .unwrap();
+    client
+        .set_ephemeral("svc3".to_owned(), "addr3".to_owned(), lapsed)
+        .unwrap();
+    // Overwritten keys outlive their session
+    client.set("svc3".to_owned(), "addr4".to_owned()).unwrap();
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(
+        client.get("svc1".to_owned()).unwrap(),
+        Some("addr1".to_owned())
+    );
+    assert_eq!(client.get("svc2".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("svc3".to_owned()).unwrap(),
+        Some("addr4".to_owned())
+    );
+    assert!(matches!(
+        client.heartbeat(lapsed),
+        Err(KvsError::UnknownSession)
+    ));
+    assert!(matches!(
+        client.set_ephemeral("svc5".to_owned(), "addr5".to_owned(), lapsed),
+        Err(KvsError::UnknownSession)
+    ));
+
+    session.close().unwrap();
+    assert_eq!(client.get("svc1".to_owned()).unwrap(), None);
+    stop_server(server);
+}
+
+// Should merge counters, lists, sets and maps on the server, popping items atomically
+#[test]
+fn composite_values() {
+    let addr = "127.0.0.1:4307";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.incr("hits".to_owned(), 3).unwrap();
+    client.incr("hits".to_owned(), 4).unwrap();
+    assert_eq!(client.get("hits".to_owned()).unwrap(), Some("7".to_owned()));
+
+    client
+        .rpush("queue".to_owned(), vec!["b".to_owned(), "c".to_owned()])
+        .unwrap();
+    client
+        .lpush("queue".to_owned(), vec!["a".to_owned()])
+        .unwrap();
+    assert_eq!(
+        client.lpop("queue".to_owned()).unwrap(),
+        Some("a".to_owned())
+    );
+    assert_eq!(
+        client.rpop("queue".to_owned()).unwrap(),
+        Some("c".to_owned())
+    );
+    assert_eq!(
+        client.lpop("queue".to_owned()).unwrap(),
+        Some("b".to_owned())
+    );
+    assert_eq!(client.lpop("queue".to_owned()).unwrap(), None);
+    assert_eq!(client.get("queue".to_owned()).unwrap(), None);
+
+    client
+        .sadd(
+            "tags".to_owned(),
+            vec!["x".to_owned(), "y".to_owned(), "x".to_owned()],
+        )
+        .unwrap();
+    client
+        .srem("tags".to_owned(), vec!["y".to_owned()])
+        .unwrap();
+    assert_eq!(
+        client.smembers("tags".to_owned()).unwrap(),
+        vec!["x".to_owned()]
+    );
+
+    client
+        .hset("user".to_owned(), "name".to_owned(), "ann".to_owned())
+        .unwrap();
+    client
+        .hset("user".to_owned(), "city".to_owned(), "oslo".to_owned())
+        .unwrap();
+    client
+        .hdel("user".to_owned(), vec!["city".to_owned()])
+        .unwrap();
+    assert_eq!(
+        client.hget("user".to_owned(), "name".to_owned()).unwrap(),
+        Some("ann".to_owned())
+    );
+    assert_eq!(client.hgetall("user".to_owned()).unwrap().len(), 1);
+    stop_server(server);
+}
+
+// Transactions should see their own writes, apply them on commit and fail on conflicting writes
+#[test]
+fn optimistic_transactions() {
+    let addr = "127.0.0.1:4308";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.set("from".to_owned(), "10".to_owned()).unwrap();
+    let mut txn = client.begin().unwrap();
+    assert_eq!(txn.get("from".to_owned()).unwrap(), Some("10".to_owned()));
+    txn.set("from".to_owned(), "7".to_owned()).unwrap();
+    txn.set("to".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(txn.get("from".to_owned()).unwrap(), Some("7".to_owned()));
+    assert_eq!(client.get("to".to_owned()).unwrap(), None);
+    txn.commit().unwrap();
+    assert_eq!(client.get("from".to_owned()).unwrap(), Some("7".to_owned()));
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("3".to_owned()));
+
+    // A write to a key read by the transaction makes it conflict
+    let mut txn = client.begin().unwrap();
+    txn.get("from".to_owned()).unwrap();
+    txn.remove("to".to_owned()).unwrap();
+    client.set("from".to_owned(), "0".to_owned()).unwrap();
+    let id = txn.id();
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("3".to_owned()));
+    assert!(matches!(
+        client.request(KvRequest::Commit(id)),
+        Err(KvsError::UnknownTransaction)
+    ));
+
+    // Writes to keys it didn't read don't
+    let mut txn = client.begin().unwrap();
+    txn.get("from".to_owned()).unwrap();
+    client.set("other".to_owned(), "1".to_owned()).unwrap();
+    txn.remove("to".to_owned()).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(client.get("to".to_owned()).unwrap(), None);
+
+    let mut txn = client.begin().unwrap();
+    txn.set("to".to_owned(), "1".to_owned()).unwrap();
+    txn.abort().unwrap();
+    assert_eq!(client.get("to".to_owned()).unwrap(), None);
+    stop_server(server);
+}
+
+// Transactions over shards should commit on every shard, or on none of them on a conflict
+#[test]
+fn sharded_transactions() {
+    let addrs = ["127.0.0.1:4309", "127.0.0.1:4310"];
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let mut servers: Vec<Child> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, dir)| start_server(addr, dir.path()))
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let shards = addrs
+        .iter()
+        .map(|addr| KvsClient::new(addr.parse().unwrap()))
+        .collect();
+    let client = ShardedClient::new(shards);
+    // A key on each shard
+    let keys: Vec<String> = (0..2)
+        .map(|shard| {
+            (0..)
+                .map(|key| format!("key{}", key))
+                .find(|key| client.shard_of(key).unwrap() == shard)
+                .unwrap()
+        })
+        .collect();
+    client.set(keys[0].clone(), "10".to_owned()).unwrap();
+
+    let mut txn = client.begin();
+    assert_eq!(txn.get(keys[0].clone()).unwrap(), Some("10".to_owned()));
+    txn.set(keys[0].clone(), "7".to_owned()).unwrap();
+    txn.set(keys[1].clone(), "3".to_owned()).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(client.get(keys[0].clone()).unwrap(), Some("7".to_owned()));
+    assert_eq!(client.get(keys[1].clone()).unwrap(), Some("3".to_owned()));
+    assert_eq!(client.shards()[0].get(keys[1].clone()).unwrap(), None);
+
+    let mut txn = client.begin();
+    txn.get(keys[0].clone()).unwrap();
+    txn.set(keys[0].clone(), "0".to_owned()).unwrap();
+    txn.set(keys[1].clone(), "0".to_owned()).unwrap();
+    client.set(keys[0].clone(), "8".to_owned()).unwrap();
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+    assert_eq!(client.get(keys[0].clone()).unwrap(), Some("8".to_owned()));
+    assert_eq!(client.get(keys[1].clone()).unwrap(), Some("3".to_owned()));
+
+    // Prepared transactions outlive a restart, and hold on to their keys until committed
+    let shard = client.shards()[1].clone();
+    let id: u64 = shard
+        .request(KvRequest::Begin)
+        .unwrap()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let write = KvRequest::TxnWrite {
+        txn: id,
+        request: Box::new(KvRequest::Set((keys[1].clone(), "5".to_owned()))),
+    };
+    shard.request(write).unwrap();
+    shard.request(KvRequest::Prepare(id)).unwrap();
+    stop_server(servers.pop().unwrap());
+    servers.push(start_server(addrs[1], temp_dirs[1].path()));
+    thread::sleep(Duration::from_secs(1));
+    let mut txn = client.begin();
+    txn.set(keys[1].clone(), "6".to_owned()).unwrap();
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+    shard.request(KvRequest::Commit(id)).unwrap();
+    assert_eq!(client.get(keys[1].clone()).unwrap(), Some("5".to_owned()));
+    for server in servers {
+        stop_server(server);
+    }
+}
+
+// Quorum reads should see the last quorum write, even with a replica down or left behind
+#[test]
+fn quorum_reads_and_writes() {
+    let addrs = ["127.0.0.1:4311", "127.0.0.1:4312", "127.0.0.1:4313"];
+    let temp_dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, dir)| start_server(addr, dir.path()))
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let shards = addrs
+        .iter()
+        .map(|addr| KvsClient::new(addr.parse().unwrap()).with_retry_policy(RetryPolicy::none()))
+        .collect();
+    let client = ShardedClient::new(shards).with_replication(3);
+    assert_eq!(client.replicas_of("key1").len(), 3);
+    client
+        .quorum_set("key1".to_owned(), "value1".to_owned(), 3)
+        .unwrap();
+    assert_eq!(
+        client.quorum_get("key1".to_owned(), 2).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.quorum_get("key1".to_owned(), 4),
+        Err(KvsError::NoQuorum)
+    ));
+
+    // A replica that was down while written still has the old value
+    stop_server(servers.pop().unwrap());
+    assert!(matches!(
+        client.quorum_set("key1".to_owned(), "value2".to_owned(), 3),
+        Err(KvsError::NoQuorum)
+    ));
+    client
+        .quorum_set("key1".to_owned(), "value3".to_owned(), 2)
+        .unwrap();
+    servers.push(start_server(addrs[2], temp_dirs[2].path()));
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        client.quorum_get("key1".to_owned(), 3).unwrap(),
+        Some("value3".to_owned())
+    );
+
+    client.quorum_remove("key1".to_owned(), 2).unwrap();
+    assert_eq!(client.quorum_get("key1".to_owned(), 3).unwrap(), None);
+    for server in servers {
+        stop_server(server);
+    }
+}
+
+// Repair should copy the writes a replica missed while down, and nothing once in sync
+#[test]
+fn anti_entropy_repair() {
+    let addrs = ["127.0.0.1:4314", "127.0.0.1:4315"];
+    let temp_dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, dir)| start_server(addr, dir.path()))
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let shards = addrs
+        .iter()
+        .map(|addr| KvsClient::new(addr.parse().unwrap()).with_retry_policy(RetryPolicy::none()))
+        .collect();
+    let client = ShardedClient::new(shards).with_replication(2);
+    for key in 0..100 {
+        client
+            .quorum_set(format!("key{}", key), "old".to_owned(), 2)
+            .unwrap();
+    }
+    // Not written by quorum writes, so not repaired
+    client.shards()[0]
+        .set("plain".to_owned(), "value".to_owned())
+        .unwrap();
+
+    stop_server(servers.pop().unwrap());
+    for key in 0..10 {
+        client
+            .quorum_set(format!("key{}", key), "new".to_owned(), 1)
+            .unwrap();
+    }
+    client.quorum_remove("key99".to_owned(), 1).unwrap();
+    servers.push(start_server(addrs[1], temp_dirs[1].path()));
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(client.repair(0, 1).unwrap(), 11);
+    assert_eq!(client.repair(1, 0).unwrap(), 0);
+    assert_eq!(client.shards()[1].get("plain".to_owned()).unwrap(), None);
+    stop_server(servers.remove(0));
+    assert_eq!(
+        client.quorum_get("key0".to_owned(), 1).unwrap(),
+        Some("new".to_owned())
+    );
+    assert_eq!(
+        client.quorum_get("key10".to_owned(), 1).unwrap(),
+        Some("old".to_owned())
+    );
+    assert_eq!(client.quorum_get("key99".to_owned(), 1).unwrap(), None);
+    for server in servers {
+        stop_server(server);
+    }
+}
+
+// Adding a shard should move its keys over while writes keep going through the client
+#[test]
+fn add_shard_online() {
+    let addrs = ["127.0.0.1:4316", "127.0.0.1:4317", "127.0.0.1:4318"];
+    let temp_dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let servers: Vec<Child> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, dir)| start_server(addr, dir.path()))
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let shards = addrs[..2]
+        .iter()
+        .map(|addr| KvsClient::new(addr.parse().unwrap()))
+        .collect();
+    let client = ShardedClient::new(shards);
+    for key in 0..200 {
+        client
+            .set(format!("key{}", key), format!("value{}", key))
+            .unwrap();
+    }
+
+    let writer = {
+        let client = client.clone();
+        thread::spawn(move || {
+            for round in 0..300 {
+                let key = format!("key{}", round % 200);
+                client.set(key, format!("round{}", round)).unwrap();
+            }
+        })
+    };
+    let moved = client
+        .add_shard(KvsClient::new(addrs[2].parse().unwrap()))
+        .unwrap();
+    writer.join().unwrap();
+    assert!(moved > 0);
+    assert_eq!(client.shards().len(), 3);
+
+    for key in 0..200 {
+        let expected = match key {
+            0..=99 => format!("round{}", key + 200),
+            _ => format!("round{}", key),
+        };
+        let key = format!("key{}", key);
+        assert_eq!(client.get(key.clone()).unwrap(), Some(expected));
+        let owner = client.shard_of(&key).unwrap();
+        for (index, shard) in client.shards().iter().enumerate() {
+            if index != owner {
+                assert_eq!(shard.get(key.clone()).unwrap(), None);
+            }
+        }
+    }
+    for server in servers {
+        stop_server(server);
+    }
+}
+
+// A replica should bootstrap from a snapshot and keep up with the changes made after it
+#[test]
+fn snapshot_bootstrap() {
+    let addr = "127.0.0.1:4319";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+    let client = KvsClient::new(addr.parse().unwrap());
+    for key in 0..500 {
+        client
+            .set(format!("key{}", key), format!("value{}", key))
+            .unwrap();
+    }
+    client.remove("key0".to_owned()).unwrap();
+    client.incr("counter".to_owned(), 5).unwrap();
+
+    let replica_dir = TempDir::new().unwrap();
+    let replica: KvStore<String, String> = KvStore::open(replica_dir.path())
+        .unwrap()
+        .with_merge_operator(ValueMerge);
+    let mut stream = client.snapshot().unwrap();
+    stream.restore(&replica).unwrap();
+    assert_eq!(replica.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        replica.get("key499".to_owned()).unwrap(),
+        Some("value499".to_owned())
+    );
+    assert_eq!(
+        replica.get("counter".to_owned()).unwrap(),
+        Some("5".to_owned())
+    );
+
+    let follower = {
+        let replica = replica.clone();
+        thread::spawn(move || stream.follow(&replica))
+    };
+    client.set("key1".to_owned(), "changed".to_owned()).unwrap();
+    client.remove("key2".to_owned()).unwrap();
+    client.incr("counter".to_owned(), 2).unwrap();
+    for _ in 0..50 {
+        if replica.get("counter".to_owned()).unwrap() == Some("7".to_owned()) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(
+        replica.get("counter".to_owned()).unwrap(),
+        Some("7".to_owned())
+    );
+    assert_eq!(
+        replica.get("key1".to_owned()).unwrap(),
+        Some("changed".to_owned())
+    );
+    assert_eq!(replica.get("key2".to_owned()).unwrap(), None);
+
+    stop_server(server);
+    assert!(follower.join().is_ok());
+}
+
+// Clients should find servers by name, and fail on names without addresses
+#[test]
+fn dns_discovery() {
+    let addr = "127.0.0.1:4320";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let discovery = Discovery::new("localhost:4320", Duration::from_secs(60)).unwrap();
+    assert!(discovery.addrs().contains(&addr.parse().unwrap()));
+    assert!(Discovery::new("nonexistent.invalid:4320", Duration::from_secs(60)).is_err());
+
+    let client = KvsClient::discover("localhost:4320", Duration::from_secs(1)).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.refresh_topology().unwrap().primary,
+        Some(addr.parse().unwrap())
+    );
+
+    let sharded = ShardedClient::discover(addr, Duration::from_millis(100)).unwrap();
+    assert_eq!(sharded.shards().len(), 1);
+    assert_eq!(
+        sharded.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(sharded.shards().len(), 1);
+    stop_server(server);
+}
+
+// Should listen on IPv4 and IPv6 wildcards with the same port, and take IPv6 literals
+#[test]
+fn dual_stack_listening() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "0.0.0.0:4321", "--addr", "[::]:4321"])
+        .current_dir(temp_dir.path())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(server.try_wait().unwrap().is_none());
+
+    let v4 = KvsClient::new(parse_addr("127.0.0.1:4321").unwrap());
+    let v6 = KvsClient::new(parse_addr("[::1]:4321").unwrap());
+    v4.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        v6.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "[::1]:4321", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    assert_eq!(
+        parse_addr("::1").unwrap(),
+        format!("[::1]:{}", DEFAULT_PORT).parse().unwrap()
+    );
+    assert_eq!(
+        parse_addr("[::1]").unwrap(),
+        format!("[::1]:{}", DEFAULT_PORT).parse().unwrap()
+    );
+    assert!(parse_addr("::1:4321:").is_err());
+    stop_server(server);
+}
+
+// Should serve clients with tuned sockets on both ends
+#[test]
+fn socket_options() {
+    let options = SocketOptions {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+        recv_buffer: Some(64 * 1024),
+        send_buffer: Some(64 * 1024),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    options.apply(&stream).unwrap();
+    assert!(stream.nodelay().unwrap());
+
+    let temp_dir = TempDir::new().unwrap();
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4322", "--nodelay", "--keepalive", "30"])
+        .args(["--recv-buffer", "65536", "--send-buffer", "65536"])
+        .current_dir(temp_dir.path())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = KvsClient::new("127.0.0.1:4322".parse().unwrap()).with_socket_options(options);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    stop_server(server);
+}
+
+// Should answer pipelined requests in order over one connection
+#[test]
+fn pipelined_requests() {
+    let addr = "127.0.0.1:4323";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+    let client = KvsClient::new(addr.parse().unwrap());
+
+    assert!(client.pipeline(Vec::new()).unwrap().is_empty());
+    let results = client
+        .pipeline(vec![
+            KvRequest::Set(("key1".to_owned(), "value1".to_owned())),
+            KvRequest::Set(("key2".to_owned(), "value2".to_owned())),
+            KvRequest::Get("key1".to_owned()),
+            KvRequest::Rm("key2".to_owned()),
+            KvRequest::Rm("key2".to_owned()),
+            KvRequest::Get("key2".to_owned()),
+            KvRequest::Watch(None),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 7);
+    assert!(matches!(results[0], Ok(None)));
+    assert!(matches!(&results[2], Ok(Some(value)) if value == "value1"));
+    assert!(matches!(results[3], Ok(None)));
+    assert!(matches!(results[4], Err(KvsError::NonExistantKey)));
+    assert!(matches!(results[5], Ok(None)));
+    assert!(results[6].is_err());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    stop_server(server);
+}
+
+// Should reject settings that can't work together, and serve with the ones that can
+#[test]
+fn client_builder() {
+    let addr = "127.0.0.1:4325";
+    let invalid = |builder: ClientBuilder| match builder.build() {
+        Err(KvsError::InvalidConfig(_)) => {}
+        result => panic!("expected an invalid config, got {:?}", result.map(|_| ())),
+    };
+    invalid(KvsClient::builder());
+    invalid(
+        KvsClient::builder()
+            .addr(addr.parse().unwrap())
+            .discover("localhost:4325", Duration::from_secs(1)),
+    );
+    invalid(
+        KvsClient::builder()
+            .discover("localhost:4325", Duration::from_secs(1))
+            .replicas(vec![addr.parse().unwrap()]),
+    );
+    invalid(
+        KvsClient::builder()
+            .addr(addr.parse().unwrap())
+            .timeout(Duration::ZERO),
+    );
+    invalid(
+        KvsClient::builder()
+            .addr(addr.parse().unwrap())
+            .retry_policy(RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::from_secs(2),
+                max_backoff: Duration::from_secs(1),
+            }),
+    );
+    invalid(
+        KvsClient::builder()
+            .addr(addr.parse().unwrap())
+            .compression(vec![Compression::Zstd, Compression::Zstd]),
+    );
+    invalid(KvsClient::builder().addr(addr.parse().unwrap()).cache(0));
+
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+    let client = KvsClient::builder()
+        .addr(addr.parse().unwrap())
+        .timeout(Duration::from_secs(5))
+        .retry_policy(RetryPolicy::none())
+        .encoding(Encoding::Json)
+        .compression(Vec::new())
+        .build()
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    stop_server(server);
+}
+
+// Writes expecting a version should only apply while the key is still at it, like HTTP If-Match
+// and If-None-Match
+#[test]
+fn versioned_writes() {
+    let addr = "127.0.0.1:4326";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    let key = || "key1".to_owned();
+    assert!(client
+        .set_if_version(key(), "value1".to_owned(), None)
+        .unwrap());
+    assert!(!client
+        .set_if_version(key(), "value2".to_owned(), None)
+        .unwrap());
+    let (_, meta) = client.get_with_meta(key()).unwrap().unwrap();
+    let version = meta.unwrap().seq;
+
+    assert!(client
+        .set_if_version(key(), "value2".to_owned(), Some(version))
+        .unwrap());
+    // The set moved the key to a new version
+    assert!(!client
+        .set_if_version(key(), "value3".to_owned(), Some(version))
+        .unwrap());
+    assert!(!client.remove_if_version(key(), Some(version)).unwrap());
+    assert_eq!(client.get(key()).unwrap(), Some("value2".to_owned()));
+
+    let (_, meta) = client.get_with_meta(key()).unwrap().unwrap();
+    assert!(client
+        .remove_if_version(key(), Some(meta.unwrap().seq))
+        .unwrap());
+    assert_eq!(client.get(key()).unwrap(), None);
+    assert!(matches!(
+        client.remove_if_version(key(), None),
+        Err(KvsError::NonExistantKey)
+    ));
+    stop_server(server);
+}
+
+// The server should report the keys it was asked for the most
+#[test]
+fn top_keys() {
+    let addr = "127.0.0.1:4327";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    for _ in 0..10 {
+        client.get("key1".to_owned()).unwrap();
+    }
+    for _ in 0..5 {
+        client.get("key2".to_owned()).unwrap();
+    }
+    client.get("key3".to_owned()).unwrap();
+
+    let top = client.top_keys(2).unwrap();
+    let keys: Vec<&str> = top.iter().map(|hot_key| hot_key.key.as_str()).collect();
+    assert_eq!(keys, ["key1", "key2"]);
+    assert!(top[0].count >= 11);
+    assert!(top[1].count >= 5);
+    stop_server(server);
+}
+
+// Requests of a batch should be answered in order, those of an atomic batch applied all together
+// or not at all
+#[test]
+fn batch() {
+    let addr = "127.0.0.1:4328";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    let results = client
+        .batch(
+            vec![
+                KvRequest::Set(("key1".to_owned(), "value1".to_owned())),
+                KvRequest::Get("key1".to_owned()),
+                KvRequest::Rm("key2".to_owned()),
+                KvRequest::Set(("key2".to_owned(), "value2".to_owned())),
+            ],
+            false,
+        )
+        .unwrap();
+    assert_eq!(results.len(), 4);
+    assert!(matches!(results[0], Ok(None)));
+    assert_eq!(results[1].as_ref().unwrap(), &Some("value1".to_owned()));
+    assert!(matches!(results[2], Err(KvsError::NonExistantKey)));
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    let results = client
+        .batch(
+            vec![
+                KvRequest::Set(("key1".to_owned(), "value3".to_owned())),
+                KvRequest::Get("key1".to_owned()),
+                KvRequest::Rm("key2".to_owned()),
+                KvRequest::Rm("key3".to_owned()),
+            ],
+            true,
+        )
+        .unwrap();
+    assert_eq!(results[1].as_ref().unwrap(), &Some("value3".to_owned()));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+
+    // Atomic batches only take gets, sets and removes, so nothing is applied
+    assert!(client
+        .batch(
+            vec![
+                KvRequest::Set(("key1".to_owned(), "value4".to_owned())),
+                KvRequest::Lock(("key4".to_owned(), Duration::from_secs(10))),
+            ],
+            true,
+        )
+        .is_err());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    assert_eq!(client.get("key4".to_owned()).unwrap(), None);
+    stop_server(server);
+}
+
+// Clients asking for version 1 in their handshake should be answered in plain JSON
+#[test]
+fn version_one_handshake() {
+    let addr = "127.0.0.1:4329";
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(addr, temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let handshake = Handshake {
+        version: 1,
+        ..Handshake::new(vec![Feature::Batching])
+    };
+    let request: KvRequest<String, String> = KvRequest::Handshake(handshake);
+    frame::write_message(&stream, &request, None).unwrap();
+    let negotiated: Result<Handshake, KvsError> =
+        frame::read_message(&stream, None).unwrap().unwrap();
+    let negotiated = negotiated.unwrap();
+    assert_eq!(negotiated.version, 1);
+    assert_eq!(negotiated.framing(), None);
+    let request = KvRequest::Set(("key1".to_owned(), "value1".to_owned()));
+    frame::write_message(&stream, &request, None).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let response: KvResponse<String> = frame::read_message(&stream, None).unwrap().unwrap();
+    assert!(matches!(response.value, Ok(None)));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    stop_server(server);
+}
@@ -0,0 +1,254 @@
+use assert_cmd::prelude::*;
+use kvs::client::{KvsClient, RetryPolicy};
+use kvs::protocol::KvRequest;
+use kvs::KvsError;
+use predicates::str::contains;
+use std::fs::{self, File};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const ADDRS: [&str; 3] = ["127.0.0.1:4200", "127.0.0.1:4201", "127.0.0.1:4202"];
+const REPLICA_ADDRS: [&str; 3] = ["127.0.0.1:4203", "127.0.0.1:4204", "127.0.0.1:4205"];
+const FAILOVER_ADDRS: [&str; 3] = ["127.0.0.1:4206", "127.0.0.1:4207", "127.0.0.1:4208"];
+const REPAIR_ADDRS: [&str; 3] = ["127.0.0.1:4209", "127.0.0.1:4210", "127.0.0.1:4211"];
+
+struct Node {
+    dir: TempDir,
+    child: Child,
+}
+
+impl Node {
+    fn start(addrs: &[&str], id: usize) -> Node {
+        let dir = TempDir::new().unwrap();
+        let mut args = vec![
+            "--addr".to_owned(),
+            addrs[id].to_owned(),
+            "--node-id".to_owned(),
+            id.to_string(),
+        ];
+        for (peer_id, peer_addr) in addrs.iter().enumerate().filter(|(i, _)| *i != id) {
+            args.push("--peer".to_owned());
+            args.push(format!("{}={}", peer_id, peer_addr));
+        }
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&args)
+            .current_dir(&dir)
+            .stderr(File::create(dir.path().join("stderr")).unwrap())
+            .spawn()
+            .unwrap();
+        Node { dir, child }
+    }
+
+    fn is_leader(&self) -> bool {
+        fs::read_to_string(self.dir.path().join("stderr"))
+            .unwrap()
+            .contains("became leader")
+    }
+
+    fn kill(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child
+            .wait()
+            .expect("failed to wait for server to exit");
+    }
+}
+
+fn wait_for_leader(nodes: &[&Node]) -> usize {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if let Some(leader) = nodes.iter().position(|node| node.is_leader()) {
+            return leader;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("no leader was elected");
+}
+
+fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(["--addr", addr]).args(args);
+    cmd
+}
+
+// Should elect a leader, replicate writes sent to any node and fail over when the leader dies
+#[test]
+fn cluster_failover() {
+    let mut nodes: Vec<Node> = (0..3).map(|id| Node::start(&ADDRS, id)).collect();
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>());
+    let follower = (leader + 1) % 3;
+
+    // Followers redirect the client to the leader
+    client(ADDRS[follower], &["set", "key1", "value1"])
+        .assert()
+        .success();
+    for addr in ADDRS {
+        client(addr, &["get", "key1"])
+            .assert()
+            .success()
+            .stdout(contains("value1"));
+    }
+
+    nodes[leader].kill();
+    let survivors: Vec<usize> = (0..3).filter(|id| *id != leader).collect();
+    let new_leader =
+        survivors[wait_for_leader(&survivors.iter().map(|id| &nodes[*id]).collect::<Vec<_>>())];
+    client(ADDRS[new_leader], &["set", "key2", "value2"])
+        .assert()
+        .success();
+    for id in survivors {
+        client(ADDRS[id], &["get", "key2"])
+            .assert()
+            .success()
+            .stdout(contains("value2"));
+    }
+
+    for (id, node) in nodes.iter_mut().enumerate() {
+        if id != leader {
+            node.kill();
+        }
+    }
+}
+
+// Should read from followers within the staleness bound and from the leader past it
+#[test]
+fn replica_reads() {
+    let mut nodes: Vec<Node> = (0..3).map(|id| Node::start(&REPLICA_ADDRS, id)).collect();
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>());
+    let followers: Vec<usize> = (0..3).filter(|id| *id != leader).collect();
+    let primary = KvsClient::new(REPLICA_ADDRS[leader].parse().unwrap()).with_replicas(
+        followers
+            .iter()
+            .map(|id| REPLICA_ADDRS[*id].parse().unwrap())
+            .collect(),
+    );
+
+    primary.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    for _ in 0..10 {
+        assert_eq!(
+            primary.get_with_max_lag("key1".to_owned(), 0).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    // A restarted follower missed every write, which it learns from the leader's heartbeats
+    let lagging = followers[0];
+    nodes[lagging].kill();
+    nodes[lagging] = Node::start(&REPLICA_ADDRS, lagging);
+    thread::sleep(Duration::from_secs(1));
+    let replica = KvsClient::new(REPLICA_ADDRS[lagging].parse().unwrap());
+    let request = KvRequest::ReplicaGet {
+        key: "key1".to_owned(),
+        max_lag: 0,
+    };
+    assert!(matches!(
+        replica.request(request),
+        Err(KvsError::ReplicaLagging(lag)) if lag > 0
+    ));
+    let request = KvRequest::ReplicaGet {
+        key: "key1".to_owned(),
+        max_lag: u64::MAX,
+    };
+    assert_eq!(replica.request(request).unwrap(), None);
+    let primary = primary.with_replicas(vec![REPLICA_ADDRS[lagging].parse().unwrap()]);
+    assert_eq!(
+        primary.get_with_max_lag("key1".to_owned(), 0).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // Unreachable replicas are skipped too
+    nodes[lagging].kill();
+    assert_eq!(
+        primary.get_with_max_lag("key1".to_owned(), 0).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    for (id, node) in nodes.iter_mut().enumerate() {
+        if id != lagging {
+            node.kill();
+        }
+    }
+}
+
+// Should learn the nodes of the cluster from any of them and follow the primary when it fails
+#[test]
+fn client_failover() {
+    let mut nodes: Vec<Node> = (0..3).map(|id| Node::start(&FAILOVER_ADDRS, id)).collect();
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>());
+    let follower = (leader + 1) % 3;
+    let client = KvsClient::new(FAILOVER_ADDRS[follower].parse().unwrap())
+        .with_timeout(Duration::from_secs(1))
+        .with_retry_policy(RetryPolicy {
+            max_retries: 30,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        });
+
+    let topology = client.refresh_topology().unwrap();
+    assert_eq!(
+        topology.primary,
+        Some(FAILOVER_ADDRS[leader].parse().unwrap())
+    );
+    assert_eq!(topology.replicas.len(), 2);
+    assert_eq!(client.addr(), FAILOVER_ADDRS[leader].parse().unwrap());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    nodes[leader].kill();
+    // Clones share what the client learns
+    client
+        .clone()
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap();
+    assert_ne!(client.addr(), FAILOVER_ADDRS[leader].parse().unwrap());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    for (id, node) in nodes.iter_mut().enumerate() {
+        if id != leader {
+            node.kill();
+        }
+    }
+}
+
+// Should answer gets of a damaged record with the value of another node and write it back
+#[test]
+fn repair_on_get() {
+    let mut nodes: Vec<Node> = (0..3).map(|id| Node::start(&REPAIR_ADDRS, id)).collect();
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>());
+    client(REPAIR_ADDRS[leader], &["set", "key1", "healthy"])
+        .assert()
+        .success();
+
+    for entry in fs::read_dir(nodes[leader].dir.path().join("db/store")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("kvs".as_ref()) {
+            continue;
+        }
+        // A record holding another key than the one read is corrupt
+        let mut contents = fs::read(&path).unwrap();
+        if let Some(at) = contents.windows(4).position(|bytes| bytes == b"key1") {
+            contents[at + 3] = b'2';
+            fs::write(&path, contents).unwrap();
+        }
+    }
+    for _ in 0..2 {
+        client(REPAIR_ADDRS[leader], &["get", "key1"])
+            .assert()
+            .success()
+            .stdout(contains("healthy"));
+    }
+    let stderr = fs::read_to_string(nodes[leader].dir.path().join("stderr")).unwrap();
+    assert_eq!(stderr.matches("Repaired key key1").count(), 1);
+
+    for node in &mut nodes {
+        node.kill();
+    }
+}
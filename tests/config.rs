@@ -0,0 +1,113 @@
+use kvs::config::{ClientConfig, Env, KvsEngineType, Peer, ServerConfig};
+use kvs::engine::hasher::KeyHasher;
+use kvs::engine::store::SyncPolicy;
+use kvs::KvsError;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn env(vars: &[(&str, &str)]) -> Env {
+    Env::from_vars(
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    )
+}
+
+// Variables should override the file, and only the settings they name
+#[test]
+fn env_overrides_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        "addr = [\"127.0.0.1:4100\"]\nthreads = 4\nsync_policy = \"always\"\n",
+    )
+    .unwrap();
+    let mut config = ServerConfig::load(&path).unwrap();
+    config
+        .apply_env(&env(&[
+            ("KVS_ADDR", "127.0.0.1:4101, [::1]"),
+            ("KVS_ENGINE", "sled"),
+            ("KVS_DATA_DIR", "/var/lib/kvs"),
+            ("KVS_NODE_ID", "1"),
+            ("KVS_PEERS", "2=127.0.0.1:4102,3=127.0.0.1:4103"),
+            ("KVS_THREADS", ""),
+            ("KVS_KEY_HASHER", "fx"),
+            ("PATH", "/bin"),
+        ]))
+        .unwrap();
+
+    assert_eq!(
+        config.addr,
+        vec![
+            "127.0.0.1:4101".parse().unwrap(),
+            "[::1]:4000".parse().unwrap()
+        ]
+    );
+    assert_eq!(config.engine, Some(KvsEngineType::Sled));
+    assert_eq!(config.data_dir, PathBuf::from("/var/lib/kvs"));
+    assert_eq!(config.node_id, Some(1));
+    assert_eq!(
+        config.peers,
+        vec![
+            Peer {
+                id: 2,
+                addr: "127.0.0.1:4102".parse().unwrap()
+            },
+            Peer {
+                id: 3,
+                addr: "127.0.0.1:4103".parse().unwrap()
+            }
+        ]
+    );
+    assert_eq!(config.threads, 4);
+    assert_eq!(config.sync_policy, SyncPolicy::Always);
+    assert_eq!(config.key_hasher, KeyHasher::Fx);
+    assert_eq!(ServerConfig::default().key_hasher, KeyHasher::SipHash);
+    config.validate().unwrap();
+}
+
+// Variables that don't parse should be reported along with their value
+#[test]
+fn invalid_env() {
+    for (name, value) in [
+        ("KVS_THREADS", "many"),
+        ("KVS_ENGINE", "rocksdb"),
+        ("KVS_SYNC_POLICY", "sometimes"),
+        ("KVS_KEY_HASHER", "md5"),
+        ("KVS_PEERS", "2=127.0.0.1:4102,3"),
+        ("KVS_JSON", "yes"),
+    ] {
+        let env = env(&[(name, value)]);
+        let error = ServerConfig::default()
+            .apply_env(&env)
+            .and_then(|_| ClientConfig::from_env(&env).map(|_| ()))
+            .unwrap_err();
+        assert!(
+            matches!(&error, KvsError::InvalidConfig(problem) if problem.starts_with(&format!("{}={}", name, value))),
+            "{:?}",
+            error
+        );
+    }
+}
+
+// Clients should connect to the first address the server listens on
+#[test]
+fn client_env() {
+    assert_eq!(
+        ClientConfig::from_env(&env(&[])).unwrap(),
+        ClientConfig::default()
+    );
+    let config = ClientConfig::from_env(&env(&[
+        ("KVS_ADDR", "127.0.0.1:4104,127.0.0.1:4105"),
+        ("KVS_DATA_DIR", "data"),
+        ("KVS_TIMEOUT_MS", "1500"),
+        ("KVS_JSON", "true"),
+    ]))
+    .unwrap();
+    assert_eq!(config.addr, "127.0.0.1:4104".parse().unwrap());
+    assert_eq!(config.data_dir, PathBuf::from("data"));
+    assert_eq!(config.timeout, Some(Duration::from_millis(1500)));
+    assert!(config.json);
+}
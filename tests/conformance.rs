@@ -0,0 +1,14 @@
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::kvs_test_suite::run_engine_conformance;
+use kvs::Result;
+
+#[test]
+fn kv_store_conformance() -> Result<()> {
+    run_engine_conformance(KvStore::<String, String>::open)
+}
+
+#[test]
+fn sled_conformance() -> Result<()> {
+    run_engine_conformance(SledKvsEngine::new)
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "ffi")]
+
+use kvs::ffi::{
+    kvs_close, kvs_connect, kvs_free_string, kvs_get, kvs_open, kvs_open_sled, kvs_remove, kvs_set,
+    KvsHandle, KvsStatus,
+};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use tempfile::TempDir;
+
+const OK: c_int = KvsStatus::Ok as c_int;
+const NOT_FOUND: c_int = KvsStatus::NotFound as c_int;
+
+// Should open, get, set, remove and close through the C API, reporting errors as status codes
+#[test]
+fn c_api() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+    let key = CString::new("key1").unwrap();
+    let value = CString::new("value1").unwrap();
+    unsafe {
+        let mut handle: *mut KvsHandle = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &mut handle), OK);
+        assert_eq!(kvs_set(handle, key.as_ptr(), value.as_ptr()), OK);
+
+        let mut out: *mut c_char = ptr::null_mut();
+        assert_eq!(kvs_get(handle, key.as_ptr(), &mut out), OK);
+        assert_eq!(CStr::from_ptr(out).to_str().unwrap(), "value1");
+        kvs_free_string(out);
+
+        assert_eq!(kvs_remove(handle, key.as_ptr()), OK);
+        assert_eq!(kvs_remove(handle, key.as_ptr()), NOT_FOUND);
+        assert_eq!(kvs_get(handle, key.as_ptr(), &mut out), NOT_FOUND);
+        assert!(out.is_null());
+        assert_eq!(
+            kvs_set(handle, ptr::null(), value.as_ptr()),
+            KvsStatus::InvalidArgument as c_int
+        );
+        assert_eq!(kvs_close(handle), OK);
+    }
+}
+
+// Should open handles on sled databases and refuse malformed server addresses
+#[test]
+fn c_api_other_engines() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+    let key = CString::new("key1").unwrap();
+    let value = CString::new("value1").unwrap();
+    unsafe {
+        let mut handle: *mut KvsHandle = ptr::null_mut();
+        assert_eq!(kvs_open_sled(path.as_ptr(), &mut handle), OK);
+        assert_eq!(kvs_set(handle, key.as_ptr(), value.as_ptr()), OK);
+        let mut out: *mut c_char = ptr::null_mut();
+        assert_eq!(kvs_get(handle, key.as_ptr(), &mut out), OK);
+        assert_eq!(CStr::from_ptr(out).to_str().unwrap(), "value1");
+        kvs_free_string(out);
+        assert_eq!(kvs_close(handle), OK);
+
+        let addr = CString::new("not an address").unwrap();
+        assert_eq!(
+            kvs_connect(addr.as_ptr(), &mut handle),
+            KvsStatus::InvalidArgument as c_int
+        );
+    }
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "http")]
+
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::http::HttpGateway;
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use kvs::Result;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Sends one request, returning the status code and body of the response
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+// Should get, set and remove keys over REST with JSON bodies and fitting status codes
+#[test]
+fn rest_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let gateway = HttpGateway::new(store.clone());
+    thread::spawn(move || gateway.serve(listener, &pool));
+
+    let (status, _) = request(addr, "PUT", "/keys/user%3A1", r#"{"value":"alice"}"#);
+    assert_eq!(status, 204);
+    assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+
+    let (status, body) = request(addr, "GET", "/keys/user%3A1", "");
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        serde_json::json!({"key": "user:1", "value": "alice"})
+    );
+
+    assert_eq!(request(addr, "PUT", "/keys/user%3A1", "alice").0, 400);
+    assert_eq!(request(addr, "POST", "/keys/user%3A1", "").0, 405);
+    assert_eq!(request(addr, "GET", "/other", "").0, 404);
+
+    assert_eq!(request(addr, "DELETE", "/keys/user%3A1", "").0, 204);
+    assert_eq!(request(addr, "DELETE", "/keys/user%3A1", "").0, 404);
+    let (status, body) = request(addr, "GET", "/keys/user%3A1", "");
+    assert_eq!(status, 404);
+    assert!(body.contains("Key not found"));
+    Ok(())
+}
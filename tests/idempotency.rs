@@ -0,0 +1,54 @@
+#![cfg(feature = "server")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use kvs::idempotency::IdempotencyCache;
+use kvs::KvsError;
+
+// Writes with other tokens should run while one is in flight, and retries of it should wait for
+// its result rather than run again
+#[test]
+fn concurrent_retries() {
+    let cache: Arc<IdempotencyCache<String>> = Arc::default();
+    let runs = Arc::new(AtomicUsize::new(0));
+    let (release, released) = mpsc::channel::<()>();
+    let first = {
+        let (cache, runs) = (cache.clone(), runs.clone());
+        thread::spawn(move || {
+            cache.run(1, || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                released.recv().unwrap();
+                Ok(Some("first".to_owned()))
+            })
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    let retry = {
+        let (cache, runs) = (cache.clone(), runs.clone());
+        thread::spawn(move || {
+            cache.run(1, || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok(Some("retry".to_owned()))
+            })
+        })
+    };
+    assert_eq!(
+        cache.run(2, || Ok(Some("other".to_owned()))).unwrap(),
+        Some("other".to_owned())
+    );
+
+    release.send(()).unwrap();
+    assert_eq!(first.join().unwrap().unwrap(), Some("first".to_owned()));
+    assert_eq!(retry.join().unwrap().unwrap(), Some("first".to_owned()));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // Failed writes run again when retried
+    assert!(cache.run(3, || Err(KvsError::Other)).is_err());
+    assert_eq!(
+        cache.run(3, || Ok(Some("third".to_owned()))).unwrap(),
+        Some("third".to_owned())
+    );
+}
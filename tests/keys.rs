@@ -0,0 +1,167 @@
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::keys::{CompositeKey, KeyCodec};
+use kvs::Result;
+use tempfile::TempDir;
+
+// Sorting keys by their encodings should give the same order as sorting the keys themselves
+fn assert_order_preserved<K: KeyCodec + Clone + std::fmt::Debug>(mut keys: Vec<K>) -> Result<()> {
+    let mut encoded: Vec<Vec<u8>> = keys.iter().map(KeyCodec::to_key_bytes).collect();
+    encoded.sort();
+    keys.sort();
+    let decoded = encoded
+        .iter()
+        .map(|bytes| K::from_key_bytes(bytes))
+        .collect::<Result<Vec<K>>>()?;
+    assert_eq!(decoded, keys);
+    Ok(())
+}
+
+// Integers, strings and tuples of them should encode in key order and decode back
+#[test]
+fn order_preserving_encodings() -> Result<()> {
+    assert_order_preserved(vec![0u64, 1, 255, 256, 65536, u64::MAX, 42])?;
+    assert_order_preserved(vec![0u32, 1, 255, 256, u32::MAX])?;
+    assert_order_preserved(vec![i64::MIN, -65536, -256, -1, 0, 1, 255, i64::MAX])?;
+    assert_order_preserved(vec![i32::MIN, -1, 0, 1, i32::MAX])?;
+    let strings = ["", "a", "a\0", "a\0b", "ab", "b", "\0", "\u{ff}", "é"];
+    assert_order_preserved(strings.iter().map(|s| s.to_string()).collect())?;
+    assert_order_preserved(strings.iter().map(|s| s.as_bytes().to_vec()).collect())?;
+    let mut tuples = Vec::new();
+    for tenant in ["", "a", "a\0", "ab"] {
+        for at in [-5i64, 0, 7] {
+            tuples.push((tenant.to_owned(), at));
+        }
+    }
+    assert_order_preserved(tuples)?;
+    assert_order_preserved(vec![
+        (1u32, "x".to_owned(), -1i8),
+        (1, "x".to_owned(), 1),
+        (0, "y".to_owned(), 0),
+    ])?;
+
+    assert!(u64::from_key_bytes(&[0, 1]).is_err());
+    assert!(String::from_key_bytes(b"abc").is_err());
+    assert!(<(u8, u8)>::from_key_bytes(&[1, 2, 3]).is_err());
+    Ok(())
+}
+
+// Range scans of typed keys in sled should come out in key order
+#[test]
+fn sled_typed_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(temp_dir.path())?;
+    for tenant in [2u64, 1, 10] {
+        for at in [-3i64, 300, 0] {
+            engine.set_typed(&(tenant, at), format!("{}@{}", tenant, at))?;
+        }
+    }
+    assert_eq!(engine.get_typed(&(10u64, 0i64))?, Some("10@0".to_owned()));
+    engine.remove_typed(&(10u64, 0i64))?;
+    assert_eq!(engine.get_typed(&(10u64, 0i64))?, None);
+
+    let scanned = engine.scan_typed((1u64, i64::MIN)..(2, 300))?;
+    assert_eq!(
+        scanned,
+        vec![
+            ((1, -3), "1@-3".to_owned()),
+            ((1, 0), "1@0".to_owned()),
+            ((1, 300), "1@300".to_owned()),
+            ((2, -3), "2@-3".to_owned()),
+            ((2, 0), "2@0".to_owned()),
+        ]
+    );
+    let keys: Vec<(u64, i64)> = engine
+        .scan_typed::<(u64, i64)>(..)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys.len(), 8);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    Ok(())
+}
+
+// Composite keys should work as keys of the kvs store and list the items of a user with a prefix
+// scan, in both engines
+#[test]
+fn composite_key_prefix_scans() -> Result<()> {
+    type ItemKey = CompositeKey<(String, u32)>;
+    let item = |user: &str, item: u32| ItemKey::from((user.to_owned(), item));
+    assert_eq!(item("alice", 7).to_string(), "alice/7");
+    assert!(item("alice", 7).has_prefix(&"alice".to_owned()));
+    assert!(!item("alice", 7).has_prefix(&"al".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<ItemKey, String>::open(temp_dir.path())?;
+    for (user, id) in [
+        ("bob", 1),
+        ("alice", 10),
+        ("al", 3),
+        ("alice", 2),
+        ("alicia", 1),
+    ] {
+        store.set(item(user, id), format!("{}:{}", user, id))?;
+    }
+    store.remove(item("alice", 10))?;
+    store.set(item("alice", 5), "alice:5".to_owned())?;
+    drop(store);
+    let store = KvStore::<ItemKey, String>::open(temp_dir.path())?;
+    assert_eq!(store.get(item("bob", 1))?, Some("bob:1".to_owned()));
+    let expected = vec![
+        (item("alice", 2), "alice:2".to_owned()),
+        (item("alice", 5), "alice:5".to_owned()),
+    ];
+    assert_eq!(store.scan_prefix(&"alice".to_owned())?, expected);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled_dir.path())?;
+    for (key, value) in &expected {
+        engine.set_typed(key, value.clone())?;
+    }
+    engine.set_typed(&item("alicia", 1), "alicia:1".to_owned())?;
+    engine.set_typed(&item("al", 3), "al:3".to_owned())?;
+    assert_eq!(engine.scan_prefix_typed(&"alice".to_owned())?, expected);
+    Ok(())
+}
+
+// Integer keys should scan in numeric order and primitive values and bytes should round trip
+// through the store
+#[test]
+fn integer_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u64, Vec<u8>>::open(temp_dir.path())?;
+    for id in [256u64, 9, 10, 1 << 40, 0] {
+        store.set(id, id.to_be_bytes().to_vec())?;
+    }
+    drop(store);
+    let store = KvStore::<u64, Vec<u8>>::open(temp_dir.path())?;
+    assert_eq!(store.scan_keys(None, 10)?, vec![0, 9, 10, 256, 1 << 40]);
+    assert_eq!(store.scan_keys(Some(10), 1)?, vec![256]);
+    assert_eq!(store.get(256)?, Some(256u64.to_be_bytes().to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<i64, f64>::open(temp_dir.path())?;
+    for id in [3i64, -300, 0, -1, i64::MAX] {
+        store.set(id, id as f64 / 2.0)?;
+    }
+    assert_eq!(store.scan_keys(None, 10)?, vec![-300, -1, 0, 3, i64::MAX]);
+    assert_eq!(store.get(-1)?, Some(-0.5));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, bool>::open(temp_dir.path())?;
+    store.set(7, true)?;
+    assert_eq!(store.get(7)?, Some(true));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled_dir.path())?;
+    for id in [256u64, 9, 10] {
+        engine.set_typed(&id, id.to_string())?;
+    }
+    let keys: Vec<u64> = engine
+        .scan_typed::<u64>(..)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec![9, 10, 256]);
+    Ok(())
+}
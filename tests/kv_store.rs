@@ -1,7 +1,27 @@
-use kvs::engine::{store::KvStore, KvsEngine};
-use kvs::Result;
-use std::sync::{Arc, Barrier};
+use kvs::engine::analyze::AnalyzeOptions;
+use kvs::engine::codec::RecordCodec;
+use kvs::engine::follower::KvFollower;
+use kvs::engine::hasher::KeyHasher;
+use kvs::engine::namespace::{NamespaceQuota, NamespacedEngine};
+use kvs::engine::storage::{
+    LocalStorage, MemoryStorage, SegmentAppender, SegmentReader, SegmentStorage,
+};
+use kvs::engine::store::{KvStore, KvStoreOptions, OpenProgress, SyncPolicy};
+use kvs::engine::tail::{Change, ChangeEvent};
+use kvs::engine::{KvsEngine, MergeOperator};
+use kvs::values::{Bitmap, HyperLogLog, List, Map, ValueMerge, ValueOp};
+use kvs::{KvsError, Result};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -64,6 +84,338 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Should read and persist records that are still in the write buffer
+#[test]
+fn buffered_sync_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_policy: SyncPolicy::Buffered,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Reads of sealed segments shouldn't flush the records in the write buffer, which takes the
+// writer lock
+#[test]
+fn sealed_reads_skip_flush() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = KvStoreOptions {
+        sync_policy: SyncPolicy::Buffered,
+        max_segment_bytes: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_storage(Arc::new(storage.clone()), options)?;
+    store.set("key1".to_owned(), "x".repeat(300))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let active = storage
+        .list()?
+        .into_iter()
+        .filter(|name| name.ends_with(".kvs"))
+        .max()
+        .unwrap();
+
+    assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(300)));
+    assert_eq!(storage.len(&active)?, Some(0));
+    assert_eq!(
+        store.get_many(&["key1".to_owned()])?,
+        vec![Some("x".repeat(300))]
+    );
+    assert_eq!(storage.len(&active)?, Some(0));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(storage.len(&active)? > Some(0));
+    Ok(())
+}
+
+// Should ignore the preallocated tail of log files and reuse files retired by compaction
+#[test]
+fn preallocated_reused_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        preallocate_bytes: 4 * 1024 * 1024,
+        reuse_files: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // Open from disk again and keep appending after the last record
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let count_files = |extension: &str| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+            .count()
+    };
+    assert_eq!(count_files("kvs"), 1);
+    // Overwrite until compaction has run at least twice
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(count_files("kvs"), 1);
+    assert_eq!(count_files("free"), 1);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should persist records written with O_DSYNC and O_DIRECT across partially filled blocks
+#[cfg(target_os = "linux")]
+#[test]
+fn direct_io_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        dsync: true,
+        direct_io: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    // Open from disk again, append to the partially filled tail block and check all data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 500..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should start new segments once the active one is full and replay them in id order
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..2 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("kvs".as_ref()))
+        .count();
+    assert!(segments > 1);
+
+    // Open from disk again and check the latest values won
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value1".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should load pairs into hinted segments that win over earlier writes and lose to later ones
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set("removed".to_owned(), "old".to_owned())?;
+
+    let pairs = (0..1000)
+        .rev()
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .chain(std::iter::once(("key1".to_owned(), "last".to_owned())));
+    assert_eq!(store.bulk_load(pairs)?, 1000);
+    store.set("key2".to_owned(), "newer".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    let hints = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("hint".as_ref()))
+        .count();
+    assert!(hints > 1);
+
+    for reopen in [false, true] {
+        let store = if reopen {
+            KvStore::open_with_options(temp_dir.path(), options)?
+        } else {
+            store.clone()
+        };
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("newer".to_owned()));
+        assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Should merge a closed store down to its live keys, dropping tombstones
+#[test]
+fn compact_offline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..5 {
+        for key_id in 0..200 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    for key_id in 100..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+    let segment_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("kvs".as_ref()))
+            .count()
+    };
+    let segments_before = segment_count();
+
+    KvStore::<String, String>::compact_offline_with_options(temp_dir.path(), options)?;
+    assert!(segment_count() < segments_before);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+    for key_id in 100..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert!(store.tombstones_since(UNIX_EPOCH).is_empty());
+
+    // Writes after the merge should still win on the next open
+    store.set("key0".to_owned(), "newer".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("newer".to_owned()));
+    Ok(())
+}
+
+// Should report value sizes, the largest values and key counts per prefix
+#[test]
+fn analyze_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("user:{}", key_id), "x".repeat(10))?;
+    }
+    store.set("user:3".to_owned(), "x".repeat(1000))?;
+    store.set("session:1".to_owned(), "x".repeat(100))?;
+    store.set("plain".to_owned(), "x".repeat(10))?;
+    store.set("gone".to_owned(), "x".repeat(10))?;
+    store.remove("gone".to_owned())?;
+
+    let report = store.analyze(AnalyzeOptions {
+        prefix_delimiter: ':',
+        top: 2,
+    })?;
+    assert_eq!(report.keys, 22);
+    assert_eq!(report.tombstones, 1);
+    // Serialized strings carry a length prefix of 1 byte below 32 bytes and 2 or 3 above
+    assert_eq!(report.value_bytes, 20 * 11 + 1003 + 102);
+    assert_eq!(report.value_sizes[4], 20);
+    assert_eq!(report.value_sizes[7], 1);
+    assert_eq!(report.value_sizes[10], 1);
+    assert_eq!(
+        report.largest,
+        vec![("user:3".to_owned(), 1003), ("session:1".to_owned(), 102)]
+    );
+    assert_eq!(report.prefixes["user"].keys, 20);
+    assert_eq!(report.prefixes["user"].value_bytes, 19 * 11 + 1003);
+    assert_eq!(report.prefixes["session"].keys, 1);
+    assert_eq!(report.prefixes[""].keys, 1);
+    Ok(())
+}
+
+// Should replay files from before segments had ids in creation order, whatever order the
+// directory lists them in
+#[test]
+fn legacy_files_replay_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let write_segment = |name: &str, value: &str| -> Result<()> {
+        let store_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(store_dir.path())?;
+        store.set("key1".to_owned(), value.to_owned())?;
+        drop(store);
+        fs::copy(
+            store_dir.path().join(format!("{:020}.kvs", 0)),
+            temp_dir.path().join(name),
+        )?;
+        Ok(())
+    };
+    // Create the newer files first, their names also sort first as strings
+    let timestamps = [
+        "90000000", "8000000", "700000", "60000", "5000", "400", "30", "2",
+    ];
+    for timestamp in timestamps {
+        write_segment(&format!("{}.kvs", timestamp), timestamp)?;
+    }
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("90000000".to_owned()));
+
+    // Open from disk again, now using the manifest
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("90000000".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -82,6 +434,112 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1".to_owned())?);
+    Ok(())
+}
+
+// Should track writes and reads, and only move the limits in adaptive mode
+#[test]
+fn adaptive_tuning() -> Result<()> {
+    let overwrite = |store: &KvStore<String, String>| -> Result<()> {
+        for iter in 0..100 {
+            for key_id in 0..1000 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    overwrite(&store)?;
+    let stats = store.stats();
+    assert!(stats.compactions > 0);
+    assert!(stats.bytes_written > 0);
+    assert_eq!(stats.reads, 1);
+    assert_eq!(stats.read_latency.count, 1);
+    assert!(stats.write_latency.count > 0);
+    assert!(stats.write_latency.p50 <= stats.write_latency.p999);
+    assert!(stats.write_latency.p999 <= stats.write_latency.max);
+    assert!(stats.write_amplification() > 1.0);
+    assert_eq!(
+        stats.compaction_threshold,
+        KvStoreOptions::default().compaction_threshold
+    );
+
+    // Compaction of a small keyspace is cheap, so adaptive mode compacts more often
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        adaptive: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    overwrite(&store)?;
+    assert!(store.stats().compaction_threshold < options.compaction_threshold);
+    Ok(())
+}
+
+// Should keep tombstones through compaction and restarts during their grace period
+#[test]
+fn tombstone_grace_period() -> Result<()> {
+    let remove_and_compact = |store: &KvStore<String, String>| -> Result<()> {
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.remove("removed".to_owned())?;
+        let compactions = store.stats().compactions;
+        while store.stats().compactions == compactions {
+            for key_id in 0..1000 {
+                store.set(format!("key{}", key_id), "value".to_owned())?;
+            }
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        tombstone_grace_period: Duration::from_secs(3600),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    remove_and_compact(&store)?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open_with_options(temp_dir.path(), options)?;
+    let tombstones = store.tombstones_since(UNIX_EPOCH);
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].0, "removed");
+    assert_eq!(store.get("removed".to_owned())?, None);
+
+    // Without a grace period compaction drops tombstones right away
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    remove_and_compact(&store)?;
+    assert!(store.tombstones_since(UNIX_EPOCH).is_empty());
+    Ok(())
+}
+
+// Should not find removed keys after reopening the store
+#[test]
+fn remove_key_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -159,54 +617,1850 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Stripe counts are rounded up to a power of two
 #[test]
-fn concurrent_get() -> Result<()> {
+fn striped_index() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
-    }
-
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
-    }
+    let options = KvStoreOptions {
+        index_stripes: 3,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().index_stripes, 4);
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", thread, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
     for handle in handles {
         handle.join().unwrap();
     }
+    for thread in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let default = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(default.stats().index_stripes.is_power_of_two());
+    Ok(())
+}
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+// Every hash function of the key maps should hold the same keys, across reopening
+#[test]
+fn key_hashers() -> Result<()> {
+    for key_hasher in [KeyHasher::SipHash, KeyHasher::AHash, KeyHasher::Fx] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            key_hasher,
+            index_stripes: 4,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in (0..200).step_by(3) {
+            store.remove(format!("key{}", i))?;
+        }
+        drop(store);
+        let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+        for i in 0..200 {
+            let expected = (i % 3 != 0).then(|| format!("value{}", i));
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                expected,
+                "{:?}",
+                key_hasher
+            );
+        }
+        assert_eq!(store.keys().count(), 133);
     }
-    for handle in handles {
-        handle.join().unwrap();
+    Ok(())
+}
+
+// Should only set absent keys with set_nx and present keys with set_xx
+#[test]
+fn conditional_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_xx("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set_xx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert!(store.set_nx("key1".to_owned(), "value4".to_owned())?);
+
+    // Only one of many racing set_nx wins
+    let barrier = Arc::new(Barrier::new(16));
+    let handles: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .set_nx("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let winners = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|won| *won)
+        .count();
+    assert_eq!(winners, 1);
+    Ok(())
+}
+
+// Bulk loads past the disk limit should fail without setting anything
+#[test]
+fn bulk_load_disk_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_disk_bytes: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let pairs = |count| (0..count).map(|key_id| (format!("key{}", key_id), "v".repeat(100)));
+
+    assert_eq!(store.bulk_load(pairs(10))?, 10);
+    // Leaves the first load to be compacted away before giving up on the next one
+    assert_eq!(store.bulk_load(pairs(10))?, 10);
+    let err = store.bulk_load(pairs(100)).unwrap_err();
+    assert!(store.stats().compactions > 0);
+    assert!(matches!(err, KvsError::OutOfSpace));
+    assert!(store.stats().disk_bytes <= 4096);
+    assert_eq!(store.get("key10".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("v".repeat(100)));
+    Ok(())
+}
+
+// Sets past the disk limit should compact first and fail once nothing can be reclaimed
+#[test]
+fn disk_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_disk_bytes: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = "v".repeat(100);
+
+    // Overwrites fit as compaction reclaims the records they replaced
+    for _ in 0..200 {
+        store.set("key".to_owned(), value.clone())?;
     }
+    assert!(store.stats().compactions > 0);
+    assert!(store.stats().disk_bytes <= 4096);
+
+    let mut key_id = 0;
+    let err = loop {
+        match store.set(format!("key{}", key_id), value.clone()) {
+            Ok(()) => key_id += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(err, KvsError::OutOfSpace));
+    assert!(key_id > 10);
+    assert_eq!(store.disk_usage()?, Some(store.stats().disk_bytes));
 
+    // Removes still go through and make room again
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set(format!("key{}", key_id), value.clone())?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.stats().disk_bytes <= 4096);
+    assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+    Ok(())
+}
+
+// Namespaces should be accounted across restarts and kept to their quotas
+#[test]
+fn namespace_quotas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let quotas_path = temp_dir.path().join("quotas.json");
+    let store: KvStore<String, String> = KvStore::open(&temp_dir.path().join("store"))?;
+    store.set("a:1".to_owned(), "xx".to_owned())?;
+    store.set("plain".to_owned(), "x".to_owned())?;
+    let store = NamespacedEngine::new(store, Some(&quotas_path))?;
+    let namespaces = store.namespaces();
+    assert_eq!(namespaces.stats()?["a"].keys, 1);
+    assert_eq!(namespaces.stats()?["a"].bytes, 5);
+    assert_eq!(namespaces.stats()?[""].keys, 1);
+
+    let quota = NamespaceQuota {
+        max_keys: Some(2),
+        max_bytes: Some(12),
+    };
+    namespaces.set_quota("a".to_owned(), quota)?;
+    store.set("a:2".to_owned(), "yy".to_owned())?;
+    let err = store.set("a:3".to_owned(), "zz".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::QuotaExceeded(namespace) if namespace == "a"));
+    assert!(matches!(
+        store.set("a:1".to_owned(), "x".repeat(10)),
+        Err(KvsError::QuotaExceeded(_))
+    ));
+    assert!(!store
+        .set_nx("a:3".to_owned(), "zz".to_owned())
+        .unwrap_or(false));
+    assert_eq!(store.get("a:3".to_owned())?, None);
+
+    // Overwrites within the quota and writes to other namespaces still go through
+    store.set("a:1".to_owned(), "ww".to_owned())?;
+    store.set("b:1".to_owned(), "x".repeat(100))?;
+    store.remove("a:2".to_owned())?;
+    store.set("a:3".to_owned(), "zz".to_owned())?;
+    assert_eq!(namespaces.stats()?["a"].keys, 2);
+    assert_eq!(namespaces.stats()?["a"].bytes, 10);
+    drop(namespaces);
+    drop(store);
+
+    let store: KvStore<String, String> = KvStore::open(&temp_dir.path().join("store"))?;
+    let store = NamespacedEngine::new(store, Some(&quotas_path))?;
+    let stats = store.namespaces().stats()?;
+    assert_eq!(stats["a"].quota, quota);
+    assert_eq!(stats["a"].keys, 2);
+    assert_eq!(stats["b"].bytes, 103);
+    Ok(())
+}
+
+// A follower should pick up sets, removes, new segments and compactions of the writer
+#[test]
+fn follower() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        compaction_threshold: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let follower: KvFollower<String, String> = KvFollower::open(temp_dir.path(), Duration::ZERO)?;
+    assert_eq!(follower.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        follower.get("key99".to_owned())?,
+        Some("value99".to_owned())
+    );
+    assert_eq!(follower.get("key1".to_owned())?, None);
+
+    for _ in 0..100 {
+        store.set("key2".to_owned(), "v".repeat(100))?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert_eq!(follower.get("key2".to_owned())?, Some("v".repeat(100)));
+    assert_eq!(follower.scan_keys(None, 1000)?.len(), 99);
+
+    assert!(matches!(
+        follower.set("key0".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        follower.remove("key0".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    // Reads within the staleness bound don't look for new records
+    let lagging: KvFollower<String, String> =
+        KvFollower::open(temp_dir.path(), Duration::from_secs(3600))?;
+    store.set("late".to_owned(), "value".to_owned())?;
+    assert_eq!(lagging.get("late".to_owned())?, None);
+    lagging.refresh()?;
+    assert_eq!(lagging.get("late".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A tail should see every write in order, wait for new ones and report what it missed
+#[test]
+fn tail_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        change_log_capacity: 4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut tail = store.tail(0);
+    assert_eq!(
+        tail.next().unwrap()?,
+        ChangeEvent {
+            seq: 0,
+            change: Change::Set(("key1".to_owned(), "value1".to_owned())),
+        }
+    );
+    assert_eq!(
+        tail.next().unwrap()?.change,
+        Change::Removed("key1".to_owned())
+    );
+    assert_eq!(tail.next_timeout(Duration::from_millis(10))?, None);
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key2".to_owned(), "value2".to_owned()))
+    };
+    let event = tail.next().unwrap()?;
+    assert_eq!(event.seq, 2);
+    assert_eq!(
+        event.change,
+        Change::Set(("key2".to_owned(), "value2".to_owned()))
+    );
+    writer.join().unwrap()?;
+    assert_eq!(store.next_change_seq()?, 3);
+
+    for i in 0..5 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(matches!(
+        tail.next(),
+        Some(Err(KvsError::ChangesDropped(4)))
+    ));
+    assert_eq!(store.tail(4).next().unwrap()?.seq, 4);
+    Ok(())
+}
+
+// A snapshot should hold every live value and the seq the changes after it start at
+#[test]
+fn snapshot_then_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_merge_operator(ValueMerge);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.merge("counter".to_owned(), ValueOp::Incr(3).encode()?)?;
+    let (seq, mut entries) = store.snapshot()?;
+    entries.sort();
+    assert_eq!(seq, 4);
+    assert_eq!(
+        entries,
+        vec![
+            ("counter".to_owned(), "3".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let event = store.changes(seq)?.next().unwrap()?;
+    assert_eq!(
+        event.change,
+        Change::Set(("key3".to_owned(), "value3".to_owned()))
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        change_log_capacity: 0,
+        ..KvStoreOptions::default()
+    };
+    let store: KvStore<String, String> = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(matches!(
+        store.snapshot(),
+        Err(KvsError::ChangesUnsupported)
+    ));
+    Ok(())
+}
+
+// Reads as of a past time should answer from retained history, across compaction and reopening
+#[test]
+fn time_travel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        history_retention: Duration::from_secs(3600),
+        compaction_threshold: 1,
+        ..KvStoreOptions::default()
+    };
+    let pause = || thread::sleep(Duration::from_millis(5));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let before = SystemTime::now();
+    pause();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    pause();
+    let first = SystemTime::now();
+    pause();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    pause();
+    let second = SystemTime::now();
+    pause();
+    store.remove("key1".to_owned())?;
+    pause();
+    let third = SystemTime::now();
+    assert!(store.stats().compactions > 0);
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get_as_of("key1".to_owned(), before)?, None);
+        assert_eq!(
+            store.get_as_of("key1".to_owned(), first)?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            store.get_as_of("key1".to_owned(), second)?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(store.get_as_of("key1".to_owned(), third)?, None);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(
+            store.scan_as_of("key0".to_owned().., second)?,
+            vec![
+                ("key1".to_owned(), "value3".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+        assert_eq!(store.scan_as_of(.."key2".to_owned(), third)?, vec![]);
+        assert!(matches!(
+            store.get_as_of("key2".to_owned(), before - Duration::from_secs(7200)),
+            Err(KvsError::HistoryUnavailable)
+        ));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Setting a field of a large map should only write the field
+#[test]
+fn map_field_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?.with_merge_operator(ValueMerge);
+    let large = "v".repeat(1000);
+    for field in 0..100 {
+        let op = ValueOp::HSet((format!("field{}", field), large.clone()));
+        store.merge("map".to_owned(), op.encode()?)?;
+    }
+    // Reading folds the fields into a single value
+    assert_eq!(
+        Map::decode(&store.get("map".to_owned())?.unwrap())?
+            .fields
+            .len(),
+        100
+    );
+    let before = store.disk_usage()?.unwrap();
+    let op = ValueOp::HSet(("field0".to_owned(), "small".to_owned()));
+    store.merge("map".to_owned(), op.encode()?)?;
+    assert!(store.disk_usage()?.unwrap() - before < 100);
+    let fields = Map::decode(&store.get("map".to_owned())?.unwrap())?.fields;
+    assert_eq!(fields["field0"], "small");
+    assert_eq!(fields["field99"], large);
+    Ok(())
+}
+
+// Merges should fold into values on read, across reopening and through compaction
+#[test]
+fn merge_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let merge = |store: &KvStore<String, String>, key: &str, op: ValueOp| {
+        store.merge(key.to_owned(), op.encode()?)
+    };
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(matches!(
+        merge(&store, "hits", ValueOp::Incr(1)),
+        Err(KvsError::MergeUnsupported)
+    ));
+    let store = store.with_merge_operator(ValueMerge);
+    store.set("hits".to_owned(), "10".to_owned())?;
+    for _ in 0..100 {
+        merge(&store, "hits", ValueOp::Incr(2))?;
+    }
+    merge(&store, "hits", ValueOp::Incr(-1))?;
+    for visitor in 0..500 {
+        merge(
+            &store,
+            "visitors",
+            ValueOp::PfAdd(vec![format!("visitor{}", visitor % 250)]),
+        )?;
+    }
+    merge(&store, "flags", ValueOp::SetBit((3, true)))?;
+    merge(&store, "flags", ValueOp::SetBit((70, true)))?;
+    merge(&store, "flags", ValueOp::SetBit((3, false)))?;
+    // Operands that don't fit the value are skipped
+    merge(&store, "flags", ValueOp::Incr(1))?;
+    assert!(store.contains_key("flags".to_owned())?);
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get("hits".to_owned())?, Some("209".to_owned()));
+        let visitors = HyperLogLog::decode(&store.get("visitors".to_owned())?.unwrap())?;
+        assert!((240..=260).contains(&visitors.count()));
+        let flags = Bitmap::decode(&store.get("flags".to_owned())?.unwrap())?;
+        assert!(!flags.get(3) && flags.get(70));
+        assert_eq!(flags.count(), 1);
+        assert_eq!(store.keys().count(), 3);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?.with_merge_operator(ValueMerge);
+    check(&store)?;
+    store.remove("flags".to_owned())?;
+    assert_eq!(store.get("flags".to_owned())?, None);
+    drop(store);
+
+    let options = KvStoreOptions {
+        compaction_threshold: 1,
+        ..KvStoreOptions::default()
+    };
+    let store =
+        KvStore::open_with_options(temp_dir.path(), options)?.with_merge_operator(ValueMerge);
+    merge(&store, "hits", ValueOp::Incr(1))?;
+    assert!(store.stats().compactions > 0);
+    assert_eq!(store.get("hits".to_owned())?, Some("210".to_owned()));
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("210".to_owned()));
+
+    // Fetching merges hand out the value folded right before, so no pop sees the same item
+    let store = store.with_merge_operator(ValueMerge);
+    let items: Vec<String> = (0..200).map(|item| item.to_string()).collect();
+    store.merge("queue".to_owned(), ValueOp::RPush(items).encode()?)?;
+    let pop = ValueOp::LPop.encode()?;
+    let popped: Vec<String> = thread::scope(|scope| {
+        let poppers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| -> Result<Vec<String>> {
+                    let mut popped = Vec::new();
+                    for _ in 0..50 {
+                        let list = store.fetch_merge("queue".to_owned(), pop.clone())?.unwrap();
+                        popped.push(List::decode(&list)?.items.pop_front().unwrap());
+                    }
+                    Ok(popped)
+                })
+            })
+            .collect();
+        poppers
+            .into_iter()
+            .flat_map(|popper| popper.join().unwrap().unwrap())
+            .collect()
+    });
+    let mut popped: Vec<usize> = popped.iter().map(|item| item.parse().unwrap()).collect();
+    popped.sort_unstable();
+    assert_eq!(popped, (0..200).collect::<Vec<_>>());
+    assert_eq!(store.get("queue".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Should write records as JSON lines when asked to, and keep using JSON after reopening
+#[test]
+fn json_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        codec: RecordCodec::Json,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let log: String = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect();
+    let records: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    // Stamped with the seq and time of the write
+    assert_eq!(records[0]["Stamped"][0], 0);
+    assert_eq!(
+        records[0]["Stamped"][2],
+        serde_json::json!({"Set": ["key1", "value1"]})
+    );
+
+    // The codec recorded for the store wins over the default one
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    KvStore::<String, String>::compact_offline(temp_dir.path())?;
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should run the same engine on storage other than the local file system
+#[test]
+fn memory_storage() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = KvStoreOptions {
+        compaction_threshold: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_storage(Arc::new(storage.clone()), options)?;
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.stats().compactions > 0);
+    drop(store);
+
+    let store = KvStore::<String, String>::open_with_storage(Arc::new(storage.clone()), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-19".to_owned()));
+    drop(store);
+    assert!(storage.list()?.contains(&"MANIFEST".to_owned()));
+    Ok(())
+}
+
+// Should list live keys from the index and scan them in order a page at a time
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key3".to_owned())?;
+
+    let mut keys: Vec<String> = store.keys().collect();
+    keys.sort();
+    assert_eq!(keys.len(), 9);
+    assert!(!keys.contains(&"key3".to_owned()));
+
+    let page = store.scan_keys(None, 4)?;
+    assert_eq!(page, vec!["key0", "key1", "key2", "key4"]);
+    let page = store.scan_keys(page.last().cloned(), 4)?;
+    assert_eq!(page, vec!["key5", "key6", "key7", "key8"]);
+    assert_eq!(store.scan_keys(Some("key8".to_owned()), 4)?, vec!["key9"]);
+    Ok(())
+}
+
+// Should serve small values from the index without reading the segments, after writes, replay
+// and compaction alike
+// Zeroes every segment, so that only values kept in memory can still be read
+fn wipe_segments(dir: &Path) {
+    for entry in WalkDir::new(dir) {
+        let path = entry.unwrap().into_path();
+        if path.extension() == Some("kvs".as_ref()) {
+            let len = fs::metadata(&path).unwrap().len() as usize;
+            fs::write(&path, vec![0u8; len]).unwrap();
+        }
+    }
+}
+
+#[test]
+fn inline_small_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        inline_value_bytes: 64,
+        ..KvStoreOptions::default()
+    };
+    let large = "x".repeat(1000);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("small".to_owned(), "value1".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.flush()?;
+    drop(store);
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    wipe_segments(temp_dir.path());
+    assert_eq!(store.get("small".to_owned())?, Some("value1".to_owned()));
+    assert_ne!(
+        store.get("large".to_owned()).ok(),
+        Some(Some(large.clone()))
+    );
+    drop(store);
+
+    // Segments merged offline come with hints instead of records to replay
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..3 {
+        store.set("small".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("large".to_owned(), large)?;
+    drop(store);
+    KvStore::<String, String>::compact_offline_with_options(temp_dir.path(), options)?;
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    wipe_segments(temp_dir.path());
+    assert_eq!(store.get("small".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Large values live in value logs that compaction only rewrites once enough of them is garbage
+#[test]
+fn separate_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_log_threshold: 256,
+        compaction_threshold: 16 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let value_logs = |dir: &Path| -> Vec<_> {
+        WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.extension() == Some("vlog".as_ref()))
+            .collect()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for iter in 0..10 {
+        store.set(
+            format!("key{}", iter),
+            format!("{}{}", iter, "x".repeat(1000)),
+        )?;
+    }
+    assert_eq!(value_logs(temp_dir.path()).len(), 1);
+    drop(store);
+
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get("key3".to_owned())?,
+        Some(format!("3{}", "x".repeat(1000)))
+    );
+    let first_logs = value_logs(temp_dir.path());
+
+    // Overwriting the large values makes garbage of them until the value log is rewritten
+    for round in 0..4 {
+        for iter in 0..10 {
+            store.set(
+                format!("key{}", iter),
+                format!("{}{}", round, "y".repeat(1000)),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(value_logs(temp_dir.path())
+        .iter()
+        .all(|log| !first_logs.contains(log)));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.get("key9".to_owned())?,
+        Some(format!("3{}", "y".repeat(1000)))
+    );
+    let value_log_len: u64 = value_logs(temp_dir.path())
+        .iter()
+        .map(|log| fs::metadata(log).unwrap().len())
+        .sum();
+    assert!(value_log_len < 20 * 1024);
+    drop(store);
+
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get("key5".to_owned())?,
+        Some(format!("3{}", "y".repeat(1000)))
+    );
+    Ok(())
+}
+
+// Should read many keys at once, whatever holds their values
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        inline_value_bytes: 32,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}{}", i, "x".repeat(i)))?;
+    }
+    store.remove("key7".to_owned())?;
+    let keys: Vec<String> = (0..110).rev().map(|i| format!("key{}", i)).collect();
+    let values = store.get_many(&keys)?;
+    for (i, value) in (0..110).rev().zip(values) {
+        let expected = match i {
+            7 | 100.. => None,
+            i => Some(format!("value{}{}", i, "x".repeat(i))),
+        };
+        assert_eq!(value, expected);
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[test]
+fn io_uring() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        io_uring: true,
+        sync_policy: SyncPolicy::Always,
+        inline_value_bytes: 16,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    // Segments with hints are replayed with batched reads
+    KvStore::<String, String>::compact_offline_with_options(temp_dir.path(), options)?;
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    for (i, value) in store.get_many(&keys)?.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+#[test]
+fn io_uring_unsupported() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        io_uring: true,
+        ..KvStoreOptions::default()
+    };
+    assert!(KvStore::<String, String>::open_with_options(temp_dir.path(), options).is_err());
+}
+
+// Snapshots walk the segments with read-ahead, across windows, gaps and records larger than one
+#[test]
+fn snapshot_read_ahead() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |i: usize, round: usize| format!("{}-{}-{}", i, round, "x".repeat(10_000));
+    for round in 0..2 {
+        for i in 0..300 {
+            // Every other key is overwritten, leaving gaps in the first round of records
+            if round == 0 || i % 2 == 0 {
+                store.set(format!("key{}", i), value(i, round))?;
+            }
+        }
+    }
+    store.set("large".to_owned(), "y".repeat(2 * 1024 * 1024))?;
+    store.set("last".to_owned(), "value".to_owned())?;
+    let (_, mut entries) = store.snapshot()?;
+    entries.sort();
+    let mut expected: Vec<(String, String)> = (0..300)
+        .map(|i| (format!("key{}", i), value(i, 1 - i % 2)))
+        .collect();
+    expected.push(("large".to_owned(), "y".repeat(2 * 1024 * 1024)));
+    expected.push(("last".to_owned(), "value".to_owned()));
+    expected.sort();
+    assert_eq!(entries, expected);
+    Ok(())
+}
+
+// Should keep the newest records in memory when opening, up to warm_bytes
+#[test]
+fn warm_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |i: usize| format!("{}{}", i, "x".repeat(1000));
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions {
+        warm_bytes: 5 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    wipe_segments(temp_dir.path());
+    for i in 16..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    assert_ne!(store.get("key0".to_owned()).ok(), Some(Some(value(0))));
+    Ok(())
+}
+
+// Should report progress after every segment and stop when asked to
+#[test]
+fn open_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let mut reports: Vec<OpenProgress> = Vec::new();
+    let store = KvStore::<String, String>::open_with_progress(temp_dir.path(), options, |p| {
+        reports.push(p.clone());
+        ControlFlow::Continue(())
+    })?;
+    assert!(reports.len() > 1);
+    let last = reports.last().unwrap();
+    assert_eq!(last.segments_done, last.segments);
+    assert_eq!(last.bytes_replayed, last.bytes);
+    assert_eq!(last.eta(), Some(Duration::ZERO));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    let mut calls = 0;
+    let opened = KvStore::<String, String>::open_with_progress(temp_dir.path(), options, |_| {
+        calls += 1;
+        ControlFlow::Break(())
+    });
+    assert!(matches!(opened, Err(KvsError::OpenCancelled)));
+    assert_eq!(calls, 1);
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// Damage a crash can leave behind is repaired, other damage stops opening the store
+#[test]
+fn open_damaged_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let files_with = |extension: &str| -> Vec<_> {
+        let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.extension() == Some(extension.as_ref()))
+            .collect();
+        files.sort();
+        files
+    };
+    // The start of a record whose write was cut short
+    let active = files_with("kvs").pop().unwrap();
+    let mut contents = fs::read(&active)?;
+    contents.extend_from_slice(&[0x81, 0xa3, b'S']);
+    fs::write(&active, contents)?;
+
+    let options = KvStoreOptions {
+        repair: false,
+        ..KvStoreOptions::default()
+    };
+    let opened = KvStore::<String, String>::open_with_options(temp_dir.path(), options);
+    assert!(matches!(&opened, Err(e) if e.is_recoverable()));
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Hints are rebuilt from the segment they describe
+    KvStore::<String, String>::compact_offline(temp_dir.path())?;
+    for hints in files_with("hint") {
+        fs::write(hints, [0xc1])?;
+    }
+    let opened = KvStore::<String, String>::open_with_options(temp_dir.path(), options);
+    assert!(matches!(opened, Err(KvsError::RepairNeeded(_))));
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let manifest = temp_dir.path().join("MANIFEST");
+    let contents = fs::read_to_string(&manifest)?;
+    fs::write(
+        &manifest,
+        contents.replace("\"segments\":[", "\"segments\":[7,"),
+    )?;
+    let opened = KvStore::<String, String>::open(temp_dir.path());
+    assert!(matches!(&opened, Err(KvsError::CorruptStore(_))));
+    assert!(!opened.err().unwrap().is_recoverable());
+    fs::write(
+        &manifest,
+        r#"{"format":99,"next_segment_id":1,"segments":[0]}"#,
+    )?;
+    let opened = KvStore::<String, String>::open(temp_dir.path());
+    assert!(matches!(opened, Err(KvsError::UnsupportedFormat(99))));
+    Ok(())
+}
+
+// A batch should be written as one: a crash partway through leaves none of its writes
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.write_batch(vec![
+        ("key1".to_owned(), None),
+        ("key2".to_owned(), Some("value2".to_owned())),
+        ("key2".to_owned(), Some("value3".to_owned())),
+        // Removing a key without a value does nothing
+        ("key4".to_owned(), None),
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let active = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .max()
+        .unwrap();
+    // Past the records, segments are zeroed
+    let records_end = || -> Result<usize> {
+        let contents = fs::read(&active)?;
+        Ok(contents.iter().rposition(|&byte| byte != 0).unwrap() + 1)
+    };
+    let before = records_end()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.write_batch(vec![
+        ("key2".to_owned(), None),
+        ("key3".to_owned(), Some("value3".to_owned())),
+        ("key5".to_owned(), Some("value5".to_owned())),
+    ])?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    drop(store);
+    let after = records_end()?;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&active)?
+        .set_len((before + (after - before) / 2) as u64)?;
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+    Ok(())
+}
+
+// Should keep the previous generation of the manifest and open from it when a crash left no
+// newer one
+#[test]
+fn manifest_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let manifest = temp_dir.path().join("MANIFEST");
+    let previous = temp_dir.path().join("MANIFEST.prev");
+    let generation = |path: &Path| {
+        let manifest: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        manifest["generation"].as_u64().unwrap()
+    };
+    assert!(generation(&previous) > 1);
+    assert_eq!(generation(&manifest), generation(&previous) + 1);
+
+    // Like a crash right after moving the current generation aside
+    fs::rename(&manifest, &previous)?;
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key199".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    // An older generation put back in place isn't taken for the current one
+    fs::copy(&previous, &manifest)?;
+    let opened = KvStore::<String, String>::open_with_options(temp_dir.path(), options);
+    assert!(matches!(opened, Err(KvsError::CorruptStore(_))));
+    Ok(())
+}
+
+// Should find the keys of a sealed segment whose bytes changed after it was sealed
+#[test]
+fn scrub() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let report = store.scrub()?.unwrap();
+    assert!(report.is_clean());
+    assert!(report.files > 1);
+    assert_eq!(report.records, 200);
+    assert!(report.bad_keys.is_empty());
+
+    // Still decodes, only the checksum can tell
+    let mut segments: Vec<PathBuf> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .collect();
+    segments.sort();
+    let mut first = fs::read(&segments[0])?;
+    let at = first
+        .windows(5)
+        .position(|window| window == b"value")
+        .unwrap();
+    first[at + 1] = b'b';
+    fs::write(&segments[0], &first)?;
+    let report = store.scrub()?.unwrap();
+    assert_eq!(report.damage.len(), 1);
+    assert!(report.bad_keys.contains(&"key0".to_owned()));
+    assert!(!report.bad_keys.contains(&"key199".to_owned()));
+    Ok(())
+}
+
+// Records that don't decode or hold another key than the one read fail as corrupt
+#[test]
+fn corrupt_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.extension() == Some("kvs".as_ref()))
+        .unwrap();
+    let mut contents = fs::read(&segment)?;
+    let find = |contents: &[u8], needle: &[u8]| {
+        contents
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap()
+    };
+    let key1 = find(&contents, b"key1");
+    contents[key1 + 3] = b'3';
+    // Never the first byte of a msgpack value
+    let value2 = find(&contents, b"value2");
+    contents[value2 - 1] = 0xc1;
+    fs::write(&segment, &contents)?;
+
+    for key in ["key1", "key2"] {
+        let error = store.get(key.to_owned()).unwrap_err();
+        assert!(
+            matches!(error.root(), KvsError::CorruptRecord(_)),
+            "{:?}",
+            error
+        );
+    }
+    Ok(())
+}
+
+// Gets with meta return the seq, time and size of the write of the value, which stay the same
+// through compaction and reopening
+#[test]
+fn value_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 4096,
+        value_log_threshold: 256,
+        ..KvStoreOptions::default()
+    };
+    let before = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), "x".repeat(1024))?;
+    let meta = |store: &KvStore<String, String>, key: &str| -> Result<_> {
+        Ok(store.get_with_meta(key.to_owned())?.unwrap().1.unwrap())
+    };
+    let small = meta(&store, "small")?;
+    let large = meta(&store, "large")?;
+    assert!(small.written_at >= before);
+    assert!(large.seq > small.seq);
+    assert!(small.size > 5 && small.size < 1024);
+    assert!(large.size > 1024);
+
+    while store.stats().compactions == 0 {
+        store.set("filler".to_owned(), "filler".repeat(20))?;
+    }
+    assert_eq!(meta(&store, "small")?, small);
+    assert_eq!(meta(&store, "large")?, large);
+    let filler = meta(&store, "filler")?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(meta(&store, "small")?, small);
+    assert_eq!(meta(&store, "large")?, large);
+    store.set("small".to_owned(), "again".to_owned())?;
+    assert!(meta(&store, "small")?.seq > filler.seq);
+    store.remove("small".to_owned())?;
+    assert_eq!(store.get_with_meta("small".to_owned())?, None);
+    Ok(())
+}
+
+// Errors reading the store name the file, offset and key they happened at
+#[test]
+fn error_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.extension() == Some("kvs".as_ref()))
+        .unwrap();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)?
+        .set_len(0)?;
+
+    let err = store.get("key2".to_owned()).unwrap_err();
+    match &err {
+        KvsError::WithContext(context, _) => {
+            assert_eq!(context.operation, "read record");
+            assert_eq!(
+                context.file.as_deref(),
+                segment.file_name().and_then(|name| name.to_str())
+            );
+            assert!(context.offset.unwrap() > 0);
+            assert_eq!(context.key.as_deref(), Some("key2"));
+        }
+        other => panic!("expected an error with context, got {:?}", other),
+    }
+    assert!(matches!(err.root(), KvsError::IOError(_)));
+    Ok(())
+}
+
+struct PanickingMerge;
+
+impl MergeOperator<String, String> for PanickingMerge {
+    fn merge(&self, _: &String, _: Option<String>, _: String) -> Option<String> {
+        panic!("merge operator failed")
+    }
+}
+
+// A merge operator panicking under the writer lock fails that request only
+#[test]
+fn writer_panic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_merge_operator(PanickingMerge);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.merge("key2".to_owned(), "operand".to_owned())?;
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| store.get("key2".to_owned())));
+    assert!(panicked.is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should count writes kept waiting on an fsync or compaction longer than the stall threshold
+#[test]
+fn write_stalls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_policy: SyncPolicy::Always,
+        compaction_threshold: 1024,
+        stall_threshold: Duration::from_nanos(1),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions > 0);
+    assert!(stats.write_stalls >= 100);
+
+    // Nothing is counted with the threshold at zero
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        stall_threshold: Duration::ZERO,
+        ..options
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats().write_stalls, 0);
+
+    Ok(())
+}
+
+// Memory storage whose appends fail like a full disk while `full` is set
+struct FullDisk {
+    storage: MemoryStorage,
+    full: Arc<AtomicBool>,
+}
+
+struct FullDiskAppender {
+    file: Box<dyn SegmentAppender>,
+    full: Arc<AtomicBool>,
+}
+
+impl SegmentStorage for FullDisk {
+    fn list(&self) -> Result<Vec<String>> {
+        self.storage.list()
+    }
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.storage.read(name)
+    }
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.storage.write(name, contents)
+    }
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        self.storage.create_segment(name)
+    }
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        self.storage.open(name)
+    }
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        Ok(Box::new(FullDiskAppender {
+            file: self.storage.append(name, position)?,
+            full: self.full.clone(),
+        }))
+    }
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.storage.rename(from, to)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        self.storage.remove(name)
+    }
+}
+
+impl Write for FullDiskAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.full.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::StorageFull.into());
+        }
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SegmentAppender for FullDiskAppender {
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+// Should stop taking writes once the disk is full, keep serving reads, and take writes again
+// once resumed, without the failed write ending up in the store
+#[test]
+fn degraded_on_full_disk() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let full = Arc::new(AtomicBool::new(false));
+    let disk = FullDisk {
+        storage: storage.clone(),
+        full: full.clone(),
+    };
+    let store = KvStore::open_with_storage(Arc::new(disk), KvStoreOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    full.store(true, Ordering::SeqCst);
+    let result = store.set("key2".to_owned(), "value2".to_owned());
+    assert!(matches!(result.unwrap_err().root(), KvsError::DiskFull));
+    let result = store.set("key3".to_owned(), "value3".to_owned());
+    assert!(matches!(result, Err(KvsError::Degraded(_))));
+    let result = store.remove("key1".to_owned());
+    assert!(matches!(result, Err(KvsError::Degraded(_))));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Still full, so writes stay stopped
+    assert!(store.resume_writes().is_err());
+
+    full.store(false, Ordering::SeqCst);
+    assert!(store.resume_writes()?.is_some());
+    assert_eq!(store.resume_writes()?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store =
+        KvStore::<String, String>::open_with_storage(Arc::new(storage), KvStoreOptions::default())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Local storage that kills the process right before its `crash_at`th change once armed
+struct CrashingStorage {
+    storage: LocalStorage,
+    armed: Arc<AtomicBool>,
+    changes: Arc<AtomicU64>,
+    crash_at: u64,
+}
+
+struct CrashingAppender {
+    file: Box<dyn SegmentAppender>,
+    storage: Arc<CrashingStorage>,
+}
+
+impl CrashingStorage {
+    fn change(&self) {
+        if self.armed.load(Ordering::SeqCst)
+            && self.changes.fetch_add(1, Ordering::SeqCst) == self.crash_at
+        {
+            process::abort();
+        }
+    }
+}
+
+// Forwards to the storage behind the `Arc` so that appenders can count their syncs
+struct SharedCrashingStorage(Arc<CrashingStorage>);
+
+impl SegmentStorage for SharedCrashingStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        self.0.storage.list()
+    }
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.0.storage.read(name)
+    }
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.0.change();
+        self.0.storage.write(name, contents)
+    }
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        self.0.change();
+        self.0.storage.create_segment(name)
+    }
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        self.0.storage.open(name)
+    }
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        self.0.change();
+        Ok(Box::new(CrashingAppender {
+            file: self.0.storage.append(name, position)?,
+            storage: self.0.clone(),
+        }))
+    }
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.0.change();
+        self.0.storage.rename(from, to)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        self.0.change();
+        self.0.storage.remove(name)
+    }
+}
+
+impl Write for CrashingAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SegmentAppender for CrashingAppender {
+    fn sync_data(&self) -> io::Result<()> {
+        self.storage.change();
+        self.file.sync_data()
+    }
+}
+
+// Overwrites every key of a store in `dir` until a compaction runs, with the storage killing
+// the process right before its `crash_at`th change during the compaction
+fn compact_until_crash(dir: &Path, crash_at: u64) -> Result<()> {
+    let options = KvStoreOptions {
+        compaction_threshold: 256,
+        ..KvStoreOptions::default()
+    };
+    let storage = Arc::new(CrashingStorage {
+        storage: LocalStorage::new(dir, options)?,
+        armed: Arc::new(AtomicBool::new(false)),
+        changes: Arc::new(AtomicU64::new(0)),
+        crash_at,
+    });
+    let store =
+        KvStore::open_with_storage(Arc::new(SharedCrashingStorage(storage.clone())), options)?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+    storage.armed.store(true, Ordering::SeqCst);
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+        if store.stats().compactions > 0 {
+            break;
+        }
+    }
+    assert_eq!(store.stats().compactions, 1);
+    Ok(())
+}
+
+// Should leave a store that opens with every key when the process is killed at any step of a
+// compaction, from creating the new segment to removing the old ones
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    if let (Ok(dir), Ok(crash_at)) = (env::var("KVS_CRASH_DIR"), env::var("KVS_CRASH_AT")) {
+        return compact_until_crash(&PathBuf::from(dir), crash_at.parse().unwrap());
+    }
+    for crash_at in 0.. {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["crash_during_compaction", "--exact", "--test-threads=1"])
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .env("KVS_CRASH_AT", crash_at.to_string())
+            .output()
+            .unwrap()
+            .status;
+        // Killed by the abort rather than failing
+        assert!(status.success() || status.code().is_none());
+
+        let store = KvStore::<String, String>::open(temp_dir.path())?;
+        let values: Vec<String> = (0..50)
+            .map(|key_id| store.get(format!("key{}", key_id)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .map(|value| value.expect("key lost by the crash"))
+            .collect();
+        // Keys were overwritten in order, the crash can only have stopped that part way
+        let overwritten = values.iter().take_while(|value| *value == "new").count();
+        assert!(values[overwritten..].iter().all(|value| value == "old"));
+        if status.success() {
+            // The compaction finished before reaching this many changes, every step was tried
+            assert!(crash_at > 4);
+            break;
+        }
+    }
+    Ok(())
+}
+
+// A read transaction should keep seeing the store as it was when it was opened, through
+// overwrites, removes, merges and compaction
+#[test]
+fn read_txn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 4096,
+        value_log_threshold: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?
+        .with_merge_operator(ValueMerge);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "x".repeat(1024))?;
+    store.set("hits".to_owned(), "10".to_owned())?;
+    store.merge("hits".to_owned(), ValueOp::Incr(5).encode()?)?;
+
+    let txn = store.read_txn()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.merge("hits".to_owned(), ValueOp::Incr(1).encode()?)?;
+    let check = || -> Result<()> {
+        assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(txn.get("key2".to_owned())?, Some("x".repeat(1024)));
+        assert_eq!(txn.get("key3".to_owned())?, None);
+        assert_eq!(txn.get("hits".to_owned())?, Some("15".to_owned()));
+        assert_eq!(
+            txn.scan("key".to_owned()..)?,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "x".repeat(1024)),
+            ]
+        );
+        Ok(())
+    };
+    check()?;
+    while store.stats().compactions == 0 {
+        store.set("key1".to_owned(), "filler".repeat(20))?;
+    }
+    check()?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("hits".to_owned())?, Some("16".to_owned()));
+
+    // Once dropped, the values it kept go with the next compaction
+    drop(txn);
+    let txn = store.read_txn()?;
+    assert_eq!(txn.get("key1".to_owned())?, Some("filler".repeat(20)));
+    assert_eq!(txn.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// An export should read every pair as of when it began while compaction retires and reuses the
+// segments under it, which are only removed once the export is dropped
+#[test]
+fn export_while_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 4096,
+        value_log_threshold: 256,
+        reuse_files: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), options)?
+        .with_merge_operator(ValueMerge);
+    let mut expected = Vec::new();
+    for i in 0..100 {
+        let value = match i % 10 {
+            0 => "x".repeat(1024),
+            _ => format!("value{}", i),
+        };
+        store.set(format!("key{:03}", i), value.clone())?;
+        expected.push((format!("key{:03}", i), value));
+    }
+    store.set("hits".to_owned(), "1".to_owned())?;
+    store.merge("hits".to_owned(), ValueOp::Incr(1).encode()?)?;
+    expected.push(("hits".to_owned(), "2".to_owned()));
+    expected.sort();
+    // Segments and value logs
+    let data_files = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| matches!(path.extension(), Some(ext) if ext == "kvs" || ext == "vlog"))
+            .count()
+    };
+
+    let mut export = store.export()?;
+    let mut exported = vec![export.next().unwrap()?];
+    let compactions = store.stats().compactions;
+    for round in 0..5 {
+        for i in 0..100 {
+            store.set(format!("key{:03}", i), format!("round{}", round).repeat(30))?;
+        }
+    }
+    store.remove("hits".to_owned())?;
+    assert!(store.stats().compactions >= compactions + 3);
+    let pinned = data_files();
+    for pair in export.by_ref() {
+        exported.push(pair?);
+    }
+    exported.sort();
+    assert_eq!(exported, expected);
+    drop(export);
+    assert!(data_files() < pinned);
+    assert_eq!(store.get("key000".to_owned())?, Some("round4".repeat(30)));
+
+    // Exports running alongside compaction on another thread see a consistent store as well
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..20 {
+                for i in 0..100 {
+                    store.set(format!("key{:03}", i), format!("{:02}", round).repeat(50))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let pairs = store.export()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 100);
+        // Every pair of an export is from the same round
+        let mut rounds: Vec<&str> = pairs.iter().map(|(_, value)| &value[..2]).collect();
+        rounds.dedup();
+        assert!(rounds.len() <= 2, "{:?}", rounds);
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// Memory storage that notes every segment synced while a value log it may point into still had
+// writes that weren't synced
+#[derive(Default)]
+struct SyncOrder {
+    storage: MemoryStorage,
+    unsynced: Arc<Mutex<Vec<String>>>,
+    out_of_order: Arc<AtomicU64>,
+}
+
+struct SyncOrderAppender {
+    file: Box<dyn SegmentAppender>,
+    name: String,
+    unsynced: Arc<Mutex<Vec<String>>>,
+    out_of_order: Arc<AtomicU64>,
+}
+
+impl SegmentStorage for SyncOrder {
+    fn list(&self) -> Result<Vec<String>> {
+        self.storage.list()
+    }
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.storage.read(name)
+    }
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.storage.write(name, contents)
+    }
+    fn create_segment(&self, name: &str) -> Result<bool> {
+        self.storage.create_segment(name)
+    }
+    fn open(&self, name: &str) -> Result<Box<dyn SegmentReader>> {
+        self.storage.open(name)
+    }
+    fn append(&self, name: &str, position: u64) -> Result<Box<dyn SegmentAppender>> {
+        Ok(Box::new(SyncOrderAppender {
+            file: self.storage.append(name, position)?,
+            name: name.to_owned(),
+            unsynced: self.unsynced.clone(),
+            out_of_order: self.out_of_order.clone(),
+        }))
+    }
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.storage.rename(from, to)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        self.storage.remove(name)
+    }
+}
+
+impl Write for SyncOrderAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        if !unsynced.contains(&self.name) {
+            unsynced.push(self.name.clone());
+        }
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SegmentAppender for SyncOrderAppender {
+    fn sync_data(&self) -> io::Result<()> {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        if self.name.ends_with(".kvs") && unsynced.iter().any(|name| name.ends_with(".vlog")) {
+            self.out_of_order.fetch_add(1, Ordering::SeqCst);
+        }
+        unsynced.retain(|name| *name != self.name);
+        self.file.sync_data()
+    }
+}
+
+// Values of segments opened from hint files should count as garbage once overwritten, so that
+// compaction still rewrites their value log
+#[test]
+fn hinted_value_garbage() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = KvStoreOptions {
+        value_log_threshold: 256,
+        compaction_threshold: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_storage(Arc::new(storage.clone()), options)?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), "x".repeat(1000))?;
+    }
+    drop(store);
+    KvStore::<String, String>::compact_offline_with_storage(Arc::new(storage.clone()), options)?;
+    assert!(storage.list()?.iter().any(|name| name.ends_with(".hint")));
+
+    let store = KvStore::open_with_storage(Arc::new(storage.clone()), options)?;
+    store.set("key0".to_owned(), "y".repeat(1000))?;
+    assert!(store.stats().value_garbage >= 1000);
+    let first_logs: Vec<String> = storage
+        .list()?
+        .into_iter()
+        .filter(|name| name.ends_with(".vlog"))
+        .collect();
+    for _ in 0..4 {
+        store.set("key1".to_owned(), "z".repeat(1000))?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(storage
+        .list()?
+        .iter()
+        .all(|name| !first_logs.contains(name)));
+    assert_eq!(store.get("key0".to_owned())?, Some("y".repeat(1000)));
+    assert_eq!(store.get("key2".to_owned())?, Some("x".repeat(1000)));
+    Ok(())
+}
+
+// Should sync the value log before any segment pointing into it, whatever the sync policy
+#[test]
+fn value_log_synced_first() -> Result<()> {
+    for sync_policy in [SyncPolicy::Flush, SyncPolicy::Buffered] {
+        let storage = SyncOrder::default();
+        let out_of_order = storage.out_of_order.clone();
+        let options = KvStoreOptions {
+            sync_policy,
+            value_log_threshold: 256,
+            max_segment_bytes: 1024,
+            compaction_threshold: 4096,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_storage(Arc::new(storage), options)?;
+        for iter in 0..40 {
+            store.set(
+                format!("key{}", iter % 10),
+                format!("{}{}", iter, "x".repeat(500)),
+            )?;
+        }
+        assert!(store.stats().compactions > 0);
+        assert_eq!(out_of_order.load(Ordering::SeqCst), 0);
+    }
     Ok(())
 }
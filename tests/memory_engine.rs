@@ -0,0 +1,29 @@
+#![cfg(feature = "wasm")]
+
+use kvs::engine::memory::MemoryEngine;
+use kvs::engine::KvsEngine;
+use kvs::{KvsError, Result};
+
+// Should behave like the other engines, with clones sharing their data
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let clone = engine.clone();
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(clone.contains_key("key1".to_owned())?);
+
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::NonExistantKey)
+    ));
+
+    assert!(engine.set_nx("key2".to_owned(), "value2".to_owned())?);
+    assert!(!clone.set_nx("key2".to_owned(), "value3".to_owned())?);
+    assert!(clone.set_xx("key2".to_owned(), "value4".to_owned())?);
+    assert!(!clone.set_xx("key3".to_owned(), "value5".to_owned())?);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
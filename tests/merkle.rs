@@ -0,0 +1,51 @@
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::merkle::{leaf_of, MerkleTree, DEPTH};
+use kvs::Result;
+use tempfile::TempDir;
+
+// Stores with the same keys should hash alike, and differ only on the path to a changed key
+#[test]
+fn merkle_hashes() -> Result<()> {
+    let (dir_a, dir_b) = (TempDir::new()?, TempDir::new()?);
+    let store_a: KvStore<String, String> = KvStore::open(dir_a.path())?;
+    let store_b: KvStore<String, String> = KvStore::open(dir_b.path())?;
+    let (tree_a, tree_b) = (MerkleTree::default(), MerkleTree::default());
+    for key in 0..1000 {
+        store_a.set(format!("key{}", key), format!("value{}", key))?;
+        store_b.set(format!("key{}", key), format!("value{}", key))?;
+    }
+    assert_eq!(
+        tree_a.hashes(&store_a, 0, &[0])?,
+        tree_b.hashes(&store_b, 0, &[0])?
+    );
+
+    let key = "key42".to_owned();
+    store_b.set(key.clone(), "changed".to_owned())?;
+    // Hashes are only updated for leaves marked written
+    assert_eq!(
+        tree_a.hashes(&store_a, 0, &[0])?,
+        tree_b.hashes(&store_b, 0, &[0])?
+    );
+    tree_b.written(&key);
+    assert_ne!(
+        tree_a.hashes(&store_a, 0, &[0])?,
+        tree_b.hashes(&store_b, 0, &[0])?
+    );
+    let leaves: Vec<u64> = (0..1 << DEPTH).collect();
+    let (leaves_a, leaves_b) = (
+        tree_a.hashes(&store_a, DEPTH, &leaves)?,
+        tree_b.hashes(&store_b, DEPTH, &leaves)?,
+    );
+    let differing: Vec<u64> = leaves
+        .into_iter()
+        .filter(|leaf| leaves_a[*leaf as usize] != leaves_b[*leaf as usize])
+        .collect();
+    assert_eq!(differing, vec![leaf_of(&key)?]);
+    let entries = tree_b.entries(&store_b, differing[0])?;
+    assert!(entries.contains(&(key, "changed".to_owned())));
+    assert!(entries
+        .iter()
+        .all(|(key, _)| leaf_of(key).unwrap() == differing[0]));
+    Ok(())
+}
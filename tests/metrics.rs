@@ -0,0 +1,65 @@
+use kvs::metrics::{Histogram, HotKeys};
+use std::time::Duration;
+
+// Percentiles should land within 1% of the exact ones
+#[test]
+fn histogram_percentiles() {
+    let histogram = Histogram::default();
+    assert_eq!(histogram.percentiles().p99, Duration::ZERO);
+    for micros in (1..=10_000).rev() {
+        histogram.record(Duration::from_micros(micros));
+    }
+
+    let percentiles = histogram.percentiles();
+    assert_eq!(percentiles.count, 10_000);
+    assert_eq!(percentiles.max, Duration::from_millis(10));
+    for (actual, expected) in [
+        (percentiles.p50, 5_000),
+        (percentiles.p95, 9_500),
+        (percentiles.p99, 9_900),
+        (percentiles.p999, 9_990),
+    ] {
+        let expected = Duration::from_micros(expected);
+        assert!(actual >= expected, "{:?} < {:?}", actual, expected);
+        assert!(
+            actual <= expected.mul_f64(1.01),
+            "{:?} > {:?}",
+            actual,
+            expected
+        );
+    }
+
+    // Small values are exact and huge ones are capped rather than lost
+    let histogram = Histogram::default();
+    histogram.record(Duration::from_nanos(3));
+    histogram.record(Duration::from_secs(100_000));
+    assert_eq!(histogram.value_at_quantile(0.5), Duration::from_nanos(3));
+    assert_eq!(histogram.percentiles().max, Duration::from_secs(100_000));
+}
+
+// Keys accessed far more than the rest should come out on top, in order, among many cold ones
+#[test]
+fn hot_keys() {
+    let hot_keys = HotKeys::default();
+    assert!(hot_keys.top_keys(3).is_empty());
+    for round in 0..1_000 {
+        for _ in 0..3 {
+            hot_keys.record("hottest");
+        }
+        hot_keys.record("hot");
+        hot_keys.record("hot");
+        hot_keys.record("warm");
+        for cold in 0..20 {
+            hot_keys.record(&format!("cold{}", round * 20 + cold));
+        }
+    }
+
+    let top = hot_keys.top_keys(3);
+    let keys: Vec<&str> = top.iter().map(|hot_key| hot_key.key.as_str()).collect();
+    assert_eq!(keys, ["hottest", "hot", "warm"]);
+    // Estimates can only be too high
+    assert!(top[0].count >= 3_000);
+    assert!(top[1].count >= 2_000);
+    assert!(top[2].count >= 1_000);
+    assert_eq!(hot_keys.top_keys(100).len(), 64);
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "otel")]
+
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
+use kvs::otel::{self, Metric, OtelConfig};
+use kvs::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Answers one request like a collector would, returning its path and body
+fn accept_export(listener: &TcpListener) -> (String, serde_json::Value) {
+    let (stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header == "\r\n" {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length: ") {
+            content_length = length.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    reader
+        .get_mut()
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+    let path = request_line.split_whitespace().nth(1).unwrap().to_owned();
+    (path, serde_json::from_slice(&body).unwrap())
+}
+
+// Should post finished spans and the current metrics to the collector as OTLP JSON
+#[test]
+fn export_spans_and_metrics() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let config = OtelConfig {
+        interval: Duration::from_millis(200),
+        ..OtelConfig::new(listener.local_addr()?)
+    };
+    otel::start(config, || {
+        vec![Metric::Counter {
+            name: "test.counter".to_owned(),
+            value: 7,
+        }]
+    });
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(otel::span("test.span").with_attribute("key", "key1"));
+
+    let (path, traces) = accept_export(&listener);
+    assert_eq!(path, "/v1/traces");
+    let spans = &traces["resourceSpans"][0]["scopeSpans"][0]["spans"];
+    let names: Vec<&str> = spans
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|span| span["name"].as_str().unwrap())
+        .collect();
+    assert!(names.contains(&"kvs.replay"));
+    assert!(names.contains(&"test.span"));
+
+    let (path, metrics) = accept_export(&listener);
+    assert_eq!(path, "/v1/metrics");
+    let metric = &metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+    assert_eq!(metric["name"], "test.counter");
+    assert_eq!(metric["sum"]["dataPoints"][0]["asInt"], "7");
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use kvs::frame::{self, Compression, Encoding, Framing, COMPRESSION_THRESHOLD, MAX_FRAME_LEN};
+use kvs::protocol::{self, Feature, Handshake, KeysCursor, KvRequest, PROTOCOL_VERSION};
+use kvs::{ErrorContext, KvsError};
+use std::error::Error;
+
+// Should agree on the lower version and the features both sides have
+#[test]
+fn negotiate_handshake() {
+    let server = Handshake::new(vec![Feature::Subscriptions, Feature::Batching]);
+    let client = Handshake {
+        version: PROTOCOL_VERSION + 1,
+        ..Handshake::new(vec![Feature::Compression, Feature::Subscriptions])
+    };
+
+    let negotiated = server.negotiate(&client).unwrap();
+    assert_eq!(negotiated.version, PROTOCOL_VERSION);
+    assert!(negotiated.supports(Feature::Subscriptions));
+    assert!(!negotiated.supports(Feature::Batching));
+    assert!(!negotiated.supports(Feature::Compression));
+    assert_eq!(client.negotiate(&server).unwrap(), negotiated);
+}
+
+// Should refuse clients older than any supported version
+#[test]
+fn negotiate_unsupported_version() {
+    let server = Handshake::new(vec![]);
+    let client = Handshake {
+        version: 0,
+        ..Handshake::new(vec![])
+    };
+    assert!(matches!(
+        server.negotiate(&client),
+        Err(KvsError::UnsupportedVersion(0))
+    ));
+}
+
+// Should still speak plain JSON to clients asking for version 1, without optional features
+#[test]
+fn negotiate_plain_json() {
+    let server = Handshake::new(vec![Feature::Batching, Feature::Compression])
+        .with_compression(vec![Compression::Lz4]);
+    let client = Handshake {
+        version: 1,
+        ..Handshake::new(vec![Feature::Batching, Feature::Compression])
+            .with_compression(vec![Compression::Lz4])
+    };
+    let negotiated = server.negotiate(&client).unwrap();
+    assert_eq!(negotiated.version, 1);
+    assert!(!negotiated.supports(Feature::Batching));
+    assert_eq!(negotiated.framing(), None);
+}
+
+// Should use the server's preferred codec out of those both sides have
+#[test]
+fn negotiate_compression() {
+    let server = Handshake::new(vec![Feature::Compression])
+        .with_compression(vec![Compression::Lz4, Compression::Zstd]);
+    let client = Handshake::new(vec![Feature::Compression])
+        .with_compression(vec![Compression::Zstd, Compression::Lz4]);
+    assert_eq!(
+        server.negotiate(&client).unwrap().compression_codec(),
+        Some(Compression::Lz4)
+    );
+
+    let client = Handshake::new(vec![Feature::Compression]);
+    let negotiated = server.negotiate(&client).unwrap();
+    assert!(!negotiated.supports(Feature::Compression));
+    assert_eq!(negotiated.compression_codec(), None);
+}
+
+// Should default to msgpack bodies and only use JSON when both sides ask for it
+#[test]
+fn negotiate_encoding() {
+    let server = Handshake::new(vec![Feature::Json]);
+    let negotiated = server.negotiate(&Handshake::new(vec![])).unwrap();
+    assert_eq!(negotiated.framing().unwrap().encoding, Encoding::Msgpack);
+    let negotiated = server
+        .negotiate(&Handshake::new(vec![Feature::Json]))
+        .unwrap();
+    assert_eq!(negotiated.framing().unwrap().encoding, Encoding::Json);
+}
+
+// Messages should read back the same in every encoding, with and without compression
+#[test]
+fn frame_round_trip() {
+    let small = KvRequest::<String, String>::Set(("key".to_owned(), "value".to_owned()));
+    let large =
+        KvRequest::<String, String>::Set(("key".to_owned(), "value".repeat(COMPRESSION_THRESHOLD)));
+    let mut framings = vec![None];
+    for encoding in [Encoding::Msgpack, Encoding::Json] {
+        for compression in [None, Some(Compression::Lz4), Some(Compression::Zstd)] {
+            framings.push(Some(Framing {
+                encoding,
+                compression,
+            }));
+        }
+    }
+    for framing in framings {
+        let mut buf = Vec::new();
+        frame::write_message(&mut buf, &small, framing).unwrap();
+        frame::write_message(&mut buf, &large, framing).unwrap();
+        if framing.and_then(|f| f.compression).is_some() {
+            // Repetitive values should shrink
+            assert!(buf.len() < COMPRESSION_THRESHOLD * 2);
+        }
+
+        let mut reader = &buf[..];
+        for expected in [&small, &large] {
+            let message: KvRequest<String, String> =
+                frame::read_message(&mut reader, framing).unwrap().unwrap();
+            assert_eq!(format!("{:?}", message), format!("{:?}", expected));
+        }
+        assert!(
+            frame::read_message::<KvRequest<String, String>>(&mut reader, framing)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
+
+// Frames should be a codec byte and big endian length followed by the body
+#[test]
+fn frame_layout() {
+    let message = KvRequest::<String, String>::Get("key".to_owned());
+    for (encoding, body) in [
+        (
+            Encoding::Msgpack,
+            rmp_serde::to_vec_named(&message).unwrap(),
+        ),
+        (Encoding::Json, serde_json::to_vec(&message).unwrap()),
+    ] {
+        let mut buf = Vec::new();
+        let framing = Framing {
+            encoding,
+            compression: Some(Compression::Lz4),
+        };
+        frame::write_message(&mut buf, &message, Some(framing)).unwrap();
+        assert_eq!(buf[0], 0);
+        assert_eq!(buf[1..5], (body.len() as u32).to_be_bytes());
+        assert_eq!(buf[5..], body[..]);
+    }
+}
+
+// Should read back the headers written without going through frame
+#[test]
+fn frame_header_round_trip() {
+    let header = protocol::frame_header(Compression::Zstd.tag(), 300).unwrap();
+    assert_eq!(header, [protocol::ZSTD, 0, 0, 1, 44]);
+    assert_eq!(protocol::parse_frame_header(header), (protocol::ZSTD, 300));
+    assert_eq!(Compression::from_tag(protocol::UNCOMPRESSED), Ok(None));
+    assert_eq!(
+        Compression::from_tag(protocol::LZ4),
+        Ok(Some(Compression::Lz4))
+    );
+    assert_eq!(Compression::from_tag(9), Err(9));
+}
+
+// Frames longer than the limit should fail before being read, and so should bodies that
+// decompress past it
+#[test]
+fn frame_limits() {
+    let framing = Some(Framing {
+        encoding: Encoding::Msgpack,
+        compression: Some(Compression::Zstd),
+    });
+    assert!(protocol::frame_header(protocol::UNCOMPRESSED, MAX_FRAME_LEN + 1).is_none());
+    let header = [protocol::UNCOMPRESSED, 0xff, 0xff, 0xff, 0xff];
+    assert!(matches!(
+        frame::read_message::<KvRequest<String, String>>(&header[..], framing),
+        Err(KvsError::SerializationError(_))
+    ));
+
+    let zeros = vec![0; MAX_FRAME_LEN + 1];
+    let lz4 = lz4_flex::compress_prepend_size(&zeros);
+    let zstd = zstd::encode_all(&zeros[..], 0).unwrap();
+    for (tag, body) in [(protocol::LZ4, lz4), (protocol::ZSTD, zstd)] {
+        let mut buf = protocol::frame_header(tag, body.len()).unwrap().to_vec();
+        buf.extend_from_slice(&body);
+        assert!(matches!(
+            frame::read_message::<KvRequest<String, String>>(&buf[..], framing),
+            Err(KvsError::SerializationError(_))
+        ));
+    }
+}
+
+#[test]
+fn keys_cursor_round_trip() {
+    let cursor = KeysCursor {
+        after: "key with spaces and ünïcode".to_owned(),
+        seq: 42,
+    };
+    let token = cursor.encode().unwrap();
+    assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(KeysCursor::<String>::decode(&token).unwrap(), cursor);
+
+    for token in ["", "abc", "zz", "7b7d"] {
+        assert!(matches!(
+            KeysCursor::<String>::decode(token),
+            Err(KvsError::InvalidCursor)
+        ));
+    }
+}
+
+// Errors print as messages and chain to their cause, without changing how they're sent
+#[test]
+fn error_display() {
+    let err = KvsError::IOError("unexpected end of file".to_owned()).with_context(|| {
+        ErrorContext::new("read record")
+            .with_file("0.kvs")
+            .with_offset(12)
+            .with_key("key1")
+    });
+    assert_eq!(
+        err.to_string(),
+        "could not read record of key key1 in 0.kvs at offset 12"
+    );
+    let source = err.source().unwrap();
+    assert_eq!(source.to_string(), "IO error: unexpected end of file");
+    assert!(source.source().is_none());
+    let boxed: Box<dyn Error + Send + Sync> = KvsError::NonExistantKey.into();
+    assert_eq!(boxed.to_string(), "key not found");
+
+    assert_eq!(
+        serde_json::to_string(&KvsError::NonExistantKey).unwrap(),
+        "\"NonExistantKey\""
+    );
+    assert_eq!(
+        serde_json::to_string(&KvsError::StaleTerm(3)).unwrap(),
+        r#"{"StaleTerm":3}"#
+    );
+}
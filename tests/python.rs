@@ -0,0 +1,57 @@
+#![cfg(feature = "python")]
+
+use kvs::python::kvs as kvs_module;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use tempfile::TempDir;
+
+// Should import the module and use a store like a dict, reopening it after it was closed
+#[test]
+fn python_module() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    pyo3::append_to_inittab!(kvs_module);
+    Python::initialize();
+    Python::attach(|py| {
+        let locals = PyDict::new(py);
+        locals
+            .set_item("path", temp_dir.path().to_str().unwrap())
+            .unwrap();
+        py.run(
+            cr#"
+import kvs
+
+with kvs.KvStore(path) as store:
+    store["key1"] = "value1"
+    store["key2"] = "value2"
+    assert store["key1"] == "value1"
+    assert "key1" in store and "key3" not in store
+    assert store.get("key3") is None and store.get("key3", 0) == 0
+    del store["key2"]
+    try:
+        del store["key2"]
+        raise AssertionError("removed a missing key")
+    except KeyError:
+        pass
+try:
+    store["key1"]
+    raise AssertionError("used a closed store")
+except ValueError:
+    pass
+
+store = kvs.KvStore(path)
+assert store["key1"] == "value1" and "key2" not in store
+store.close()
+
+assert issubclass(kvs.KvStore, kvs.Handle) and issubclass(kvs.KvsError, Exception)
+try:
+    kvs.KvsClient("not an address")
+    raise AssertionError("connected to a malformed address")
+except ValueError:
+    pass
+"#,
+            None,
+            Some(&locals),
+        )
+        .unwrap();
+    });
+}
@@ -0,0 +1,198 @@
+#![cfg(feature = "s3")]
+
+use kvs::engine::storage::{S3Config, S3Storage, SegmentStorage};
+use kvs::engine::store::{KvStore, KvStoreOptions};
+use kvs::engine::KvsEngine;
+use kvs::Result;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+// Keys listed per page, small so that listing has to follow continuation tokens
+const PAGE_KEYS: usize = 3;
+
+/// Serves the objects of one bucket like S3 would, the listings chunked and a few keys a page
+fn serve_bucket(listener: TcpListener, objects: Objects) {
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        let objects = objects.clone();
+        thread::spawn(move || answer(stream, &objects));
+    }
+}
+
+fn answer(stream: TcpStream, objects: &Objects) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut headers = BTreeMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header == "\r\n" {
+            break;
+        }
+        let (name, value) = header.split_once(':').unwrap();
+        headers.insert(name.to_lowercase(), value.trim().to_owned());
+    }
+    let mut body = vec![0; headers["content-length"].parse().unwrap()];
+    reader.read_exact(&mut body).unwrap();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let key = path
+        .strip_prefix("/bucket/")
+        .map(|key| key.replace("%2F", "/"));
+
+    let mut objects = objects.lock().unwrap();
+    let (status, body, chunked) = if !headers
+        .get("authorization")
+        .is_some_and(|auth| auth.starts_with("AWS4-HMAC-SHA256 Credential=access/"))
+    {
+        (403, Vec::new(), false)
+    } else if let (Some(key), "PUT") = (&key, method) {
+        let exists = objects.contains_key(key);
+        if headers.get("if-none-match").is_some_and(|tag| tag == "*") && exists {
+            (412, Vec::new(), false)
+        } else if let Some(source) = headers.get("x-amz-copy-source") {
+            let source = objects[&source.strip_prefix("/bucket/").unwrap().to_owned()].clone();
+            objects.insert(key.clone(), source);
+            (
+                200,
+                b"<CopyObjectResult></CopyObjectResult>".to_vec(),
+                false,
+            )
+        } else {
+            objects.insert(key.clone(), body);
+            (200, Vec::new(), false)
+        }
+    } else if let (Some(key), "DELETE") = (&key, method) {
+        objects.remove(key);
+        (204, Vec::new(), false)
+    } else if let Some(key) = &key {
+        match objects.get(key) {
+            Some(object) => match headers.get("range") {
+                Some(range) => {
+                    let (start, end) = range
+                        .strip_prefix("bytes=")
+                        .unwrap()
+                        .split_once('-')
+                        .unwrap();
+                    let (start, end): (usize, usize) =
+                        (start.parse().unwrap(), end.parse().unwrap());
+                    (
+                        206,
+                        object[start..=end.min(object.len() - 1)].to_vec(),
+                        false,
+                    )
+                }
+                None => (200, object.clone(), false),
+            },
+            None => (
+                404,
+                b"<Error><Code>NoSuchKey</Code></Error>".to_vec(),
+                false,
+            ),
+        }
+    } else {
+        assert!(query.contains("list-type=2"));
+        let after = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("continuation-token="))
+            .unwrap_or("")
+            .replace("%2F", "/");
+        let keys: Vec<&String> = objects
+            .keys()
+            .filter(|key| **key > after)
+            .take(PAGE_KEYS + 1)
+            .collect();
+        let mut listing = String::from("<ListBucketResult>");
+        for key in keys.iter().take(PAGE_KEYS) {
+            listing.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+        }
+        if keys.len() > PAGE_KEYS {
+            listing.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                keys[PAGE_KEYS - 1]
+            ));
+        }
+        listing.push_str("</ListBucketResult>");
+        (200, listing.into_bytes(), true)
+    };
+    drop(objects);
+
+    let stream = reader.get_mut();
+    write!(stream, "HTTP/1.1 {} Whatever\r\n", status).unwrap();
+    if method == "HEAD" {
+        write!(stream, "Content-Length: {}\r\n\r\n", body.len()).unwrap();
+    } else if chunked {
+        write!(stream, "Transfer-Encoding: chunked\r\n\r\n").unwrap();
+        for chunk in body.chunks(16) {
+            write!(stream, "{:x}\r\n", chunk.len()).unwrap();
+            stream.write_all(chunk).unwrap();
+            write!(stream, "\r\n").unwrap();
+        }
+        write!(stream, "0\r\n\r\n").unwrap();
+    } else {
+        write!(stream, "Content-Length: {}\r\n\r\n", body.len()).unwrap();
+        stream.write_all(&body).unwrap();
+    }
+}
+
+// Should run the engine on objects of a bucket, across segment rollovers, compactions and
+// reopening
+#[test]
+fn s3_storage() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let config = S3Config {
+        prefix: "stores/one/".to_owned(),
+        ..S3Config::new(
+            listener.local_addr()?.to_string(),
+            "bucket",
+            "access",
+            "secret",
+        )
+    };
+    let objects = Objects::default();
+    let serving = objects.clone();
+    thread::spawn(move || serve_bucket(listener, serving));
+
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        compaction_threshold: 2048,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_storage(Arc::new(S3Storage::new(config.clone())), options)?;
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.stats().compactions > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-4".to_owned()));
+    drop(store);
+
+    assert!(objects
+        .lock()
+        .unwrap()
+        .keys()
+        .all(|key| key.starts_with("stores/one/")));
+    let storage = S3Storage::new(config);
+    assert!(storage.list()?.contains(&"MANIFEST".to_owned()));
+    let store = KvStore::<String, String>::open_with_storage(Arc::new(storage), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use kvs::cluster::Role;
+use kvs::protocol::{ClusterMessage, KvRequest};
+use kvs::sim::Simulation;
+use kvs::{KvsError, Result};
+use std::time::Duration;
+
+// Enough for an election to time out and finish, at most twice the default election timeout
+const ELECTION: Duration = Duration::from_millis(1100);
+
+fn set(key: &str, value: &str) -> KvRequest<String, String> {
+    KvRequest::Set((key.to_owned(), value.to_owned()))
+}
+
+fn get(key: &str) -> KvRequest<String, String> {
+    KvRequest::Get(key.to_owned())
+}
+
+// Runs the same script twice, which must elect the same leader in the same term
+#[test]
+fn simulation_is_deterministic() -> Result<()> {
+    let run = || -> Result<(u64, u64)> {
+        let simulation = Simulation::new(5)?;
+        simulation.advance(ELECTION);
+        let leader = simulation.leader().expect("no leader elected");
+        Ok((leader, simulation.term(leader).unwrap()))
+    };
+    assert_eq!(run()?, run()?);
+    Ok(())
+}
+
+// Writes go through the leader to every follower, and are refused by followers
+#[test]
+fn simulated_replication() -> Result<()> {
+    let simulation = Simulation::new(3)?;
+    assert_eq!(simulation.leader(), None);
+    simulation.advance(ELECTION);
+    let leader = simulation.leader().expect("no leader elected");
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    assert_eq!(simulation.role(follower), Some(Role::Follower));
+
+    simulation.request(leader, set("key1", "value1"))?;
+    for id in 1..=3 {
+        assert_eq!(
+            simulation.request(id, get("key1"))?,
+            Some("value1".to_owned())
+        );
+    }
+    assert!(matches!(
+        simulation.request(follower, set("key2", "value2")),
+        Err(KvsError::NotLeader(Some(_)))
+    ));
+
+    // A dropped message leaves that follower a write behind until the next heartbeat
+    simulation.drop_next(1);
+    simulation.request(leader, set("key2", "value2"))?;
+    simulation.advance(Duration::from_millis(100));
+    let behind = (1..=3)
+        .filter(|id| simulation.lag(*id) == Some(1))
+        .collect::<Vec<_>>();
+    assert_eq!(behind.len(), 1);
+    assert!(matches!(
+        simulation.request(
+            behind[0],
+            KvRequest::ReplicaGet {
+                key: "key2".to_owned(),
+                max_lag: 0
+            }
+        ),
+        Err(KvsError::ReplicaLagging(1))
+    ));
+    Ok(())
+}
+
+// A leader cut off from the majority loses its quorum, and the majority moves on without it
+#[test]
+fn simulated_partition() -> Result<()> {
+    let simulation = Simulation::new(3)?;
+    simulation.advance(ELECTION);
+    let old_leader = simulation.leader().expect("no leader elected");
+    let old_term = simulation.term(old_leader).unwrap();
+
+    simulation.partition(&[old_leader]);
+    assert!(matches!(
+        simulation.request(old_leader, set("key1", "value1")),
+        Err(KvsError::NoQuorum)
+    ));
+    // A write that failed isn't applied by the leader either
+    assert_eq!(simulation.request(old_leader, get("key1"))?, None);
+    simulation.advance(ELECTION * 2);
+    let new_leader = simulation.leader().expect("majority elected no leader");
+    assert_ne!(new_leader, old_leader);
+    assert!(simulation.term(new_leader).unwrap() > old_term);
+    simulation.request(new_leader, set("key2", "value2"))?;
+    // Cut off, the old leader can't tell how far behind it is
+    assert_eq!(simulation.lag(old_leader), Some(0));
+
+    simulation.heal();
+    simulation.advance(Duration::from_millis(200));
+    assert_eq!(simulation.role(old_leader), Some(Role::Follower));
+    assert_eq!(simulation.leader(), Some(new_leader));
+    simulation.request(new_leader, set("key3", "value3"))?;
+    assert_eq!(
+        simulation.request(old_leader, get("key3"))?,
+        Some("value3".to_owned())
+    );
+    Ok(())
+}
+
+// Crashed nodes keep their store, and a cluster without a majority takes no writes
+#[test]
+fn simulated_crash() -> Result<()> {
+    let mut simulation = Simulation::new(3)?;
+    simulation.advance(ELECTION);
+    let leader = simulation.leader().expect("no leader elected");
+    let followers: Vec<u64> = (1..=3).filter(|id| *id != leader).collect();
+
+    simulation.request(leader, set("key1", "value1"))?;
+    simulation.crash(followers[0]);
+    simulation.request(leader, set("key2", "value2"))?;
+    simulation.crash(followers[1]);
+    assert!(matches!(
+        simulation.request(leader, set("key3", "value3")),
+        Err(KvsError::NoQuorum)
+    ));
+    assert!(simulation.request(followers[0], get("key1")).is_err());
+
+    simulation.restart(followers[0])?;
+    assert_eq!(
+        simulation.request(followers[0], get("key1"))?,
+        Some("value1".to_owned())
+    );
+    // Missed while down, there is no catching up
+    assert_eq!(simulation.request(followers[0], get("key2"))?, None);
+    simulation.advance(Duration::from_millis(200));
+    simulation.request(leader, set("key4", "value4"))?;
+
+    // The term and vote outlive a restart
+    let term = simulation.term(leader);
+    simulation.restart(leader)?;
+    assert_eq!(simulation.term(leader), term);
+    Ok(())
+}
+
+// A node that missed writes a majority has can't get elected, however high its term
+#[test]
+fn simulated_stale_candidate() -> Result<()> {
+    let simulation = Simulation::new(3)?;
+    simulation.advance(ELECTION);
+    let leader = simulation.leader().expect("no leader elected");
+    let stale = (1..=3).find(|id| *id != leader).unwrap();
+
+    simulation.partition(&[stale]);
+    simulation.request(leader, set("key1", "value1"))?;
+    // Cut off, it keeps starting elections in newer terms
+    simulation.advance(ELECTION * 3);
+    let term = simulation.term(stale).unwrap();
+    assert!(term > simulation.term(leader).unwrap());
+    let vote = KvRequest::Cluster(ClusterMessage::RequestVote {
+        term: term + 1,
+        candidate: stale,
+        applied_term: 0,
+        applied_seq: 0,
+    });
+    assert!(matches!(
+        simulation.request(leader, vote),
+        Err(KvsError::VoteDenied)
+    ));
+
+    simulation.heal();
+    simulation.advance(ELECTION * 3);
+    let new_leader = simulation.leader().expect("no leader elected");
+    assert_ne!(new_leader, stale);
+    assert_eq!(
+        simulation.request(new_leader, get("key1"))?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
@@ -1,13 +1,21 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 
 use kvs::thread_pool::*;
 use kvs::Result;
+use std::future::Future;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
 
 use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::bridge::AsyncPool;
 use kvs::thread_pool::naive::NaiveThreadPool;
+use kvs::thread_pool::priority::{Priority, PriorityThreadPool};
 use kvs::thread_pool::rayon::RayonThreadPool;
 use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::work_stealing::WorkStealingThreadPool;
+use kvs::KvsError;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -71,3 +79,214 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+// Jobs spawned from within other jobs should all run before the pool finishes dropping
+#[test]
+fn work_stealing_thread_pool_nested_spawn() -> Result<()> {
+    let pool = Arc::new(WorkStealingThreadPool::new(4)?);
+    let counter = Arc::new(AtomicUsize::new(0));
+    let outer = (0..10)
+        .map(|_| {
+            let inner_pool = Arc::clone(&pool);
+            let counter = Arc::clone(&counter);
+            pool.spawn_with_handle(move || {
+                for _ in 0..100 {
+                    let counter = Arc::clone(&counter);
+                    inner_pool.spawn(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in outer {
+        handle.join()?;
+    }
+    match Arc::try_unwrap(pool) {
+        Ok(pool) => drop(pool),
+        Err(_) => panic!("jobs still hold the pool"),
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 1000);
+    Ok(())
+}
+
+// Handles should hand back results and report panics
+#[test]
+fn spawn_with_handle() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let handle = pool.spawn_with_handle(|| 6 * 7);
+    let panicked = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert_eq!(handle.join()?, 42);
+    assert!(matches!(panicked.join(), Err(KvsError::JobPanicked)));
+    Ok(())
+}
+
+// Scoped jobs should be able to borrow from the caller and all finish before scope returns
+#[test]
+fn scoped_spawn() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    let values: Vec<u64> = (1..=1000).collect();
+    let visited = AtomicUsize::new(0);
+    let total: u64 = scope(&pool, |s| {
+        let handles: Vec<_> = values
+            .chunks(100)
+            .map(|chunk| {
+                s.spawn(|| {
+                    visited.fetch_add(chunk.len(), Ordering::SeqCst);
+                    chunk.iter().sum::<u64>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum()
+    });
+    assert_eq!(total, 500500);
+    assert_eq!(visited.load(Ordering::SeqCst), 1000);
+    Ok(())
+}
+
+#[test]
+fn priority_thread_pool_spawn_counter() -> Result<()> {
+    let pool = PriorityThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn priority_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<PriorityThreadPool>()
+}
+
+// Queued jobs should run most urgent first, in the order they were spawned within a priority
+#[test]
+fn priority_thread_pool_order() -> Result<()> {
+    let pool = PriorityThreadPool::new(1)?;
+    let (unblock, blocked) = channel::<()>();
+    pool.spawn(move || blocked.recv().unwrap());
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for (id, priority) in [
+        (0, Priority::Low),
+        (1, Priority::Normal),
+        (2, Priority::High),
+        (3, Priority::Normal),
+        (4, Priority::High),
+    ] {
+        let order = Arc::clone(&order);
+        pool.spawn_with_priority(priority, move || order.lock().unwrap().push(id));
+    }
+    unblock.send(()).unwrap();
+    drop(pool);
+    assert_eq!(*order.lock().unwrap(), vec![2, 4, 1, 3, 0]);
+    Ok(())
+}
+
+// Metrics should count jobs while they wait, while they run, and once they finish or panic
+#[test]
+fn thread_pool_metrics() -> Result<()> {
+    let pool = PriorityThreadPool::new(1)?;
+    let metrics = pool.metrics();
+    let (started, wait_started) = channel::<()>();
+    let (unblock, blocked) = channel::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+    });
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    pool.spawn(|| {});
+    wait_started.recv().unwrap();
+    assert_eq!(metrics.busy(), 1);
+    assert_eq!(metrics.queued(), 2);
+
+    unblock.send(()).unwrap();
+    drop(pool);
+    assert_eq!(metrics.busy(), 0);
+    assert_eq!(metrics.queued(), 0);
+    assert_eq!(metrics.completed(), 3);
+    assert_eq!(metrics.panics(), 1);
+    Ok(())
+}
+
+// Zero threads should mean one per cpu, and workers should carry the configured name
+#[test]
+fn thread_pool_config() -> Result<()> {
+    let config = ThreadPoolConfig::new(0)
+        .with_name("test-pool")
+        .with_pinned_cores();
+    assert_eq!(
+        config.thread_count() as usize,
+        thread::available_parallelism().unwrap().get()
+    );
+
+    let pool = SharedQueueThreadPool::with_config(config.clone())?;
+    let name = pool.spawn_with_handle(|| thread::current().name().map(str::to_owned));
+    assert!(name.join()?.unwrap().starts_with("test-pool-"));
+    let pool = RayonThreadPool::with_config(config)?;
+    let name = pool.spawn_with_handle(|| thread::current().name().map(str::to_owned));
+    assert!(name.join()?.unwrap().starts_with("test-pool-"));
+    Ok(())
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// Blocking jobs and futures handed to a pool should resolve to their result, or fail on panic
+#[test]
+fn async_pool() -> Result<()> {
+    let pool = AsyncPool::new(SharedQueueThreadPool::new(2)?);
+    assert_eq!(block_on(pool.spawn_blocking(|| 6 * 7))?, 42);
+    assert!(matches!(
+        block_on(pool.spawn_blocking(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })),
+        Err(KvsError::JobPanicked)
+    ));
+
+    let inner = pool.clone();
+    let future = pool.spawn(async move { inner.spawn_blocking(|| 2).await.unwrap() * 21 });
+    assert_eq!(block_on(future)?, 42);
+    assert!(matches!(
+        block_on(pool.spawn(async {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })),
+        Err(KvsError::JobPanicked)
+    ));
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::protocol::KvRequest;
+use kvs::txn::Transactions;
+use kvs::{KvsError, Result};
+
+// Commits should wait for a write outside of transactions to be noted, and then conflict with it
+#[test]
+fn commit_waits_for_writes() -> Result<()> {
+    let transactions: Arc<Transactions<String, String>> = Arc::default();
+    let key = "key".to_owned();
+    let txn = transactions.begin();
+    transactions.read(txn, &key)?;
+    transactions.write(txn, KvRequest::Set((key.clone(), "txn".to_owned())))?;
+
+    let written = transactions.write_guard();
+    let (sender, receiver) = mpsc::channel();
+    let committing = transactions.clone();
+    let handle = thread::spawn(move || {
+        let result = committing.commit(txn).map(|_| ());
+        sender.send(()).unwrap();
+        result
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(receiver.try_recv().is_err());
+    transactions.written(&key);
+    drop(written);
+
+    assert!(matches!(handle.join().unwrap(), Err(KvsError::Conflict)));
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use kvs::engine::MergeOperator;
+use kvs::values::{Bitmap, HyperLogLog, List, Map, Set, ValueMerge, ValueOp, MAX_BITMAP_BITS};
+use kvs::{KvsError, Result};
+
+// Should estimate distinct items within a few percent, and count sketches merged together
+#[test]
+fn hyperloglog_count() -> Result<()> {
+    let mut hll = HyperLogLog::new();
+    assert_eq!(hll.count(), 0);
+    for item in 0..100_000 {
+        hll.add(format!("item{}", item).as_bytes());
+        hll.add(format!("item{}", item).as_bytes());
+    }
+    let count = hll.count() as f64;
+    assert!((count - 100_000.0).abs() < 10_000.0, "{}", count);
+
+    let mut other = HyperLogLog::new();
+    for item in 50_000..150_000 {
+        other.add(format!("item{}", item).as_bytes());
+    }
+    hll.union(&other);
+    let count = hll.count() as f64;
+    assert!((count - 150_000.0).abs() < 15_000.0, "{}", count);
+    assert_eq!(HyperLogLog::decode(&hll.encode())?, hll);
+    assert!(matches!(
+        HyperLogLog::decode("hll:00"),
+        Err(KvsError::InvalidValue)
+    ));
+    Ok(())
+}
+
+// Should set and clear bits, refusing those past the limit
+#[test]
+fn bitmap_bits() -> Result<()> {
+    let mut bitmap = Bitmap::default();
+    bitmap.set(0, true)?;
+    bitmap.set(1000, true)?;
+    bitmap.set(5000, false)?;
+    assert!(bitmap.get(0) && bitmap.get(1000) && !bitmap.get(1) && !bitmap.get(5000));
+    assert_eq!(Bitmap::decode(&bitmap.encode())?, bitmap);
+    assert!(matches!(
+        bitmap.set(MAX_BITMAP_BITS, true),
+        Err(KvsError::InvalidValue)
+    ));
+    Ok(())
+}
+
+// Should apply operands to missing values and leave values they don't fit as they are
+#[test]
+fn value_merge() -> Result<()> {
+    let key = "key".to_owned();
+    let incr = ValueOp::Incr(5).encode()?;
+    assert_eq!(
+        ValueMerge.merge(&key, None, incr.clone()),
+        Some("5".to_owned())
+    );
+    assert_eq!(
+        ValueMerge.merge(&key, Some("-7".to_owned()), incr.clone()),
+        Some("-2".to_owned())
+    );
+    assert_eq!(
+        ValueMerge.merge(&key, Some("text".to_owned()), incr),
+        Some("text".to_owned())
+    );
+    assert_eq!(
+        ValueMerge.merge(&key, Some("1".to_owned()), "garbage".to_owned()),
+        Some("1".to_owned())
+    );
+    let hll = ValueMerge
+        .merge(&key, None, ValueOp::PfAdd(vec!["a".to_owned()]).encode()?)
+        .unwrap();
+    assert_eq!(HyperLogLog::decode(&hll)?.count(), 1);
+    Ok(())
+}
+
+// Should push and pop at both ends of lists and drop emptied lists and sets
+#[test]
+fn list_and_set_ops() -> Result<()> {
+    let apply = |ops: &[ValueOp]| -> Result<Option<String>> {
+        let mut value = None;
+        for op in ops {
+            value = op.apply(value.as_deref())?;
+        }
+        Ok(value)
+    };
+    let list = apply(&[
+        ValueOp::RPush(vec!["b".to_owned(), "c".to_owned()]),
+        ValueOp::LPush(vec!["a".to_owned(), "z".to_owned()]),
+        ValueOp::RPop,
+    ])?;
+    let items: Vec<String> = List::decode(&list.unwrap())?.items.into();
+    assert_eq!(items, vec!["z", "a", "b"]);
+    assert_eq!(
+        apply(&[ValueOp::RPush(vec!["a".to_owned()]), ValueOp::LPop])?,
+        None
+    );
+
+    let set = apply(&[
+        ValueOp::SAdd(vec!["b".to_owned(), "a".to_owned(), "b".to_owned()]),
+        ValueOp::SRem(vec!["c".to_owned()]),
+    ])?;
+    let members: Vec<String> = Set::decode(&set.unwrap())?.members.into_iter().collect();
+    assert_eq!(members, vec!["a", "b"]);
+    assert_eq!(
+        apply(&[
+            ValueOp::SAdd(vec!["a".to_owned()]),
+            ValueOp::SRem(vec!["a".to_owned()])
+        ])?,
+        None
+    );
+    assert!(matches!(
+        ValueOp::LPop.apply(Some("set:[]")),
+        Err(KvsError::InvalidValue)
+    ));
+    Ok(())
+}
+
+// Should set and remove single fields of maps, dropping emptied maps
+#[test]
+fn map_ops() -> Result<()> {
+    let map = ValueOp::HSet(("b".to_owned(), "2".to_owned())).apply(Some("map:{\"a\":\"1\"}"))?;
+    let map = ValueOp::HSet(("a".to_owned(), "3".to_owned())).apply(map.as_deref())?;
+    let fields = Map::decode(&map.clone().unwrap())?.fields;
+    assert_eq!(fields["a"], "3");
+    assert_eq!(fields["b"], "2");
+    let map = ValueOp::HDel(vec!["a".to_owned(), "c".to_owned()]).apply(map.as_deref())?;
+    assert_eq!(Map::decode(&map.clone().unwrap())?.fields.len(), 1);
+    assert_eq!(
+        ValueOp::HDel(vec!["b".to_owned()]).apply(map.as_deref())?,
+        None
+    );
+    Ok(())
+}
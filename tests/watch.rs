@@ -0,0 +1,23 @@
+use std::time::{Duration, SystemTime};
+
+use kvs::watch::Watcher;
+
+// Keys written after being listed as expired should no longer be taken for removal
+#[test]
+fn expiry_rechecked() {
+    let watcher: Watcher<String, String> = Watcher::default();
+    let now = SystemTime::now();
+    watcher.expire_at("rewritten".to_owned(), now - Duration::from_secs(2));
+    watcher.expire_at("extended".to_owned(), now - Duration::from_secs(1));
+    watcher.expire_at("expired".to_owned(), now);
+    watcher.expire_at("later".to_owned(), now + Duration::from_secs(60));
+    assert_eq!(watcher.expired(now), ["rewritten", "extended", "expired"]);
+
+    watcher.clear_expiry(&"rewritten".to_owned());
+    watcher.expire_at("extended".to_owned(), now + Duration::from_secs(60));
+    assert_eq!(watcher.take_expired(&"rewritten".to_owned(), now), None);
+    assert_eq!(watcher.take_expired(&"extended".to_owned(), now), None);
+    let expired = watcher.take_expired(&"expired".to_owned(), now).unwrap();
+    assert_eq!(expired.expired_at, now);
+    assert!(watcher.expired(now).is_empty());
+}